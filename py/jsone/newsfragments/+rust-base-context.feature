@@ -0,0 +1 @@
+The Rust crate now supports a base context shared by every render with a `Renderer`, via `Renderer::base_context`.
@@ -0,0 +1 @@
+The Rust crate now prints a parsed expression in canonical form, with only the parentheses that precedence requires, with `ParsedExpression::canonical` or the alternate `{:#}` format.
@@ -0,0 +1 @@
+The Rust crate now includes a `json-e` command for rendering and validating template files.
@@ -0,0 +1 @@
+The Rust crate now takes the current time from a per-renderer `Clock`, or a fixed time given to `Renderer::now` or `CompiledTemplate::render_at`, and deprecates the process-wide `use_test_now`, which will be removed in the next major version.
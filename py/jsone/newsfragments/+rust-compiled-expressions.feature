@@ -0,0 +1 @@
+The Rust crate compiles expressions once, when a template is compiled, rather than parsing numbers and resolving operators each time they are evaluated.
@@ -0,0 +1 @@
+The Rust crate now supports compiling a template once with `Renderer::compile` and rendering the resulting `CompiledTemplate` with many contexts.
@@ -0,0 +1 @@
+The Rust crate now supports custom functions, which may be closures, through the new `Renderer` type.
@@ -0,0 +1 @@
+The Rust crate now supports custom `$`-operators, registered with `Renderer::operator`.
@@ -0,0 +1 @@
+The Rust crate now fails to render a template nested more than 256 levels deep with `ErrorKind::StackLimitExceeded`, by default, rather than overflowing the stack.  Context values are checked against the stack limit only when it is given with `Limits::max_stack`.
@@ -0,0 +1 @@
+Errors from the Rust crate now carry the location in the template at which they occurred, including the offset within expressions.
@@ -0,0 +1 @@
+The Rust crate now supports parsing and evaluating single expressions, outside of templates, with `parse_expression` and `evaluate_expression`.
@@ -0,0 +1 @@
+The Rust crate now supports finding the context values a template refers to, with `free_variables`.
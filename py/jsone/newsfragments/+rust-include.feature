@@ -0,0 +1 @@
+The Rust crate now supports an `$include` operator, loading templates by name through a `Loader` given to `Renderer::loader`.
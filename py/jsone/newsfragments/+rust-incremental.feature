@@ -0,0 +1 @@
+The Rust crate now renders templates incrementally with the optional `incremental` feature, rendering again only the parts of the result affected by a change to the context and reporting the changes as a JSON Patch.
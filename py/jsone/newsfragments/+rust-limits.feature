@@ -0,0 +1 @@
+The Rust crate can limit the resources used to render untrusted templates, and parses deeply nested expressions in linear time.
@@ -0,0 +1 @@
+The Rust crate now renders the iterations of `$map` and the values of plain objects in parallel with the optional `parallel` feature.
@@ -0,0 +1 @@
+The Rust crate now supports rendering a template with only some of its context, producing a residual template, with `partial_render`.
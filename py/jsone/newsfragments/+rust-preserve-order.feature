@@ -0,0 +1 @@
+The Rust crate now supports keeping the key order of templates and contexts in rendered objects with the optional `preserve_order` feature.
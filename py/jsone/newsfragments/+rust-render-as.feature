@@ -0,0 +1 @@
+The Rust crate now supports rendering from and into any serde type, with `Renderer::render_as` and `CompiledTemplate::render_as`.
@@ -0,0 +1 @@
+The Rust crate now supports streaming rendered output to a writer or serde serializer with `render_to_writer` and `render_to_serializer`.
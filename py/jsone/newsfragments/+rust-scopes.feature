@@ -0,0 +1 @@
+The Rust crate no longer allocates a new scope for each iteration of `$map`, `$reduce`, and `$find`, and binds `$let` values and the render context without copying them.
@@ -0,0 +1 @@
+The Rust crate no longer copies context values each time an expression refers to them, which makes templates that use large contexts much faster.
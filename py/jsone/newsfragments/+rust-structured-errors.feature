@@ -0,0 +1 @@
+Errors from the Rust crate are now a single `json_e::Error` type, with an `ErrorKind` giving structured information about the failure.
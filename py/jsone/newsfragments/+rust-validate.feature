@@ -0,0 +1 @@
+The Rust crate now supports checking a template for errors without rendering it, with `validate`.
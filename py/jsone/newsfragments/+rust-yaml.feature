@@ -0,0 +1 @@
+The Rust crate now supports YAML templates, contexts, and output with the optional `yaml` feature.
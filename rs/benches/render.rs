@@ -0,0 +1,149 @@
+//! Benchmarks of rendering with large contexts, where copying context values dominates, and of
+//! rendering templates dominated by evaluating expressions.
+//!
+//! Run with `cargo bench`, optionally followed by `--` and a substring of the names of the
+//! benchmarks to run.  Each is run for about a second, after warming up, and its mean time per
+//! iteration is printed.
+
+use json_e::Renderer;
+use serde_json::{json, Value};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Time the given function, if the benchmark is selected, printing its mean time per iteration.
+fn bench<R>(name: &str, mut f: impl FnMut() -> R) {
+    if let Some(filter) = std::env::args().skip(1).find(|a| !a.starts_with("--")) {
+        if !name.contains(&filter) {
+            return;
+        }
+    }
+
+    // warm up, finding how many iterations run in about a second
+    let start = Instant::now();
+    let mut iterations = 0;
+    while start.elapsed() < Duration::from_millis(200) {
+        black_box(f());
+        iterations += 1;
+    }
+    let iterations = iterations * 5;
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(f());
+    }
+    println!(
+        "{:<45} {:>12.3?}/iter ({} iterations)",
+        name,
+        start.elapsed() / iterations,
+        iterations
+    );
+}
+
+/// A context with `items`, an array of the given length, and `data`, an object of 1000
+/// properties with 100-character values.
+fn context(items: usize) -> Value {
+    let data: serde_json::Map<String, Value> = (0..1000)
+        .map(|i| (format!("k{}", i), json!("x".repeat(100))))
+        .collect();
+    json!({
+        "items": (0..items).map(|i| json!({"id": i, "name": format!("item {}", i)})).collect::<Vec<_>>(),
+        "data": data,
+    })
+}
+
+fn lookups() {
+    let context = context(1000);
+
+    // every iteration looks up the large `data` object
+    let template = Renderer::new().compile(&json!({
+        "$map": {"$eval": "items"},
+        "each(x)": {"$eval": "data.k1 + x.name"},
+    }));
+    bench("map with lookups in a large object", || {
+        template.render(black_box(&context)).unwrap()
+    });
+
+    let template = Renderer::new().compile(&json!({
+        "$map": {"$eval": "items"},
+        "each(x)": {"$eval": "data['k' + str(x.id)]"},
+    }));
+    bench("map with indexing into a large object", || {
+        template.render(black_box(&context)).unwrap()
+    });
+
+    let template = Renderer::new().compile(&json!({
+        "$let": {"all": {"$eval": "items"}},
+        "in": {"$map": {"$eval": "items[:100]"}, "each(x)": {"$eval": "len(all)"}},
+    }));
+    bench("map with lookups in a large array", || {
+        template.render(black_box(&context)).unwrap()
+    });
+}
+
+fn loops() {
+    let context = context(10000);
+
+    let template = Renderer::new().compile(&json!({
+        "$map": {"$eval": "items"},
+        "each(x,i)": {"$eval": "x.id + i"},
+    }));
+    bench("map over a large array", || {
+        template.render(black_box(&context)).unwrap()
+    });
+
+    let template = Renderer::new().compile(&json!({
+        "$reduce": {"$eval": "items"},
+        "initial": 0,
+        "each(acc,x)": {"$eval": "acc + x.id"},
+    }));
+    bench("reduce over a large array", || {
+        template.render(black_box(&context)).unwrap()
+    });
+
+    let template = Renderer::new().compile(&json!({
+        "$sort": {"$eval": "items"},
+        "by(x)": "x.name",
+    }));
+    bench("sort a large array by a property", || {
+        template.render(black_box(&context)).unwrap()
+    });
+}
+
+fn merges() {
+    let context = context(1000);
+    let template = Renderer::new().compile(&json!({
+        "$mergeDeep": [{"$eval": "{d: data}"}, {"$eval": "{d: data}"}, {"d": {"k0": "y"}}],
+    }));
+    bench("mergeDeep of large objects", || {
+        template.render(black_box(&context)).unwrap()
+    });
+}
+
+fn expressions() {
+    let context = context(10000);
+
+    // the output is small, so evaluating expressions dominates
+    let template = Renderer::new().compile(&json!({
+        "$reduce": {"$eval": "items"},
+        "initial": 0,
+        "each(acc,x)": {"$eval": "acc + (x.id * 2 + 1) ** 2 / 4 - 0.5 + len([x.id > 100 && x.id in [1, 2, 3] || x.id < 10])"},
+    }));
+    bench("reduce with arithmetic and comparisons", || {
+        template.render(black_box(&context)).unwrap()
+    });
+
+    let template = Renderer::new().compile(&json!({
+        "$map": {"$eval": "items"},
+        "each(x)": "${x.name[5:]}-${x.id + 1}-${len(x.name)}",
+    }));
+    bench("map with interpolations", || {
+        template.render(black_box(&context)).unwrap()
+    });
+}
+
+fn main() {
+    lookups();
+    loops();
+    merges();
+    expressions();
+}
@@ -29,7 +29,26 @@ fn main() {
     let mut test_file = File::create(&test_path).unwrap();
 
     writeln!(test_file, "use serde_json::Value;").unwrap();
-    writeln!(test_file, "use json_e::{{render, use_test_now}};").unwrap();
+    writeln!(test_file, "use json_e::{{Error, Renderer}};").unwrap();
+    writeln!(
+        test_file,
+        r##"
+/// Render with the time the specification uses for `now`, 2017-01-19T16:27:20.974Z, checking
+/// that rendering to a writer gives the same result
+fn render(template: &Value, context: &Value) -> Result<Value, Error> {{
+    let now = std::time::UNIX_EPOCH + std::time::Duration::from_millis(1484843240974);
+    let template = Renderer::new().now(now).compile(template);
+    let result = template.render(context);
+    let mut output = Vec::new();
+    match (&result, template.render_to_writer(context, &mut output)) {{
+        (Ok(value), Ok(())) => assert_eq!(&serde_json::from_slice::<Value>(&output).unwrap(), value),
+        (Err(err), Err(streamed)) => assert_eq!(err, &streamed),
+        (result, streamed) => panic!("render gave {{:?}} but render_to_writer gave {{:?}}", result, streamed),
+    }}
+    result
+}}"##
+    )
+    .unwrap();
 
     let mut section = String::from("unknown");
 
@@ -41,6 +60,7 @@ fn main() {
     let error_key = Yaml::String("error".into());
 
     let mut test_names = HashSet::new();
+    let mut templates = vec![];
 
     for item in spec {
         if let Yaml::Hash(ref h) = item {
@@ -55,6 +75,7 @@ fn main() {
             let template = h.get(&template_key).unwrap();
             let result = h.get(&result_key);
             let error = h.get(&error_key);
+            templates.push(to_json(template));
 
             write_test(
                 &mut test_file,
@@ -70,6 +91,10 @@ fn main() {
             panic!("YAML sub-document is not an object: {:?}", item);
         }
     }
+
+    // the templates alone, for unit tests of the expressions they contain
+    let templates_path = Path::new(&out_dir).join("spec_templates.json");
+    std::fs::write(templates_path, to_string(&Value::Array(templates)).unwrap()).unwrap();
 }
 
 /// Convert the given Yaml value to a serde_json::Value
@@ -119,20 +144,22 @@ fn write_test(
 
     let context = to_json_str(context);
     let template = to_json_str(template);
+    // the description is printed as a format string, so any braces in it are escaped
+    let description = format!("{} - {}", section, title)
+        .replace('{', "{{")
+        .replace('}', "}}");
 
     write!(
         test_file,
         r##"
 #[test]
 fn {test_name}() {{
-    use_test_now();
-    println!("{{}} - {{}}", {section:?}, {title:?});
+    println!({description:?});
     let context: Value = serde_json::from_str(r#"{context}"#).unwrap();
     let template: Value = serde_json::from_str(r#"{template}"#).unwrap();
 "##,
         test_name = test_name,
-        section = section,
-        title = title,
+        description = description,
         context = context,
         template = template
     )
@@ -0,0 +1,628 @@
+//! Static analysis of templates.
+
+use crate::errors::{Error, Location};
+use crate::interpreter::{Context, Node};
+use crate::render::{condition_error, Renderer};
+use crate::template::{Expression, Fragment, Interpolation, Operator, Template};
+use crate::value::Value;
+use serde_json::Value as SerdeValue;
+
+/// A reference from a template to a value it expects to find in the context.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FreeVariable {
+    name: String,
+    location: Location,
+}
+
+impl FreeVariable {
+    /// The name of the context value
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The location of the reference in the template.  Its offset is that of the beginning of the
+    /// expression containing the reference, as for errors in that expression.
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+}
+
+/// Find the context values that the given template may refer to, with the location of each
+/// reference.  See [`CompiledTemplate::free_variables`](crate::CompiledTemplate::free_variables).
+pub fn free_variables(template: &SerdeValue) -> Vec<FreeVariable> {
+    Renderer::new().compile(template).free_variables()
+}
+
+/// Find every error in the given template that does not depend on the context, such as unknown
+/// operators, undefined operator properties, and syntax errors in expressions, with the location
+/// of each.  See [`CompiledTemplate::validate`](crate::CompiledTemplate::validate).
+pub fn validate(template: &SerdeValue) -> Result<(), Vec<Error>> {
+    Renderer::new().compile(template).validate()
+}
+
+/// The result of analyzing a compiled template.
+pub(crate) struct Analysis {
+    /// The free variables in the template: those names in its expressions that are not bound by
+    /// an operator within the template, and are not functions or `now`
+    pub(crate) free: Vec<FreeVariable>,
+    /// The errors found when compiling the template, which would otherwise be deferred until the
+    /// part of the template containing them is rendered
+    pub(crate) errors: Vec<Error>,
+    /// Whether the template depends on the current time, through `now`, `fromNow`, or `$fromNow`
+    /// without `from`
+    pub(crate) uses_now: bool,
+    /// Whether the template refers to `defined`, whose result depends on which names are in the
+    /// context
+    pub(crate) uses_defined: bool,
+    /// Whether the template renders other templates that are not known until it is rendered,
+    /// through `$include` or `$find`, or contains custom operators
+    pub(crate) opaque: bool,
+}
+
+/// Analyze a compiled template, given the functions available to it.
+pub(crate) fn analyze(template: &Template, functions: &Context) -> Analysis {
+    let mut analyzer = Analyzer {
+        functions,
+        path: Vec::new(),
+        bound: Vec::new(),
+        free: Vec::new(),
+        errors: Vec::new(),
+        uses_now: false,
+        uses_defined: false,
+        opaque: false,
+    };
+    analyzer.template(template);
+    Analysis {
+        free: analyzer.free,
+        errors: analyzer.errors,
+        uses_now: analyzer.uses_now,
+        uses_defined: analyzer.uses_defined,
+        opaque: analyzer.opaque,
+    }
+}
+
+struct Analyzer<'a> {
+    functions: &'a Context<'a>,
+    /// The path to the part of the template being analyzed
+    path: Vec<String>,
+    /// The names bound by operators enclosing the part of the template being analyzed
+    bound: Vec<String>,
+    free: Vec<FreeVariable>,
+    errors: Vec<Error>,
+    uses_now: bool,
+    uses_defined: bool,
+    opaque: bool,
+}
+
+impl<'a> Analyzer<'a> {
+    /// Analyze within the given path segment.
+    fn at<F: FnOnce(&mut Self)>(&mut self, segment: &str, f: F) {
+        self.path.push(segment.to_owned());
+        f(self);
+        self.path.pop();
+    }
+
+    /// Analyze with the given names bound.
+    fn bind<'n, F, I>(&mut self, names: I, f: F)
+    where
+        F: FnOnce(&mut Self),
+        I: IntoIterator<Item = &'n str>,
+    {
+        let len = self.bound.len();
+        self.bound.extend(names.into_iter().map(str::to_owned));
+        f(self);
+        self.bound.truncate(len);
+    }
+
+    fn template(&mut self, template: &Template) {
+        match template {
+            Template::Literal(_) => {}
+            Template::Error(e) => self.error(e),
+            Template::String(_, s) => self.interpolation(s),
+            Template::Array(elements) => {
+                for (i, e) in elements.iter().enumerate() {
+                    self.at(&i.to_string(), |a| a.template(e));
+                }
+            }
+            // keys containing interpolations are among the properties
+            Template::Object { properties, .. } => {
+                for (raw, k, v) in properties {
+                    self.at(raw, |a| {
+                        a.interpolation(k);
+                        a.template(v);
+                    });
+                }
+            }
+            Template::Operator {
+                dynamic_keys,
+                key,
+                operator,
+                ..
+            } => {
+                for (raw, k) in dynamic_keys {
+                    self.at(raw, |a| a.interpolation(k));
+                }
+                self.at(key, |a| match operator {
+                    Ok(operator) => a.operator(operator),
+                    Err(e) => a.error(e),
+                });
+            }
+            Template::At(segment, template) => self.at(segment, |a| a.template(template)),
+        }
+    }
+
+    fn operator(&mut self, operator: &Operator) {
+        match operator {
+            Operator::Eval(expr) => self.expression(expr),
+            Operator::Flatten(value)
+            | Operator::FlattenDeep(value)
+            | Operator::Json(value)
+            | Operator::Merge(value)
+            | Operator::MergeDeep(value)
+            | Operator::Reverse(value) => self.template(value),
+            Operator::FromNow { value, from } => {
+                self.uses_now |= from.is_none();
+                self.template(value);
+                self.templates(from);
+            }
+            Operator::If {
+                condition,
+                then,
+                else_,
+            } => {
+                self.expression(condition);
+                self.templates(then);
+                self.templates(else_);
+            }
+            // the included template is not known until it is rendered
+            Operator::Include { name, with } => {
+                self.opaque = true;
+                self.template(name);
+                self.templates(with);
+            }
+            Operator::Let { bindings, in_ } => {
+                self.template(bindings);
+                self.bind(bound_keys(bindings), |a| a.template(in_));
+            }
+            Operator::Map {
+                value,
+                value_var,
+                index_var,
+                each,
+            } => {
+                self.template(value);
+                let names = Some(value_var.as_str()).into_iter().chain(index_var.as_deref());
+                self.bind(names, |a| a.template(each));
+            }
+            Operator::Reduce {
+                value,
+                acc_var,
+                value_var,
+                index_var,
+                each,
+                ..
+            } => {
+                self.template(value);
+                let names = vec![acc_var.as_str(), value_var.as_str()]
+                    .into_iter()
+                    .chain(index_var.as_deref());
+                self.bind(names, |a| a.template(each));
+            }
+            // the found value is itself rendered as a template, but is not known until then
+            Operator::Find {
+                value,
+                value_var,
+                index_var,
+                each,
+                ..
+            } => {
+                self.opaque = true;
+                self.template(value);
+                let names = Some(value_var.as_str()).into_iter().chain(index_var.as_deref());
+                self.bind(names, |a| a.expression(each));
+            }
+            Operator::Match(cases) => self.cases("$match", cases),
+            Operator::Switch { cases, default } => {
+                self.cases("$switch", cases);
+                self.templates(default);
+            }
+            Operator::Sort { value, by } => {
+                self.template(value);
+                match by {
+                    Some(Ok((var, expr))) => self.bind(Some(var.as_str()), |a| a.expression(expr)),
+                    Some(Err(e)) => self.error(e),
+                    None => {}
+                }
+            }
+            Operator::Custom {
+                value, properties, ..
+            } => {
+                self.opaque = true;
+                self.template(value);
+                for (_, property) in properties {
+                    self.template(property);
+                }
+            }
+        }
+    }
+
+    fn templates(&mut self, template: &Option<Template>) {
+        if let Some(template) = template {
+            self.template(template);
+        }
+    }
+
+    /// Analyze the cases of `$match` or `$switch`, reporting a condition that does not parse as
+    /// rendering would.
+    fn cases(&mut self, operator: &str, cases: &[(Expression, Template)]) {
+        for (condition, template) in cases {
+            if condition.node().is_err() {
+                self.error(&condition_error(operator, condition));
+            } else {
+                self.expression(condition);
+            }
+            self.template(template);
+        }
+    }
+
+    fn interpolation(&mut self, interpolation: &Interpolation) {
+        for fragment in interpolation.fragments() {
+            match fragment {
+                Fragment::Literal(_) => {}
+                Fragment::Expression(code, _, offset) => self.node(code.node(), *offset),
+                Fragment::Error(e) => self.error(e),
+            }
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        let analyze = |a: &mut Self| match expression.node() {
+            Ok(node) => a.node(node, 0),
+            Err(e) => a.error(&e),
+        };
+        match expression.segment() {
+            Some(segment) => self.at(segment, analyze),
+            None => analyze(self),
+        }
+    }
+
+    /// Analyze an expression node, within an expression at the given offset.
+    fn node(&mut self, node: &Node, offset: usize) {
+        match node {
+            Node::Ident(name) => self.reference(name, offset),
+            Node::Number(_) | Node::String(_) | Node::Null | Node::True | Node::False => {}
+            Node::Array(items) => {
+                for item in items {
+                    self.node(item, offset);
+                }
+            }
+            // object keys are strings, not references
+            Node::Object(properties) => {
+                for (_, value) in properties {
+                    self.node(value, offset);
+                }
+            }
+            Node::Un(_, operand) | Node::Dot(operand, _) => self.node(operand, offset),
+            Node::Op(left, _, right) | Node::Index(left, right) => {
+                self.node(left, offset);
+                self.node(right, offset);
+            }
+            Node::Slice(value, begin, end) => {
+                self.node(value, offset);
+                for bound in begin.iter().chain(end.iter()) {
+                    self.node(bound, offset);
+                }
+            }
+            Node::Func(function, args) => {
+                self.node(function, offset);
+                for arg in args {
+                    self.node(arg, offset);
+                }
+                // the name given to `defined` as a string is a reference to it
+                if let (Node::Ident(name), [Node::String(arg)]) = (&**function, &args[..]) {
+                    if name == "defined" && !self.bound.iter().any(|b| b == name) {
+                        self.reference(arg, offset);
+                    }
+                }
+            }
+        }
+    }
+
+    fn reference(&mut self, name: &str, offset: usize) {
+        if self.bound.iter().any(|b| b == name) {
+            return;
+        }
+        if name == "now" || name == "fromNow" {
+            self.uses_now = true;
+        }
+        self.uses_defined |= name == "defined";
+        if name == "now" || self.functions.get(name).is_some() {
+            return;
+        }
+        self.free.push(FreeVariable {
+            name: name.to_owned(),
+            location: Location::new(self.path.clone(), Some(offset)),
+        });
+    }
+
+    /// Record an error at the current path, just as it would be located if it were rendered.
+    fn error(&mut self, error: &Error) {
+        let error = self
+            .path
+            .iter()
+            .rev()
+            .fold(error.clone(), |e, segment| e.add_location(segment));
+        self.errors.push(error);
+    }
+}
+
+/// The names bound by `$let`, where they are known without rendering: those keys of the bindings
+/// that contain no interpolations.
+fn bound_keys(bindings: &Template) -> Vec<&str> {
+    match bindings {
+        Template::Literal(Value::Object(o)) => o.keys().map(String::as_str).collect(),
+        Template::Object { properties, .. } => properties
+            .iter()
+            .filter_map(|(_, k, _)| k.as_literal())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    /// The free variables in the given template, as (name, pointer, offset)
+    fn free(template: SerdeValue) -> Vec<(String, String, Option<usize>)> {
+        free_variables(&template)
+            .into_iter()
+            .map(|v| (v.name().to_owned(), v.location().pointer(), v.location().offset()))
+            .collect()
+    }
+
+    fn var(name: &str, pointer: &str, offset: usize) -> (String, String, Option<usize>) {
+        (name.to_owned(), pointer.to_owned(), Some(offset))
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(free(json!({"a": [1, "two", null]})), vec![]);
+    }
+
+    #[test]
+    fn interpolation() {
+        assert_eq!(
+            free(json!({"${f}": 1, "a": ["x", "${b} and ${c.d[e]}"]})),
+            vec![
+                var("f", "/${f}", 2),
+                var("b", "/a/1", 2),
+                var("c", "/a/1", 11),
+                var("e", "/a/1", 11),
+            ]
+        );
+    }
+
+    #[test]
+    fn expressions() {
+        assert_eq!(
+            free(json!({"$eval": "[a, {k: -b}, c[1:d], e.f, g(h)]"})),
+            vec![
+                var("a", "/$eval", 0),
+                var("b", "/$eval", 0),
+                var("c", "/$eval", 0),
+                var("d", "/$eval", 0),
+                var("e", "/$eval", 0),
+                var("g", "/$eval", 0),
+                var("h", "/$eval", 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn builtins_and_now_are_not_free() {
+        assert_eq!(free(json!({"$eval": "max(len(x), 1) + now"})), vec![var("x", "/$eval", 0)]);
+    }
+
+    #[test]
+    fn defined_names() {
+        assert_eq!(
+            free(json!({"$if": "defined('token') && defined(name)", "then": 1})),
+            vec![var("token", "/$if", 0), var("name", "/$if", 0)]
+        );
+        assert_eq!(
+            free(json!({"$let": {"token": 1}, "in": {"$eval": "defined('token')"}})),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!(
+            free(json!([
+                {"$if": "a", "then": "${b}", "else": {"$eval": "c"}},
+                {"$switch": {"d": 1, "$default": "${e}"}},
+                {"$match": {"f": "${g}"}},
+            ])),
+            vec![
+                var("a", "/0/$if", 0),
+                var("b", "/0/$if/then", 2),
+                var("c", "/0/$if/else/$eval", 0),
+                var("d", "/1/$switch/d", 0),
+                var("e", "/1/$switch/$default", 2),
+                var("f", "/2/$match/f", 0),
+                var("g", "/2/$match/f", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn let_bindings() {
+        assert_eq!(
+            free(json!({"$let": {"${c}": 1, "a": "${b}"}, "in": {"$eval": "a + b + c"}})),
+            vec![
+                var("c", "/$let/${c}", 2),
+                var("b", "/$let/a", 2),
+                var("b", "/$let/in/$eval", 0),
+                var("c", "/$let/in/$eval", 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn let_bindings_are_scoped() {
+        assert_eq!(
+            free(json!([{"$let": {"a": 1}, "in": "${a}"}, "${a}"])),
+            vec![var("a", "/1", 2)]
+        );
+    }
+
+    #[test]
+    fn each_bindings() {
+        assert_eq!(
+            free(json!([
+                {"$map": {"$eval": "xs"}, "each(x, i)": "${x}${i}${y}"},
+                {"$reduce": "${xs}", "initial": 0, "each(acc, x)": {"$eval": "acc + x + z"}},
+                {"$find": {"$eval": "xs"}, "each(x)": "x == w"},
+                {"$sort": {"$eval": "xs"}, "by(x)": "x[v]"},
+            ])),
+            vec![
+                var("xs", "/0/$map/$eval", 0),
+                var("y", "/0/$map/each(x, i)", 10),
+                var("xs", "/1/$reduce", 2),
+                var("z", "/1/$reduce/each(acc, x)/$eval", 0),
+                var("xs", "/2/$find/$eval", 0),
+                var("w", "/2/$find/each(x)", 0),
+                var("xs", "/3/$sort/$eval", 0),
+                var("v", "/3/$sort/by(x)", 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_operators() {
+        assert_eq!(
+            free(json!([
+                {"$json": "${a}"},
+                {"$merge": [{"$eval": "b"}]},
+                {"$fromNow": "${c}", "from": "${d}"},
+                {"$include": "${e}", "with": {"x": "${f}"}},
+            ])),
+            vec![
+                var("a", "/0/$json", 2),
+                var("b", "/1/$merge/0/$eval", 0),
+                var("c", "/2/$fromNow", 2),
+                var("d", "/2/$fromNow/from", 2),
+                var("e", "/3/$include", 2),
+                var("f", "/3/$include/with/x", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_are_ignored() {
+        assert_eq!(free(json!([{"$eval": "a +"}, {"$unknown": "${b}"}, "${c"])), vec![]);
+    }
+
+    /// The errors in the given template, as (message, pointer, offset)
+    fn errors(template: SerdeValue) -> Vec<(String, String, Option<usize>)> {
+        match validate(&template) {
+            Ok(()) => vec![],
+            Err(errors) => errors
+                .into_iter()
+                .map(|e| (e.message().to_owned(), e.location().pointer(), e.location().offset()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn valid() {
+        assert_eq!(
+            validate(&json!({"$if": "a", "then": {"$map": [1], "each(x)": "${x}"}})),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn all_errors() {
+        let template = json!({
+            "$if": "a",
+            "then": {"$mape": []},
+            "else": [
+                {"$map": [], "each(x)": 1, "extra": 2},
+                {"$sort": [], "by(x)": "x", "by(y)": "y"},
+                {"$reduce": [], "each(1)": 1, "initial": 0},
+                "${1 +}",
+                {"$eval": "x +"},
+                {"$switch": {"x ==": 1}},
+                {"$let": {"a": 1}},
+            ],
+        });
+        assert_eq!(
+            errors(template),
+            vec![
+                (
+                    "$<identifier> is reserved; use $$<identifier> ($mape)".to_owned(),
+                    "/$if/then/$mape".to_owned(),
+                    None
+                ),
+                (
+                    "$map has undefined properties: extra".to_owned(),
+                    "/$if/else/0/$map".to_owned(),
+                    None
+                ),
+                (
+                    "only one by(..) is allowed".to_owned(),
+                    "/$if/else/1/$sort".to_owned(),
+                    None
+                ),
+                (
+                    "$reduce has undefined properties: each(1)".to_owned(),
+                    "/$if/else/2/$reduce".to_owned(),
+                    None
+                ),
+                (
+                    "unterminated ${..} expression".to_owned(),
+                    "/$if/else/3".to_owned(),
+                    Some(4)
+                ),
+                (
+                    "Unexpected trailing characters +".to_owned(),
+                    "/$if/else/4/$eval".to_owned(),
+                    Some(2)
+                ),
+                (
+                    "parsing error in condition".to_owned(),
+                    "/$if/else/5/$switch/x ==".to_owned(),
+                    None
+                ),
+                (
+                    "$let operator requires an `in` clause".to_owned(),
+                    "/$if/else/6/$let".to_owned(),
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_operators() {
+        let template = json!({"$twice": 1});
+        assert!(validate(&template).is_err());
+        let renderer = Renderer::new().operator("$twice", |_: &crate::Invocation| Ok(None));
+        assert_eq!(renderer.compile(&template).validate(), Ok(()));
+    }
+
+    #[test]
+    fn renderer_functions() {
+        let renderer = Renderer::new().function("f", |_: &[SerdeValue]| Ok(json!(1)));
+        let free: Vec<_> = renderer
+            .compile(&json!({"$eval": "f(g)"}))
+            .free_variables()
+            .into_iter()
+            .map(|v| v.name().to_owned())
+            .collect();
+        assert_eq!(free, vec!["g"]);
+    }
+}
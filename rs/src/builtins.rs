@@ -1,9 +1,11 @@
 use crate::fromnow::from_now;
 use crate::interpreter::Context;
+use crate::number::Number;
 use crate::value::{Function, Value};
-use anyhow::Result;
+use crate::errors::{ErrorKind, Result};
 use lazy_static::lazy_static;
 use std::convert::TryInto;
+use std::sync::Arc;
 
 lazy_static! {
     pub(crate) static ref BUILTINS: Context<'static> = {
@@ -67,12 +69,16 @@ lazy_static! {
 
 // utility functions
 
-fn array_arithmetic<F: Fn(f64, f64) -> f64>(args: &[Value], f: F) -> Result<Value> {
+fn array_arithmetic<F: Fn(Number, Number) -> Number>(
+    name: &str,
+    args: &[Value],
+    f: F,
+) -> Result<Value> {
     let mut res = None;
     for arg in args {
-        let arg = *arg
-            .as_f64()
-            .ok_or_else(|| interpreter_error!("invalid arguments to builtin: min"))?;
+        let arg = arg
+            .as_number()
+            .ok_or_else(|| builtin_error!(name, "invalid arguments to builtin: min"))?;
         if let Some(r) = res {
             res = Some(f(arg, r));
         } else {
@@ -82,211 +88,222 @@ fn array_arithmetic<F: Fn(f64, f64) -> f64>(args: &[Value], f: F) -> Result<Valu
     if let Some(r) = res {
         Ok(Value::Number(r))
     } else {
-        Err(interpreter_error!("invalid arguments to builtin: min"))
+        Err(builtin_error!(name, "invalid arguments to builtin: min"))
     }
 }
 
-fn unary_arithmetic<F: Fn(f64) -> f64>(args: &[Value], op: F) -> Result<Value> {
+fn unary_arithmetic<F: Fn(Number) -> Number>(name: &str, args: &[Value], op: F) -> Result<Value> {
     if args.len() != 1 {
-        return Err(interpreter_error!("expected one argument"));
+        return Err(builtin_error!(name, "expected one argument"));
     }
     let v = &args[0];
 
     match v {
         Value::Number(n) => Ok(Value::Number(op(*n))),
-        _ => Err(interpreter_error!("invalid arguments to builtin")),
+        _ => Err(builtin_error!(name, "invalid arguments to builtin")),
     }
 }
 
-fn unary_string<F: Fn(&str) -> String>(args: &[Value], op: F) -> Result<Value> {
+fn unary_string<F: Fn(&str) -> String>(name: &str, args: &[Value], op: F) -> Result<Value> {
     if args.len() != 1 {
-        return Err(interpreter_error!("expected one argument"));
+        return Err(builtin_error!(name, "expected one argument"));
     }
     let v = &args[0];
 
     match v {
-        Value::String(s) => Ok(Value::String(op(s.as_ref()))),
-        _ => Err(interpreter_error!("invalid arguments to builtin")),
+        Value::String(s) => Ok(Value::String(op(s).into())),
+        _ => Err(builtin_error!(name, "invalid arguments to builtin")),
     }
 }
 
 // builtin implementations
 
 fn abs_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
-    unary_arithmetic(args, f64::abs)
+    unary_arithmetic("abs", args, Number::abs)
 }
 
 fn str_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
     if args.len() != 1 {
-        return Err(interpreter_error!("str expects one argument"));
+        return Err(builtin_error!("str", "str expects one argument"));
     }
     let v = &args[0];
 
     match v {
         Value::Null | Value::String(_) | Value::Number(_) | Value::Bool(_) => {
-            v.stringify().map(Value::String)
+            v.stringify().map(|s| Value::String(s.into()))
         }
-        _ => Err(interpreter_error!("invalid arguments to builtin: str")),
+        _ => Err(builtin_error!("str", "invalid arguments to builtin: str")),
     }
 }
 
 fn len_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
     if args.len() != 1 {
-        return Err(interpreter_error!("len expects one argument"));
+        return Err(builtin_error!("len", "len expects one argument"));
     }
     let v = &args[0];
 
     match v {
-        Value::String(s) => Ok(Value::Number(s.chars().count() as f64)),
-        Value::Array(a) => Ok(Value::Number(a.len() as f64)),
-        _ => Err(interpreter_error!("invalid arguments to builtin: len")),
+        Value::String(s) => Ok(Value::Number(s.chars().count().into())),
+        Value::Array(a) => Ok(Value::Number(a.len().into())),
+        _ => Err(builtin_error!("len", "invalid arguments to builtin: len")),
     }
 }
 
 fn min_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
-    array_arithmetic(args, |a, b| if a < b { a } else { b })
+    array_arithmetic("min", args, |a, b| if a < b { a } else { b })
 }
 
 fn max_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
-    array_arithmetic(args, |a, b| if a < b { b } else { a })
+    array_arithmetic("max", args, |a, b| if a < b { b } else { a })
 }
 
 fn sqrt_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
-    unary_arithmetic(args, f64::sqrt)
+    unary_arithmetic("sqrt", args, Number::sqrt)
 }
 
 fn ceil_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
-    unary_arithmetic(args, f64::ceil)
+    unary_arithmetic("ceil", args, Number::ceil)
 }
 
 fn floor_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
-    unary_arithmetic(args, f64::floor)
+    unary_arithmetic("floor", args, Number::floor)
 }
 
 fn lowercase_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
-    unary_string(args, str::to_lowercase)
+    unary_string("lowercase", args, str::to_lowercase)
 }
 
 fn uppercase_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
-    unary_string(args, str::to_uppercase)
+    unary_string("uppercase", args, str::to_uppercase)
 }
 
 fn number_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
     if args.len() != 1 {
-        return Err(interpreter_error!("number expects one argument"));
+        return Err(builtin_error!("number", "number expects one argument"));
     }
     let v = &args[0];
-    let num: f64 = match v {
+    let num: Number = match v {
         Value::String(s) => match s.parse() {
             Ok(num) => num,
-            Err(_) => return Err(interpreter_error!("string can't be converted to number")),
+            Err(_) => return Err(builtin_error!("number", "string can't be converted to number")),
         },
-        _ => return Err(interpreter_error!("invalid arguments to builtin: number")),
+        _ => return Err(builtin_error!("number", "invalid arguments to builtin: number")),
     };
     Ok(Value::Number(num))
 }
 
 fn strip_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
-    unary_string(args, |s| str::trim(s).to_owned())
+    unary_string("strip", args, |s| str::trim(s).to_owned())
 }
 
-fn range_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
+fn range_builtin(context: &Context, args: &[Value]) -> Result<Value> {
    if args.len() < 2 || args.len() > 3 {
-        return Err(interpreter_error!("range requires two arguments and optionally supports a third"));
+        return Err(builtin_error!("range", "range requires two arguments and optionally supports a third"));
     }
-    let start = &args[0];
-    let start: i64 = match start {
-        Value::Number(n) if n.fract() == 0.0 => n.round() as i64,
-        _ => return Err(interpreter_error!("invalid arguments to builtin: range")),
-    };
-    let stop = &args[1];
-    let stop: i64 = match stop {
-        Value::Number(n) if n.fract() == 0.0 => n.round() as i64,
-        _ => return Err(interpreter_error!("invalid arguments to builtin: range")),
+    let integer = |v: &Value| {
+        v.as_number()
+            .and_then(Number::as_i64)
+            .ok_or_else(|| builtin_error!("range", "invalid arguments to builtin: range"))
     };
+    let start: i64 = integer(&args[0])?;
+    let stop: i64 = integer(&args[1])?;
     let step: i64 = match args.get(2) {
         // If step is not provided by the user, it defaults to 1.
         None => 1,
-        Some(val) => match val {
-            Value::Number(n) if n.fract() == 0.0 => n.round() as i64,
-            _ => return Err(interpreter_error!("invalid arguments to builtin: range")),
-        }
+        Some(val) => integer(val)?,
     };
 
-    if step > 0 {
-        let step: usize = step.try_into()?;
-        let range = (start..stop).step_by(step).map(|i| Value::Number(i as f64)).collect();
-        Ok(Value::Array(range))
-    } else if step < 0 {
-        let step: usize = (-step).try_into()?;
-        let range = (stop+1..=start).rev().step_by(step).map(|i| Value::Number(i as f64)).collect();
-        Ok(Value::Array(range))
-    } else {
-        Err(interpreter_error!("invalid argument `step` to builtin: range"))
+    if step == 0 {
+        return Err(builtin_error!("range", "invalid argument `step` to builtin: range"));
     }
+
+    // check the length of the result before creating it, computing in i128 so that no
+    // combination of arguments overflows
+    let (start, stop, step) = (start as i128, stop as i128, step as i128);
+    let length = if (stop - start).signum() == step.signum() {
+        (stop - start - step.signum()) / step + 1
+    } else {
+        0
+    };
+    context.check_array(length.try_into().unwrap_or(usize::MAX))?;
+
+    // each element lies between start and stop, so fits in an i64
+    let range = (0..length)
+        .map(|i| Value::Number(((start + i * step) as i64).into()))
+        .collect();
+    Ok(Value::Array(range))
 }
 
 fn rstrip_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
-    unary_string(args, |s| str::trim_end(s).to_owned())
+    unary_string("rstrip", args, |s| str::trim_end(s).to_owned())
 }
 
 fn lstrip_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
-    unary_string(args, |s| str::trim_start(s).to_owned())
+    unary_string("lstrip", args, |s| str::trim_start(s).to_owned())
 }
 
-fn join_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
+fn join_builtin(context: &Context, args: &[Value]) -> Result<Value> {
     if args.len() != 2 {
-        return Err(interpreter_error!("join expects two arguments"));
+        return Err(builtin_error!("join", "join expects two arguments"));
     }
     let v = &args[0];
     let sep = &args[1];
 
     let sep = match sep.stringify() {
         Ok(s) => s,
-        Err(_) => return Err(interpreter_error!("invalid separator for split")),
+        Err(_) => return Err(builtin_error!("join", "invalid separator for split")),
     };
 
     match v {
         Value::Array(v) => {
             let strings: Result<Vec<String>> = v.iter().map(|val| val.stringify()).collect();
             match strings {
-                Ok(s) => Ok(Value::String(s.join(&sep))),
-                Err(_) => Err(interpreter_error!(
+                Ok(s) => {
+                    let length = s.iter().map(String::len).sum::<usize>()
+                        + sep.len() * s.len().saturating_sub(1);
+                    context.check_string(length)?;
+                    Ok(Value::String(s.join(&sep).into()))
+                }
+                Err(_) => Err(builtin_error!(
+                    "join",
                     "BuiltinError: invalid arguments to builtin: join"
                 )),
             }
         }
-        _ => Err(interpreter_error!(
+        _ => Err(builtin_error!(
+            "join",
             "BuiltinError: invalid arguments to builtin: join"
         )),
     }
 }
 
-fn split_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
+fn split_builtin(context: &Context, args: &[Value]) -> Result<Value> {
     if args.len() != 2 {
-        return Err(interpreter_error!("split expects two arguments"));
+        return Err(builtin_error!("split", "split expects two arguments"));
     }
     let v = &args[0];
     let sep = &args[1];
 
     let sep = match sep.stringify() {
         Ok(s) => s,
-        Err(_) => return Err(interpreter_error!("invalid separator for split")),
+        Err(_) => return Err(builtin_error!("split", "invalid separator for split")),
     };
 
     match v {
         Value::String(s) => {
             if s.is_empty() {
-                return Ok(Value::Array(vec![Value::String("".to_string())]));
+                return Ok(Value::Array(Arc::new([Value::String("".into())])));
             };
-            let strings = s
+            let strings: Arc<[Value]> = s
                 .split(&sep)
                 .filter(|v| !v.is_empty())
-                .map(|v| Value::String(v.to_string()))
+                .map(|v| Value::String(v.into()))
                 .collect();
+            context.check_array(strings.len())?;
             Ok(Value::Array(strings))
         }
-        _ => Err(interpreter_error!(
+        _ => Err(builtin_error!(
+            "split",
             "BuiltinError: invalid arguments to builtin: split"
         )),
     }
@@ -294,16 +311,17 @@ fn split_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
 
 fn from_now_builtin(context: &Context, args: &[Value]) -> Result<Value> {
     if args.len() != 1 && args.len() != 2 {
-        return Err(interpreter_error!("from_now expects one or two arguments"));
+        return Err(builtin_error!("fromNow", "from_now expects one or two arguments"));
     }
 
     let v = &args[0];
 
     let reference = if args.len() == 2 {
         match &args[1] {
-            Value::String(s) => s.to_owned(),
+            Value::String(s) => s.to_string(),
             _ => {
-                return Err(interpreter_error!(
+                return Err(builtin_error!(
+                    "fromNow",
                     "BuiltinError: invalid arguments to builtin: fromNow"
                 ))
             }
@@ -311,14 +329,20 @@ fn from_now_builtin(context: &Context, args: &[Value]) -> Result<Value> {
     } else {
         match context.get("now") {
             None => unreachable!(), // this is set in render()
-            Some(Value::String(s)) => s.to_owned(),
-            _ => return Err(interpreter_error!("context value `now` must be a string")),
+            Some(Value::String(s)) => s.to_string(),
+            _ => {
+                return Err(error_of_kind!(
+                    ErrorKind::InvalidContext,
+                    "context value `now` must be a string"
+                ))
+            }
         }
     };
 
     match v {
-        Value::String(s) => Ok(Value::String(from_now(s, &reference)?)),
-        _ => Err(interpreter_error!(
+        Value::String(s) => Ok(Value::String(from_now(s, &reference)?.into())),
+        _ => Err(builtin_error!(
+            "fromNow",
             "BuiltinError: invalid arguments to builtin: fromNow"
         )),
     }
@@ -326,7 +350,7 @@ fn from_now_builtin(context: &Context, args: &[Value]) -> Result<Value> {
 
 fn typeof_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
     if args.len() != 1 {
-        return Err(interpreter_error!("typeof expects one argument"));
+        return Err(builtin_error!("typeof", "typeof expects one argument"));
     }
 
     let v = &args[0];
@@ -340,24 +364,26 @@ fn typeof_builtin(_context: &Context, args: &[Value]) -> Result<Value> {
         Value::Null => "null",
         Value::Function(_) => "function",
         _ => {
-            return Err(interpreter_error!(
+            return Err(builtin_error!(
+                "typeof",
                 "BuiltinError: invalid arguments to builtin: split"
             ))
         }
     };
 
-    Ok(Value::String(type_.to_string()))
+    Ok(Value::String(type_.into()))
 }
 
 fn defined_builtin(context: &Context, args: &[Value]) -> Result<Value> {
     if args.len() != 1 {
-        return Err(interpreter_error!("builtin expects one argument"));
+        return Err(builtin_error!("defined", "builtin expects one argument"));
     }
     let v = &args[0];
 
     match v {
         Value::String(s) => Ok(Value::Bool(context.get(s).is_some())),
-        _ => Err(interpreter_error!(
+        _ => Err(builtin_error!(
+            "defined",
             "BuiltinError: invalid arguments to builtin: split"
         )),
     }
@@ -1,75 +1,433 @@
 #![allow(unused_macros)]
 
+use std::fmt;
 use thiserror::Error;
 
-/// Construct a new interpreter error, as an anyhow::Error
-macro_rules! interpreter_error {
-    ($err:expr $(,)?) => ({
-        anyhow::Error::new($crate::errors::InterpreterError($err.to_string()))
+/// Construct a new error of the given kind, with a message
+macro_rules! error_of_kind {
+    ($kind:expr, $err:expr $(,)?) => ({
+        $crate::errors::Error::new($kind, $err.to_string())
     });
-    ($fmt:expr, $($arg:tt)*) => {
-        anyhow::Error::new($crate::errors::InterpreterError(format!($fmt, $($arg)*)))
+    ($kind:expr, $fmt:expr, $($arg:tt)*) => {
+        $crate::errors::Error::new($kind, format!($fmt, $($arg)*))
+    };
+}
+
+/// Construct a new interpreter error, for a failure evaluating an expression
+macro_rules! interpreter_error {
+    ($($arg:tt)*) => {
+        error_of_kind!($crate::errors::ErrorKind::Evaluation, $($arg)*)
     };
 }
 
-/// Construct a new template error, as an anyhow::Error
+/// Construct a new template error, for a template that is not well-formed
 macro_rules! template_error {
-    ($err:expr $(,)?) => ({
-        anyhow::Error::new($crate::errors::TemplateError($err.to_string()))
-    });
-    ($fmt:expr, $($arg:tt)*) => {
-        anyhow::Error::new($crate::errors::TemplateError(format!($fmt, $($arg)*)))
+    ($($arg:tt)*) => {
+        error_of_kind!($crate::errors::ErrorKind::Template { operator: None }, $($arg)*)
+    };
+}
+
+/// Construct a new template error for a misused operator, given as the first argument
+macro_rules! operator_error {
+    ($operator:expr, $($arg:tt)*) => {
+        error_of_kind!(
+            $crate::errors::ErrorKind::Template { operator: Some(String::from($operator)) },
+            $($arg)*
+        )
+    };
+}
+
+/// Construct a new syntax error
+macro_rules! syntax_error {
+    ($($arg:tt)*) => {
+        error_of_kind!($crate::errors::ErrorKind::Syntax, $($arg)*)
+    };
+}
+
+/// Construct a new builtin argument error for the named builtin
+macro_rules! builtin_error {
+    ($function:expr, $($arg:tt)*) => {
+        error_of_kind!(
+            $crate::errors::ErrorKind::BuiltinArgument { function: String::from($function) },
+            $($arg)*
+        )
     };
 }
 
-/// Utility for asserting that an anyhow::Result contains an interpreter error
+/// Construct a new type error, given the operation, a description of the expected types, and a
+/// list of the values actually given
+macro_rules! type_error {
+    ($operation:expr, $expected:expr, [$($actual:expr),* $(,)?], $($arg:tt)*) => {
+        error_of_kind!(
+            $crate::errors::ErrorKind::Type {
+                operation: String::from($operation),
+                expected: String::from($expected),
+                actual: vec![$(String::from($crate::value::Value::type_name($actual))),*],
+            },
+            $($arg)*
+        )
+    };
+}
+
+/// Utility for asserting that a Result contains an interpreter error with the given message
 #[cfg(test)]
 macro_rules! assert_interpreter_error {
     ($left:expr, $right:expr) => ({
-        assert_eq!(
-            $left.expect_err("Expected an error, got").downcast_ref::<$crate::errors::InterpreterError>().expect("Expected a InterpreterError"),
-            &$crate::errors::InterpreterError($right.to_string())
-        );
+        let err = $left.expect_err("Expected an error, got");
+        assert!(!err.is_template_error(), "Expected an interpreter error, got {:?}", err);
+        assert_eq!(err.message(), $right.to_string());
     });
     ($left:expr, $right:expr,) => ({
         assert_interpreter_error!($left, $right)
     });
     ($left:expr, $right:expr, $($arg:tt)+) => ({
-        assert_eq!(
-            $left.expect_err("Expected an error, got").downcast_ref::<$crate::errors::InterpreterError>().expect("Expected a InterpreterError"),
-            &$crate::errors::InterpreterError($right.to_string()),
-            $($arg)*
-        );
+        let err = $left.expect_err("Expected an error, got");
+        assert!(!err.is_template_error(), "Expected an interpreter error, got {:?}", err);
+        assert_eq!(err.message(), $right.to_string(), $($arg)*);
     });
 }
 
-/// Utility for asserting that an anyhow::Result contains an template error
+/// Utility for asserting that a Result contains a template error with the given message
 #[cfg(test)]
 macro_rules! assert_template_error {
     ($left:expr, $right:expr) => ({
-        assert_eq!(
-            $left.expect_err("Expected an error, got").downcast_ref::<$crate::errors::TemplateError>().expect("Expected a TemplateError"),
-            &$crate::errors::TemplateError($right.to_string())
-        );
+        let err = $left.expect_err("Expected an error, got");
+        assert!(err.is_template_error(), "Expected a template error, got {:?}", err);
+        assert_eq!(err.message(), $right.to_string());
     });
     ($left:expr, $right:expr,) => ({
         assert_template_error!($left, $right)
     });
     ($left:expr, $right:expr, $($arg:tt)+) => ({
-        assert_eq!(
-            $left.expect_err("Expected an error, got").downcast_ref::<$crate::errors::TemplateError>().expect("Expected a TemplateError"),
-            &$crate::errors::TemplateError($right.to_string()),
-            $($arg)*
-        );
+        let err = $left.expect_err("Expected an error, got");
+        assert!(err.is_template_error(), "Expected a template error, got {:?}", err);
+        assert_eq!(err.message(), $right.to_string(), $($arg)*);
     });
 }
 
-/// An InterpreterError indicates something that failed during evaluation of a JSON-e expression.
-#[derive(Debug, Error, Eq, PartialEq, Clone)]
-#[error("Interpreter Error: {0}")]
-pub struct InterpreterError(pub(crate) String);
+/// The result type used throughout this crate
+pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
 
-/// An TemplateError indicates something that failed during evaluation of a JSON-e expression.
+/// An error that occurred while rendering a template.
 #[derive(Debug, Error, Eq, PartialEq, Clone)]
-#[error("TemplateError: {0}")]
-pub struct TemplateError(pub(crate) String);
+#[error("{}{}", .0.message, .0.location)]
+pub struct Error(Box<ErrorInner>);
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct ErrorInner {
+    kind: ErrorKind,
+    message: String,
+    location: Location,
+}
+
+/// The kind of an [`Error`], with any machine-readable information about it.
+#[derive(Debug, Eq, PartialEq, Clone)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// An expression could not be parsed.  The error's location gives the offset at which parsing
+    /// failed.
+    Syntax,
+
+    /// An expression referred to a name that is not defined in the context.
+    UnknownContextValue {
+        /// The undefined name
+        name: String,
+    },
+
+    /// An operator or operation was applied to values of the wrong type.
+    Type {
+        /// The operation, such as `+` or `$map`
+        operation: String,
+        /// A description of the expected types, such as `numbers or strings`
+        expected: String,
+        /// The types of the values that were given, as returned by `typeof`
+        actual: Vec<String>,
+    },
+
+    /// A built-in function was called with invalid arguments.
+    BuiltinArgument {
+        /// The name of the built-in function
+        function: String,
+    },
+
+    /// A custom function returned an error, described in the error message.
+    Function {
+        /// The name of the custom function
+        name: String,
+    },
+
+    /// A custom operator returned an error, described in the error message.
+    Operator {
+        /// The name of the custom operator, such as `$secret`
+        name: String,
+    },
+
+    /// A template to `$include` could not be loaded, or does not exist.
+    Include {
+        /// The name of the template
+        name: String,
+    },
+
+    /// A template included itself, directly or through other templates.
+    IncludeCycle {
+        /// The names of the templates forming the cycle, beginning and ending with the same name
+        names: Vec<String>,
+    },
+
+    /// The template is not well-formed, such as an operator with missing or unknown properties.
+    Template {
+        /// The operator involved, if any
+        operator: Option<String>,
+    },
+
+    /// The context is not an object, or contains invalid keys or values.
+    InvalidContext,
+
+    /// The rendered value could not be deserialized into the requested type.
+    Deserialize {
+        /// The location within the rendered value at which deserialization failed, as a JSON
+        /// pointer such as `/spec/replicas`.  This is empty if the location is the root, or
+        /// cannot be determined.
+        path: String,
+    },
+
+    /// The rendered value could not be written to the writer or serializer given to
+    /// [`CompiledTemplate::render_to_writer`] or [`CompiledTemplate::render_to_serializer`].
+    ///
+    /// [`CompiledTemplate::render_to_writer`]: crate::CompiledTemplate::render_to_writer
+    /// [`CompiledTemplate::render_to_serializer`]: crate::CompiledTemplate::render_to_serializer
+    Output,
+
+    /// A YAML template or context could not be parsed, or holds a value that has no JSON
+    /// equivalent, such as a non-string mapping key or a NaN.  Only produced with the `yaml`
+    /// feature.
+    Yaml {
+        /// The location within the YAML document of the value with no JSON equivalent, as a JSON
+        /// pointer, or an empty string for errors parsing the document
+        path: String,
+    },
+
+    /// Evaluation failed for another reason, such as division by zero or an index out of bounds.
+    Evaluation,
+
+    /// The render nested more deeply than allowed by [`Limits::max_depth`].
+    ///
+    /// [`Limits::max_depth`]: crate::Limits::max_depth
+    DepthLimitExceeded {
+        /// The limit that was exceeded
+        limit: usize,
+    },
+
+    /// The render took more steps than allowed by [`Limits::max_steps`].
+    ///
+    /// [`Limits::max_steps`]: crate::Limits::max_steps
+    StepLimitExceeded {
+        /// The limit that was exceeded
+        limit: usize,
+    },
+
+    /// The render created more array elements and object properties than allowed by
+    /// [`Limits::max_output_nodes`].
+    ///
+    /// [`Limits::max_output_nodes`]: crate::Limits::max_output_nodes
+    OutputNodeLimitExceeded {
+        /// The limit that was exceeded
+        limit: usize,
+    },
+
+    /// The render created a string longer than allowed by [`Limits::max_string_length`].
+    ///
+    /// [`Limits::max_string_length`]: crate::Limits::max_string_length
+    StringLengthLimitExceeded {
+        /// The limit that was exceeded
+        limit: usize,
+    },
+
+    /// The render created an array longer than allowed by [`Limits::max_array_length`].
+    ///
+    /// [`Limits::max_array_length`]: crate::Limits::max_array_length
+    ArrayLengthLimitExceeded {
+        /// The limit that was exceeded
+        limit: usize,
+    },
+
+    /// The render used more stack space, in bytes, than allowed by [`Limits::max_stack`], or
+    /// a value or expression was nested too deeply for it.
+    ///
+    /// [`Limits::max_stack`]: crate::Limits::max_stack
+    StackLimitExceeded {
+        /// The limit that was exceeded
+        limit: usize,
+    },
+}
+
+impl ErrorKind {
+    /// True if this error is due to exceeding one of the render's [`Limits`].
+    ///
+    /// [`Limits`]: crate::Limits
+    pub fn is_limit_exceeded(&self) -> bool {
+        matches!(
+            self,
+            ErrorKind::DepthLimitExceeded { .. }
+                | ErrorKind::StepLimitExceeded { .. }
+                | ErrorKind::OutputNodeLimitExceeded { .. }
+                | ErrorKind::StringLengthLimitExceeded { .. }
+                | ErrorKind::ArrayLengthLimitExceeded { .. }
+                | ErrorKind::StackLimitExceeded { .. }
+        )
+    }
+}
+
+impl Error {
+    pub(crate) fn new(kind: ErrorKind, message: String) -> Error {
+        Error(Box::new(ErrorInner {
+            kind,
+            message,
+            location: Location::default(),
+        }))
+    }
+
+    /// The kind of this error
+    pub fn kind(&self) -> &ErrorKind {
+        &self.0.kind
+    }
+
+    /// The error message, without any location information
+    pub fn message(&self) -> &str {
+        &self.0.message
+    }
+
+    /// The location in the template at which the error occurred
+    pub fn location(&self) -> &Location {
+        &self.0.location
+    }
+
+    /// True if this error is due to the structure of the template or its context, rather than
+    /// evaluation of an expression.
+    #[cfg(test)]
+    pub(crate) fn is_template_error(&self) -> bool {
+        match self.0.kind {
+            ErrorKind::Template { .. } | ErrorKind::InvalidContext => true,
+            ErrorKind::Type { ref operation, .. } => operation.starts_with('$'),
+            _ => false,
+        }
+    }
+
+    /// Add a path segment to the beginning of the location of this error.  This is called as the
+    /// error unwinds through the template, so the segments are added from the innermost outward.
+    pub(crate) fn add_location<S: ToString>(mut self, segment: S) -> Error {
+        self.0.location.path.insert(0, segment.to_string());
+        self
+    }
+
+    /// Add the given character offset to the offset of this error, setting the offset if none is
+    /// present.
+    pub(crate) fn add_offset(mut self, offset: usize) -> Error {
+        self.0.location.offset = Some(self.0.location.offset.unwrap_or(0) + offset);
+        self
+    }
+}
+
+/// The location of an error within a template.
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct Location {
+    path: Vec<String>,
+    offset: Option<usize>,
+}
+
+impl Location {
+    pub(crate) fn new(path: Vec<String>, offset: Option<usize>) -> Location {
+        Location { path, offset }
+    }
+
+    /// The path from the root of the template to the value containing the error, as a sequence
+    /// of object keys and array indexes.  Properties of an operator, such as `each(x)` for
+    /// `$map`, appear beneath the operator's name.
+    pub fn path(&self) -> &[String] {
+        &self.path[..]
+    }
+
+    /// The path as a JSON-pointer-style string, such as `/spec/containers/3/$map/each(x)`.
+    pub fn pointer(&self) -> String {
+        json_pointer(&self.path)
+    }
+
+    /// For errors in expressions, the character offset within the string containing the
+    /// expression.  For syntax errors, this is the offset at which parsing failed; for other
+    /// errors it is the offset of the beginning of the expression that failed.
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.path.is_empty() {
+            write!(f, " at {}", self.pointer())?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " (offset {})", offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Format the given path segments as a JSON pointer.
+pub(crate) fn json_pointer<S: AsRef<str>>(segments: &[S]) -> String {
+    segments
+        .iter()
+        .map(|s| format!("/{}", s.as_ref().replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+/// The character offset of `rest`, a suffix of `source`, within `source`.
+pub(crate) fn char_offset(source: &str, rest: &str) -> usize {
+    source[..source.len() - rest.len()].chars().count()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn add_location_prepends() {
+        let err = template_error!("uhoh")
+            .add_location("b")
+            .add_location(3)
+            .add_location("a/~");
+        assert_eq!(err.location().path(), &["a/~", "3", "b"]);
+        assert_eq!(err.location().pointer(), "/a~1~0/3/b");
+    }
+
+    #[test]
+    fn add_offset_accumulates() {
+        let err = syntax_error!("uhoh").add_offset(2).add_offset(5);
+        assert_eq!(err.location().offset(), Some(7));
+    }
+
+    #[test]
+    fn type_error_kind() {
+        let err = type_error!(
+            "+",
+            "numbers or strings",
+            [&Value::Null, &Value::Bool(true)],
+            "This operator expects numbers or strings"
+        );
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::Type {
+                operation: "+".into(),
+                expected: "numbers or strings".into(),
+                actual: vec!["null".into(), "boolean".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn display_location() {
+        let err = interpreter_error!("uhoh").add_location("$eval").add_offset(4);
+        assert_eq!(format!("{}", err), "uhoh at /$eval (offset 4)");
+        assert_eq!(format!("{}", template_error!("uhoh")), "uhoh");
+    }
+}
@@ -0,0 +1,173 @@
+//! Evaluation of JSON-e expressions on their own, outside of any template.
+//!
+//! An expression is evaluated just as it would be by `$eval`: with the built-in functions and
+//! `now` available alongside the context.  With a [`Renderer`], any custom functions, limits, and
+//! clock that it has are used as well.
+//!
+//! ```
+//! # use json_e::{evaluate_expression, parse_expression};
+//! # use serde_json::json;
+//! let context = json!({"branch": "main", "files": ["README.md", "src/lib.rs"]});
+//! let result = evaluate_expression("branch == 'main' && len(files) > 1", &context).unwrap();
+//! assert_eq!(result, json!(true));
+//!
+//! let when = parse_expression("branch in ['main', 'release']").unwrap();
+//! assert_eq!(when.evaluate(&context).unwrap(), json!(true));
+//! assert_eq!(when.evaluate(&json!({"branch": "dev"})).unwrap(), json!(false));
+//! ```
+
+use crate::errors::{Error, Result};
+use crate::interpreter::{self, Compiled};
+use crate::limits::{Budget, Limits};
+use crate::render::Renderer;
+use serde_json::Value as SerdeValue;
+use std::fmt;
+use std::str::FromStr;
+
+/// A parsed JSON-e expression, which can be evaluated many times without parsing it again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedExpression {
+    source: String,
+    code: Compiled,
+}
+
+impl ParsedExpression {
+    /// Parse the given expression.  A syntax error is an [`ErrorKind::Syntax`](crate::ErrorKind)
+    /// error whose location gives the offset within the expression at which it occurred.
+    pub fn parse(source: &str) -> Result<ParsedExpression, Error> {
+        let budget = Budget::new(Limits::default());
+        Ok(ParsedExpression {
+            source: source.to_owned(),
+            code: Compiled::new(interpreter::parse_all(source, &budget)?, &budget)?,
+        })
+    }
+
+    /// The source text of this expression
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The canonical source text of this expression, printed from its syntax tree, which parses
+    /// to the same tree.  Operators are separated by single spaces, strings are double-quoted
+    /// unless they contain a double quote, and only the parentheses that precedence requires are
+    /// kept, so `((a+b)*c)` is printed as `(a + b) * c`.  The syntax tree itself is not public,
+    /// so this, or the alternate form of `Display` (`{:#}`), is how it is printed.
+    pub fn canonical(&self) -> String {
+        // the strings in a parsed expression never contain both kinds of quote, as they are
+        // parsed from quoted literals, so they can always be written
+        self.code.node().to_source().unwrap()
+    }
+
+    /// Evaluate this expression with the given context, which must be an object, along with the
+    /// built-in functions and `now`.  See [`Renderer::evaluate`] to use custom functions.
+    pub fn evaluate(&self, context: &SerdeValue) -> Result<SerdeValue, Error> {
+        Renderer::new().evaluate(self, context)
+    }
+
+    pub(crate) fn code(&self) -> &Compiled {
+        &self.code
+    }
+}
+
+impl FromStr for ParsedExpression {
+    type Err = Error;
+
+    fn from_str(source: &str) -> Result<ParsedExpression, Error> {
+        ParsedExpression::parse(source)
+    }
+}
+
+/// Expressions display as their source text, or with the alternate flag (`{:#}`) as their
+/// canonical source text, as for [`ParsedExpression::canonical`].
+impl fmt::Display for ParsedExpression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if f.alternate() {
+            write!(f, "{}", self.canonical())
+        } else {
+            write!(f, "{}", self.source)
+        }
+    }
+}
+
+/// Parse the given JSON-e expression.  See [`ParsedExpression::parse`].
+pub fn parse_expression(source: &str) -> Result<ParsedExpression, Error> {
+    ParsedExpression::parse(source)
+}
+
+/// Evaluate the given JSON-e expression with the given context, just as `$eval` would.
+pub fn evaluate_expression(source: &str, context: &SerdeValue) -> Result<SerdeValue, Error> {
+    ParsedExpression::parse(source)?.evaluate(context)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errors::ErrorKind;
+    use serde_json::json;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn evaluates() {
+        let context = json!({"x": 2, "items": [1, 2, 3]});
+        assert_eq!(
+            evaluate_expression("x * len(items) + items[-1]", &context).unwrap(),
+            json!(9)
+        );
+        assert_eq!(
+            evaluate_expression("{a: x, b: items[1:]}", &context).unwrap(),
+            json!({"a": 2, "b": [2, 3]})
+        );
+    }
+
+    #[test]
+    fn reuse() {
+        let expression: ParsedExpression = "x > 1".parse().unwrap();
+        assert_eq!(expression.to_string(), "x > 1");
+        assert_eq!(expression.evaluate(&json!({"x": 2})).unwrap(), json!(true));
+        assert_eq!(expression.evaluate(&json!({"x": 0})).unwrap(), json!(false));
+    }
+
+    #[test]
+    fn canonical() {
+        let expression = parse_expression("((a+b)*c)[ 'k' ] ||!d").unwrap();
+        assert_eq!(expression.canonical(), "((a + b) * c)[\"k\"] || !d");
+        assert_eq!(expression.source(), "((a+b)*c)[ 'k' ] ||!d");
+        assert_eq!(format!("{:#}", expression), expression.canonical());
+        let reparsed = parse_expression(&expression.canonical()).unwrap();
+        assert_eq!(reparsed.code(), expression.code());
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let err = parse_expression("x +").unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Syntax);
+        assert_eq!(err.location().offset(), Some(2));
+    }
+
+    #[test]
+    fn evaluation_errors() {
+        let err = evaluate_expression("x", &json!({})).unwrap_err();
+        assert_eq!(
+            *err.kind(),
+            ErrorKind::UnknownContextValue {
+                name: "x".to_owned()
+            }
+        );
+        let err = evaluate_expression("1 + 'a'", &json!({})).unwrap_err();
+        assert_eq!(err.location().offset(), Some(0));
+        assert!(evaluate_expression("len", &json!({})).is_err());
+        assert!(evaluate_expression("1", &json!([])).is_err());
+    }
+
+    #[test]
+    fn renderer() {
+        let renderer = Renderer::new()
+            .function("double", |args| Ok(json!(args[0].as_f64().unwrap() * 2.0)))
+            .now(UNIX_EPOCH + Duration::from_secs(3600));
+        let expression = parse_expression("[double(x), now, fromNow('1 hour')]").unwrap();
+        assert_eq!(
+            renderer.evaluate(&expression, &json!({"x": 2})).unwrap(),
+            json!([4, "1970-01-01T01:00:00.000Z", "1970-01-01T02:00:00.000Z"])
+        );
+    }
+}
@@ -1,6 +1,6 @@
 #![allow(clippy::type_complexity)]
 use crate::whitespace::ws;
-use anyhow::{anyhow, Result};
+use crate::errors::Result;
 use chrono::{DateTime, Duration, Utc};
 use nom::{
     branch::alt,
@@ -11,34 +11,100 @@ use nom::{
     IResult,
 };
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration as StdDuration, SystemTime};
 
 const SIMPLIFIED_EXTENDED_ISO_8601: &str = "%Y-%m-%dT%H:%M:%S%.3fZ";
 static USE_TEST_TIME: AtomicBool = AtomicBool::new(false);
 
-/// Get the current time, as a properly-formatted string
-pub(crate) fn now() -> String {
-    // when testing, we use a fixed value for "now"
-    if USE_TEST_TIME.load(Ordering::Acquire) {
-        return "2017-01-19T16:27:20.974Z".to_string();
+/// A Clock gives the current time for a render.  That time is the `now` context value, and is the
+/// reference time for `$fromNow` and `fromNow` when no other is given.
+///
+/// Any `Fn() -> SystemTime` is a clock, so a test can control time with a closure:
+///
+/// ```
+/// # use json_e::Renderer;
+/// # use serde_json::json;
+/// use std::sync::{Arc, Mutex};
+/// use std::time::{Duration, SystemTime};
+///
+/// let time = Arc::new(Mutex::new(SystemTime::UNIX_EPOCH));
+/// let clock_time = time.clone();
+/// let renderer = Renderer::new().clock(move || *clock_time.lock().unwrap());
+///
+/// let template = json!({"$fromNow": "1 hour"});
+/// assert_eq!(renderer.render(&template, &json!({})).unwrap(), json!("1970-01-01T01:00:00.000Z"));
+/// *time.lock().unwrap() += Duration::from_secs(300);
+/// assert_eq!(renderer.render(&template, &json!({})).unwrap(), json!("1970-01-01T01:05:00.000Z"));
+/// ```
+pub trait Clock: Send + Sync {
+    /// The current time
+    fn now(&self) -> SystemTime;
+}
+
+/// The system clock, which is the default for a [`Renderer`](crate::Renderer).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
     }
-    format!("{}", Utc::now().format(SIMPLIFIED_EXTENDED_ISO_8601))
 }
 
-/// Use the test time (2017-01-19T16:27:20.974Z) as the current time for all
-/// subsequent operations.  This is only useful in testing this library.
+/// The clock of a renderer that is given none: the system clock, or the test time after
+/// [`use_test_now`].
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct DefaultClock;
+
+impl Clock for DefaultClock {
+    fn now(&self) -> SystemTime {
+        if USE_TEST_TIME.load(Ordering::Acquire) {
+            // 2017-01-19T16:27:20.974Z
+            SystemTime::UNIX_EPOCH + StdDuration::from_millis(1484843240974)
+        } else {
+            SystemTime::now()
+        }
+    }
+}
+
+/// Use the test time (2017-01-19T16:27:20.974Z) as the current time for all subsequent renders
+/// by renderers that are not given a clock.  This is only useful in testing this library.
+#[deprecated(
+    note = "give a renderer its time with `Renderer::now` or `Renderer::clock` instead; \
+            `use_test_now` will be removed in the next major version"
+)]
 pub fn use_test_now() {
     USE_TEST_TIME.store(true, Ordering::Release);
 }
 
+impl<F> Clock for F
+where
+    F: Fn() -> SystemTime + Send + Sync,
+{
+    fn now(&self) -> SystemTime {
+        self()
+    }
+}
+
+/// Format the given time as a date-time string, as used for `now`
+pub(crate) fn format_time(time: SystemTime) -> String {
+    format!(
+        "{}",
+        DateTime::<Utc>::from(time).format(SIMPLIFIED_EXTENDED_ISO_8601)
+    )
+}
+
 /// Calculate a time offset from a reference time.
 ///
 /// Date-times are are specified in simplified extended ISO format (ISO 8601) with zero timezone offset;
 /// this is the format used by the JS `Date.toISOString()` function, and has the form
 /// `YYYY-MM-DDTHH:mm:ss(.sss)?Z`, where the decimal portion of the seconds is optional.
 pub(crate) fn from_now(offset: &str, reference: &str) -> Result<String> {
-    let reference: DateTime<Utc> = reference.parse()?;
+    let reference: DateTime<Utc> = reference
+        .parse()
+        .map_err(|_| interpreter_error!("String '{}' isn't a valid date-time", reference))?;
     let dur = parse_duration(offset)
-        .ok_or_else(|| anyhow!("String '{}' isn't a time expression", offset))?;
+        .ok_or_else(|| interpreter_error!("String '{}' isn't a time expression", offset))?;
     Ok(format!(
         "{}",
         (reference + dur).format(SIMPLIFIED_EXTENDED_ISO_8601)
@@ -155,25 +221,25 @@ fn duration(input: &str) -> IResult<&str, Duration> {
     ) -> Result<Duration, ()> {
         let mut dur = Duration::zero();
         if let Some(d) = input.2 {
-            dur = dur + d;
+            dur += d;
         }
         if let Some(d) = input.3 {
-            dur = dur + d;
+            dur += d;
         }
         if let Some(d) = input.4 {
-            dur = dur + d;
+            dur += d;
         }
         if let Some(d) = input.5 {
-            dur = dur + d;
+            dur += d;
         }
         if let Some(d) = input.6 {
-            dur = dur + d;
+            dur += d;
         }
         if let Some(d) = input.7 {
-            dur = dur + d;
+            dur += d;
         }
         if let Some(d) = input.8 {
-            dur = dur + d;
+            dur += d;
         }
         // input.1 is true if there was a `-` in the offset
         if input.1 == Some(true) {
@@ -0,0 +1,334 @@
+//! Template includes, loaded through a [`Loader`] given to a [`Renderer`](crate::Renderer).
+//!
+//! A template includes another with `{"$include": <name>}`, optionally with
+//! `"with": {<bindings>}` giving additional context values, as for `$let`.  The included template
+//! is rendered in the current context, along with those bindings.
+//!
+//! ```
+//! # use json_e::{MemoryLoader, Renderer};
+//! # use serde_json::json;
+//! let loader = MemoryLoader::new().template("greeting", json!("hello, ${name}"));
+//! let renderer = Renderer::new().loader(loader);
+//! let template = json!({"$include": "greeting", "with": {"name": "world"}});
+//! assert_eq!(renderer.render(&template, &json!({})).unwrap(), json!("hello, world"));
+//! ```
+
+use crate::errors::{ErrorKind, Result};
+use crate::limits::Limits;
+use crate::operators::Operators;
+use crate::template::Template;
+use serde_json::Value as SerdeValue;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A source of templates for `$include`, by name.
+pub trait Loader: Send + Sync {
+    /// Load the template with the given name, returning `None` if there is no such template.  An
+    /// error returned from the loader is reported as an [`ErrorKind::Include`] error, with the
+    /// error's string form in its message.
+    fn load(
+        &self,
+        name: &str,
+    ) -> Result<Option<SerdeValue>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// A loader for templates held in memory.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryLoader {
+    templates: HashMap<String, SerdeValue>,
+}
+
+impl MemoryLoader {
+    /// Create a new MemoryLoader, with no templates.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a template with the given name, replacing any existing template of that name.
+    pub fn template(mut self, name: &str, template: SerdeValue) -> Self {
+        self.templates.insert(name.to_owned(), template);
+        self
+    }
+}
+
+impl Loader for MemoryLoader {
+    fn load(
+        &self,
+        name: &str,
+    ) -> Result<Option<SerdeValue>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.templates.get(name).cloned())
+    }
+}
+
+/// A loader for JSON templates in files beneath a directory, named by their paths relative to
+/// that directory, such as `snippets/labels.json`.  With the `yaml` feature, files ending in
+/// `.yaml` or `.yml` are read as YAML.
+///
+/// Names are confined to the directory: absolute paths and paths containing `..` are errors, as
+/// are symbolic links that lead outside of the directory.
+#[derive(Debug, Clone)]
+pub struct DirectoryLoader {
+    root: PathBuf,
+}
+
+impl DirectoryLoader {
+    /// Create a new DirectoryLoader for templates beneath the given directory.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        DirectoryLoader { root: root.into() }
+    }
+}
+
+impl Loader for DirectoryLoader {
+    fn load(
+        &self,
+        name: &str,
+    ) -> Result<Option<SerdeValue>, Box<dyn std::error::Error + Send + Sync>> {
+        let relative = Path::new(name);
+        if name.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(format!("{:?} is not a relative path within the directory", name).into());
+        }
+
+        let root = self.root.canonicalize()?;
+        let path = match root.join(relative).canonicalize() {
+            Ok(path) => path,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if !path.starts_with(&root) {
+            return Err(format!("{:?} leads outside of the directory", name).into());
+        }
+
+        let contents = fs::read_to_string(&path)?;
+        #[cfg(feature = "yaml")]
+        {
+            if crate::yaml::is_yaml_path(&path) {
+                return Ok(Some(crate::yaml::from_yaml_str(&contents)?));
+            }
+        }
+        Ok(Some(serde_json::from_str(&contents)?))
+    }
+}
+
+/// The loader used by a renderer, along with the templates it has already loaded and compiled.
+pub(crate) struct Includes {
+    loader: Arc<dyn Loader>,
+    /// The custom operators available to included templates
+    operators: Arc<Operators>,
+    limits: Limits,
+    cache: Mutex<HashMap<String, Arc<Template>>>,
+}
+
+impl Includes {
+    pub(crate) fn new(loader: Arc<dyn Loader>, operators: Arc<Operators>, limits: Limits) -> Self {
+        Includes {
+            loader,
+            operators,
+            limits,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn loader(&self) -> &Arc<dyn Loader> {
+        &self.loader
+    }
+
+    /// Get the named template, compiling it when it is first loaded.  Errors from the loader are
+    /// not cached, so a later include of the same name will try again.
+    pub(crate) fn get(&self, name: &str) -> Result<Arc<Template>> {
+        if let Some(template) = self.cache.lock().unwrap().get(name) {
+            return Ok(template.clone());
+        }
+
+        let template = match self.loader.load(name) {
+            Ok(Some(template)) => template,
+            Ok(None) => {
+                return Err(error_of_kind!(
+                    ErrorKind::Include {
+                        name: name.to_owned()
+                    },
+                    "no template named {:?} to include",
+                    name
+                ))
+            }
+            Err(e) => {
+                return Err(error_of_kind!(
+                    ErrorKind::Include {
+                        name: name.to_owned()
+                    },
+                    "could not load template {:?} to include: {}",
+                    name,
+                    e
+                ))
+            }
+        };
+        let template = Arc::new(Template::compile_root(
+            &template,
+            self.limits,
+            &self.operators,
+        ));
+
+        // another render may have loaded the same template in the meantime, in which case its
+        // copy is kept
+        Ok(self
+            .cache
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_insert(template)
+            .clone())
+    }
+}
+
+/// The chain of templates being included at some point in a render, used to detect cycles.
+pub(crate) struct IncludeStack<'a> {
+    includes: &'a Includes,
+    /// The name of the innermost included template, or None at the root of the render
+    name: Option<&'a str>,
+    parent: Option<&'a IncludeStack<'a>>,
+}
+
+impl<'a> IncludeStack<'a> {
+    /// The stack at the root of a render, before any templates are included
+    pub(crate) fn new(includes: &'a Includes) -> Self {
+        IncludeStack {
+            includes,
+            name: None,
+            parent: None,
+        }
+    }
+
+    /// The stack within the named template, included from this one
+    pub(crate) fn push(&'a self, name: &'a str) -> IncludeStack<'a> {
+        IncludeStack {
+            includes: self.includes,
+            name: Some(name),
+            parent: Some(self),
+        }
+    }
+
+    pub(crate) fn includes(&self) -> &'a Includes {
+        self.includes
+    }
+
+    /// Check that including the named template from this one does not form a cycle.
+    pub(crate) fn check_cycle(&self, name: &str) -> Result<()> {
+        let mut names = vec![name.to_owned()];
+        let mut stack = Some(self);
+        while let Some(IncludeStack {
+            name: Some(n),
+            parent,
+            ..
+        }) = stack
+        {
+            names.push((*n).to_owned());
+            if *n == name {
+                names.reverse();
+                return Err(error_of_kind!(
+                    ErrorKind::IncludeCycle {
+                        names: names.clone()
+                    },
+                    "$include cycle: {}",
+                    names.join(" -> ")
+                ));
+            }
+            stack = *parent;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::env;
+    use std::ops::Deref;
+    use std::path::Path;
+    use std::process;
+
+    /// A temporary directory, which is removed when dropped
+    struct Directory(PathBuf);
+
+    impl Deref for Directory {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for Directory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A fresh directory of templates for a test
+    fn directory(test: &str) -> Directory {
+        let dir = Directory(env::temp_dir().join(format!("json-e-{}-{}", test, process::id())));
+        let _ = fs::remove_dir_all(&*dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("top.json"), r#"{"a": 1}"#).unwrap();
+        fs::write(dir.join("sub").join("inner.json"), r#"["${x}"]"#).unwrap();
+        dir
+    }
+
+    #[test]
+    fn directory_loads() {
+        let dir = directory("loads");
+        let loader = DirectoryLoader::new(dir.to_path_buf());
+        assert_eq!(loader.load("top.json").unwrap(), Some(json!({"a": 1})));
+        assert_eq!(loader.load("sub/inner.json").unwrap(), Some(json!(["${x}"])));
+        assert_eq!(loader.load("missing.json").unwrap(), None);
+    }
+
+    #[test]
+    fn directory_is_sandboxed() {
+        let dir = directory("sandboxed");
+        let loader = DirectoryLoader::new(dir.join("sub"));
+        assert!(loader.load("../top.json").is_err());
+        assert!(loader.load("./inner.json").is_err());
+        assert!(loader.load(dir.join("top.json").to_str().unwrap()).is_err());
+        assert!(loader.load("").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directory_symlinks_are_sandboxed() {
+        let dir = directory("symlinks");
+        std::os::unix::fs::symlink(dir.join("top.json"), dir.join("sub").join("link.json"))
+            .unwrap();
+        assert!(DirectoryLoader::new(dir.join("sub"))
+            .load("link.json")
+            .is_err());
+        assert_eq!(
+            DirectoryLoader::new(dir.to_path_buf())
+                .load("sub/link.json")
+                .unwrap(),
+            Some(json!({"a": 1}))
+        );
+    }
+
+    #[test]
+    fn directory_invalid_json() {
+        let dir = directory("invalid");
+        fs::write(dir.join("bad.json"), "{").unwrap();
+        assert!(DirectoryLoader::new(dir.to_path_buf())
+            .load("bad.json")
+            .is_err());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn directory_yaml() {
+        let dir = directory("yaml");
+        fs::write(dir.join("a.yaml"), "a: [1, '${x}']").unwrap();
+        fs::write(dir.join("b.yml"), "{1: x}").unwrap();
+        let loader = DirectoryLoader::new(dir.to_path_buf());
+        assert_eq!(loader.load("a.yaml").unwrap(), Some(json!({"a": [1, "${x}"]})));
+        assert!(loader.load("b.yml").is_err());
+    }
+}
@@ -0,0 +1,745 @@
+//! Incremental rendering, with the `incremental` feature.
+//!
+//! The arrays and the objects without interpolated keys at the top of a template are rendered
+//! part by part, as `_render` would render them.  Each remaining part, such as a string, an
+//! operator, or an object with interpolated keys, is rendered as a whole, and the context paths
+//! it looks up are recorded along with it.  A path is a name, followed by the properties and
+//! indices of a chain of `.` and `[..]` applied to it, where those are constants, as in
+//! `form.address.city` or `items[0]`.  When the context changes, only the parts that looked up a
+//! path within, or containing, a changed value are rendered again, and the differences between
+//! their old and new results are gathered into a JSON Patch against the previous output.
+
+use crate::errors::{Error, ErrorKind, Result};
+use crate::interpreter::{Context, Reads};
+use crate::render::{_render, CompiledTemplate};
+use crate::template::Template;
+use crate::value::{Object, Value};
+use json_patch::jsonptr::{Pointer, PointerBuf};
+use json_patch::{Patch, PatchOperation};
+use serde_json::{Map, Value as SerdeValue};
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::time::SystemTime;
+
+/// An IncrementalRender is the result of rendering a template with
+/// [`CompiledTemplate::render_incremental`], which can be updated for a new context by rendering
+/// again only the parts of the template that depend on the context values that changed.
+///
+/// Each element of an array and each property of an object whose keys contain no interpolations
+/// is tracked separately, recursively, while any other part of the template, such as an operator
+/// or a string, is rendered as a whole whenever a value it referred to changes.  A part that
+/// refers to a property or element of a context value, such as `form.name` or `items[0]`, is
+/// affected only by changes to that part of the value, while one that uses the value in any other
+/// way, such as `len(items)` or `form[key]`, is affected by a change anywhere within it.
+///
+/// The result of each update is the same as rendering the template with the new context, as
+/// long as the renderer's functions and operators give the same results for the same arguments,
+/// except that `now` remains the time of the first render.  Limits apply to each update as to a
+/// render, counting only the work of the parts rendered again.
+///
+/// ```
+/// # use json_e::Renderer;
+/// # use serde_json::json;
+/// let template = json!({
+///     "title": "${title}",
+///     "tags": {"$map": {"$eval": "tags"}, "each(t)": "#${t}"},
+/// });
+/// let compiled = Renderer::new().compile(&template);
+/// let mut render = compiled
+///     .render_incremental(&json!({"title": "Draft", "tags": ["a"]}))
+///     .unwrap();
+/// assert_eq!(render.output(), &json!({"title": "Draft", "tags": ["#a"]}));
+///
+/// // only the title is rendered again
+/// let patch = render.update(&json!({"title": "Final", "tags": ["a"]})).unwrap();
+/// assert_eq!(
+///     serde_json::to_value(&patch).unwrap(),
+///     json!([{"op": "replace", "path": "/title", "value": "Final"}]),
+/// );
+/// assert_eq!(render.output(), &json!({"title": "Final", "tags": ["#a"]}));
+/// ```
+pub struct IncrementalRender<'a> {
+    compiled: &'a CompiledTemplate,
+    context: SerdeValue,
+    now: SystemTime,
+    output: SerdeValue,
+    tracked: Tracked<'a>,
+}
+
+/// A part of a template, as tracked for incremental rendering
+enum Tracked<'a> {
+    /// An array, with each of its elements tracked separately
+    Array(Vec<Tracked<'a>>),
+    /// An object whose keys contain no interpolations, with each of its properties, along with
+    /// its raw and literal key, tracked separately
+    Object(Vec<(&'a str, &'a str, Tracked<'a>)>),
+    /// Any other part, which is rendered as a whole
+    Leaf {
+        template: &'a Template,
+        /// The context paths looked up when this part was last rendered
+        paths: BTreeSet<Vec<String>>,
+        /// False if this part last rendered as a deletion marker, and so is absent from the output
+        present: bool,
+    },
+}
+
+impl<'a> IncrementalRender<'a> {
+    pub(crate) fn new(
+        compiled: &'a CompiledTemplate,
+        template: &'a Template,
+        context: &SerdeValue,
+        now: SystemTime,
+    ) -> Result<IncrementalRender<'a>> {
+        compiled.check_depth(context)?;
+        let reads = Reads::default();
+        let (tracked, output) =
+            compiled.with_values(&context.into(), now, Some(&reads), |context| {
+                track(template, context, &reads)
+            })?;
+        Ok(IncrementalRender {
+            compiled,
+            context: context.clone(),
+            now,
+            // note that this will convert DeletionMarker into Null
+            output: output.try_into()?,
+            tracked,
+        })
+    }
+
+    /// The output for the current context.
+    pub fn output(&self) -> &SerdeValue {
+        &self.output
+    }
+
+    /// The current context.
+    pub fn context(&self) -> &SerdeValue {
+        &self.context
+    }
+
+    /// Update the output for the given context, returning a JSON Patch that transforms the
+    /// previous output into the new one.  If rendering fails, the error is returned and the
+    /// context and output are left as they were.
+    pub fn update(&mut self, context: &SerdeValue) -> Result<Patch, Error> {
+        self.compiled.check_depth(context)?;
+        let values: Value = context.into();
+        Context::object_of(&values)?;
+        let changed = changed_paths(&self.context, context);
+
+        // render the affected parts, so that nothing has changed if any of them fails
+        let reads = Reads::default();
+        let rendered = self
+            .compiled
+            .with_values(&values, self.now, Some(&reads), |context| {
+                let mut rendered = Vec::new();
+                rerender(&self.tracked, &changed, context, &reads, &mut rendered)?;
+                Ok(rendered)
+            })?
+            .into_iter()
+            .map(|(value, paths)| {
+                Ok(match value {
+                    Value::DeletionMarker => (None, paths),
+                    value => (Some(value.try_into()?), paths),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut patch = Patch::default();
+        let mut rendered = rendered.into_iter();
+        match &mut self.tracked {
+            Tracked::Leaf { paths, .. } => {
+                if let Some(value) = take_rendered(paths, &changed, &mut rendered) {
+                    // note that a deletion marker is rendered as Null
+                    let value = value.unwrap_or(SerdeValue::Null);
+                    replace(&mut self.output, value, &PointerBuf::new(), &mut patch);
+                }
+            }
+            tracked => update_part(
+                tracked,
+                &mut self.output,
+                &mut PointerBuf::new(),
+                &changed,
+                &mut rendered,
+                &mut patch,
+            ),
+        }
+        self.context = context.clone();
+        Ok(patch)
+    }
+
+    /// Update the output for the result of applying the given JSON Patch to the current context,
+    /// as for [`update`](Self::update).  If the patch cannot be applied, this fails with
+    /// [`ErrorKind::InvalidContext`].
+    pub fn patch_context(&mut self, patch: &Patch) -> Result<Patch, Error> {
+        let mut context = self.context.clone();
+        json_patch::patch(&mut context, patch).map_err(|e| {
+            error_of_kind!(
+                ErrorKind::InvalidContext,
+                "context patch could not be applied: {}",
+                e
+            )
+        })?;
+        self.update(&context)
+    }
+}
+
+/// Render the given template, as `_render` would, tracking its parts.
+fn track<'a>(
+    template: &'a Template,
+    context: &Context,
+    reads: &Reads,
+) -> Result<(Tracked<'a>, Value)> {
+    // the checks and their order here follow those of `_render`
+    match template {
+        Template::Array(elements) => {
+            let _depth = context.enter()?;
+            context.check_array(elements.len())?;
+            let mut tracked = Vec::with_capacity(elements.len());
+            let mut result = Vec::with_capacity(elements.len());
+            for (i, element) in elements.iter().enumerate() {
+                let (t, v) = track(element, context, reads).map_err(|e| e.add_location(i))?;
+                tracked.push(t);
+                if v != Value::DeletionMarker {
+                    result.push(v);
+                }
+            }
+            Ok((Tracked::Array(tracked), Value::Array(result.into())))
+        }
+        Template::Object {
+            dynamic_keys,
+            properties,
+        } if dynamic_keys.is_empty() => {
+            let _depth = context.enter()?;
+            context.add_nodes(properties.len())?;
+            let mut tracked = Vec::with_capacity(properties.len());
+            let mut result = Object::new();
+            // with no dynamic keys, every key is a literal, so the keys are distinct
+            for (raw, k, v) in properties {
+                let key = k
+                    .as_literal()
+                    .expect("key of a tracked object is not literal");
+                let (t, v) = track(v, context, reads).map_err(|e| e.add_location(raw))?;
+                tracked.push((raw.as_str(), key, t));
+                if v != Value::DeletionMarker {
+                    result.insert(key.to_owned(), v);
+                }
+            }
+            Ok((Tracked::Object(tracked), Value::Object(result.into())))
+        }
+        template => {
+            let value = _render(template, context)?;
+            let tracked = Tracked::Leaf {
+                template,
+                paths: reads.take(),
+                present: value != Value::DeletionMarker,
+            };
+            Ok((tracked, value))
+        }
+    }
+}
+
+/// The paths to the values that differ between the given contexts, which are objects: the
+/// paths to properties that were added or removed, and to values that were replaced, except that
+/// the paths to the properties or elements that differ within objects, or within arrays of the
+/// same length, are given instead of the paths to those objects and arrays.
+fn changed_paths(old: &SerdeValue, new: &SerdeValue) -> Vec<Vec<String>> {
+    fn diff(
+        old: &SerdeValue,
+        new: &SerdeValue,
+        path: &mut Vec<String>,
+        changed: &mut Vec<Vec<String>>,
+    ) {
+        match (old, new) {
+            _ if old == new => {}
+            (SerdeValue::Object(old), SerdeValue::Object(new)) => {
+                for (k, v) in old {
+                    path.push(k.clone());
+                    match new.get(k) {
+                        Some(n) => diff(v, n, path, changed),
+                        None => changed.push(path.clone()),
+                    }
+                    path.pop();
+                }
+                for k in new.keys().filter(|k| !old.contains_key(*k)) {
+                    path.push(k.clone());
+                    changed.push(path.clone());
+                    path.pop();
+                }
+            }
+            (SerdeValue::Array(old), SerdeValue::Array(new)) if old.len() == new.len() => {
+                for (i, (o, n)) in old.iter().zip(new).enumerate() {
+                    path.push(i.to_string());
+                    diff(o, n, path, changed);
+                    path.pop();
+                }
+            }
+            _ => changed.push(path.clone()),
+        }
+    }
+
+    let mut changed = vec![];
+    diff(old, new, &mut vec![], &mut changed);
+    changed
+}
+
+/// Whether any of the given paths looked up by a part of the template is within, or contains, any
+/// of the given changed paths.
+fn affected(paths: &BTreeSet<Vec<String>>, changed: &[Vec<String>]) -> bool {
+    paths
+        .iter()
+        .any(|p| changed.iter().any(|c| p.starts_with(c) || c.starts_with(p)))
+}
+
+/// Render again each leaf of the given tracked part that is affected by the given changed paths,
+/// in order, adding its result and the paths it looked up to `rendered`.
+fn rerender(
+    tracked: &Tracked,
+    changed: &[Vec<String>],
+    context: &Context,
+    reads: &Reads,
+    rendered: &mut Vec<(Value, BTreeSet<Vec<String>>)>,
+) -> Result<()> {
+    match tracked {
+        Tracked::Array(elements) => {
+            let _depth = context.enter()?;
+            for (i, element) in elements.iter().enumerate() {
+                rerender(element, changed, context, reads, rendered)
+                    .map_err(|e| e.add_location(i))?;
+            }
+        }
+        Tracked::Object(properties) => {
+            let _depth = context.enter()?;
+            for (raw, _, property) in properties {
+                rerender(property, changed, context, reads, rendered)
+                    .map_err(|e| e.add_location(raw))?;
+            }
+        }
+        Tracked::Leaf {
+            template, paths, ..
+        } => {
+            if affected(paths, changed) {
+                let value = _render(template, context)?;
+                rendered.push((value, reads.take()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Update the output of the given tracked array or object with the results of `rerender`,
+/// adding the changes to the given patch.
+fn update_part<I>(
+    tracked: &mut Tracked,
+    output: &mut SerdeValue,
+    path: &mut PointerBuf,
+    changed: &[Vec<String>],
+    rendered: &mut I,
+    patch: &mut Patch,
+) where
+    I: Iterator<Item = (Option<SerdeValue>, BTreeSet<Vec<String>>)>,
+{
+    match (tracked, output) {
+        (Tracked::Array(elements), SerdeValue::Array(items)) => {
+            // the index in the output of the next element present in it
+            let mut i = 0;
+            for element in elements {
+                path.push_back(i);
+                match element {
+                    Tracked::Leaf { paths, present, .. } => {
+                        if let Some(value) = take_rendered(paths, changed, rendered) {
+                            match (value, *present) {
+                                (Some(value), true) => replace(&mut items[i], value, path, patch),
+                                (Some(value), false) => {
+                                    add(path, &value, patch);
+                                    items.insert(i, value);
+                                    *present = true;
+                                }
+                                (None, true) => {
+                                    remove(path, patch);
+                                    items.remove(i);
+                                    *present = false;
+                                }
+                                (None, false) => {}
+                            }
+                        }
+                        if *present {
+                            i += 1;
+                        }
+                    }
+                    element => {
+                        update_part(element, &mut items[i], path, changed, rendered, patch);
+                        i += 1;
+                    }
+                }
+                path.pop_back();
+            }
+        }
+        (Tracked::Object(properties), SerdeValue::Object(map)) => {
+            let mut reorder = false;
+            for (_, key, property) in properties.iter_mut() {
+                path.push_back(*key);
+                match property {
+                    Tracked::Leaf { paths, present, .. } => {
+                        if let Some(value) = take_rendered(paths, changed, rendered) {
+                            match (value, map.get_mut(*key)) {
+                                (Some(value), Some(item)) => replace(item, value, path, patch),
+                                (Some(value), None) => {
+                                    add(path, &value, patch);
+                                    map.insert(key.to_string(), value);
+                                    *present = true;
+                                    reorder = true;
+                                }
+                                (None, Some(_)) => {
+                                    remove(path, patch);
+                                    map.remove(*key);
+                                    *present = false;
+                                    reorder = true;
+                                }
+                                (None, None) => {}
+                            }
+                        }
+                    }
+                    property => {
+                        let item = map.get_mut(*key).expect("tracked property is missing");
+                        update_part(property, item, path, changed, rendered, patch);
+                    }
+                }
+                path.pop_back();
+            }
+
+            // keep the properties in the order in which a render would produce them
+            if reorder {
+                let mut old = std::mem::take(map);
+                *map = properties
+                    .iter()
+                    .filter_map(|(_, key, _)| old.remove(*key).map(|v| (key.to_string(), v)))
+                    .collect::<Map<_, _>>();
+            }
+        }
+        _ => unreachable!("output does not match tracked part"),
+    }
+}
+
+/// Take the result of rendering again the leaf that last looked up the given paths, if it is
+/// affected by the changed paths, replacing its paths with those it looked up this time.  This
+/// visits the leaves in the same order as `rerender`, so the results are taken in the order they
+/// were rendered.
+fn take_rendered<I>(
+    paths: &mut BTreeSet<Vec<String>>,
+    changed: &[Vec<String>],
+    rendered: &mut I,
+) -> Option<Option<SerdeValue>>
+where
+    I: Iterator<Item = (Option<SerdeValue>, BTreeSet<Vec<String>>)>,
+{
+    if !affected(paths, changed) {
+        return None;
+    }
+    let (value, reads) = rendered.next().expect("leaf was not rendered again");
+    *paths = reads;
+    Some(value)
+}
+
+/// Replace the output value at the given path, adding the differences to the patch.
+fn replace(output: &mut SerdeValue, value: SerdeValue, path: &Pointer, patch: &mut Patch) {
+    for op in json_patch::diff(output, &value).0 {
+        patch.0.push(match op {
+            PatchOperation::Replace(mut op) => {
+                op.path = path.concat(&op.path);
+                PatchOperation::Replace(op)
+            }
+            PatchOperation::Add(mut op) => {
+                op.path = path.concat(&op.path);
+                PatchOperation::Add(op)
+            }
+            PatchOperation::Remove(mut op) => {
+                op.path = path.concat(&op.path);
+                PatchOperation::Remove(op)
+            }
+            _ => unreachable!("diff produced an operation other than add, remove, or replace"),
+        });
+    }
+    *output = value;
+}
+
+fn add(path: &Pointer, value: &SerdeValue, patch: &mut Patch) {
+    patch.0.push(PatchOperation::Add(json_patch::AddOperation {
+        path: path.to_buf(),
+        value: value.clone(),
+    }));
+}
+
+fn remove(path: &Pointer, patch: &mut Patch) {
+    patch
+        .0
+        .push(PatchOperation::Remove(json_patch::RemoveOperation {
+            path: path.to_buf(),
+        }));
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{ErrorKind, Patch, Renderer};
+    use serde_json::{json, Value as SerdeValue};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Render the given template incrementally, updating it for each of the given contexts in
+    /// turn, and check that the output is the same as a full render, including the order of keys,
+    /// and that the patch transforms the previous output into it.
+    fn check_updates(template: SerdeValue, contexts: &[SerdeValue]) {
+        let renderer = Renderer::new();
+        let compiled = renderer.compile(&template);
+        let mut render = compiled.render_incremental(&contexts[0]).unwrap();
+        for context in contexts {
+            let previous = render.output().clone();
+            let patch = render.update(context).unwrap();
+            let expected = compiled.render(context).unwrap();
+            assert_eq!(
+                render.output().to_string(),
+                expected.to_string(),
+                "{}",
+                context
+            );
+            // applying a patch need not keep the order of keys
+            let mut patched = previous;
+            json_patch::patch(&mut patched, &patch).unwrap();
+            assert_eq!(patched, expected, "{}", context);
+            assert_eq!(render.context(), context);
+        }
+    }
+
+    #[test]
+    fn only_affected_parts_are_rendered() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let renderer = Renderer::new().function("count", move |args| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(args[0].clone())
+        });
+        let template = json!({
+            "a": {"$eval": "count(a)"},
+            "b": [{"$eval": "count(b)"}, {"c": {"$eval": "count(b + c)"}}],
+        });
+        let compiled = renderer.compile(&template);
+        let mut render = compiled
+            .render_incremental(&json!({"a": 1, "b": 2, "c": 3}))
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let patch = render.update(&json!({"a": 1, "b": 2, "c": 4})).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert_eq!(
+            serde_json::to_value(&patch).unwrap(),
+            json!([{"op": "replace", "path": "/b/1/c", "value": 6}]),
+        );
+
+        let patch = render.update(&json!({"a": 1, "b": 2, "c": 4})).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert!(patch.0.is_empty());
+
+        render.update(&json!({"a": 1, "b": 3, "c": 4})).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+        assert_eq!(render.output(), &json!({"a": 1, "b": [3, {"c": 7}]}));
+    }
+
+    #[test]
+    fn same_as_render() {
+        check_updates(
+            json!({
+                "title": "${title}",
+                "maybe": {"$if": "show", "then": "shown"},
+                "items": [
+                    {"$if": "first", "then": 1},
+                    "${title}",
+                    {"$if": "!first", "then": 2},
+                    {"$if": "show", "then": {"x": "${title}", "n": {"$eval": "n"}}},
+                    {"$if": "first", "then": 3},
+                ],
+                "list": {"$map": {"$eval": "range(0, n)"}, "each(i)": "${title}${i}"},
+                "nested": {"deep": {"$eval": "[n, title]"}, "$$lit": "${title}"},
+                "z": "literal",
+            }),
+            &[
+                json!({"title": "a", "show": true, "first": true, "n": 2}),
+                json!({"title": "b", "show": true, "first": true, "n": 2}),
+                json!({"title": "b", "show": false, "first": false, "n": 2}),
+                json!({"title": "b", "show": true, "first": false, "n": 3}),
+                json!({"title": "c", "show": false, "first": true, "n": 0}),
+                json!({"title": "c", "show": true, "first": true, "n": 1}),
+            ],
+        );
+    }
+
+    #[test]
+    fn root_is_rendered_as_a_whole() {
+        check_updates(
+            json!({"$if": "x", "then": {"a": "${x}"}}),
+            &[
+                json!({"x": ""}),
+                json!({"x": "y"}),
+                json!({"x": "z"}),
+                json!({"x": ""}),
+            ],
+        );
+        check_updates(
+            json!({"$let": {"y": "${x}"}, "in": ["${y}"]}),
+            &[json!({"x": 1}), json!({"x": 2})],
+        );
+    }
+
+    #[test]
+    fn dependencies_follow_each_render() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let renderer = Renderer::new().function("count", move |args| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(args[0].clone())
+        });
+        let template = json!({
+            "a": {"$if": "x", "then": {"$eval": "count(y)"}, "else": "none"},
+            "b": {"$eval": "count(defined('z'))"},
+        });
+        let compiled = renderer.compile(&template);
+        let mut render = compiled.render_incremental(&json!({"x": false})).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // `a` did not look up `y`
+        render.update(&json!({"x": false, "y": 1})).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        render.update(&json!({"x": true, "y": 1})).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        render.update(&json!({"x": true, "y": 2})).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // `b` looked up `z`, which was missing
+        render
+            .update(&json!({"x": true, "y": 2, "z": null}))
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert_eq!(render.output(), &json!({"a": 2, "b": true}));
+    }
+
+    #[test]
+    fn only_affected_paths_are_rendered() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let renderer = Renderer::new().function("count", move |args| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(args[0].clone())
+        });
+        let template = json!({
+            "name": {"$eval": "count(form.name)"},
+            "city": {"$eval": "count(form['address'].city)"},
+            "first": {"$eval": "count(form.tags[0])"},
+            "tags": {"$eval": "count(len(form.tags))"},
+        });
+        let compiled = renderer.compile(&template);
+        let form = |name, city, tags| {
+            json!({"form": {"name": name, "address": {"city": city}, "tags": tags}})
+        };
+        let mut render = compiled
+            .render_incremental(&form("a", "b", json!(["c"])))
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let patch = render.update(&form("a", "x", json!(["c"]))).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+        assert_eq!(
+            serde_json::to_value(&patch).unwrap(),
+            json!([{"op": "replace", "path": "/city", "value": "x"}]),
+        );
+
+        // changing an element only affects the parts that used that element, or the whole array
+        render.update(&form("a", "x", json!(["d"]))).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 7);
+
+        // as does changing the length of the array
+        render.update(&form("a", "x", json!(["d", "e"]))).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 9);
+
+        // adding a property beside those used affects nothing
+        let mut context = form("a", "x", json!(["d", "e"]));
+        context["form"]["address"]["zip"] = json!(1);
+        let patch = render.update(&context).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 9);
+        assert!(patch.0.is_empty());
+    }
+
+    #[test]
+    fn same_as_render_for_paths() {
+        check_updates(
+            json!({
+                "a": "${x.a}",
+                "b": {"$eval": "x.b[1]"},
+                "last": {"$eval": "x.b[-1]"},
+                "c": {"$eval": "x['c'].d"},
+                "key": {"$eval": "x[k]"},
+                "bound": {"$let": {"y": {"$eval": "x.b"}}, "in": {"$eval": "y[0]"}},
+            }),
+            &[
+                json!({"x": {"a": 1, "b": [1, 2], "c": {"d": 3}}, "k": "a"}),
+                json!({"x": {"a": 2, "b": [1, 2], "c": {"d": 3}}, "k": "a"}),
+                json!({"x": {"a": 2, "b": [1, 3], "c": {"d": 3}}, "k": "a"}),
+                json!({"x": {"a": 2, "b": [0, 3, 4], "c": {"d": 3}}, "k": "a"}),
+                json!({"x": {"a": 2, "b": [0, 3, 4], "c": {"d": 4}}, "k": "a"}),
+                json!({"x": {"a": 2, "b": [0, 3, 4], "c": {"d": 4, "e": 5}}, "k": "c"}),
+                json!({"x": {"a": 3, "b": [5, 3, 4], "c": {"d": 4, "e": 5}}, "k": "c"}),
+            ],
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let template = json!({"a": "${a}", "b": [1, {"$eval": "b + 1"}]});
+        let compiled = Renderer::new().compile(&template);
+
+        let context = json!({"a": "x", "b": "y"});
+        let err = compiled.render_incremental(&context).err().unwrap();
+        assert_eq!(err.location().pointer(), "/b/1/$eval");
+        assert_eq!(err, compiled.render(&context).unwrap_err());
+
+        let mut render = compiled
+            .render_incremental(&json!({"a": "x", "b": 1}))
+            .unwrap();
+        let err = render.update(&json!({"a": "z", "b": "y"})).unwrap_err();
+        assert_eq!(err.location().pointer(), "/b/1/$eval");
+        assert_eq!(render.output(), &json!({"a": "x", "b": [1, 2]}));
+        assert_eq!(render.context(), &json!({"a": "x", "b": 1}));
+
+        let err = render.update(&json!([])).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidContext);
+
+        // the failed update rendered `a`, but did not change it
+        let patch = render.update(&json!({"a": "z", "b": 1})).unwrap();
+        assert_eq!(
+            serde_json::to_value(&patch).unwrap(),
+            json!([{"op": "replace", "path": "/a", "value": "z"}]),
+        );
+    }
+
+    #[test]
+    fn patch_context() {
+        let template = json!({"greeting": "hello ${name}", "n": {"$eval": "len(items)"}});
+        let compiled = Renderer::new().compile(&template);
+        let mut render = compiled
+            .render_incremental(&json!({"name": "a", "items": []}))
+            .unwrap();
+
+        let diff: Patch = serde_json::from_value(json!([
+            {"op": "add", "path": "/items/-", "value": 1},
+            {"op": "replace", "path": "/name", "value": "b"},
+        ]))
+        .unwrap();
+        let patch = render.patch_context(&diff).unwrap();
+        assert_eq!(render.context(), &json!({"name": "b", "items": [1]}));
+        assert_eq!(render.output(), &json!({"greeting": "hello b", "n": 1}));
+        assert_eq!(patch.0.len(), 2);
+
+        let diff: Patch =
+            serde_json::from_value(json!([{"op": "remove", "path": "/nosuch"}])).unwrap();
+        let err = render.patch_context(&diff).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::InvalidContext);
+        assert_eq!(render.context(), &json!({"name": "b", "items": [1]}));
+    }
+}
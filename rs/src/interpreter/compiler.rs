@@ -0,0 +1,313 @@
+//! Compilation of expressions into trees of closures.
+//!
+//! The tree walker in `evaluator` matches on each node, parses number literals, and resolves
+//! operators from their source text every time an expression is evaluated.  Compiling does that
+//! work once: each node becomes a closure that holds its pre-parsed constants, resolved
+//! operators, and the closures of its operands.  Both share the operations of `evaluator`, so
+//! they give the same results and errors, and count the same depth against the budget.
+
+use super::context::Context;
+use super::evaluator::{self, BinaryOp, UnaryOp};
+use super::node::Node;
+use crate::errors::Result;
+use crate::limits::Budget;
+use crate::value::{Object, Value};
+use std::fmt;
+use std::sync::Arc;
+
+/// The executable form of a node
+type Code = Box<dyn Fn(&Context) -> Result<Value> + Send + Sync>;
+
+/// A compiled expression, along with the node from which it was compiled.
+#[derive(Clone)]
+pub(crate) struct Compiled {
+    node: Node,
+    code: Arc<Code>,
+}
+
+impl Compiled {
+    /// Compile the given node, failing if compiling it would use more stack space than the
+    /// given budget allows.
+    pub(crate) fn new(node: Node, budget: &Budget) -> Result<Compiled> {
+        let code = Arc::new(compile(&node, false, budget)?);
+        Ok(Compiled { node, code })
+    }
+
+    pub(crate) fn node(&self) -> &Node {
+        &self.node
+    }
+
+    /// Evaluate this expression, exactly as `interpreter::evaluate` would evaluate its node.
+    pub(crate) fn evaluate(&self, context: &Context) -> Result<Value> {
+        (self.code)(context)
+    }
+}
+
+impl fmt::Debug for Compiled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Compiled").field(&self.node).finish()
+    }
+}
+
+/// Compiled expressions are equal if their nodes are.
+impl PartialEq for Compiled {
+    fn eq(&self, other: &Compiled) -> bool {
+        self.node == other.node
+    }
+}
+
+impl Eq for Compiled {}
+
+/// Wrap the code for a node so that it enters the context, as the tree walker does for every
+/// node it evaluates.  The left operand of a left-associative operation, a link in a chain such
+/// as `a + b + c`, takes a step at the same depth as the operation instead.
+fn code<F>(link: bool, f: F) -> Code
+where
+    F: Fn(&Context) -> Result<Value> + Send + Sync + 'static,
+{
+    if link {
+        Box::new(move |context| {
+            context.step()?;
+            f(context)
+        })
+    } else {
+        Box::new(move |context| {
+            let _depth = context.enter()?;
+            f(context)
+        })
+    }
+}
+
+/// A constant, which may be an error to return when it is evaluated
+fn constant(link: bool, value: Result<Value>) -> Code {
+    code(link, move |_| value.clone())
+}
+
+/// Compile a node, which is the left operand of a left-associative operation if `link` is set.
+/// Compiling recurses to the height of the node's tree, so checks the stack at each level.
+fn compile(node: &Node, link: bool, budget: &Budget) -> Result<Code> {
+    compile_within(node, link, vec![], budget)
+}
+
+/// Compile a node, as for `compile`, of whose value only the part at the given path will be used,
+/// as it is the operand of a chain of `.` and `[..]` with constant properties and indices.  An
+/// identifier records that path when it is looked up.
+fn compile_within(node: &Node, link: bool, within: Vec<String>, budget: &Budget) -> Result<Code> {
+    budget.check_stack()?;
+    let operand = |node: &Node| compile(node, false, budget);
+    // the path within the operand of `.` or `[..]`, given the path within their result
+    let operand_within = |segment: Option<String>| match segment {
+        Some(segment) => Some(segment).into_iter().chain(within.clone()).collect(),
+        None => vec![],
+    };
+    Ok(match *node {
+        Node::Number(ref n) => constant(link, evaluator::number(n)),
+        Node::String(ref s) => constant(link, Ok(Value::String(s.as_str().into()))),
+        Node::Ident(ref i) => {
+            let i = i.clone();
+            if within.is_empty() {
+                code(link, move |context| evaluator::ident(context, &i))
+            } else {
+                code(link, move |context| {
+                    evaluator::ident_within(context, &i, &within)
+                })
+            }
+        }
+        Node::True => constant(link, Ok(Value::Bool(true))),
+        Node::False => constant(link, Ok(Value::Bool(false))),
+        Node::Null => constant(link, Ok(Value::Null)),
+        Node::Array(ref items) => {
+            let items: Vec<Code> = items.iter().map(operand).collect::<Result<_>>()?;
+            code(link, move |context| {
+                context.check_array(items.len())?;
+                Ok(Value::Array(
+                    items
+                        .iter()
+                        .map(|i| i(context))
+                        .collect::<Result<_>>()?,
+                ))
+            })
+        }
+        Node::Object(ref items) => {
+            let items: Vec<(String, Code)> = items
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), operand(v)?)))
+                .collect::<Result<_>>()?;
+            code(link, move |context| {
+                context.add_nodes(items.len())?;
+                let mut map = Object::new();
+                for (k, v) in items.iter() {
+                    let v = v(context)?;
+                    map.insert(k.to_owned(), v);
+                }
+                Ok(Value::Object(map.into()))
+            })
+        }
+        Node::Un(ref op, ref v) => {
+            let op = UnaryOp::new(op);
+            let v = operand(v)?;
+            code(link, move |context| op.apply(v(context)?))
+        }
+        Node::Op(ref l, ref o, ref r) => {
+            let l = compile(l, o != "**", budget)?;
+            let o = BinaryOp::new(o);
+            let r = operand(r)?;
+            code(link, move |context| {
+                let l = l(context)?;
+                if let Some(result) = o.short_circuit(&l) {
+                    return Ok(result);
+                }
+                o.apply(context, l, r(context)?)
+            })
+        }
+        Node::Index(ref v, ref i) => {
+            let segment = match **i {
+                Node::String(ref s) => Some(s.clone()),
+                // a negative index depends on the length of the array
+                Node::Number(ref n) => n.parse::<usize>().ok().map(|n| n.to_string()),
+                _ => None,
+            };
+            let v = compile_within(v, true, operand_within(segment), budget)?;
+            let i = operand(i)?;
+            code(link, move |context| {
+                evaluator::index(v(context)?, i(context)?)
+            })
+        }
+        Node::Slice(ref v, ref a, ref b) => {
+            let v = compile(v, true, budget)?;
+            let a = a.as_deref().map(operand).transpose()?;
+            let b = b.as_deref().map(operand).transpose()?;
+            code(link, move |context| {
+                let v = v(context)?;
+                let len = evaluator::slice_len(&v)?;
+                let a = match a {
+                    Some(ref a) => evaluator::slice_bound(&a(context)?, len)?,
+                    None => 0,
+                };
+                let b = match b {
+                    Some(ref b) => evaluator::slice_bound(&b(context)?, len)?,
+                    None => len,
+                };
+                Ok(evaluator::slice(v, a, b))
+            })
+        }
+        Node::Dot(ref v, ref p) => {
+            let v = compile_within(v, true, operand_within(Some(p.clone())), budget)?;
+            let p = p.clone();
+            code(link, move |context| evaluator::dot(v(context)?, &p))
+        }
+        Node::Func(ref f, ref args) => {
+            let f = compile(f, true, budget)?;
+            let args: Vec<Code> = args.iter().map(operand).collect::<Result<_>>()?;
+            code(link, move |context| {
+                let f = f(context)?;
+                let args = args
+                    .iter()
+                    .map(|x| x(context))
+                    .collect::<Result<Vec<_>>>()?;
+                evaluator::call(context, f, &args)
+            })
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builtins::BUILTINS;
+    use crate::interpreter::{evaluate, parse_all};
+    use crate::limits::{Budget, Limits};
+    use serde_json::json;
+
+    /// Check that the compiled form of each expression gives the same result as the tree walker
+    fn check_same(context: &Context, expressions: &[&str]) {
+        let budget = Budget::new(Limits::default());
+        for source in expressions {
+            let node = parse_all(source, &budget).unwrap();
+            let compiled = Compiled::new(node.clone(), &budget).unwrap();
+            match (compiled.evaluate(context), evaluate(&node, context)) {
+                (Ok(c), Ok(w)) => assert_eq!(c, w, "{}", source),
+                (Err(c), Err(w)) => {
+                    assert_eq!(c.kind(), w.kind(), "{}", source);
+                    assert_eq!(c.message(), w.message(), "{}", source);
+                }
+                (c, w) => panic!("{}: compiled {:?}, walked {:?}", source, c, w),
+            }
+        }
+    }
+
+    fn context() -> Context<'static> {
+        Context::from_serde_value(
+            &json!({
+                "a": 1,
+                "b": 2.5,
+                "s": "héllo",
+                "arr": [1, [2, 3], {"x": "y"}],
+                "obj": {"x": {"y": [true, null]}, "n": 18446744073709551615u64},
+            }),
+            Some(&BUILTINS),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn same_as_walker() {
+        check_same(
+            &context(),
+            &[
+                "1", "1.5", "18446744073709551616", "'x'", "true", "false", "null", "a",
+                "[1, a, [s]]", "{x: a, y: {z: b}}", "-a", "+b", "!a", "!(!null)",
+                "a + b * 2 ** 3 / 4 - 1", "s + s", "a < b", "s >= 'h'", "a == 1.0", "a != b",
+                "'é' in s", "2 in arr", "'x' in obj", "a || nosuch", "null && nosuch",
+                "a && 0", "null || 'x'", "arr[1][0]", "arr[-1].x", "s[1]", "s[-9]", "obj['x']",
+                "obj['nosuch']", "arr[1:]", "s[-3:-1]", "arr[:9]", "s[3:1]", "obj.x.y[0]",
+                "obj.n * 2", "len(arr)", "max(a, b, 3)", "str(obj.n)", "lowercase(s)",
+                "fromNow == fromNow",
+            ],
+        );
+    }
+
+    #[test]
+    fn errors_same_as_walker() {
+        check_same(
+            &context(),
+            &[
+                "nosuch", "-s", "+arr", "a ** s", "s * 2", "a / 0", "a / s", "a + arr",
+                "s - 1", "s < 1", "arr > arr", "null <= 1", "obj >= obj", "1 in 1",
+                "arr in obj", "arr[1.5]", "arr[9]", "s['x']", "s[9]", "obj[1]", "a[0]",
+                "a[1:]", "arr[s:]", "arr[:s]", "nosuch[nosuch:]", "a.x", "obj.nosuch",
+                "a(1)", "len(nosuch)", "nosuch(1)", "len(1, 2)", "max()",
+            ],
+        );
+    }
+
+    #[test]
+    fn invalid_number_is_deferred() {
+        let budget = Budget::new(Limits::default());
+        let compiled = Compiled::new(Node::Number("1x".into()), &budget).unwrap();
+        assert_interpreter_error!(compiled.evaluate(&context()), "invalid number 1x");
+        assert_eq!(*compiled.node(), Node::Number("1x".into()));
+    }
+
+    #[test]
+    fn depth_is_limited() {
+        let budget = Budget::new(Limits::default().max_depth(10));
+        let mut context = context();
+        context.set_budget(&budget);
+        let source = format!("{}1{}", "[".repeat(20), "]".repeat(20));
+        let node = parse_all(&source, &Budget::new(Limits::default())).unwrap();
+        let compiled = Compiled::new(node.clone(), &budget).unwrap();
+        let c = compiled.evaluate(&context).unwrap_err();
+        let w = evaluate(&node, &context).unwrap_err();
+        assert_eq!(c.kind(), w.kind());
+    }
+
+    #[test]
+    fn compiling_checks_the_stack() {
+        let chain = vec!["1"; 200].join(" + ");
+        let node = parse_all(&chain, &Budget::new(Limits::default())).unwrap();
+        let budget = Budget::new(Limits::default().max_stack(4096));
+        let err = Compiled::new(node, &budget).unwrap_err();
+        assert_eq!(err.kind(), &crate::ErrorKind::StackLimitExceeded { limit: 4096 });
+    }
+}
@@ -2,14 +2,52 @@
 #![allow(dead_code)]
 
 use crate::render::is_identifier;
-use crate::value::Value;
-use anyhow::{anyhow, Result};
+use crate::value::{Object, Value};
+use crate::errors::{ErrorKind, Result};
+use crate::include::IncludeStack;
+use crate::limits::{Budget, Depth};
 use serde_json::Value as SerdeValue;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
 
+/// A scope for expression evaluation, with a parent scope to which it defers for names it does
+/// not bind.  Each level is a frame on the Rust stack, so a chain of contexts is a stack of frames
+/// that is searched from the innermost outward.
+///
+/// Most levels bind only a name or two, such as the variables of a `$map` iteration.  These are
+/// kept in a small list and found by comparing names, without hashing.  A level can also bind the
+/// properties of an object, such as the context given to `render` or the bindings of a `$let`,
+/// which it shares rather than copies.
+#[derive(Clone)]
 pub(crate) struct Context<'a> {
-    content: HashMap<String, Value>,
+    /// Names bound individually at this level
+    names: Vec<(Cow<'a, str>, Value)>,
+    /// An object whose properties are bound at this level, unless shadowed by `names`
+    object: Option<Arc<Object>>,
     parent: Option<&'a Context<'a>>,
+    budget: Option<&'a Budget>,
+    includes: Option<&'a IncludeStack<'a>>,
+    /// Where to record the names looked up at this level, whether or not they are bound here
+    reads: Option<&'a Reads>,
+}
+
+/// The paths looked up in a level of a context, as recorded for incremental rendering.  Each
+/// path is a name, followed by the properties and indices taken from its value, if only part of
+/// it was used.
+#[derive(Debug, Default)]
+pub(crate) struct Reads(Mutex<BTreeSet<Vec<String>>>);
+
+impl Reads {
+    fn record(&self, name: &str, within: &[String]) {
+        let path = Some(name.to_owned()).into_iter().chain(within.iter().cloned());
+        self.0.lock().unwrap().insert(path.collect());
+    }
+
+    /// The paths recorded since this was last taken
+    pub(crate) fn take(&self) -> BTreeSet<Vec<String>> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
 }
 
 /// Context for expression evaluation.
@@ -17,17 +55,25 @@ impl<'a> Context<'a> {
     /// Create a new, empty context.
     pub(crate) fn new() -> Context<'a> {
         Context {
-            content: HashMap::new(),
+            names: Vec::new(),
+            object: None,
             parent: None,
+            budget: None,
+            includes: None,
+            reads: None,
         }
     }
 
     /// Create a child context, which will defer to the parent context when a value
-    /// is not defined.
+    /// is not defined.  This does not allocate until a name is inserted.
     pub(crate) fn child(&'a self) -> Context<'a> {
         Context {
-            content: HashMap::new(),
+            names: Vec::new(),
+            object: None,
             parent: Some(self),
+            budget: self.budget,
+            includes: self.includes,
+            reads: None,
         }
     }
 
@@ -36,47 +82,155 @@ impl<'a> Context<'a> {
         value: &'_ SerdeValue,
         parent: Option<&'a Context>,
     ) -> Result<Context<'a>> {
-        // TODO: this could be more efficient by converting only the values and not cloning
         let value: Value = value.into();
         Context::from_value(&value, parent)
     }
 
-    /// Create a context from a json-e Value, which must be an object
+    /// Create a context from a json-e Value, which must be an object.  The object is shared with
+    /// the context, not copied.
     pub(crate) fn from_value(value: &'_ Value, parent: Option<&'a Context>) -> Result<Context<'a>> {
         let mut c = Context {
-            content: HashMap::new(),
+            names: Vec::new(),
+            object: None,
             parent,
+            budget: parent.and_then(|p| p.budget),
+            includes: parent.and_then(|p| p.includes),
+            reads: None,
         };
+        c.bind_object(Context::object_of(value)?);
+        Ok(c)
+    }
 
+    /// The object whose properties a context created from the given value binds, checking that
+    /// it is an object whose keys are identifiers.
+    pub(crate) fn object_of(value: &Value) -> Result<Arc<Object>> {
         if let Value::Object(o) = value {
             if o.keys().any(|k| !is_identifier(k)) {
-                return Err(template_error!(
+                return Err(error_of_kind!(
+                    ErrorKind::InvalidContext,
                     "top level keys of context must follow /[a-zA-Z_][a-zA-Z0-9_]"
                 ));
             }
-            for (k, v) in o.iter() {
-                c.insert(k, v.clone());
-            }
+            Ok(o.clone())
         } else {
-            return Err(anyhow!("Context is not an Object"));
+            Err(error_of_kind!(ErrorKind::InvalidContext, "Context is not an Object"))
         }
+    }
 
-        Ok(c)
+    /// Insert a value into this context, replacing any value already inserted with that name.
+    /// Replacing a value does not allocate, so a loop can reuse a single child context for all
+    /// of its iterations.
+    pub(crate) fn insert<K: Into<Cow<'a, str>>>(&mut self, k: K, v: Value) {
+        let k = k.into();
+        match self.names.iter_mut().find(|(name, _)| *name == k) {
+            Some((_, value)) => *value = v,
+            None => self.names.push((k, v)),
+        }
+    }
+
+    /// Bind each property of the given object in this context, with the caller having checked
+    /// that its keys are identifiers.  Values inserted by name take precedence.
+    pub(crate) fn bind_object(&mut self, object: Arc<Object>) {
+        self.object = Some(object);
+    }
+
+    /// Limit the resources used by rendering with this context and its children to the given
+    /// budget.
+    pub(crate) fn set_budget(&mut self, budget: &'a Budget) {
+        self.budget = Some(budget);
+    }
+
+    /// The budget limiting the resources used by rendering with this context, if any.
+    pub(crate) fn budget(&self) -> Option<&'a Budget> {
+        self.budget
+    }
+
+    /// Make templates available to `$include` when rendering with this context and its children,
+    /// from within the templates already included in the given stack.
+    pub(crate) fn set_includes(&mut self, includes: &'a IncludeStack<'a>) {
+        self.includes = Some(includes);
+    }
+
+    /// Record the names looked up in this context or its children that are not bound by its
+    /// children, whether or not they are bound here.
+    pub(crate) fn set_reads(&mut self, reads: &'a Reads) {
+        self.reads = Some(reads);
+    }
+
+    /// The templates available to `$include`, if any, and those already included.
+    pub(crate) fn includes(&self) -> Option<&'a IncludeStack<'a>> {
+        self.includes
+    }
+
+    /// Take a step one level deeper into the template or expression, returning a guard that
+    /// leaves that level when dropped.
+    pub(crate) fn enter(&self) -> Result<Depth<'a>> {
+        match self.budget {
+            Some(budget) => budget.enter(),
+            None => Ok(Depth::unlimited()),
+        }
+    }
+
+    /// Take a step without going any deeper, checking the stack space used.
+    pub(crate) fn step(&self) -> Result<()> {
+        self.budget.map_or(Ok(()), Budget::step)
+    }
+
+    /// Count the creation of the given number of object properties.
+    pub(crate) fn add_nodes(&self, count: usize) -> Result<()> {
+        self.budget.map_or(Ok(()), |b| b.add_nodes(count))
     }
 
-    /// Insert a value into this context.
-    pub(crate) fn insert<K: Into<String>>(&mut self, k: K, v: Value) {
-        self.content.insert(k.into(), v);
+    /// Check the length, in bytes, of a string about to be created.
+    pub(crate) fn check_string(&self, length: usize) -> Result<()> {
+        self.budget.map_or(Ok(()), |b| b.check_string(length))
+    }
+
+    /// Check the length of an array about to be created, counting its elements.
+    pub(crate) fn check_array(&self, length: usize) -> Result<()> {
+        self.budget.map_or(Ok(()), |b| b.check_array(length))
+    }
+
+    /// The names defined in this context and its parents, up to but not including the given
+    /// ancestor.  A name defined at more than one level appears more than once.
+    pub(crate) fn names_within<'b>(&'b self, ancestor: &Context) -> Vec<&'b str> {
+        let mut names: Vec<&str> = self.names.iter().map(|(name, _)| name.as_ref()).collect();
+        if let Some(object) = &self.object {
+            names.extend(
+                object
+                    .keys()
+                    .map(String::as_str)
+                    .filter(|k| !self.names.iter().any(|(name, _)| name == k)),
+            );
+        }
+        if let Some(p) = self.parent {
+            if !std::ptr::eq(p, ancestor) {
+                names.extend(p.names_within(ancestor));
+            }
+        }
+        names
     }
 
     /// Get a value from this context (or its parents)
     pub(crate) fn get<'b>(&'b self, k: &'_ str) -> Option<&'b Value> {
-        match self.content.get(k) {
-            Some(v) => Some(v),
-            None => match self.parent {
-                Some(p) => p.get(k),
-                None => None,
-            },
+        self.get_within(k, &[])
+    }
+
+    /// Get a value from this context (or its parents), of which only the part at the given path
+    /// of properties and indices will be used, recording the name along with that path.
+    pub(crate) fn get_within<'b>(&'b self, k: &'_ str, within: &[String]) -> Option<&'b Value> {
+        let mut context = self;
+        loop {
+            if let Some(reads) = context.reads {
+                reads.record(k, within);
+            }
+            if let Some((_, v)) = context.names.iter().find(|(name, _)| name == k) {
+                return Some(v);
+            }
+            if let Some(v) = context.object.as_ref().and_then(|o| o.get(k)) {
+                return Some(v);
+            }
+            context = context.parent?;
         }
     }
 }
@@ -85,6 +239,24 @@ impl<'a> Context<'a> {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_reads() {
+        let reads = Reads::default();
+        let mut c1 = Context::new();
+        c1.insert("a", Value::Null);
+        c1.set_reads(&reads);
+        let mut c2 = c1.child();
+        c2.insert("b", Value::Null);
+        c2.get("a");
+        c2.get("b");
+        c2.get("c");
+        c2.get_within("c", &["d".to_owned()]);
+        c2.get("c");
+        let paths: Vec<_> = reads.take().into_iter().collect();
+        assert_eq!(paths, vec![vec!["a"], vec!["c"], vec!["c", "d"]]);
+        assert!(reads.take().is_empty());
+    }
+
     #[test]
     fn test_get_not_found() {
         let c = Context::new();
@@ -110,4 +282,39 @@ mod test {
         assert_eq!(c2.get("def"), Some(&Value::Bool(false)));
         assert_eq!(c2.get("ghi"), Some(&Value::String("hi".into())));
     }
+
+    #[test]
+    fn test_insert_replaces() {
+        let c1 = Context::new();
+        let mut c2 = c1.child();
+        for i in 0..3usize {
+            c2.insert("x", Value::Number(i.into()));
+        }
+        assert_eq!(c2.get("x"), Some(&Value::Number(2usize.into())));
+        assert_eq!(c2.names_within(&c1), vec!["x"]);
+    }
+
+    #[test]
+    fn test_bind_object() {
+        let mut object = Object::new();
+        object.insert("abc".into(), Value::Null);
+        object.insert("def".into(), Value::Bool(true));
+        let object = Arc::new(object);
+
+        let mut c1 = Context::new();
+        c1.insert("ghi", Value::Bool(false));
+        let mut c2 = c1.child();
+        c2.bind_object(object.clone());
+        c2.insert("def", Value::Bool(false));
+        assert_eq!(c2.get("abc"), Some(&Value::Null));
+        // names inserted individually take precedence over the object
+        assert_eq!(c2.get("def"), Some(&Value::Bool(false)));
+        assert_eq!(c2.get("ghi"), Some(&Value::Bool(false)));
+        assert_eq!(c2.get("xyz"), None);
+        let mut names = c2.names_within(&c1);
+        names.sort_unstable();
+        assert_eq!(names, vec!["abc", "def"]);
+        // the object is shared, not copied
+        assert_eq!(Arc::strong_count(&object), 2);
+    }
 }
@@ -2,154 +2,313 @@
 #![allow(dead_code)]
 use super::context::Context;
 use super::node::Node;
+use crate::number::Number;
 use crate::value::{Object, Value};
-use anyhow::Result;
+use crate::errors::{ErrorKind, Result};
+use std::sync::Arc;
 
 pub(crate) fn evaluate(node: &Node, context: &Context) -> Result<Value> {
+    let _depth = context.enter()?;
+    evaluate_node(node, context)
+}
+
+/// Evaluate the left operand of a left-associative operation, at the same depth as the
+/// operation, so that a chain of operations such as `a + b + c` counts as a single level.
+fn evaluate_link(node: &Node, context: &Context) -> Result<Value> {
+    context.step()?;
+    evaluate_node(node, context)
+}
+
+fn evaluate_node(node: &Node, context: &Context) -> Result<Value> {
     match *node {
-        Node::Number(n) => Ok(Value::Number(n.parse()?)),
-        Node::String(s) => Ok(Value::String(s.to_owned())),
-        Node::Ident(i) => match context.get(i) {
-            Some(v) => Ok(v.clone()),
-            None => Err(interpreter_error!("unknown context value {}", i)),
-        },
+        Node::Number(ref n) => number(n),
+        Node::String(ref s) => Ok(Value::String(s.as_str().into())),
+        Node::Ident(ref i) => ident(context, i),
         Node::True => Ok(Value::Bool(true)),
         Node::False => Ok(Value::Bool(false)),
         Node::Null => Ok(Value::Null),
-        Node::Array(ref items) => Ok(Value::Array(
-            items
-                .iter()
-                .map(|i| evaluate(i, context))
-                .collect::<Result<Vec<Value>>>()?,
-        )),
+        Node::Array(ref items) => {
+            context.check_array(items.len())?;
+            Ok(Value::Array(
+                items
+                    .iter()
+                    .map(|i| evaluate(i, context))
+                    .collect::<Result<_>>()?,
+            ))
+        }
         Node::Object(ref items) => {
+            context.add_nodes(items.len())?;
             let mut map = Object::new();
             for (k, v) in items.iter() {
                 let v = evaluate(v, context)?;
-                map.insert((*k).to_owned(), v);
+                map.insert(k.to_owned(), v);
+            }
+            Ok(Value::Object(map.into()))
+        }
+        Node::Un(ref op, ref v) => UnaryOp::new(op).apply(evaluate(v, context)?),
+        Node::Op(ref l, ref o, ref r) => {
+            let l = if o == "**" {
+                evaluate(l, context)?
+            } else {
+                evaluate_link(l, context)?
+            };
+            let o = BinaryOp::new(o);
+            if let Some(result) = o.short_circuit(&l) {
+                return Ok(result);
             }
-            Ok(Value::Object(map))
+            o.apply(context, l, evaluate(r, context)?)
+        }
+        Node::Index(ref v, ref i) => index(evaluate_link(v, context)?, evaluate(i, context)?),
+        Node::Slice(ref v, ref a, ref b) => {
+            let v = evaluate_link(v, context)?;
+            let len = slice_len(&v)?;
+            let a = match a {
+                Some(a) => slice_bound(&evaluate(a, context)?, len)?,
+                None => 0,
+            };
+            let b = match b {
+                Some(b) => slice_bound(&evaluate(b, context)?, len)?,
+                None => len,
+            };
+            Ok(slice(v, a, b))
+        }
+        Node::Dot(ref v, ref p) => dot(evaluate_link(v, context)?, p),
+        Node::Func(ref f, ref args) => {
+            let f = evaluate_link(f, context)?;
+            let args = args
+                .iter()
+                .map(|x| evaluate(x, context))
+                .collect::<Result<Vec<_>>>()?;
+            call(context, f, &args)
         }
-        Node::Un(op, ref v) => un(context, op, v.as_ref()),
-        Node::Op(ref l, o, ref r) => op(context, l.as_ref(), o, r.as_ref()),
-        Node::Index(ref v, ref i) => index(context, v.as_ref(), i.as_ref()),
-        Node::Slice(ref v, ref a, ref b) => slice(context, v.as_ref(), a.as_deref(), b.as_deref()),
-        Node::Dot(ref v, p) => dot(context, v.as_ref(), p),
-        Node::Func(ref f, ref args) => func(context, f.as_ref(), &args[..]),
     }
 }
 
 /// Convert numbers that can be represented as an i64 into an i64.  This is used
 /// for indexing and slicing.
 fn number_to_i64(v: &Value) -> Option<i64> {
-    match v {
-        Value::Number(n) => {
-            let i = *n as i64;
-            if i as f64 == *n {
-                Some(i)
-            } else {
-                None
-            }
-        }
-        _ => None,
-    }
+    v.as_number().and_then(Number::as_i64)
 }
 
-fn un(context: &Context, op: &str, v: &Node) -> Result<Value> {
-    let v = evaluate(v, context)?;
-    match (op, v) {
-        ("-", Value::Number(ref n)) => Ok(Value::Number(-*n)),
-        ("-", _) => Err(interpreter_error!("This operator expects a number")),
-
-        ("+", v @ Value::Number(_)) => Ok(v),
-        ("+", _) => Err(interpreter_error!("This operator expects a number")),
+/// The value of a number literal
+pub(super) fn number(n: &str) -> Result<Value> {
+    Ok(Value::Number(
+        n.parse()
+            .map_err(|_| syntax_error!("invalid number {}", n))?,
+    ))
+}
 
-        ("!", v) => Ok(Value::Bool(!bool::from(v))),
+/// The value of an identifier in the context
+pub(super) fn ident(context: &Context, i: &str) -> Result<Value> {
+    ident_within(context, i, &[])
+}
 
-        _ => unreachable!(),
+/// The value of an identifier in the context, of which only the part at the given path will be
+/// used, as for [`Context::get_within`]
+pub(super) fn ident_within(context: &Context, i: &str, within: &[String]) -> Result<Value> {
+    match context.get_within(i, within) {
+        Some(v) => Ok(v.clone()),
+        None => Err(error_of_kind!(
+            ErrorKind::UnknownContextValue { name: i.to_owned() },
+            "unknown context value {}",
+            i
+        )),
     }
 }
 
-fn op(context: &Context, l: &Node, o: &str, r: &Node) -> Result<Value> {
-    let l = evaluate(l, context)?;
+/// A unary operator, resolved from its source text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum UnaryOp {
+    Minus,
+    Plus,
+    Not,
+}
 
-    // perform the short-circuiting operations first
-    if o == "||" && bool::from(&l) {
-        return Ok(Value::Bool(true));
-    } else if o == "&&" && !bool::from(&l) {
-        return Ok(Value::Bool(false));
+impl UnaryOp {
+    pub(super) fn new(op: &str) -> UnaryOp {
+        match op {
+            "-" => UnaryOp::Minus,
+            "+" => UnaryOp::Plus,
+            "!" => UnaryOp::Not,
+            _ => unreachable!(),
+        }
     }
 
-    // now we can unconditionally evaluate the right operand
-    let r = evaluate(r, context)?;
-
-    match (l, o, r) {
-        (Value::Number(ref l), "**", Value::Number(ref r)) => Ok(Value::Number(l.powf(*r))),
-        (_, "**", _) => Err(interpreter_error!("This operator expects numbers")),
-
-        (Value::Number(ref l), "*", Value::Number(ref r)) => Ok(Value::Number(*l * *r)),
-        (_, "*", _) => Err(interpreter_error!("This operator expects numbers")),
-
-        (Value::Number(ref l), "/", Value::Number(ref r)) => {
-            let q = *l / *r;
-            if !q.is_infinite() {
-                Ok(Value::Number(q))
-            } else {
-                Err(interpreter_error!("division by zero"))
-            }
+    pub(super) fn as_str(self) -> &'static str {
+        match self {
+            UnaryOp::Minus => "-",
+            UnaryOp::Plus => "+",
+            UnaryOp::Not => "!",
         }
-        (_, "/", _) => Err(interpreter_error!("This operator expects numbers")),
+    }
 
-        (Value::String(ref l), "+", Value::String(ref r)) => {
-            Ok(Value::String(format!("{}{}", l, r)))
+    pub(super) fn apply(self, v: Value) -> Result<Value> {
+        match (self, v) {
+            (UnaryOp::Minus, Value::Number(n)) => Ok(Value::Number(-n)),
+            (UnaryOp::Plus, v @ Value::Number(_)) => Ok(v),
+            (UnaryOp::Minus, ref v) | (UnaryOp::Plus, ref v) => Err(type_error!(
+                self.as_str(),
+                "number",
+                [v],
+                "This operator expects a number"
+            )),
+
+            (UnaryOp::Not, v) => Ok(Value::Bool(!bool::from(v))),
         }
-        (Value::Number(ref l), "+", Value::Number(ref r)) => Ok(Value::Number(*l + *r)),
-        (_, "+", _) => Err(interpreter_error!(
-            "This operator expects numbers or strings"
-        )),
-
-        (Value::Number(ref l), "-", Value::Number(ref r)) => Ok(Value::Number(*l - *r)),
-        (_, "-", _) => Err(interpreter_error!("This operator expects numbers")),
-
-        (Value::String(ref a), "<", Value::String(ref b)) => Ok(Value::Bool(a < b)),
-        (Value::Number(a), "<", Value::Number(b)) => Ok(Value::Bool(a < b)),
-        (_, "<", _) => Err(interpreter_error!("Expected numbers or strings")),
+    }
+}
 
-        (Value::String(ref a), ">", Value::String(ref b)) => Ok(Value::Bool(a > b)),
-        (Value::Number(a), ">", Value::Number(b)) => Ok(Value::Bool(a > b)),
-        (_, ">", _) => Err(interpreter_error!("Expected numbers or strings")),
+/// A binary operator, resolved from its source text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum BinaryOp {
+    Pow,
+    Mul,
+    Div,
+    Add,
+    Sub,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+    In,
+    And,
+    Or,
+}
 
-        (Value::String(ref a), "<=", Value::String(ref b)) => Ok(Value::Bool(a <= b)),
-        (Value::Number(a), "<=", Value::Number(b)) => Ok(Value::Bool(a <= b)),
-        (_, "<=", _) => Err(interpreter_error!("Expected numbers or strings")),
+impl BinaryOp {
+    pub(super) fn new(op: &str) -> BinaryOp {
+        match op {
+            "**" => BinaryOp::Pow,
+            "*" => BinaryOp::Mul,
+            "/" => BinaryOp::Div,
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Sub,
+            "<" => BinaryOp::Lt,
+            ">" => BinaryOp::Gt,
+            "<=" => BinaryOp::Le,
+            ">=" => BinaryOp::Ge,
+            "==" => BinaryOp::Eq,
+            "!=" => BinaryOp::Ne,
+            "in" => BinaryOp::In,
+            "&&" => BinaryOp::And,
+            "||" => BinaryOp::Or,
+            _ => unreachable!(),
+        }
+    }
 
-        (Value::String(ref a), ">=", Value::String(ref b)) => Ok(Value::Bool(a >= b)),
-        (Value::Number(a), ">=", Value::Number(b)) => Ok(Value::Bool(a >= b)),
-        (_, ">=", _) => Err(interpreter_error!("Expected numbers or strings")),
+    pub(super) fn as_str(self) -> &'static str {
+        match self {
+            BinaryOp::Pow => "**",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Lt => "<",
+            BinaryOp::Gt => ">",
+            BinaryOp::Le => "<=",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::In => "in",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        }
+    }
 
-        (l, "==", r) => Ok(Value::Bool(l == r)),
-        (l, "!=", r) => Ok(Value::Bool(l != r)),
+    /// The result of a short-circuiting operation given only its left operand, or None if the
+    /// right operand must be evaluated.
+    pub(super) fn short_circuit(self, l: &Value) -> Option<Value> {
+        match self {
+            BinaryOp::Or if bool::from(l) => Some(Value::Bool(true)),
+            BinaryOp::And if !bool::from(l) => Some(Value::Bool(false)),
+            _ => None,
+        }
+    }
 
-        (Value::String(ref l), "in", Value::String(ref r)) => Ok(Value::Bool(r.contains(l))),
-        (ref l, "in", Value::Array(ref r)) => Ok(Value::Bool(r.iter().any(|x| l == x))),
-        (Value::String(ref l), "in", Value::Object(ref r)) => Ok(Value::Bool(r.contains_key(l))),
-        (_, "in", _) => Err(interpreter_error!("Expected proper args for in")),
+    /// Apply this operator to evaluated operands, after checking `short_circuit`.
+    pub(super) fn apply(self, context: &Context, l: Value, r: Value) -> Result<Value> {
+        use BinaryOp::*;
+        let o = self.as_str();
+        match (l, self, r) {
+            (Value::Number(l), Pow, Value::Number(r)) => Ok(Value::Number(l.pow(r))),
+            (Value::Number(l), Mul, Value::Number(r)) => Ok(Value::Number(l * r)),
+            (Value::Number(l), Div, Value::Number(r)) => match l.checked_div(r) {
+                Some(q) => Ok(Value::Number(q)),
+                None => Err(interpreter_error!("division by zero")),
+            },
+            (Value::Number(l), Sub, Value::Number(r)) => Ok(Value::Number(l - r)),
+            (ref l, Pow, ref r) | (ref l, Mul, ref r) | (ref l, Div, ref r) | (ref l, Sub, ref r) => {
+                Err(type_error!(
+                    o,
+                    "numbers",
+                    [l, r],
+                    "This operator expects numbers"
+                ))
+            }
 
-        // We have already handled the left operand of the logical operators above, so these
-        // consider only the right.
-        (_, "&&", r) => Ok(Value::Bool(r.into())),
-        (_, "||", r) => Ok(Value::Bool(r.into())),
+            (Value::String(ref l), Add, Value::String(ref r)) => {
+                context.check_string(l.len() + r.len())?;
+                Ok(Value::String(format!("{}{}", l, r).into()))
+            }
+            (Value::Number(l), Add, Value::Number(r)) => Ok(Value::Number(l + r)),
+            (ref l, Add, ref r) => Err(type_error!(
+                o,
+                "numbers or strings",
+                [l, r],
+                "This operator expects numbers or strings"
+            )),
+
+            (Value::String(ref a), Lt, Value::String(ref b)) => Ok(Value::Bool(a < b)),
+            (Value::Number(a), Lt, Value::Number(b)) => Ok(Value::Bool(a < b)),
+            (Value::String(ref a), Gt, Value::String(ref b)) => Ok(Value::Bool(a > b)),
+            (Value::Number(a), Gt, Value::Number(b)) => Ok(Value::Bool(a > b)),
+            (Value::String(ref a), Le, Value::String(ref b)) => Ok(Value::Bool(a <= b)),
+            (Value::Number(a), Le, Value::Number(b)) => Ok(Value::Bool(a <= b)),
+            (Value::String(ref a), Ge, Value::String(ref b)) => Ok(Value::Bool(a >= b)),
+            (Value::Number(a), Ge, Value::Number(b)) => Ok(Value::Bool(a >= b)),
+            (ref l, Lt, ref r) | (ref l, Gt, ref r) | (ref l, Le, ref r) | (ref l, Ge, ref r) => {
+                Err(type_error!(
+                    o,
+                    "numbers or strings",
+                    [l, r],
+                    "Expected numbers or strings"
+                ))
+            }
 
-        (_, _, _) => unreachable!(),
+            (l, Eq, r) => Ok(Value::Bool(l == r)),
+            (l, Ne, r) => Ok(Value::Bool(l != r)),
+
+            (Value::String(ref l), In, Value::String(ref r)) => Ok(Value::Bool(r.contains(&**l))),
+            (ref l, In, Value::Array(ref r)) => Ok(Value::Bool(r.iter().any(|x| l == x))),
+            (Value::String(ref l), In, Value::Object(ref r)) => Ok(Value::Bool(r.contains_key(&**l))),
+            (ref l, In, ref r) => Err(type_error!(
+                o,
+                "a string in a string or object, or any value in an array",
+                [l, r],
+                "Expected proper args for in"
+            )),
+
+            // We have already handled the left operand of the logical operators in
+            // `short_circuit`, so these consider only the right.
+            (_, And, r) => Ok(Value::Bool(r.into())),
+            (_, Or, r) => Ok(Value::Bool(r.into())),
+        }
     }
 }
 
-fn index(context: &Context, v: &Node, i: &Node) -> Result<Value> {
-    match (evaluate(v, context)?, evaluate(i, context)?) {
+pub(super) fn index(v: Value, i: Value) -> Result<Value> {
+    match (v, i) {
         (Value::Array(ref a), ref n) => {
-            let mut i = number_to_i64(n).ok_or(interpreter_error!(
-                "should only use integers to access arrays or strings"
-            ))?;
+            let mut i = number_to_i64(n).ok_or_else(|| {
+                type_error!(
+                    "index",
+                    "integer",
+                    [n],
+                    "should only use integers to access arrays or strings"
+                )
+            })?;
             if i < 0 {
                 i += a.len() as i64
             }
@@ -161,9 +320,14 @@ fn index(context: &Context, v: &Node, i: &Node) -> Result<Value> {
         }
 
         (Value::String(ref s), ref n) => {
-            let mut i = number_to_i64(n).ok_or(interpreter_error!(
-                "should only use integers to access arrays or strings"
-            ))?;
+            let mut i = number_to_i64(n).ok_or_else(|| {
+                type_error!(
+                    "index",
+                    "integer",
+                    [n],
+                    "should only use integers to access arrays or strings"
+                )
+            })?;
             if i < 0 {
                 i += s.chars().count() as i64;
                 if i < 0 {
@@ -171,7 +335,7 @@ fn index(context: &Context, v: &Node, i: &Node) -> Result<Value> {
                 }
             }
             if let Some(c) = s.chars().nth(i as usize) {
-                Ok(Value::String(c.into()))
+                Ok(Value::String(c.to_string().into()))
             } else {
                 Err(interpreter_error!(
                     "index out of bounds or not on utf8 boundary"
@@ -180,57 +344,61 @@ fn index(context: &Context, v: &Node, i: &Node) -> Result<Value> {
         }
 
         (Value::Object(ref o), Value::String(ref s)) => {
-            if let Some(v) = o.get(s) {
+            if let Some(v) = o.get(&**s) {
                 Ok(v.clone())
             } else {
                 Ok(Value::Null)
             }
         }
-        (Value::Object(_), _) => Err(interpreter_error!("object keys must be strings")),
-        _ => Err(interpreter_error!(
+        (Value::Object(_), ref i) => Err(type_error!(
+            "index",
+            "string",
+            [i],
+            "object keys must be strings"
+        )),
+        (ref v, _) => Err(type_error!(
+            "index",
+            "object, string, or array",
+            [v],
             "indexing operator expects an object, string, or array"
         )),
     }
 }
 
-fn slice(context: &Context, v: &Node, a: Option<&Node>, b: Option<&Node>) -> Result<Value> {
-    let mut v = evaluate(v, context)?;
-    let len = match v {
-        Value::String(ref s) => s.chars().count(),
-        Value::Array(ref v) => v.len(),
-        _ => Err(interpreter_error!("can only slice strings and arrays"))?,
-    };
-
-    /// Handle wrapping and limiting in accordance with JSON-e rules
-    fn wrap(mut x: i64, len: usize) -> usize {
-        if x < 0 {
-            x += len as i64;
-        }
-        if x < 0 {
-            return 0;
-        }
-        if x > len as i64 {
-            return len;
-        }
-        x as usize
+/// The length of a value to be sliced, which is checked before evaluating the slice bounds.
+pub(super) fn slice_len(v: &Value) -> Result<usize> {
+    match *v {
+        Value::String(ref s) => Ok(s.chars().count()),
+        Value::Array(ref v) => Ok(v.len()),
+        ref v => Err(type_error!(
+            "slice",
+            "string or array",
+            [v],
+            "can only slice strings and arrays"
+        )),
     }
+}
 
-    let a = a
-        .map(|x| evaluate(x, context))
-        .transpose()?
-        .map(|x| number_to_i64(&x).ok_or(interpreter_error!("slice indices must be integers")))
-        .transpose()?
-        .map(|x| wrap(x, len))
-        .unwrap_or(0);
-    let b = b
-        .map(|x| evaluate(x, context))
-        .transpose()?
-        .map(|x| number_to_i64(&x).ok_or(interpreter_error!("slice indices must be integers")))
-        .transpose()?
-        .map(|x| wrap(x, len))
-        .unwrap_or(len);
-
-    let r = Ok(match v {
+/// A slice bound, wrapped and limited in accordance with JSON-e rules
+pub(super) fn slice_bound(x: &Value, len: usize) -> Result<usize> {
+    let mut x = number_to_i64(x).ok_or_else(|| {
+        type_error!("slice", "integer", [x], "slice indices must be integers")
+    })?;
+    if x < 0 {
+        x += len as i64;
+    }
+    if x < 0 {
+        return Ok(0);
+    }
+    if x > len as i64 {
+        return Ok(len);
+    }
+    Ok(x as usize)
+}
+
+/// Slice a value already checked with `slice_len`, between bounds from `slice_bound`
+pub(super) fn slice(v: Value, a: usize, b: usize) -> Value {
+    match v {
         Value::String(ref s) => {
             if a < b {
                 // To index characters, we must scan the string from the start.
@@ -245,25 +413,24 @@ fn slice(context: &Context, v: &Node, a: Option<&Node>, b: Option<&Node>) -> Res
                         .into(),
                 )
             } else {
-                Value::String(String::new())
+                Value::String("".into())
             }
         }
 
-        Value::Array(ref mut v) => {
+        Value::Array(ref v) => {
             if a < b {
-                Value::Array(v.drain(a..b).collect())
+                Value::Array(v[a..b].into())
             } else {
-                Value::Array(Vec::new())
+                Value::Array(Arc::new([]))
             }
         }
 
         _ => unreachable!(),
-    });
-    r
+    }
 }
 
-fn dot(context: &Context, v: &Node, p: &str) -> Result<Value> {
-    match evaluate(v, context)? {
+pub(super) fn dot(v: Value, p: &str) -> Result<Value> {
+    match v {
         Value::Object(ref o) => {
             if let Some(v) = o.get(p) {
                 Ok(v.clone())
@@ -271,19 +438,22 @@ fn dot(context: &Context, v: &Node, p: &str) -> Result<Value> {
                 Err(interpreter_error!("object has no property {}", p))
             }
         }
-        _ => Err(interpreter_error!("dot operator expects an object")),
+        ref v => Err(type_error!(
+            ".",
+            "object",
+            [v],
+            "dot operator expects an object"
+        )),
     }
 }
 
-fn func(context: &Context, f: &Node, args: &[Node]) -> Result<Value> {
-    let f = evaluate(f, context)?;
-    let args = args
-        .iter()
-        .map(|x| evaluate(x, context))
-        .collect::<Result<Vec<_>>>()?;
+pub(super) fn call(context: &Context, f: Value, args: &[Value]) -> Result<Value> {
     match f {
-        Value::Function(ref f) => Ok(f.call(context, &args)?),
-        _ => Err(interpreter_error!(
+        Value::Function(ref f) => Ok(f.call(context, args)?),
+        ref f => Err(type_error!(
+            "function call",
+            "function",
+            [f],
             "function invocation requires a function"
         )),
     }
@@ -309,19 +479,19 @@ mod test {
     #[test]
     fn test_number() {
         assert_eq!(
-            evaluate(&Node::Number("13"), &Context::new()).unwrap(),
-            Value::Number(13.0),
+            evaluate(&Node::Number("13".into()), &Context::new()).unwrap(),
+            Value::Number(13.0.into()),
         );
         assert_eq!(
-            evaluate(&Node::Number("13.5"), &Context::new()).unwrap(),
-            Value::Number(13.5),
+            evaluate(&Node::Number("13.5".into()), &Context::new()).unwrap(),
+            Value::Number(13.5.into()),
         );
     }
 
     #[test]
     fn test_string() {
         assert_eq!(
-            evaluate(&Node::String("abc"), &Context::new()).unwrap(),
+            evaluate(&Node::String("abc".into()), &Context::new()).unwrap(),
             Value::String("abc".into()),
         );
     }
@@ -329,25 +499,39 @@ mod test {
     #[test]
     fn test_ident() {
         let mut c = Context::new();
-        c.insert("a", Value::Number(29.0));
+        c.insert("a", Value::Number(29.0.into()));
         assert_eq!(
-            evaluate(&Node::Ident("a"), &c).unwrap(),
-            Value::Number(29.0)
+            evaluate(&Node::Ident("a".into()), &c).unwrap(),
+            Value::Number(29.0.into())
         );
     }
 
+    #[test]
+    fn test_lookups_share_values() {
+        let mut c = Context::new();
+        let items: Arc<[Value]> = vec![Value::String("x".repeat(100).into())].into();
+        let mut obj = Object::new();
+        obj.insert("items".into(), Value::Array(items.clone()));
+        c.insert("obj", Value::Object(obj.into()));
+        let node = Node::Dot(Box::new(Node::Ident("obj".into())), "items".into());
+        match evaluate(&node, &c).unwrap() {
+            Value::Array(a) => assert!(Arc::ptr_eq(&a, &items)),
+            v => panic!("unexpected {:?}", v),
+        }
+    }
+
     #[test]
     fn test_ident_nosuch() {
         let c = Context::new();
-        assert_interpreter_error!(evaluate(&Node::Ident("a"), &c), "unknown context value a");
+        assert_interpreter_error!(evaluate(&Node::Ident("a".into()), &c), "unknown context value a");
     }
 
     #[test]
     fn test_unary_minus_i64() {
         let c = Context::new();
         assert_eq!(
-            evaluate(&Node::Un("-", Box::new(Node::Number("-10"))), &c).unwrap(),
-            Value::Number(10.0),
+            evaluate(&Node::Un("-".into(), Box::new(Node::Number("-10".into()))), &c).unwrap(),
+            Value::Number(10.0.into()),
         );
     }
 
@@ -357,7 +541,7 @@ mod test {
         assert_eq!(
             evaluate(
                 // this number is larger that i64::MAX
-                &Node::Un("-", Box::new(Node::Number("9223372036854775809"))),
+                &Node::Un("-".into(), Box::new(Node::Number("9223372036854775809".into()))),
                 &c
             )
             .unwrap(),
@@ -371,11 +555,11 @@ mod test {
         assert_eq!(
             evaluate(
                 // this number is larger that i64::MAX
-                &Node::Un("-", Box::new(Node::Number("29.25"))),
+                &Node::Un("-".into(), Box::new(Node::Number("29.25".into()))),
                 &c
             )
             .unwrap(),
-            Value::Number(-29.25),
+            Value::Number((-29.25).into()),
         );
     }
 
@@ -385,7 +569,7 @@ mod test {
         assert_interpreter_error!(
             evaluate(
                 // this number is larger that i64::MAX
-                &Node::Un("-", Box::new(Node::String("abc"))),
+                &Node::Un("-".into(), Box::new(Node::String("abc".into()))),
                 &c
             ),
             "This operator expects a number"
@@ -396,8 +580,8 @@ mod test {
     fn test_unary_plus() {
         let c = Context::new();
         assert_eq!(
-            evaluate(&Node::Un("+", Box::new(Node::Number("29.25"))), &c).unwrap(),
-            Value::Number(29.25),
+            evaluate(&Node::Un("+".into(), Box::new(Node::Number("29.25".into()))), &c).unwrap(),
+            Value::Number(29.25.into()),
         );
     }
 
@@ -405,7 +589,7 @@ mod test {
     fn test_unary_plus_not_number() {
         let c = Context::new();
         assert_interpreter_error!(
-            evaluate(&Node::Un("-", Box::new(Node::String("abc"))), &c),
+            evaluate(&Node::Un("-".into(), Box::new(Node::String("abc".into()))), &c),
             "This operator expects a number"
         );
     }
@@ -414,7 +598,7 @@ mod test {
     fn test_unary_bang() {
         let c = Context::new();
         assert_eq!(
-            evaluate(&Node::Un("!", Box::new(Node::False)), &c).unwrap(),
+            evaluate(&Node::Un("!".into(), Box::new(Node::False)), &c).unwrap(),
             Value::Bool(true),
         );
     }
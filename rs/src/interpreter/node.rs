@@ -1,15 +1,17 @@
+use std::fmt::{self, Write};
+
 /// A node in the AST.  Minimal interpretation is done to construct this tree, so most nodes
-/// contain &str references to their content, for parsing only during evaluation.
+/// contain copies of their source text, for parsing only during evaluation.
 #[derive(Debug, Eq, PartialEq, Clone)]
-pub(crate) enum Node<'a> {
+pub(crate) enum Node {
     /// Literal number
-    Number(&'a str),
+    Number(String),
 
     /// Literal string
-    String(&'a str),
+    String(String),
 
     /// Literal identifier
-    Ident(&'a str),
+    Ident(String),
 
     /// Literal null
     Null,
@@ -21,26 +23,344 @@ pub(crate) enum Node<'a> {
     False,
 
     /// Array Literal
-    Array(Vec<Node<'a>>),
+    Array(Vec<Node>),
 
     /// Object Literal
-    Object(Vec<(&'a str, Node<'a>)>),
+    Object(Vec<(String, Node)>),
 
     /// Unary operation
-    Un(&'a str, Box<Node<'a>>),
+    Un(String, Box<Node>),
 
     /// Binary operation
-    Op(Box<Node<'a>>, &'a str, Box<Node<'a>>),
+    Op(Box<Node>, String, Box<Node>),
 
     /// Index operation (`x[y]`)
-    Index(Box<Node<'a>>, Box<Node<'a>>),
+    Index(Box<Node>, Box<Node>),
 
     /// Slice operation (`w[x:y]`)
-    Slice(Box<Node<'a>>, Option<Box<Node<'a>>>, Option<Box<Node<'a>>>),
+    Slice(Box<Node>, Option<Box<Node>>, Option<Box<Node>>),
 
     /// Dot operation
-    Dot(Box<Node<'a>>, &'a str),
+    Dot(Box<Node>, String),
 
     /// Function invocation
-    Func(Box<Node<'a>>, Vec<Node<'a>>),
+    Func(Box<Node>, Vec<Node>),
+}
+
+/// Precedence levels of nodes, as in the parser: a node must be parenthesized where an operand of
+/// a higher level is expected.  The binary operators, other than `**`, have levels from 1 (`||`)
+/// to 7 (`*` and `/`).
+const EXPONENT: u8 = 8;
+/// Index, slice, property, and call operations
+const POSTFIX: u8 = 9;
+const UNARY: u8 = 10;
+/// Literals, identifiers, and array and object literals
+const ATOM: u8 = 11;
+
+impl Node {
+    fn precedence(&self) -> u8 {
+        match self {
+            Node::Op(_, op, _) => match op.as_str() {
+                "||" => 1,
+                "&&" => 2,
+                "in" => 3,
+                "==" | "!=" => 4,
+                "<" | ">" | "<=" | ">=" => 5,
+                "+" | "-" => 6,
+                "*" | "/" => 7,
+                _ => EXPONENT,
+            },
+            Node::Index(..) | Node::Slice(..) | Node::Dot(..) | Node::Func(..) => POSTFIX,
+            Node::Un(..) => UNARY,
+            _ => ATOM,
+        }
+    }
+
+    /// The source text of this node, which parses to the same node, with only the parentheses
+    /// that the precedence of its operations requires.  There is none for a node containing a
+    /// string or object key with both kinds of quote, as strings have no escapes.  Nodes are not
+    /// public, so this is exposed as [`ParsedExpression::canonical`](crate::ParsedExpression).
+    pub(crate) fn to_source(&self) -> Option<String> {
+        let mut source = String::new();
+        write!(source, "{}", Source(self)).ok()?;
+        Some(source)
+    }
+
+    /// The height of this node's tree, found without recursion, as a long chain of operations
+    /// makes for a deep tree.
+    pub(crate) fn height(&self) -> usize {
+        let mut height = 0;
+        let mut stack = vec![(self, 1)];
+        while let Some((node, depth)) = stack.pop() {
+            height = height.max(depth);
+            node.for_each_operand(|n| stack.push((n, depth + 1)));
+        }
+        height
+    }
+
+    /// Call `f` with each of this node's operands.
+    pub(crate) fn for_each_operand<'a>(&'a self, mut f: impl FnMut(&'a Node)) {
+        match self {
+            Node::Array(items) => items.iter().for_each(f),
+            Node::Object(properties) => properties.iter().for_each(|(_, v)| f(v)),
+            Node::Un(_, v) | Node::Dot(v, _) => f(v),
+            Node::Op(l, _, r) | Node::Index(l, r) => {
+                f(l);
+                f(r);
+            }
+            Node::Slice(v, a, b) => {
+                f(v);
+                a.iter().chain(b.iter()).for_each(|n| f(n));
+            }
+            Node::Func(v, args) => {
+                f(v);
+                args.iter().for_each(f);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Nodes are dropped without recursion, by moving the operands of each onto a stack, so that a
+/// tree too deep to drop recursively, such as of a long chain of operations, can be dropped.
+impl Drop for Node {
+    fn drop(&mut self) {
+        fn take(node: &mut Node, stack: &mut Vec<Node>) {
+            fn unbox(b: &mut Node) -> Node {
+                std::mem::replace(b, Node::Null)
+            }
+            match node {
+                Node::Array(items) => stack.append(items),
+                Node::Object(properties) => stack.extend(properties.drain(..).map(|(_, v)| v)),
+                Node::Un(_, v) | Node::Dot(v, _) => stack.push(unbox(v)),
+                Node::Op(l, _, r) | Node::Index(l, r) => stack.extend([unbox(l), unbox(r)]),
+                Node::Slice(v, a, b) => {
+                    stack.push(unbox(v));
+                    stack.extend(a.take().into_iter().chain(b.take()).map(|n| *n));
+                }
+                Node::Func(v, args) => {
+                    stack.push(unbox(v));
+                    stack.append(args);
+                }
+                _ => {}
+            }
+        }
+
+        let mut stack = Vec::new();
+        take(self, &mut stack);
+        while let Some(mut node) = stack.pop() {
+            take(&mut node, &mut stack);
+        }
+    }
+}
+
+/// A node, displayed as its source text.  Displaying fails for a string that cannot be written.
+struct Source<'a>(&'a Node);
+
+impl fmt::Display for Source<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Node::Number(n) => write!(f, "{}", n),
+            Node::String(s) => write_string(f, s),
+            Node::Ident(i) => write!(f, "{}", i),
+            Node::Null => write!(f, "null"),
+            Node::True => write!(f, "true"),
+            Node::False => write!(f, "false"),
+            Node::Array(items) => {
+                write!(f, "[")?;
+                write_list(f, items)?;
+                write!(f, "]")
+            }
+            Node::Object(properties) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in properties.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_string(f, k)?;
+                    write!(f, ": {}", Source(v))?;
+                }
+                write!(f, "}}")
+            }
+            // the operand of a unary operation is a single value, so `-a[0]` is `(-a)[0]`
+            Node::Un(op, v) => write!(f, "{}{}", op, Operand(v, ATOM)),
+            // exponentiation is right-associative, and its left operand binds tighter than a
+            // unary operation
+            Node::Op(l, op, r) if op == "**" => {
+                write!(f, "{} ** {}", Operand(l, POSTFIX), Operand(r, EXPONENT))
+            }
+            Node::Op(l, op, r) => {
+                let precedence = self.0.precedence();
+                write!(
+                    f,
+                    "{} {} {}",
+                    Operand(l, precedence),
+                    op,
+                    Operand(r, precedence + 1)
+                )
+            }
+            Node::Index(v, i) => write!(f, "{}[{}]", Operand(v, POSTFIX), Source(i)),
+            Node::Slice(v, a, b) => {
+                write!(f, "{}[", Operand(v, POSTFIX))?;
+                if let Some(a) = a {
+                    write!(f, "{}", Source(a))?;
+                }
+                write!(f, ":")?;
+                if let Some(b) = b {
+                    write!(f, "{}", Source(b))?;
+                }
+                write!(f, "]")
+            }
+            Node::Dot(v, p) => write!(f, "{}.{}", Operand(v, POSTFIX), p),
+            Node::Func(v, args) => {
+                write!(f, "{}(", Operand(v, POSTFIX))?;
+                write_list(f, args)?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// An operand of an operation, which is parenthesized unless its precedence is at least the given
+/// level.
+struct Operand<'a>(&'a Node, u8);
+
+impl fmt::Display for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.precedence() < self.1 {
+            write!(f, "({})", Source(self.0))
+        } else {
+            write!(f, "{}", Source(self.0))
+        }
+    }
+}
+
+fn write_list(f: &mut fmt::Formatter, nodes: &[Node]) -> fmt::Result {
+    for (i, node) in nodes.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", Source(node))?;
+    }
+    Ok(())
+}
+
+/// Write a string literal, failing for a string containing both kinds of quote.
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    if !s.contains('"') {
+        write!(f, "\"{}\"", s)
+    } else if !s.contains('\'') {
+        write!(f, "'{}'", s)
+    } else {
+        Err(fmt::Error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Node;
+    use crate::interpreter::parse_all;
+    use crate::limits::{Budget, Limits};
+    use crate::template::{Fragment, Interpolation};
+    use serde_json::Value as SerdeValue;
+
+    /// Assert that the given expression displays as expected, and that this parses to the same
+    /// node.
+    fn assert_displays(source: &str, expected: &str) {
+        let budget = Budget::new(Limits::default());
+        let node = parse_all(source, &budget).unwrap();
+        let displayed = node.to_source().unwrap();
+        assert_eq!(displayed, expected);
+        assert_eq!(parse_all(&displayed, &budget).unwrap(), node);
+    }
+
+    #[test]
+    fn display_literals() {
+        assert_displays("[1, 2.5, 'a', \"b'\", null, true, false, x]", "[1, 2.5, \"a\", \"b'\", null, true, false, x]");
+        assert_displays("{a: 1, 'b c': {}}", "{\"a\": 1, \"b c\": {}}");
+    }
+
+    #[test]
+    fn display_operations() {
+        assert_displays("a + b * -c ** d", "a + b * -c ** d");
+        assert_displays("!(a || b) && c in d", "!(a || b) && c in d");
+        assert_displays("((a - b) - c) - (d - e)", "a - b - c - (d - e)");
+        assert_displays("(a * b) + (c / d) < e", "a * b + c / d < e");
+        assert_displays("(a + b) * c", "(a + b) * c");
+        assert_displays("a ** (b ** c)", "a ** b ** c");
+        assert_displays("(a ** b) ** c", "(a ** b) ** c");
+        assert_displays("(a[0]) ** (-b)", "a[0] ** -b");
+        assert_displays("a - -1", "a - -1");
+        assert_displays("-(-a)", "-(-a)");
+        assert_displays("!(a == b)", "!(a == b)");
+        assert_displays("(a == b) == (c in d)", "a == b == (c in d)");
+        assert_displays("((a || b))", "a || b");
+    }
+
+    #[test]
+    fn display_accessors() {
+        assert_displays("a.b[c][1:][:2][:](d, e)()", "a.b[c][1:][:2][:](d, e)()");
+        assert_displays("(a + b).c", "(a + b).c");
+        assert_displays("(1).x", "1.x");
+        assert_displays("(1.5)[0]", "1.5[0]");
+        assert_displays("-a[0]", "-a[0]");
+        assert_displays("-(a[0])", "-(a[0])");
+        assert_displays("(-a).b(c + d)", "-a.b(c + d)");
+        assert_displays("[a || b][(c)]", "[a || b][c]");
+    }
+
+    #[test]
+    fn display_strings() {
+        assert_displays("'\"x\"' + \"it's\"", "'\"x\"' + \"it's\"");
+        assert_displays("{'\"': 1, \"'\": 2}", "{'\"': 1, \"'\": 2}");
+
+        // strings have no escapes, so these cannot be written
+        assert_eq!(Node::String("it's \"x\"".to_owned()).to_source(), None);
+        let object = Node::Object(vec![("'\"".to_owned(), Node::Null)]);
+        assert_eq!(Node::Array(vec![object]).to_source(), None);
+    }
+
+    /// The strings in the given value, including object keys
+    fn strings(value: &SerdeValue, found: &mut Vec<String>) {
+        match value {
+            SerdeValue::String(s) => found.push(s.clone()),
+            SerdeValue::Array(items) => items.iter().for_each(|v| strings(v, found)),
+            SerdeValue::Object(o) => {
+                for (k, v) in o {
+                    found.push(k.clone());
+                    strings(v, found);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn display_specification_expressions() {
+        let templates: SerdeValue = serde_json::from_str(include_str!(concat!(
+            env!("OUT_DIR"),
+            "/spec_templates.json"
+        )))
+        .unwrap();
+        let mut found = vec![];
+        strings(&templates, &mut found);
+
+        // every string that is an expression, or that interpolates expressions, is checked, which
+        // covers every expression in the templates
+        let budget = Budget::new(Limits::default());
+        let mut nodes = vec![];
+        for s in &found {
+            nodes.extend(parse_all(s, &budget));
+            for fragment in Interpolation::compile(s, &budget).fragments() {
+                if let Fragment::Expression(code, _, _) = fragment {
+                    nodes.push(code.node().clone());
+                }
+            }
+        }
+        assert!(nodes.len() > 1000, "only {} expressions found", nodes.len());
+        for node in nodes {
+            let source = node.to_source().unwrap();
+            assert_eq!(parse_all(&source, &budget).unwrap(), node, "{}", source);
+        }
+    }
 }
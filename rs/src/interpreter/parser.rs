@@ -6,24 +6,27 @@
 #![allow(dead_code)]
 
 use super::Node;
+use crate::errors::char_offset;
 use crate::whitespace::ws;
-use anyhow::{anyhow, Result};
+use crate::errors::{Error, Result};
+use crate::limits::{Budget, StackGuard};
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_till},
     character::complete::{alpha1, alphanumeric1, char, digit1},
     combinator::{map_res, not, opt, recognize},
-    multi::{fold_many0, many0, separated_list0},
+    multi::{many0, separated_list0},
     sequence::{delimited, pair, tuple},
     Err, IResult,
 };
+use std::cell::Cell;
 
 // atomic values
 
 /// Parse a number token (integer or decimal)
-fn number(input: &str) -> IResult<&str, Node<'_>> {
-    fn node(input: &str) -> Result<Node<'_>, std::num::ParseIntError> {
-        Ok(Node::Number(input))
+fn number(input: &str) -> IResult<&str, Node> {
+    fn node(input: &str) -> Result<Node, std::num::ParseIntError> {
+        Ok(Node::Number(input.to_owned()))
     }
 
     map_res(
@@ -33,8 +36,8 @@ fn number(input: &str) -> IResult<&str, Node<'_>> {
 }
 
 /// Parse a atomic literal JSON value (true, false, null)
-fn literal(input: &str) -> IResult<&str, Node<'_>> {
-    fn node(input: &str) -> Result<Node<'_>, ()> {
+fn literal(input: &str) -> IResult<&str, Node> {
+    fn node(input: &str) -> Result<Node, ()> {
         Ok(match input {
             "true" => Node::True,
             "false" => Node::False,
@@ -63,9 +66,9 @@ fn ident_str(input: &str) -> IResult<&str, &str> {
 }
 
 /// Parse an identifier as a Node
-fn ident(input: &str) -> IResult<&str, Node<'_>> {
-    fn node(input: &str) -> Result<Node<'_>, ()> {
-        Ok(Node::Ident(input))
+fn ident(input: &str) -> IResult<&str, Node> {
+    fn node(input: &str) -> Result<Node, ()> {
+        Ok(Node::Ident(input.to_owned()))
     }
 
     map_res(ident_str, node)(input)
@@ -86,16 +89,16 @@ fn string_str(input: &str) -> IResult<&str, &str> {
 }
 
 /// Parse a string as a Node
-fn string(input: &str) -> IResult<&str, Node<'_>> {
-    fn node(input: &str) -> Result<Node<'_>, ()> {
-        Ok(Node::String(input))
+fn string(input: &str) -> IResult<&str, Node> {
+    fn node(input: &str) -> Result<Node, ()> {
+        Ok(Node::String(input.to_owned()))
     }
 
     map_res(string_str, node)(input)
 }
 
 /// Parse any atomic value
-fn atom(input: &str) -> IResult<&str, Node<'_>> {
+fn atom(input: &str) -> IResult<&str, Node> {
     alt((number, literal, ident, string))(input)
 }
 
@@ -129,13 +132,13 @@ fn mul_op(input: &str) -> IResult<&str, &str> {
 // combinations of atoms into larger structures
 
 /// A parenthesized expression
-fn parens(input: &str) -> IResult<&str, Node<'_>> {
+fn parens(input: &str) -> IResult<&str, Node> {
     ws(delimited(char('('), expression, char(')')))(input)
 }
 
 /// An array literal
-fn array_literal(input: &str) -> IResult<&str, Node<'_>> {
-    fn node<'a>(input: Vec<Node<'a>>) -> Result<Node<'a>, ()> {
+fn array_literal(input: &str) -> IResult<&str, Node> {
+    fn node(input: Vec<Node>) -> Result<Node, ()> {
         Ok(Node::Array(input))
     }
     map_res(
@@ -149,10 +152,10 @@ fn array_literal(input: &str) -> IResult<&str, Node<'_>> {
 }
 
 /// An object literal, allowing either strings or identifiers as keys
-fn object_literal(input: &str) -> IResult<&str, Node<'_>> {
-    fn node<'a>(mut input: Vec<(&'a str, &'a str, Node<'a>)>) -> Result<Node<'a>, ()> {
+fn object_literal(input: &str) -> IResult<&str, Node> {
+    fn node(mut input: Vec<(&str, &str, Node)>) -> Result<Node, ()> {
         Ok(Node::Object(
-            input.drain(..).map(|(k, _, v)| (k, v)).collect(),
+            input.drain(..).map(|(k, _, v)| (k.to_owned(), v)).collect(),
         ))
     }
     map_res(
@@ -169,14 +172,14 @@ fn object_literal(input: &str) -> IResult<&str, Node<'_>> {
 }
 
 /// A single value (an atom, parenthesized value, or compound literal
-fn value(input: &str) -> IResult<&str, Node<'_>> {
+fn value(input: &str) -> IResult<&str, Node> {
     alt((atom, parens, array_literal, object_literal))(input)
 }
 
 /// A unary expression
-fn unary_expr(input: &str) -> IResult<&str, Node<'_>> {
-    fn node<'a>(input: (&'a str, Node<'a>)) -> Result<Node<'a>, ()> {
-        Ok(Node::Un(input.0, Box::new(input.1)))
+fn unary_expr(input: &str) -> IResult<&str, Node> {
+    fn node(input: (&str, Node)) -> Result<Node, ()> {
+        Ok(Node::Un(input.0.to_owned(), Box::new(input.1)))
     }
     alt((
         map_res(ws(tuple((alt((bang_op, tag("-"), tag("+"))), value))), node),
@@ -186,57 +189,50 @@ fn unary_expr(input: &str) -> IResult<&str, Node<'_>> {
 
 /// An index expression (`x[i]`, `x[a..b]` or `x.p`) or function call.  These are left-associative
 /// at equal precedence.
-fn index_or_fn_expr(input: &str) -> IResult<&str, Node<'_>> {
-    // An index operation without its left-hand side.  The `fold_multi0` closure attaches
-    // these to their LHS's and creates Nodes.
-    enum ExprKind<'a> {
-        Index(Box<Node<'a>>),
-        Slice(Option<Box<Node<'a>>>, Option<Box<Node<'a>>>),
-        Dot(&'a str),
-        Func(Vec<Node<'a>>),
-    }
-
-    fn index_expr<'a>(input: (&'a str, Node<'a>, &'a str)) -> Result<ExprKind<'a>, ()> {
-        Ok(ExprKind::Index(Box::new(input.1)))
-    }
-
-    fn slice_expr<'a>(
-        input: (
-            &'a str,
-            Option<Node<'a>>,
-            &'a str,
-            Option<Node<'a>>,
-            &'a str,
-        ),
-    ) -> Result<ExprKind<'a>> {
-        Ok(ExprKind::Slice(
-            input.1.map(Box::new),
-            input.3.map(Box::new),
-        ))
+fn index_or_fn_expr(input: &str) -> IResult<&str, Node> {
+    // An index operation without its left-hand side, which is attached to its LHS to create a
+    // Node.
+    enum ExprKind {
+        Index(Box<Node>),
+        Slice(Option<Box<Node>>, Option<Box<Node>>),
+        Dot(String),
+        Func(Vec<Node>),
+    }
+
+    /// An index or a slice, which share a prefix that is parsed only once: `[a]` is an index,
+    /// and `[a:b]` (where either side may be omitted) is a slice.
+    #[allow(clippy::type_complexity)]
+    fn index_or_slice_expr(
+        input: (&str, Option<Node>, Option<(&str, Option<Node>)>, &str),
+    ) -> Result<ExprKind, ()> {
+        match input {
+            (_, Some(i), None, _) => Ok(ExprKind::Index(Box::new(i))),
+            (_, a, Some((_, b)), _) => Ok(ExprKind::Slice(a.map(Box::new), b.map(Box::new))),
+            (_, None, None, _) => Err(()),
+        }
     }
 
-    fn dot_expr<'a>(input: (&'a str, &'a str)) -> Result<ExprKind<'a>> {
-        Ok(ExprKind::Dot(input.1))
+    fn dot_expr(input: (&str, &str)) -> Result<ExprKind> {
+        Ok(ExprKind::Dot(input.1.to_owned()))
     }
 
-    fn func_expr<'a>(input: (&'a str, Vec<Node<'a>>, &'a str)) -> Result<ExprKind<'a>> {
+    fn func_expr(input: (&str, Vec<Node>, &str)) -> Result<ExprKind> {
         Ok(ExprKind::Func(input.1))
     }
 
-    let (i, init) = unary_expr(input)?;
-
-    fold_many0(
-        ws(alt((
-            map_res(tuple((tag("["), expression, tag("]"))), index_expr),
+    // parse the operations in a loop, rather than with `fold_many0`, which would clone the left
+    // operand, whose tree may be deep
+    let (mut i, mut acc) = unary_expr(input)?;
+    loop {
+        let expr_kind = ws(alt((
             map_res(
                 tuple((
                     tag("["),
                     opt(expression),
-                    tag(":"),
-                    opt(expression),
+                    opt(pair(tag(":"), opt(expression))),
                     tag("]"),
                 )),
-                slice_expr,
+                index_or_slice_expr,
             ),
             map_res(tuple((tag("."), ident_str)), dot_expr),
             map_res(
@@ -247,48 +243,53 @@ fn index_or_fn_expr(input: &str) -> IResult<&str, Node<'_>> {
                 ))),
                 func_expr,
             ),
-        ))),
-        // This clone is necessary because backtracking may result in this
-        // parser running more than once.
-        move || init.clone(),
-        |acc: Node, expr_kind: ExprKind| {
-            let acc = Box::new(acc);
-            match expr_kind {
-                ExprKind::Index(i) => Node::Index(acc, i),
-                ExprKind::Slice(a, b) => Node::Slice(acc, a, b),
-                ExprKind::Dot(p) => Node::Dot(acc, p),
-                ExprKind::Func(args) => Node::Func(acc, args),
-            }
-        },
-    )(i)
+        )))(i);
+        let (rest, expr_kind) = match expr_kind {
+            Ok(parsed) => parsed,
+            Err(Err::Error(_)) => return Ok((i, acc)),
+            Err(e) => return Err(e),
+        };
+        let left = Box::new(acc);
+        acc = match expr_kind {
+            ExprKind::Index(i) => Node::Index(left, i),
+            ExprKind::Slice(a, b) => Node::Slice(left, a, b),
+            ExprKind::Dot(p) => Node::Dot(left, p),
+            ExprKind::Func(args) => Node::Func(left, args),
+        };
+        i = rest;
+    }
 }
 
 /// Exponentiation is right-associative
-fn exp_expr(input: &str) -> IResult<&str, Node<'_>> {
-    fn node<'a>(input: (Node<'a>, &'a str, Node<'a>)) -> Result<Node<'a>, ()> {
-        Ok(Node::Op(Box::new(input.0), input.1, Box::new(input.2)))
+fn exp_expr(input: &str) -> IResult<&str, Node> {
+    let (input, _) = check_stack(input)?;
+    // parse the left operand only once, as re-parsing it would take time exponential in the
+    // nesting of the expression
+    let (i, left) = index_or_fn_expr(input)?;
+    match opt(pair(tag("**"), exp_expr))(i)? {
+        (i, Some((op, right))) => Ok((i, Node::Op(Box::new(left), op.to_owned(), Box::new(right)))),
+        (i, None) => Ok((i, left)),
     }
-
-    alt((
-        map_res(tuple((index_or_fn_expr, tag("**"), exp_expr)), node),
-        index_or_fn_expr,
-    ))(input)
 }
 
 /// Define a simple left-associative binary operation which chains to a
 /// higher-precedence operation.
 macro_rules! binop {
     ($name:ident, $higher_prec:ident, $ops:expr) => {
-        fn $name(input: &str) -> IResult<&str, Node<'_>> {
-            let (i, init) = $higher_prec(input)?;
-
-            fold_many0(
-                pair($ops, $higher_prec),
-                // This clone is necessary because backtracking may result in this
-                // parser running more than once.
-                move || init.clone(),
-                |acc: Node, (op, val): (&str, Node)| Node::Op(Box::new(acc), op, Box::new(val)),
-            )(i)
+        fn $name(input: &str) -> IResult<&str, Node> {
+            // as for index expressions, this loops rather than using `fold_many0`, so as not to
+            // clone the left operand
+            let (mut i, mut acc) = $higher_prec(input)?;
+            loop {
+                match pair($ops, $higher_prec)(i) {
+                    Ok((rest, (op, val))) => {
+                        acc = Node::Op(Box::new(acc), op.to_owned(), Box::new(val));
+                        i = rest;
+                    }
+                    Err(Err::Error(_)) => return Ok((i, acc)),
+                    Err(e) => return Err(e),
+                }
+            }
         }
     };
 }
@@ -306,48 +307,119 @@ binop!(and_expr, in_expr, tag("&&"));
 binop!(or_expr, and_expr, tag("||"));
 
 /// Parse a JSON-e expression.
-fn expression(input: &str) -> IResult<&str, Node<'_>> {
+fn expression(input: &str) -> IResult<&str, Node> {
+    let (input, _) = check_stack(input)?;
     alt((or_expr, value))(input)
 }
 
+thread_local! {
+    /// The stack guard of the budget for the parse under way on this thread, checked by the
+    /// parsers that recurse, as they cannot be given the budget itself
+    static STACK_GUARD: Cell<Option<StackGuard>> = const { Cell::new(None) };
+}
+
+/// Fail, without backtracking, if the parse under way has used more stack space than its budget
+/// allows, before recursing further
+fn check_stack(input: &str) -> IResult<&str, ()> {
+    match STACK_GUARD.with(Cell::get) {
+        Some(guard) if guard.check().is_err() => Err(Err::Failure(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::TooLarge,
+        ))),
+        _ => Ok((input, ())),
+    }
+}
+
+/// The nesting depth of the expression at the beginning of the given input, which ends at the
+/// first unmatched closing bracket.  This is the depth of nested brackets, plus one for each `**`
+/// since that operator is right-associative.  Parsing recurses to this depth, so it is checked
+/// before parsing.
+fn nesting(input: &str) -> usize {
+    let mut depth = 0;
+    let mut max_depth = 0;
+    let mut exponents = 0;
+    let mut quote = None;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '(') | (None, '[') | (None, '{') => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            (None, ')') | (None, ']') | (None, '}') => {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+            }
+            (None, '*') if chars.peek() == Some(&'*') => {
+                chars.next();
+                exponents += 1;
+            }
+            _ => {}
+        }
+    }
+    max_depth + exponents
+}
+
 /// Parse an entire string as an expression.  Un-parsed characters are treated as an error.
-pub(crate) fn parse_all(input: &str) -> anyhow::Result<Node<'_>> {
-    match expression(input) {
-        Ok(("", node)) => Ok(node),
-        Ok((unused, _)) => Err(anyhow!("Unexpected trailing characters {}", unused)),
-        Err(Err::Incomplete(_)) => unreachable!(),
-        Err(Err::Error(e)) => Err(anyhow!("Parse error at {:?}", e.input)),
-        Err(Err::Failure(e)) => Err(anyhow!("Parse error at {:?}", e.input)),
+pub(crate) fn parse_all(input: &str, budget: &Budget) -> Result<Node> {
+    match parse_partial(input, budget)? {
+        (node, "") => Ok(node),
+        (_, unused) => Err(syntax_error!("Unexpected trailing characters {}", unused)
+            .add_offset(char_offset(input, unused))),
     }
 }
 
 /// Parse a part of a string as an expression, returning the remainder of the string.
-pub(crate) fn parse_partial(input: &str) -> anyhow::Result<(Node<'_>, &str)> {
-    match expression(input) {
-        Ok((unused, node)) => Ok((node, unused)),
+pub(crate) fn parse_partial<'a>(input: &'a str, budget: &Budget) -> Result<(Node, &'a str)> {
+    budget.check_nesting(nesting(input))?;
+    let guard = budget.stack_guard();
+    let previous = STACK_GUARD.with(|g| g.replace(Some(guard)));
+    let result = expression(input);
+    STACK_GUARD.with(|g| g.set(previous));
+    match result {
+        Ok((unused, node)) => {
+            budget.check_height(node.height())?;
+            Ok((node, unused))
+        }
         Err(Err::Incomplete(_)) => unreachable!(),
-        Err(Err::Error(e)) => Err(anyhow!("Parse error at {:?}", e.input)),
-        Err(Err::Failure(e)) => Err(anyhow!("Parse error at {:?}", e.input)),
+        Err(Err::Error(e)) => Err(parse_error(input, e.input)),
+        Err(Err::Failure(e)) if e.code == nom::error::ErrorKind::TooLarge => Err(guard.error()),
+        Err(Err::Failure(e)) => Err(parse_error(input, e.input)),
     }
 }
 
+/// Construct a syntax error for a failure to parse `input` at `rest`
+fn parse_error(input: &str, rest: &str) -> Error {
+    syntax_error!("Parse error at {:?}", rest).add_offset(char_offset(input, rest))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::limits::Limits;
+
+    fn budget() -> Budget {
+        Budget::new(Limits::default())
+    }
 
     #[test]
     fn test_number_integer() {
-        assert_eq!(number("123"), Ok(("", Node::Number("123"))));
+        assert_eq!(number("123"), Ok(("", Node::Number("123".into()))));
     }
 
     #[test]
     fn test_number_integer_ws() {
-        assert_eq!(number("  123\t\n"), Ok(("", Node::Number("123"))));
+        assert_eq!(number("  123\t\n"), Ok(("", Node::Number("123".into()))));
     }
 
     #[test]
     fn test_number_decimal() {
-        assert_eq!(number("123.456"), Ok(("", Node::Number("123.456"))));
+        assert_eq!(number("123.456"), Ok(("", Node::Number("123.456".into()))));
     }
 
     #[test]
@@ -372,7 +444,7 @@ mod test {
 
     #[test]
     fn test_ident() {
-        assert_eq!(ident("abc"), Ok(("", Node::Ident("abc"))));
+        assert_eq!(ident("abc"), Ok(("", Node::Ident("abc".into()))));
     }
 
     #[test]
@@ -382,37 +454,37 @@ mod test {
 
     #[test]
     fn test_ident_literal_prefix_as_atom() {
-        assert_eq!(atom("falsey"), Ok(("", Node::Ident("falsey"))));
+        assert_eq!(atom("falsey"), Ok(("", Node::Ident("falsey".into()))));
     }
 
     #[test]
     fn test_ident_underscore() {
-        assert_eq!(ident("_abc"), Ok(("", Node::Ident("_abc"))));
+        assert_eq!(ident("_abc"), Ok(("", Node::Ident("_abc".into()))));
     }
 
     #[test]
     fn test_ident_underscore_numeric() {
-        assert_eq!(ident("_abc0def"), Ok(("", Node::Ident("_abc0def"))));
+        assert_eq!(ident("_abc0def"), Ok(("", Node::Ident("_abc0def".into()))));
     }
 
     #[test]
     fn test_string_single_quote() {
-        assert_eq!(string(" 'ab \"cd'"), Ok(("", Node::String("ab \"cd"))));
+        assert_eq!(string(" 'ab \"cd'"), Ok(("", Node::String("ab \"cd".into()))));
     }
 
     #[test]
     fn test_string_double_quote() {
-        assert_eq!(string("\"a' bcd\" "), Ok(("", Node::String("a' bcd"))));
+        assert_eq!(string("\"a' bcd\" "), Ok(("", Node::String("a' bcd".into()))));
     }
 
     #[test]
     fn test_empty_string_single_quote() {
-        assert_eq!(string("''"), Ok(("", Node::String(""))));
+        assert_eq!(string("''"), Ok(("", Node::String("".into()))));
     }
 
     #[test]
     fn test_empty_string_double_quote() {
-        assert_eq!(string("\"\""), Ok(("", Node::String(""))));
+        assert_eq!(string("\"\""), Ok(("", Node::String("".into()))));
     }
 
     #[test]
@@ -432,9 +504,9 @@ mod test {
             Ok((
                 "",
                 Node::Op(
-                    Box::new(Node::Un("-", Box::new(Node::Number("1")))),
-                    "+",
-                    Box::new(Node::Un("-", Box::new(Node::Number("2"))))
+                    Box::new(Node::Un("-".into(), Box::new(Node::Number("1".into())))),
+                    "+".into(),
+                    Box::new(Node::Un("-".into(), Box::new(Node::Number("2".into()))))
                 )
             ))
         );
@@ -447,11 +519,11 @@ mod test {
             Ok((
                 "",
                 Node::Index(
-                    Box::new(Node::Ident("a")),
+                    Box::new(Node::Ident("a".into())),
                     Box::new(Node::Op(
-                        Box::new(Node::Number("2")),
-                        "+",
-                        Box::new(Node::Number("3"))
+                        Box::new(Node::Number("2".into())),
+                        "+".into(),
+                        Box::new(Node::Number("3".into()))
                     )),
                 )
             ))
@@ -465,9 +537,9 @@ mod test {
             Ok((
                 "",
                 Node::Slice(
-                    Box::new(Node::Ident("a")),
-                    Some(Box::new(Node::Number("2"))),
-                    Some(Box::new(Node::Number("3")))
+                    Box::new(Node::Ident("a".into())),
+                    Some(Box::new(Node::Number("2".into()))),
+                    Some(Box::new(Node::Number("3".into())))
                 )
             ))
         );
@@ -477,7 +549,7 @@ mod test {
     fn test_slice_none() {
         assert_eq!(
             expression("a[:]"),
-            Ok(("", Node::Slice(Box::new(Node::Ident("a")), None, None)))
+            Ok(("", Node::Slice(Box::new(Node::Ident("a".into())), None, None)))
         );
     }
 
@@ -485,7 +557,7 @@ mod test {
     fn test_dot() {
         assert_eq!(
             expression("a.b"),
-            Ok(("", Node::Dot(Box::new(Node::Ident("a")), "b")))
+            Ok(("", Node::Dot(Box::new(Node::Ident("a".into())), "b".into())))
         );
     }
 
@@ -496,8 +568,8 @@ mod test {
             Ok((
                 "",
                 Node::Func(
-                    Box::new(Node::Un("-", Box::new(Node::Number("1")))),
-                    vec![Node::Number("2"), Node::Number("3"),],
+                    Box::new(Node::Un("-".into(), Box::new(Node::Number("1".into())))),
+                    vec![Node::Number("2".into()), Node::Number("3".into()),],
                 )
             ))
         );
@@ -511,10 +583,10 @@ mod test {
                 "",
                 Node::Index(
                     Box::new(Node::Func(
-                        Box::new(Node::Ident("f")),
-                        vec![Node::Number("2")]
+                        Box::new(Node::Ident("f".into())),
+                        vec![Node::Number("2".into())]
                     )),
-                    Box::new(Node::Number("0")),
+                    Box::new(Node::Number("0".into())),
                 ),
             ))
         );
@@ -528,10 +600,10 @@ mod test {
                 "",
                 Node::Dot(
                     Box::new(Node::Func(
-                        Box::new(Node::Ident("f")),
-                        vec![Node::Number("2")]
+                        Box::new(Node::Ident("f".into())),
+                        vec![Node::Number("2".into())]
                     )),
-                    "result",
+                    "result".into(),
                 ),
             ))
         );
@@ -546,10 +618,10 @@ mod test {
                 Node::Op(
                     Box::new(Node::Op(
                         Box::new(Node::True),
-                        "||",
-                        Box::new(Node::Op(Box::new(Node::False), "||", Box::new(Node::True)))
+                        "||".into(),
+                        Box::new(Node::Op(Box::new(Node::False), "||".into(), Box::new(Node::True)))
                     )),
-                    "||",
+                    "||".into(),
                     Box::new(Node::False),
                 )
             ))
@@ -564,16 +636,16 @@ mod test {
                 "",
                 Node::Op(
                     Box::new(Node::Op(
-                        Box::new(Node::Ident("a")),
-                        "||",
+                        Box::new(Node::Ident("a".into())),
+                        "||".into(),
                         Box::new(Node::Op(
-                            Box::new(Node::Ident("b")),
-                            "&&",
-                            Box::new(Node::Ident("c"))
+                            Box::new(Node::Ident("b".into())),
+                            "&&".into(),
+                            Box::new(Node::Ident("c".into()))
                         ))
                     )),
-                    "||",
-                    Box::new(Node::Ident("d")),
+                    "||".into(),
+                    Box::new(Node::Ident("d".into())),
                 )
             ))
         );
@@ -587,15 +659,15 @@ mod test {
                 "",
                 Node::Op(
                     Box::new(Node::Op(
-                        Box::new(Node::Number("1")),
-                        "<",
-                        Box::new(Node::Number("2")),
+                        Box::new(Node::Number("1".into())),
+                        "<".into(),
+                        Box::new(Node::Number("2".into())),
                     )),
-                    "==",
+                    "==".into(),
                     Box::new(Node::Op(
-                        Box::new(Node::Number("3")),
-                        ">=",
-                        Box::new(Node::Number("4"))
+                        Box::new(Node::Number("3".into())),
+                        ">=".into(),
+                        Box::new(Node::Number("4".into()))
                     ))
                 )
             ))
@@ -612,21 +684,21 @@ mod test {
 
     #[test]
     fn test_parse_all() {
-        assert_eq!(parse_all("abcd").unwrap(), Node::Ident("abcd"));
+        assert_eq!(parse_all("abcd", &budget()).unwrap(), Node::Ident("abcd".into()));
     }
 
     #[test]
     fn test_exp_high_followed_by_low_prec() {
         assert_eq!(
-            parse_all("9 * 10 + 11").unwrap(),
+            parse_all("9 * 10 + 11", &budget()).unwrap(),
             Node::Op(
                 Box::new(Node::Op(
-                    Box::new(Node::Number("9")),
-                    "*",
-                    Box::new(Node::Number("10")),
+                    Box::new(Node::Number("9".into())),
+                    "*".into(),
+                    Box::new(Node::Number("10".into())),
                 )),
-                "+",
-                Box::new(Node::Number("11"))
+                "+".into(),
+                Box::new(Node::Number("11".into()))
             )
         );
     }
@@ -634,14 +706,14 @@ mod test {
     #[test]
     fn test_parse_function_call_in_operator() {
         assert_eq!(
-            parse_all("x(10) + 11").unwrap(),
+            parse_all("x(10) + 11", &budget()).unwrap(),
             Node::Op(
                 Box::new(Node::Func(
-                    Box::new(Node::Ident("x")),
-                    vec![Node::Number("10")]
+                    Box::new(Node::Ident("x".into())),
+                    vec![Node::Number("10".into())]
                 )),
-                "+",
-                Box::new(Node::Number("11"))
+                "+".into(),
+                Box::new(Node::Number("11".into()))
             )
         );
     }
@@ -649,37 +721,87 @@ mod test {
     #[test]
     fn test_parse_no_args_fn() {
         assert_eq!(
-            parse_all("f()").unwrap(),
-            Node::Func(Box::new(Node::Ident("f")), vec![],)
+            parse_all("f()", &budget()).unwrap(),
+            Node::Func(Box::new(Node::Ident("f".into())), vec![],)
         );
     }
 
     #[test]
     fn test_parse_all_err() {
-        assert!(parse_all("~~~").is_err());
+        assert!(parse_all("~~~", &budget()).is_err());
     }
 
     #[test]
     fn test_parse_all_trailing_chars() {
-        assert!(parse_all("abc 123").is_err());
+        assert!(parse_all("abc 123", &budget()).is_err());
     }
 
     #[test]
     fn test_parse_partial() {
-        assert_eq!(parse_partial("abcd").unwrap(), (Node::Ident("abcd"), ""));
+        assert_eq!(parse_partial("abcd", &budget()).unwrap(), (Node::Ident("abcd".into()), ""));
     }
 
     #[test]
     fn test_parse_partial_err() {
-        assert!(parse_partial("~~~").is_err());
+        assert!(parse_partial("~~~", &budget()).is_err());
     }
 
     #[test]
     fn test_parse_partial_trailing_chars() {
         // note that this consumes the whitespace, too
         assert_eq!(
-            parse_partial("abc 123").unwrap(),
-            (Node::Ident("abc"), "123")
+            parse_partial("abc 123", &budget()).unwrap(),
+            (Node::Ident("abc".into()), "123")
         );
     }
+
+    #[test]
+    fn test_nesting() {
+        assert_eq!(nesting("[[1], (2)] + {a: 3}"), 2);
+        assert_eq!(nesting("'((' + \"[[\""), 0);
+        assert_eq!(nesting("a ** (b ** c)"), 3);
+        assert_eq!(nesting("a[1]} ((("), 1);
+    }
+
+    #[test]
+    fn test_parse_nested() {
+        // each of these would take time exponential in the nesting if parsed with backtracking
+        let nested_arrays = format!("{}1{}", "[".repeat(50), "]".repeat(50));
+        assert!(parse_all(&nested_arrays, &budget()).is_ok());
+        let nested_slices = format!("{}1{}", "a[".repeat(50), ":]".repeat(50));
+        assert!(parse_all(&nested_slices, &budget()).is_ok());
+    }
+
+    #[test]
+    fn test_parse_too_deep() {
+        use crate::errors::ErrorKind;
+        use crate::limits::Limits;
+
+        // the stack limit is reached before the stack overflows
+        let nested = format!("{}1{}", "(".repeat(10_000), ")".repeat(10_000));
+        let err = parse_all(&nested, &budget()).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::StackLimitExceeded { limit: 1 << 20 });
+
+        let limited = Budget::new(Limits::default().max_depth(64));
+        let nested = format!("{}1{}", "(".repeat(65), ")".repeat(65));
+        let err = parse_all(&nested, &limited).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::DepthLimitExceeded { limit: 64 });
+    }
+
+    #[test]
+    fn test_parse_long_chain() {
+        use crate::errors::ErrorKind;
+        use crate::limits::Limits;
+
+        // a chain of operations is parsed without recursion, but its tree must not be too deep
+        // to clone or compare
+        let chain = vec!["1"; 200].join(" + ");
+        assert!(parse_all(&chain, &budget()).is_ok());
+        let chain = vec!["a.b(1)[0]"; 200].join(" + ");
+        assert!(parse_all(&chain, &budget()).is_ok());
+        let chain = vec!["1"; 100_000].join("+");
+        let limited = Budget::new(Limits::default().max_depth(64).max_steps(1000));
+        let err = parse_all(&chain, &limited).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::StackLimitExceeded { limit: 1 << 20 });
+    }
 }
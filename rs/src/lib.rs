@@ -1,12 +1,41 @@
 #[macro_use]
 mod errors;
+mod analysis;
 mod builtins;
+mod expression;
 mod fromnow;
+mod include;
+#[cfg(feature = "incremental")]
+mod incremental;
 mod interpreter;
+mod limits;
+mod number;
 mod op_props;
+mod operators;
+#[cfg(feature = "parallel")]
+mod parallel;
+mod partial;
 mod render;
+mod stream;
+mod template;
 mod value;
 mod whitespace;
+#[cfg(feature = "yaml")]
+mod yaml;
 
+pub use analysis::{free_variables, validate, FreeVariable};
+pub use errors::{Error, ErrorKind, Location};
+pub use expression::{evaluate_expression, parse_expression, ParsedExpression};
+#[allow(deprecated)]
 pub use fromnow::use_test_now;
-pub use render::render;
+pub use fromnow::{Clock, SystemClock};
+pub use include::{DirectoryLoader, Loader, MemoryLoader};
+#[cfg(feature = "incremental")]
+pub use incremental::IncrementalRender;
+#[cfg(feature = "incremental")]
+pub use json_patch::Patch;
+pub use limits::Limits;
+pub use operators::{CustomOperator, Invocation};
+pub use render::{render, render_to_writer, CompiledTemplate, Renderer};
+#[cfg(feature = "yaml")]
+pub use yaml::{from_yaml_str, render_yaml, to_yaml_string};
@@ -0,0 +1,521 @@
+use crate::errors::{Error, ErrorKind, Result};
+use serde_json::Value as SerdeValue;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Limits on the resources a render may use, for rendering untrusted templates.  A render that
+/// exceeds a limit fails with an error of the corresponding [`ErrorKind`].
+///
+/// By default only the stack space used is limited, so that however deeply a template or
+/// expression nests, rendering it fails with an error rather than overflowing the stack.  The
+/// default does not limit the nesting of context values, which are checked against the stack
+/// limit only if it is given with [`Limits::max_stack`].
+///
+/// ```
+/// # use json_e::{Limits, Renderer};
+/// let renderer = Renderer::new().limits(
+///     Limits::default()
+///         .max_steps(100_000)
+///         .max_string_length(1 << 20),
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    max_depth: usize,
+    max_steps: usize,
+    max_output_nodes: usize,
+    max_string_length: usize,
+    max_array_length: usize,
+    max_stack: usize,
+    /// Whether the stack limit was given, rather than the default, in which case it also bounds
+    /// the nesting of context values
+    max_stack_given: bool,
+}
+
+/// The stack space a render may use by default, leaving ample room within the 2 MiB stack of a
+/// thread spawned by `std::thread`.
+const DEFAULT_MAX_STACK: usize = 1 << 20;
+
+/// The stack space allowed for each level of nesting of a value or expression tree, which is
+/// converted, compared or dropped recursively without checking the stack at each level.
+const STACK_PER_LEVEL: usize = 1 << 12;
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_depth: usize::MAX,
+            max_steps: usize::MAX,
+            max_output_nodes: usize::MAX,
+            max_string_length: usize::MAX,
+            max_array_length: usize::MAX,
+            max_stack: DEFAULT_MAX_STACK,
+            max_stack_given: false,
+        }
+    }
+}
+
+impl Limits {
+    /// Limit the nesting depth of templates and expressions being rendered, and of the template
+    /// and context values themselves, failing with [`ErrorKind::DepthLimitExceeded`].  A chain
+    /// of operations such as `a + b + c`, or `a.b.c`, counts as a single level.
+    pub fn max_depth(mut self, limit: usize) -> Self {
+        self.max_depth = limit;
+        self
+    }
+
+    /// Limit the number of evaluation steps, where each template value rendered and each
+    /// expression node evaluated is a step, failing with [`ErrorKind::StepLimitExceeded`].
+    pub fn max_steps(mut self, limit: usize) -> Self {
+        self.max_steps = limit;
+        self
+    }
+
+    /// Limit the total number of array elements and object properties created during the
+    /// render, including intermediate values, failing with
+    /// [`ErrorKind::OutputNodeLimitExceeded`].
+    pub fn max_output_nodes(mut self, limit: usize) -> Self {
+        self.max_output_nodes = limit;
+        self
+    }
+
+    /// Limit the length, in bytes, of strings created during the render, failing with
+    /// [`ErrorKind::StringLengthLimitExceeded`].
+    pub fn max_string_length(mut self, limit: usize) -> Self {
+        self.max_string_length = limit;
+        self
+    }
+
+    /// Limit the length of arrays created during the render, failing with
+    /// [`ErrorKind::ArrayLengthLimitExceeded`].
+    pub fn max_array_length(mut self, limit: usize) -> Self {
+        self.max_array_length = limit;
+        self
+    }
+
+    /// Limit the stack space, in bytes, used by the render, failing with
+    /// [`ErrorKind::StackLimitExceeded`].  This is 1 MiB by default, which suits the 2 MiB stack
+    /// of a thread spawned by `std::thread`; raise it to render more deeply nested templates on a
+    /// thread with a larger stack.  Templates and expressions may nest one level for each 4 KiB
+    /// of this limit, or 256 levels by default.  Context values are checked against this limit,
+    /// at the same cost per level, only when it is given: by default they may nest as deeply as
+    /// the stack of the rendering thread allows.
+    pub fn max_stack(mut self, bytes: usize) -> Self {
+        self.max_stack = bytes;
+        self.max_stack_given = true;
+        self
+    }
+}
+
+/// The resources used so far by a single render, checked against its limits.
+#[derive(Debug)]
+pub(crate) struct Budget {
+    limits: Limits,
+    depth: AtomicUsize,
+    /// The position of the stack when this budget was created, from which the stack space used
+    /// is measured
+    stack_base: usize,
+    /// The steps and output nodes used, shared with any budgets forked from this one
+    usage: Arc<Usage>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    steps: AtomicUsize,
+    nodes: AtomicUsize,
+}
+
+impl Budget {
+    pub(crate) fn new(limits: Limits) -> Budget {
+        Budget {
+            limits,
+            depth: AtomicUsize::new(0),
+            stack_base: stack_position(),
+            usage: Arc::new(Usage::default()),
+        }
+    }
+
+    /// The limits this budget checks against
+    pub(crate) fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// The stack space used since this budget was created, measured on the thread rendering with
+    /// it, for passing to [`Budget::fork`].
+    #[cfg(feature = "parallel")]
+    pub(crate) fn stack_used(&self) -> usize {
+        stack_position().abs_diff(self.stack_base)
+    }
+
+    /// A budget for one branch of a render that is evaluated alongside others, which shares the
+    /// steps and output nodes of this budget, but tracks its own depth starting from this
+    /// budget's current depth, so that branches do not count against one another's depth.  The
+    /// fork may run on another thread, so it measures its stack space from where it is created,
+    /// but as continuing from `stack_used`, the space this budget had used when the branch began.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn fork(&self, stack_used: usize) -> Budget {
+        let here = stack_position();
+        let stack_base = if stack_grows_down() {
+            here.saturating_add(stack_used)
+        } else {
+            here.saturating_sub(stack_used)
+        };
+        Budget {
+            limits: self.limits,
+            depth: AtomicUsize::new(self.depth.load(Ordering::Relaxed)),
+            stack_base,
+            usage: self.usage.clone(),
+        }
+    }
+
+    /// A budget for compiling a template, which limits only its depth and stack space: the
+    /// remaining limits apply when the template is rendered.
+    pub(crate) fn for_compilation(limits: Limits) -> Budget {
+        Budget::new(Limits {
+            max_depth: limits.max_depth,
+            max_stack: limits.max_stack,
+            max_stack_given: limits.max_stack_given,
+            ..Limits::default()
+        })
+    }
+
+    /// Take a step one level deeper, returning a guard which leaves that level when dropped.
+    pub(crate) fn enter(&self) -> Result<Depth<'_>> {
+        self.step()?;
+        let depth = Depth(Some(self));
+        if self.depth.fetch_add(1, Ordering::Relaxed) >= self.limits.max_depth {
+            return Err(self.depth_error());
+        }
+        Ok(depth)
+    }
+
+    /// Take a step without going any deeper, such as to the next link of a chain of operations,
+    /// which still uses the stack when it is evaluated recursively.
+    pub(crate) fn step(&self) -> Result<()> {
+        let steps = self.usage.steps.fetch_add(1, Ordering::Relaxed) + 1;
+        if steps > self.limits.max_steps {
+            return Err(error_of_kind!(
+                ErrorKind::StepLimitExceeded {
+                    limit: self.limits.max_steps
+                },
+                "evaluation step limit of {} exceeded",
+                self.limits.max_steps
+            ));
+        }
+        self.check_stack()
+    }
+
+    /// Check that the stack space used since this budget was created is within the limit, before
+    /// recursing further.
+    pub(crate) fn check_stack(&self) -> Result<()> {
+        self.stack_guard().check()
+    }
+
+    /// The stack guard of this budget, for code that checks the stack without access to it.
+    pub(crate) fn stack_guard(&self) -> StackGuard {
+        StackGuard {
+            base: self.stack_base,
+            limit: self.limits.max_stack,
+        }
+    }
+
+    /// Check that the given height of an expression tree, which is cloned, compared and dropped
+    /// recursively, is within the nesting allowed by the stack limit.
+    pub(crate) fn check_height(&self, height: usize) -> Result<()> {
+        if height > self.limits.max_stack / STACK_PER_LEVEL {
+            return Err(stack_error(self.limits.max_stack));
+        }
+        Ok(())
+    }
+
+    /// Check that recursing to the given additional depth, such as to parse an expression, stays
+    /// within the depth limit.
+    pub(crate) fn check_nesting(&self, nesting: usize) -> Result<()> {
+        if self.depth.load(Ordering::Relaxed).saturating_add(nesting) > self.limits.max_depth {
+            return Err(self.depth_error());
+        }
+        Ok(())
+    }
+
+    /// Check the nesting depth of the given context value, without recursion, as for
+    /// [`Budget::check_context_nesting`].
+    pub(crate) fn check_context_depth(&self, value: &SerdeValue) -> Result<()> {
+        self.check_context_nesting(value_depth(value))
+    }
+
+    /// Check that a context value nested to the given depth is within the depth limit, and
+    /// within the stack limit if it was given, so that a value too deeply nested to be converted
+    /// safely is rejected before converting it.  The default stack limit does not apply, as
+    /// contexts rendered without limits may nest as deeply as the stack allows.
+    pub(crate) fn check_context_nesting(&self, depth: usize) -> Result<()> {
+        if self.limits.max_stack_given {
+            self.check_value_nesting(depth)
+        } else if depth > self.limits.max_depth {
+            Err(self.depth_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that a template value nested to the given depth, as found by [`value_depth`], is
+    /// within the limits, so that it is rejected before converting it.
+    fn check_value_nesting(&self, depth: usize) -> Result<()> {
+        if depth > self.limits.max_depth {
+            return Err(self.depth_error());
+        }
+        if depth > self.limits.max_stack / STACK_PER_LEVEL {
+            return Err(stack_error(self.limits.max_stack));
+        }
+        Ok(())
+    }
+
+    /// Check the nesting depth of the given template value, without recursion, as for
+    /// [`Budget::check_value_nesting`], giving the error the location of the value nested too
+    /// deeply.
+    pub(crate) fn check_template_depth(&self, value: &SerdeValue) -> Result<()> {
+        self.check_value_depth(value)
+            .map_err(|(e, path)| path.iter().rev().fold(e, |e, s| e.add_location(s)))
+    }
+
+    /// Check the nesting depth of the given value, returning the error along with the path to the
+    /// value nested too deeply.
+    fn check_value_depth(
+        &self,
+        value: &SerdeValue,
+    ) -> std::result::Result<(), (Error, Vec<String>)> {
+        // values are visited depth-first, so the path to each is that to its parent, which is
+        // the path so far truncated to its parent's depth, followed by its own segment
+        let mut path = Vec::new();
+        let mut stack = vec![(value, 1, None)];
+        while let Some((value, depth, segment)) = stack.pop() {
+            if let Some(segment) = segment {
+                path.truncate(depth - 2);
+                path.push(segment);
+            }
+            if let Err(e) = self.check_value_nesting(depth) {
+                return Err((e, path));
+            }
+            match value {
+                SerdeValue::Array(a) => stack.extend(
+                    a.iter()
+                        .enumerate()
+                        .rev()
+                        .map(|(i, v)| (v, depth + 1, Some(i.to_string()))),
+                ),
+                SerdeValue::Object(o) => {
+                    stack.extend(o.iter().rev().map(|(k, v)| (v, depth + 1, Some(k.clone()))))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn depth_error(&self) -> Error {
+        error_of_kind!(
+            ErrorKind::DepthLimitExceeded {
+                limit: self.limits.max_depth
+            },
+            "depth limit of {} exceeded",
+            self.limits.max_depth
+        )
+    }
+
+    /// Count the creation of the given number of array elements or object properties.
+    pub(crate) fn add_nodes(&self, count: usize) -> Result<()> {
+        let nodes = self
+            .usage
+            .nodes
+            .fetch_add(count, Ordering::Relaxed)
+            .saturating_add(count);
+        if nodes > self.limits.max_output_nodes {
+            return Err(error_of_kind!(
+                ErrorKind::OutputNodeLimitExceeded {
+                    limit: self.limits.max_output_nodes
+                },
+                "output node limit of {} exceeded",
+                self.limits.max_output_nodes
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the length of a string about to be created.
+    pub(crate) fn check_string(&self, length: usize) -> Result<()> {
+        if length > self.limits.max_string_length {
+            return Err(error_of_kind!(
+                ErrorKind::StringLengthLimitExceeded {
+                    limit: self.limits.max_string_length
+                },
+                "string length limit of {} exceeded",
+                self.limits.max_string_length
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the length of an array about to be created, and count its elements.
+    pub(crate) fn check_array(&self, length: usize) -> Result<()> {
+        if length > self.limits.max_array_length {
+            return Err(error_of_kind!(
+                ErrorKind::ArrayLengthLimitExceeded {
+                    limit: self.limits.max_array_length
+                },
+                "array length limit of {} exceeded",
+                self.limits.max_array_length
+            ));
+        }
+        self.add_nodes(length)
+    }
+}
+
+/// The stack position and stack limit of a budget, against which code that cannot reach the
+/// budget itself, such as the expression parser, checks the stack before recursing.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StackGuard {
+    base: usize,
+    limit: usize,
+}
+
+impl StackGuard {
+    /// Check that the stack space used since the budget was created is within its limit.
+    pub(crate) fn check(&self) -> Result<()> {
+        if stack_position().abs_diff(self.base) > self.limit {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+
+    /// The error for exceeding this guard's limit.
+    pub(crate) fn error(&self) -> Error {
+        stack_error(self.limit)
+    }
+}
+
+/// The nesting depth of the given value, found without recursion: 1 for a scalar or an empty
+/// array or object.
+pub(crate) fn value_depth(value: &SerdeValue) -> usize {
+    let mut max = 0;
+    let mut stack = vec![(value, 1)];
+    while let Some((value, depth)) = stack.pop() {
+        max = max.max(depth);
+        match value {
+            SerdeValue::Array(a) => stack.extend(a.iter().map(|v| (v, depth + 1))),
+            SerdeValue::Object(o) => stack.extend(o.values().map(|v| (v, depth + 1))),
+            _ => {}
+        }
+    }
+    max
+}
+
+fn stack_error(limit: usize) -> Error {
+    error_of_kind!(
+        ErrorKind::StackLimitExceeded { limit },
+        "stack limit of {} bytes exceeded",
+        limit
+    )
+}
+
+/// The approximate position of the top of the stack.
+#[inline(never)]
+fn stack_position() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+
+/// Whether the stack grows towards lower addresses, as found by comparing the position of a
+/// callee's frame with that of its caller.
+#[cfg(feature = "parallel")]
+#[inline(never)]
+fn stack_grows_down() -> bool {
+    let marker = 0u8;
+    stack_position() < std::hint::black_box(&marker) as *const u8 as usize
+}
+
+/// A guard for one level of depth in a render, returned from [`Budget::enter`].
+#[derive(Debug)]
+pub(crate) struct Depth<'a>(Option<&'a Budget>);
+
+impl<'a> Depth<'a> {
+    /// A guard for a context without a budget, which does nothing.
+    pub(crate) fn unlimited() -> Depth<'a> {
+        Depth(None)
+    }
+}
+
+impl Drop for Depth<'_> {
+    fn drop(&mut self) {
+        if let Some(budget) = self.0 {
+            budget.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn depth_is_released() {
+        let budget = Budget::new(Limits::default().max_depth(2));
+        {
+            let _a = budget.enter().unwrap();
+            let _b = budget.enter().unwrap();
+            let err = budget.enter().unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::DepthLimitExceeded { limit: 2 });
+        }
+        let _a = budget.enter().unwrap();
+        let _b = budget.enter().unwrap();
+    }
+
+    #[test]
+    fn steps_accumulate() {
+        let budget = Budget::new(Limits::default().max_steps(3));
+        for _ in 0..3 {
+            budget.enter().unwrap();
+        }
+        let err = budget.enter().unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::StepLimitExceeded { limit: 3 });
+    }
+
+    #[test]
+    fn arrays_count_as_nodes() {
+        let budget = Budget::new(Limits::default().max_array_length(10).max_output_nodes(15));
+        budget.check_array(10).unwrap();
+        let err = budget.check_array(11).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::ArrayLengthLimitExceeded { limit: 10 });
+        let err = budget.check_array(10).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::OutputNodeLimitExceeded { limit: 15 });
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn forks_share_usage_but_not_depth() {
+        let budget = Budget::new(Limits::default().max_depth(2).max_steps(4));
+        let _a = budget.enter().unwrap();
+        let fork = budget.fork(budget.stack_used());
+        let _b = fork.enter().unwrap();
+        // the fork's depth does not count against the original
+        let _c = budget.enter().unwrap();
+        let err = fork.enter().unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::DepthLimitExceeded { limit: 2 });
+        // but its steps do
+        let err = budget.enter().unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::StepLimitExceeded { limit: 4 });
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn forks_continue_stack_used() {
+        let budget = Budget::new(Limits::default().max_stack(1 << 16));
+        // a fork continues from the stack its parent had used, on whichever thread it runs
+        let limit = ErrorKind::StackLimitExceeded { limit: 1 << 16 };
+        assert!(budget.fork(0).check_stack().is_ok());
+        assert_eq!(budget.fork(1 << 17).check_stack().unwrap_err().kind(), &limit);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert!(budget.fork(0).check_stack().is_ok());
+                assert_eq!(budget.fork(1 << 17).check_stack().unwrap_err().kind(), &limit);
+            });
+        });
+    }
+}
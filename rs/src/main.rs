@@ -0,0 +1,383 @@
+//! The `json-e` command, which renders a template file with a context built from context files
+//! and `--set` options, or checks a template for errors.
+
+use json_e::ErrorKind;
+use serde_json::{Map, Value};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+const USAGE: &str = "\
+Usage: json-e [OPTIONS] TEMPLATE [CONTEXT...]
+       json-e validate TEMPLATE
+
+Render the JSON-e template in the file TEMPLATE, with a context made by merging
+the JSON objects in the CONTEXT files, in order, and then the --set values, and
+print the result as JSON.  A file named - is read from standard input.  When
+json-e is built with the yaml feature, files ending in .yaml or .yml are read as
+YAML.
+
+The validate command instead prints every error in TEMPLATE that does not
+depend on the context, such as unknown operators and syntax errors.
+
+Options:
+  --set KEY=VALUE  Set a context value.  VALUE is parsed as JSON, or is taken
+                   as a string if it is not valid JSON.
+  --pretty         Print the result indented over several lines (default)
+  --compact        Print the result on a single line
+  --yaml           Print the result as YAML (requires the yaml feature)
+  -h, --help       Print this help
+  -V, --version    Print the version
+
+Exit status:
+  0  Success
+  1  The template failed to render, or is not valid
+  2  The command line is not valid
+  3  An input file could not be read, or is not valid JSON or YAML, or a context
+     key is not an identifier
+  4  The output could not be written
+";
+
+const EXIT_RENDER: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_INPUT: i32 = 3;
+const EXIT_OUTPUT: i32 = 4;
+
+/// A command, as given on the command line
+#[derive(Debug, PartialEq)]
+enum Command {
+    Render(Options),
+    Validate { template: String },
+    Help,
+    Version,
+}
+
+/// The options for rendering a template
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    template: String,
+    contexts: Vec<String>,
+    /// The `--set` values, in order
+    sets: Vec<(String, Value)>,
+    format: Format,
+}
+
+/// The format in which to print the result
+#[derive(Debug, Default, PartialEq)]
+enum Format {
+    #[default]
+    Pretty,
+    Compact,
+    Yaml,
+}
+
+/// A failure of the command, with its exit status
+#[derive(Debug, PartialEq)]
+struct Failure {
+    status: i32,
+    message: String,
+}
+
+impl Failure {
+    fn usage<S: Into<String>>(message: S) -> Failure {
+        Failure {
+            status: EXIT_USAGE,
+            message: format!("{}\nTry 'json-e --help' for more information.", message.into()),
+        }
+    }
+
+    fn input<S: Into<String>>(message: S) -> Failure {
+        Failure {
+            status: EXIT_INPUT,
+            message: message.into(),
+        }
+    }
+}
+
+fn main() {
+    let result = parse_args(std::env::args().skip(1)).and_then(run);
+    if let Err(failure) = result {
+        eprintln!("json-e: {}", failure.message);
+        process::exit(failure.status);
+    }
+}
+
+/// Parse the command-line arguments, not including the program name.
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Command, Failure> {
+    let mut args = args.into_iter();
+    let mut options = Options::default();
+    let mut positional = vec![];
+    let mut validate = false;
+    let mut only_positional = false;
+
+    while let Some(arg) = args.next() {
+        if only_positional || arg == "-" || !arg.starts_with('-') {
+            if positional.is_empty() && !validate && arg == "validate" && !only_positional {
+                validate = true;
+            } else {
+                positional.push(arg);
+            }
+            continue;
+        }
+        match arg.as_str() {
+            "--" => only_positional = true,
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "--pretty" => options.format = Format::Pretty,
+            "--compact" => options.format = Format::Compact,
+            "--yaml" if cfg!(feature = "yaml") => options.format = Format::Yaml,
+            "--yaml" => return Err(Failure::usage("json-e was built without the yaml feature")),
+            "--set" => {
+                let set = args
+                    .next()
+                    .ok_or_else(|| Failure::usage("--set requires a KEY=VALUE argument"))?;
+                options.sets.push(parse_set(&set)?);
+            }
+            _ => match arg.strip_prefix("--set=") {
+                Some(set) => options.sets.push(parse_set(set)?),
+                None => return Err(Failure::usage(format!("unknown option {}", arg))),
+            },
+        }
+    }
+
+    if positional.iter().filter(|p| *p == "-").count() > 1 {
+        return Err(Failure::usage("standard input can only be read once"));
+    }
+    let mut positional = positional.into_iter();
+    let template = positional
+        .next()
+        .ok_or_else(|| Failure::usage("no template file given"))?;
+    if validate {
+        if positional.next().is_some() {
+            return Err(Failure::usage("validate takes only a template file"));
+        }
+        if options != Options::default() {
+            return Err(Failure::usage("validate takes no options"));
+        }
+        return Ok(Command::Validate { template });
+    }
+    options.template = template;
+    options.contexts = positional.collect();
+    Ok(Command::Render(options))
+}
+
+/// Parse the argument of `--set`.
+fn parse_set(set: &str) -> Result<(String, Value), Failure> {
+    match set.find('=') {
+        Some(0) | None => Err(Failure::usage(format!(
+            "--set argument {:?} is not of the form KEY=VALUE",
+            set
+        ))),
+        Some(i) => {
+            let value = &set[i + 1..];
+            let value = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+            Ok((set[..i].to_owned(), value))
+        }
+    }
+}
+
+fn run(command: Command) -> Result<(), Failure> {
+    match command {
+        Command::Help => print!("{}", USAGE),
+        Command::Version => println!("json-e {}", env!("CARGO_PKG_VERSION")),
+        Command::Render(options) => {
+            let template = read_input(&options.template)?;
+            let context = context(&options)?;
+            // the library checks the keys of the context, so that it is given just those it accepts
+            let result = json_e::render(&template, &context).map_err(|e| match e.kind() {
+                ErrorKind::InvalidContext => Failure::input(format!("invalid context: {}", e)),
+                _ => Failure {
+                    status: EXIT_RENDER,
+                    message: format!("{}: {}", options.template, e),
+                },
+            })?;
+            // the rendered value is JSON, so it always serializes
+            let output = match options.format {
+                Format::Pretty => serde_json::to_string_pretty(&result).unwrap() + "\n",
+                Format::Compact => serde_json::to_string(&result).unwrap() + "\n",
+                Format::Yaml => to_yaml(&result)?,
+            };
+            if let Err(e) = io::stdout().write_all(output.as_bytes()) {
+                // a closed pipe, such as when piping to `head`, is not an error
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    return Err(Failure {
+                        status: EXIT_OUTPUT,
+                        message: format!("could not write output: {}", e),
+                    });
+                }
+            }
+        }
+        Command::Validate { template: path } => {
+            let template = read_input(&path)?;
+            if let Err(errors) = json_e::validate(&template) {
+                for e in &errors {
+                    eprintln!("{}: {}", path, e);
+                }
+                return Err(Failure {
+                    status: EXIT_RENDER,
+                    message: format!(
+                        "{}: {} error{} found",
+                        path,
+                        errors.len(),
+                        if errors.len() == 1 { "" } else { "s" }
+                    ),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Build the context from the context files and `--set` values.
+fn context(options: &Options) -> Result<Value, Failure> {
+    let mut context = Map::new();
+    for path in &options.contexts {
+        match read_input(path)? {
+            Value::Object(o) => context.extend(o),
+            _ => {
+                return Err(Failure::input(format!(
+                    "{}: context must be an object",
+                    path
+                )))
+            }
+        }
+    }
+    for (k, v) in &options.sets {
+        context.insert(k.clone(), v.clone());
+    }
+    Ok(Value::Object(context))
+}
+
+/// Read the JSON or YAML in the given file, or the JSON on standard input for `-`.
+fn read_input(path: &str) -> Result<Value, Failure> {
+    let contents = if path == "-" {
+        let mut contents = String::new();
+        io::stdin()
+            .read_to_string(&mut contents)
+            .map(|_| contents)
+    } else {
+        fs::read_to_string(path)
+    };
+    let contents =
+        contents.map_err(|e| Failure::input(format!("could not read {}: {}", path, e)))?;
+    #[cfg(feature = "yaml")]
+    {
+        if path.ends_with(".yaml") || path.ends_with(".yml") {
+            return json_e::from_yaml_str(&contents)
+                .map_err(|e| Failure::input(format!("{}: {}", path, e)));
+        }
+    }
+    serde_json::from_str(&contents)
+        .map_err(|e| Failure::input(format!("{}: invalid JSON: {}", path, e)))
+}
+
+#[cfg(feature = "yaml")]
+fn to_yaml(value: &Value) -> Result<String, Failure> {
+    json_e::to_yaml_string(value).map_err(|e| Failure {
+        status: EXIT_RENDER,
+        message: e.to_string(),
+    })
+}
+
+#[cfg(not(feature = "yaml"))]
+fn to_yaml(_value: &Value) -> Result<String, Failure> {
+    unreachable!("--yaml is rejected without the yaml feature")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(args: &[&str]) -> Result<Command, Failure> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn render_args() {
+        assert_eq!(
+            parse(&["--compact", "t.json", "a.json", "--set", "x=1", "--set=y=a=b", "-"]),
+            Ok(Command::Render(Options {
+                template: "t.json".to_owned(),
+                contexts: vec!["a.json".to_owned(), "-".to_owned()],
+                sets: vec![
+                    ("x".to_owned(), json!(1)),
+                    ("y".to_owned(), json!("a=b"))
+                ],
+                format: Format::Compact,
+            }))
+        );
+        assert_eq!(
+            parse(&["--compact", "--pretty", "--", "--set"]),
+            Ok(Command::Render(Options {
+                template: "--set".to_owned(),
+                ..Options::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn set_values() {
+        assert_eq!(parse_set("x={\"a\": [1]}"), Ok(("x".to_owned(), json!({"a": [1]}))));
+        assert_eq!(parse_set("x=true"), Ok(("x".to_owned(), json!(true))));
+        assert_eq!(parse_set("x=\"1\""), Ok(("x".to_owned(), json!("1"))));
+        assert_eq!(parse_set("x=hello"), Ok(("x".to_owned(), json!("hello"))));
+        assert_eq!(parse_set("x="), Ok(("x".to_owned(), json!(""))));
+        assert_eq!(parse_set("=1").unwrap_err().status, EXIT_USAGE);
+        assert_eq!(parse_set("x").unwrap_err().status, EXIT_USAGE);
+        assert_eq!(parse_set("_x1=1"), Ok(("_x1".to_owned(), json!(1))));
+        // keys that are not identifiers are rejected along with the rest of the context
+        assert_eq!(parse_set("a-b=1"), Ok(("a-b".to_owned(), json!(1))));
+    }
+
+    #[test]
+    fn other_commands() {
+        assert_eq!(parse(&["t.json", "--help"]), Ok(Command::Help));
+        assert_eq!(parse(&["-V"]), Ok(Command::Version));
+        assert_eq!(
+            parse(&["validate", "t.json"]),
+            Ok(Command::Validate {
+                template: "t.json".to_owned()
+            })
+        );
+        assert_eq!(
+            parse(&["./validate"]),
+            Ok(Command::Render(Options {
+                template: "./validate".to_owned(),
+                ..Options::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn yaml_format() {
+        let result = parse(&["--yaml", "t.yaml"]);
+        if cfg!(feature = "yaml") {
+            assert_eq!(
+                result,
+                Ok(Command::Render(Options {
+                    template: "t.yaml".to_owned(),
+                    format: Format::Yaml,
+                    ..Options::default()
+                }))
+            );
+        } else {
+            assert_eq!(result.unwrap_err().status, EXIT_USAGE);
+        }
+    }
+
+    #[test]
+    fn usage_errors() {
+        for args in &[
+            &[][..],
+            &["--set"],
+            &["--bogus", "t.json"],
+            &["-", "-"],
+            &["validate"],
+            &["validate", "t.json", "c.json"],
+            &["validate", "--compact", "t.json"],
+        ] {
+            assert_eq!(parse(args).unwrap_err().status, EXIT_USAGE, "{:?}", args);
+        }
+    }
+}
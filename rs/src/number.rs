@@ -0,0 +1,327 @@
+//! Numbers in JSON-e values.
+//!
+//! Integers in the range of `i64` and `u64` are kept exactly, so that IDs and counters larger
+//! than 2^53 survive a render unchanged.  Arithmetic on integers gives an integer when the result
+//! is an integer in that range, and otherwise falls back to floating point, as does any
+//! arithmetic involving a float.
+
+use std::cmp::Ordering;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::ops::{Add, Mul, Neg, Sub};
+use std::str::FromStr;
+
+/// The smallest integer held exactly, `i64::MIN`
+const MIN_INTEGER: i128 = i64::MIN as i128;
+
+/// The largest integer held exactly, `u64::MAX`
+const MAX_INTEGER: i128 = u64::MAX as i128;
+
+/// A JSON-e number.  Numbers compare and test equal by their numeric value, so `1` equals `1.0`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Number {
+    /// An integer, always between `i64::MIN` and `u64::MAX`
+    Integer(i128),
+    Float(f64),
+}
+
+impl Number {
+    /// The given integer, as a float if it is outside the range held exactly
+    fn integer(i: i128) -> Number {
+        if (MIN_INTEGER..=MAX_INTEGER).contains(&i) {
+            Number::Integer(i)
+        } else {
+            Number::Float(i as f64)
+        }
+    }
+
+    pub(crate) fn as_f64(self) -> f64 {
+        match self {
+            Number::Integer(i) => i as f64,
+            Number::Float(f) => f,
+        }
+    }
+
+    /// This number as an i64, if it is an integer in that range, such as `3` or `3.0`
+    pub(crate) fn as_i64(self) -> Option<i64> {
+        match self {
+            Number::Integer(i) => i.try_into().ok(),
+            Number::Float(f) => {
+                let i = f as i64;
+                if i as f64 == f {
+                    Some(i)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    pub(crate) fn is_zero(self) -> bool {
+        self == Number::Integer(0)
+    }
+
+    pub(crate) fn is_negative(self) -> bool {
+        self < Number::Integer(0)
+    }
+
+    /// Divide by the given number, or None when dividing by zero
+    pub(crate) fn checked_div(self, rhs: Number) -> Option<Number> {
+        if let (Number::Integer(l), Number::Integer(r)) = (self, rhs) {
+            if r != 0 && l % r == 0 {
+                return Some(Number::integer(l / r));
+            }
+        }
+        let q = self.as_f64() / rhs.as_f64();
+        if q.is_infinite() {
+            None
+        } else {
+            Some(Number::Float(q))
+        }
+    }
+
+    /// Raise this number to the given power
+    pub(crate) fn pow(self, rhs: Number) -> Number {
+        if let (Number::Integer(l), Number::Integer(r)) = (self, rhs) {
+            if let Some(p) = u32::try_from(r).ok().and_then(|r| l.checked_pow(r)) {
+                return Number::integer(p);
+            }
+        }
+        Number::Float(self.as_f64().powf(rhs.as_f64()))
+    }
+
+    pub(crate) fn abs(self) -> Number {
+        match self {
+            Number::Integer(i) => Number::integer(i.abs()),
+            Number::Float(f) => Number::Float(f.abs()),
+        }
+    }
+
+    pub(crate) fn ceil(self) -> Number {
+        match self {
+            Number::Integer(_) => self,
+            Number::Float(f) => Number::Float(f.ceil()),
+        }
+    }
+
+    pub(crate) fn floor(self) -> Number {
+        match self {
+            Number::Integer(_) => self,
+            Number::Float(f) => Number::Float(f.floor()),
+        }
+    }
+
+    pub(crate) fn sqrt(self) -> Number {
+        Number::Float(self.as_f64().sqrt())
+    }
+
+    /// Apply an arithmetic operation, exactly for integers if it does not overflow, and otherwise
+    /// in floating point
+    fn arithmetic(
+        self,
+        rhs: Number,
+        integer: fn(i128, i128) -> Option<i128>,
+        float: fn(f64, f64) -> f64,
+    ) -> Number {
+        if let (Number::Integer(l), Number::Integer(r)) = (self, rhs) {
+            if let Some(result) = integer(l, r) {
+                return Number::integer(result);
+            }
+        }
+        Number::Float(float(self.as_f64(), rhs.as_f64()))
+    }
+}
+
+impl Add for Number {
+    type Output = Number;
+
+    fn add(self, rhs: Number) -> Number {
+        self.arithmetic(rhs, i128::checked_add, |l, r| l + r)
+    }
+}
+
+impl Sub for Number {
+    type Output = Number;
+
+    fn sub(self, rhs: Number) -> Number {
+        self.arithmetic(rhs, i128::checked_sub, |l, r| l - r)
+    }
+}
+
+impl Mul for Number {
+    type Output = Number;
+
+    fn mul(self, rhs: Number) -> Number {
+        self.arithmetic(rhs, i128::checked_mul, |l, r| l * r)
+    }
+}
+
+impl Neg for Number {
+    type Output = Number;
+
+    fn neg(self) -> Number {
+        match self {
+            Number::Integer(i) => Number::integer(-i),
+            Number::Float(f) => Number::Float(-f),
+        }
+    }
+}
+
+/// Compare an integer with a float exactly, even where the integer has no exact float
+/// representation.
+fn compare_integer_float(i: i128, f: f64) -> Option<Ordering> {
+    match (i as f64).partial_cmp(&f)? {
+        // the float is an integer no larger in magnitude than the integer (after rounding), so
+        // converting it is exact
+        Ordering::Equal => Some(i.cmp(&(f as i128))),
+        ordering => Some(ordering),
+    }
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Number) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Number) -> Option<Ordering> {
+        match (*self, *other) {
+            (Number::Integer(l), Number::Integer(r)) => Some(l.cmp(&r)),
+            (Number::Float(l), Number::Float(r)) => l.partial_cmp(&r),
+            (Number::Integer(l), Number::Float(r)) => compare_integer_float(l, r),
+            (Number::Float(l), Number::Integer(r)) => {
+                compare_integer_float(r, l).map(Ordering::reverse)
+            }
+        }
+    }
+}
+
+/// Numbers display as JSON-e stringifies them, with integer-valued floats displayed without a
+/// fractional part.
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Number::Integer(i) => write!(f, "{}", i),
+            Number::Float(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// Parse a number, as an integer if it is written as one and is in range.
+impl FromStr for Number {
+    type Err = std::num::ParseFloatError;
+
+    fn from_str(s: &str) -> Result<Number, Self::Err> {
+        match s.parse::<i128>() {
+            Ok(i) => Ok(Number::integer(i)),
+            Err(_) => s.parse().map(Number::Float),
+        }
+    }
+}
+
+impl From<f64> for Number {
+    fn from(f: f64) -> Number {
+        Number::Float(f)
+    }
+}
+
+impl From<i64> for Number {
+    fn from(i: i64) -> Number {
+        Number::Integer(i.into())
+    }
+}
+
+impl From<u64> for Number {
+    fn from(i: u64) -> Number {
+        Number::Integer(i.into())
+    }
+}
+
+impl From<usize> for Number {
+    fn from(i: usize) -> Number {
+        Number::integer(i as i128)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BIG: u64 = (1 << 53) + 1;
+
+    fn n(s: &str) -> Number {
+        s.parse().unwrap()
+    }
+
+    impl Number {
+        fn as_integer(self) -> Option<i128> {
+            match self {
+                Number::Integer(i) => Some(i),
+                Number::Float(_) => None,
+            }
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert!(matches!(n("9007199254740993"), Number::Integer(i) if i == BIG as i128));
+        assert!(matches!(n("18446744073709551615"), Number::Integer(_)));
+        assert!(matches!(n("18446744073709551616"), Number::Float(_)));
+        assert!(matches!(n("-9223372036854775808"), Number::Integer(_)));
+        assert!(matches!(n("-9223372036854775809"), Number::Float(_)));
+        assert!(matches!(n("1.0"), Number::Float(_)));
+        assert!(matches!(n("1e3"), Number::Float(_)));
+    }
+
+    #[test]
+    fn integer_arithmetic_is_exact() {
+        let big = Number::from(BIG);
+        assert_eq!((big + Number::from(1i64)).as_integer(), Some(BIG as i128 + 1));
+        assert_eq!((big - Number::from(2i64)).as_integer(), Some(BIG as i128 - 2));
+        assert_eq!((big * Number::from(2i64)).as_integer(), Some(BIG as i128 * 2));
+        assert_eq!(
+            (big * Number::from(2i64)).checked_div(Number::from(2i64)).unwrap().as_integer(),
+            Some(BIG as i128)
+        );
+        assert_eq!(Number::from(2i64).pow(Number::from(63i64)).as_integer(), Some(1 << 63));
+        assert_eq!((-Number::from(i64::MIN)).as_integer(), Some(1 << 63));
+        assert_eq!(Number::from(i64::MIN).abs().as_integer(), Some(1 << 63));
+    }
+
+    #[test]
+    fn arithmetic_falls_back_to_float() {
+        assert!(matches!(n("7").checked_div(n("2")), Some(Number::Float(f)) if f == 3.5));
+        assert!(n("1").checked_div(n("0")).is_none());
+        assert!(matches!(n("2").pow(n("-1")), Number::Float(f) if f == 0.5));
+        assert!(matches!(n("2").pow(n("64")), Number::Float(_)));
+        assert!(matches!(Number::from(u64::MAX) + n("1"), Number::Float(_)));
+        assert!(matches!(Number::from(u64::MAX) * n("-1"), Number::Float(_)));
+        assert!(matches!(Number::from(u64::MAX) * Number::from(u64::MAX), Number::Float(_)));
+        assert!(matches!(n("1") + n("0.5"), Number::Float(f) if f == 1.5));
+        assert!(matches!(n("4").sqrt(), Number::Float(f) if f == 2.0));
+    }
+
+    #[test]
+    fn comparison_is_exact() {
+        assert_eq!(n("1"), n("1.0"));
+        assert_ne!(Number::from(BIG), Number::from(BIG - 1));
+        // BIG rounds to 2^53 as a float, but is still greater than it
+        assert_ne!(Number::from(BIG), Number::Float((1u64 << 53) as f64));
+        assert!(Number::from(BIG) > Number::Float((1u64 << 53) as f64));
+        assert!(Number::Float((1u64 << 53) as f64) < Number::from(BIG));
+        assert!(n("2") < n("2.5"));
+        assert_eq!(Number::Float(f64::NAN).partial_cmp(&n("1")), None);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(n("3.0").as_i64(), Some(3));
+        assert_eq!(n("3.5").as_i64(), None);
+        assert_eq!(Number::from(u64::MAX).as_i64(), None);
+        assert_eq!(n("-3").to_string(), "-3");
+        assert_eq!(n("3.0").to_string(), "3");
+        assert_eq!(n("3.5").to_string(), "3.5");
+        assert_eq!(Number::from(BIG).to_string(), "9007199254740993");
+    }
+}
@@ -1,4 +1,3 @@
-use anyhow::Result;
 use nom::{
     branch::alt,
     bytes::complete::tag,
@@ -58,7 +57,7 @@ pub(crate) fn parse_each_three(input: &str) -> Option<(&str, &str, Option<&str>)
 }
 
 fn by(input: &str) -> IResult<&str, &str> {
-    fn to_result<'a>(input: (&str, &'a str, &str)) -> Result<&'a str> {
+    fn to_result<'a>(input: (&str, &'a str, &str)) -> Result<&'a str, ()> {
         Ok(input.1)
     }
     map_res(tuple((tag("by("), ident, tag(")"))), to_result)(input)
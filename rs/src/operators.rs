@@ -0,0 +1,206 @@
+//! Custom `$`-operators, registered with a [`Renderer`](crate::Renderer).
+
+use crate::errors::{Error, ErrorKind, Result};
+use crate::interpreter::{self, Context};
+use crate::limits::{Budget, Limits};
+use crate::render::{_render, is_identifier};
+use crate::template::Template;
+use crate::value::Value;
+use serde_json::{Map, Value as SerdeValue};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::Arc;
+
+/// A custom operator, which a template invokes with an object containing the operator's name as
+/// a key, just like a built-in operator such as `$let` or `$map`.
+///
+/// Any `Fn(&Invocation) -> Result<Option<serde_json::Value>, Error>` is an operator that allows
+/// no other properties alongside the operator.
+///
+/// ```
+/// # use json_e::{CustomOperator, Error, Invocation, Renderer};
+/// # use serde_json::{json, Value};
+/// /// `{"$repeat": <value>, "times": <number>}` renders to an array of copies of the value
+/// struct Repeat;
+///
+/// impl CustomOperator for Repeat {
+///     fn allows_property(&self, property: &str) -> bool {
+///         property == "times"
+///     }
+///
+///     fn render(&self, invocation: &Invocation) -> Result<Option<Value>, Error> {
+///         let times = match invocation.render_property("times")? {
+///             Some(Value::Number(n)) if n.is_u64() => n.as_u64().unwrap(),
+///             _ => return Err(invocation.error("times must be a non-negative integer")),
+///         };
+///         Ok(invocation
+///             .render_value()?
+///             .map(|value| Value::Array(vec![value; times as usize])))
+///     }
+/// }
+///
+/// let renderer = Renderer::new().operator("$repeat", Repeat);
+/// let template = json!({"$repeat": "${x}", "times": 2});
+/// assert_eq!(renderer.render(&template, &json!({"x": 1})).unwrap(), json!(["1", "1"]));
+/// ```
+pub trait CustomOperator: Send + Sync {
+    /// Whether the given property may appear alongside the operator.  Any other property is
+    /// reported as an error, just as for a built-in operator.  By default, no other properties are
+    /// allowed.
+    fn allows_property(&self, property: &str) -> bool {
+        let _ = property;
+        false
+    }
+
+    /// Render an invocation of the operator, returning `None` to render nothing at all, in the
+    /// same way as `$if` without a matching `then` or `else`.
+    fn render(&self, invocation: &Invocation) -> Result<Option<SerdeValue>, Error>;
+}
+
+impl<F> CustomOperator for F
+where
+    F: Fn(&Invocation) -> Result<Option<SerdeValue>, Error> + Send + Sync,
+{
+    fn render(&self, invocation: &Invocation) -> Result<Option<SerdeValue>, Error> {
+        self(invocation)
+    }
+}
+
+/// The custom operators registered with a renderer, by name
+pub(crate) type Operators = HashMap<String, RegisteredOperator>;
+
+/// A registered custom operator
+#[derive(Clone)]
+pub(crate) struct RegisteredOperator(pub(crate) Arc<dyn CustomOperator>);
+
+impl fmt::Debug for RegisteredOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RegisteredOperator(..)")
+    }
+}
+
+/// An invocation of a custom operator, giving access to the operator's value and the other
+/// properties of the object invoking it, as well as to the context in which it is rendered.
+pub struct Invocation<'a> {
+    name: &'a str,
+    value: &'a Template,
+    properties: &'a [(String, Template)],
+    context: &'a Context<'a>,
+}
+
+impl<'a> Invocation<'a> {
+    pub(crate) fn new(
+        name: &'a str,
+        value: &'a Template,
+        properties: &'a [(String, Template)],
+        context: &'a Context<'a>,
+    ) -> Invocation<'a> {
+        Invocation {
+            name,
+            value,
+            properties,
+            context,
+        }
+    }
+
+    /// The name of the operator, such as `$secret`
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    /// The names of the other properties of the object invoking the operator
+    pub fn properties(&self) -> impl Iterator<Item = &str> {
+        self.properties.iter().map(|(k, _)| k.as_str())
+    }
+
+    /// Render the operator's value in the current context.
+    pub fn render_value(&self) -> Result<Option<SerdeValue>, Error> {
+        to_output(_render(self.value, self.context)?)
+    }
+
+    /// Render the operator's value in a child of the current context, with the given additional
+    /// values, just as `$let` renders its `in` property.
+    pub fn render_value_with(
+        &self,
+        bindings: &Map<String, SerdeValue>,
+    ) -> Result<Option<SerdeValue>, Error> {
+        let context = self.child_context(bindings)?;
+        to_output(_render(self.value, &context)?)
+    }
+
+    /// Render the given property in the current context, returning `None` if the property is not
+    /// present or renders to nothing.
+    pub fn render_property(&self, property: &str) -> Result<Option<SerdeValue>, Error> {
+        match self.property(property) {
+            Some(template) => to_output(_render(template, self.context)?),
+            None => Ok(None),
+        }
+    }
+
+    /// Render the given property in a child of the current context, with the given additional
+    /// values, just as `$map` renders its `each(..)` property.  This returns `None` if the
+    /// property is not present or renders to nothing.
+    pub fn render_property_with(
+        &self,
+        property: &str,
+        bindings: &Map<String, SerdeValue>,
+    ) -> Result<Option<SerdeValue>, Error> {
+        match self.property(property) {
+            Some(template) => {
+                let context = self.child_context(bindings)?;
+                to_output(_render(template, &context)?)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Evaluate the given expression in the current context.
+    pub fn evaluate(&self, expression: &str) -> Result<SerdeValue, Error> {
+        // rendering always has a budget, but one is required to parse even without
+        let budget = Budget::new(Limits::default());
+        let node = interpreter::parse_all(expression, self.context.budget().unwrap_or(&budget))?;
+        (&interpreter::evaluate(&node, self.context)?).try_into()
+    }
+
+    /// Create an error for this operator, with the given message, such as for an invalid value.
+    /// Its kind is [`ErrorKind::Operator`].
+    pub fn error<S: fmt::Display>(&self, message: S) -> Error {
+        Error::new(
+            ErrorKind::Operator {
+                name: self.name.to_owned(),
+            },
+            message.to_string(),
+        )
+    }
+
+    fn property(&self, property: &str) -> Option<&'a Template> {
+        self.properties
+            .iter()
+            .find(|(k, _)| k == property)
+            .map(|(_, t)| t)
+    }
+
+    fn child_context(&self, bindings: &Map<String, SerdeValue>) -> Result<Context<'_>> {
+        let mut context = self.context.child();
+        for (k, v) in bindings {
+            if !is_identifier(k) {
+                return Err(self.error(format!(
+                    "binding {:?} for {} must follow /[a-zA-Z_][a-zA-Z0-9_]*/",
+                    k, self.name
+                )));
+            }
+            context.insert(k.clone(), v.into());
+        }
+        Ok(context)
+    }
+}
+
+/// Convert a rendered value to the output of an operator, with `None` representing the deletion
+/// marker.
+fn to_output(value: Value) -> Result<Option<SerdeValue>> {
+    match value {
+        Value::DeletionMarker => Ok(None),
+        v => Ok(Some((&v).try_into()?)),
+    }
+}
@@ -0,0 +1,162 @@
+//! Rendering independent parts of a template in parallel, with the `parallel` feature.
+//!
+//! The iterations of `$map` and the property values of a plain object do not depend on one
+//! another, so they are rendered on rayon's thread pool.  Results are assembled in their original
+//! order, and when more than one fails, the error is that of the first by index, just as when
+//! rendering one after another.
+//!
+//! Each branch has its own depth, so branches running at once do not count against one
+//! another's depth limit, and each continues from the stack space used where the branches began,
+//! on whichever thread it runs.  Steps and output nodes are counted across all branches, so when
+//! such a limit is exceeded, which branch reports it depends on scheduling.
+
+use crate::errors::Result;
+use crate::interpreter::Context;
+use rayon::prelude::*;
+
+/// Call `f` with each of the given items and its index, in parallel, returning the results in
+/// order, or the error of the first item, by index, for which `f` failed.
+pub(crate) fn map_in_order<T, R, F>(items: &[T], context: &Context, f: F) -> Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(usize, &T, &Context) -> Result<R> + Sync,
+{
+    // the stack used so far is measured here, as each branch may run on another thread
+    let stack_used = context.budget().map(|budget| budget.stack_used());
+    let results: Vec<Result<R>> = items
+        .par_iter()
+        .enumerate()
+        .map(|(i, item)| match context.budget() {
+            Some(budget) => {
+                let budget = budget.fork(stack_used.unwrap_or(0));
+                let mut context = context.child();
+                context.set_budget(&budget);
+                f(i, item, &context)
+            }
+            None => f(i, item, context),
+        })
+        .collect();
+    results.into_iter().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::render::Renderer;
+    use crate::{ErrorKind, Limits, MemoryLoader};
+    use serde_json::json;
+
+    /// Run the given function on a pool of several threads, so that branches run concurrently
+    /// however many CPUs there are
+    fn on_threads<R: Send>(f: impl FnOnce() -> R + Send) -> R {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap()
+            .install(f)
+    }
+
+    #[test]
+    fn send_and_sync() {
+        fn check<T: Send + Sync>() {}
+        check::<Context>();
+        check::<crate::value::Value>();
+        check::<crate::template::Template>();
+    }
+
+    #[test]
+    fn results_in_order() {
+        let items: Vec<usize> = (0..1000).collect();
+        let context = Context::new();
+        let result = map_in_order(&items, &context, |i, item, _| Ok(i + item)).unwrap();
+        assert_eq!(result, (0..1000).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn first_error_by_index() {
+        let items: Vec<usize> = (0..1000).collect();
+        let context = Context::new();
+        for _ in 0..10 {
+            let err = on_threads(|| {
+                map_in_order(&items, &context, |i, _, _| match i % 97 {
+                    50 => Err(interpreter_error!("failed at {}", i)),
+                    _ => Ok(i),
+                })
+            })
+            .unwrap_err();
+            assert_eq!(err.message(), "failed at 50");
+        }
+    }
+
+    #[test]
+    fn render_reports_first_error() {
+        // elements after the 500th fail with a type error, but the 500th fails first
+        let template = json!({
+            "$map": {"$eval": "items"},
+            "each(x)": {"a": {"$eval": "x.a"}, "b": {"$eval": "x.b + 1"}},
+        });
+        let mut items: Vec<_> = (0..1000).map(|_| json!({"a": 0, "b": 0})).collect();
+        items[500] = json!({"b": 0});
+        items[501..].iter_mut().for_each(|i| *i = json!({"a": 0, "b": "x"}));
+        for _ in 0..10 {
+            let err = on_threads(|| Renderer::new().render(&template, &json!({ "items": items })))
+                .unwrap_err();
+            assert_eq!(err.message(), "object has no property a");
+            assert_eq!(err.location().pointer(), "/$map/each(x)/a/$eval");
+        }
+
+        // and likewise within an object, where "b" fails before "c"
+        let template = json!({"a": 1, "b": {"$eval": "nosuch"}, "c": {"$eval": "1 + true"}});
+        let err = Renderer::new().render(&template, &json!({})).unwrap_err();
+        assert_eq!(err.location().pointer(), "/b/$eval");
+    }
+
+    #[test]
+    fn branches_have_their_own_depth() {
+        // rendered one after another, the elements need a depth of 9, which must not be exceeded
+        // when rendering them at once; each builds a large array at its deepest point, so that
+        // the elements overlap
+        let renderer = Renderer::new().limits(Limits::default().max_depth(9));
+        let template = json!({
+            "$map": {"$eval": "range(0, 100)"},
+            "each(x)": {"a": {"b": {"$eval": "len(range(0, 20000)) + x"}}},
+        });
+        let result = on_threads(|| renderer.render(&template, &json!({}))).unwrap();
+        assert_eq!(result.as_array().unwrap().len(), 100);
+
+        // while steps are counted across all branches
+        let renderer = Renderer::new().limits(Limits::default().max_steps(300));
+        let err = on_threads(|| renderer.render(&template, &json!({}))).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::StepLimitExceeded { limit: 300 });
+    }
+
+    #[test]
+    fn branches_continue_stack_used() {
+        // each included template maps over the next within nested `$let`s, so that each branch
+        // starts deeper in the stack than the last; the stack limit is reached rather than
+        // overflowing the stack
+        fn nested(depth: usize, inner: serde_json::Value) -> serde_json::Value {
+            (0..depth).fold(inner, |template, _| {
+                let mut object = serde_json::Map::new();
+                object.insert("$let".into(), json!({}));
+                object.insert("in".into(), template);
+                serde_json::Value::Object(object)
+            })
+        }
+        let mut loader = MemoryLoader::new().template("100", json!(1));
+        for i in 0..100 {
+            let inner = json!({"$map": [1], "each(x)": {"$include": (i + 1).to_string()}});
+            loader = loader.template(&i.to_string(), nested(50, inner));
+        }
+        let renderer = Renderer::new().loader(loader);
+        let err = std::thread::Builder::new()
+            .stack_size(2 << 20)
+            .spawn(move || renderer.render(&json!({"$include": "0"}), &json!({})))
+            .unwrap()
+            .join()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::StackLimitExceeded { limit: 1 << 20 });
+    }
+}
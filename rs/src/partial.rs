@@ -0,0 +1,1913 @@
+//! Partial rendering: rendering a template against an incomplete context, producing a residual
+//! template.  Rendering the residual template with the rest of the context gives the same result
+//! as rendering the original template with the whole context.
+//!
+//! Every part of the template that depends only on known values is rendered.  Operators whose
+//! inputs are known are applied, `$if` and `$switch` with known conditions are reduced to the
+//! chosen branch, and `$map` over a known array is expanded.  Expressions are simplified, with
+//! known context values substituted as literals.  Parts whose result cannot be determined remain
+//! as templates, with their literal text escaped so that it is not interpreted a second time.
+//! Parts that fail to render also remain, as they may be in a branch that is never rendered.
+//!
+//! The current time is never known: `now`, `fromNow`, and `$fromNow` without `from` are left for
+//! the final render.
+
+use crate::analysis::analyze;
+use crate::errors::{Error, Result};
+use crate::interpreter::{self, Context, Node};
+use crate::limits::Budget;
+use crate::number::Number;
+use crate::render::{
+    _render, check_dynamic_key, condition_error, evaluate, interpolate_value, is_identifier,
+    render_operator, sort_operator,
+};
+use crate::template::{Compiler, Expression, Fragment, Interpolation, Operator, Template};
+use crate::value::{Object, Value};
+use serde_json::{Map, Value as SerdeValue};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// The result of partially rendering part of a template
+enum Partial {
+    /// The part renders to this value, whatever the unknown context values are
+    Known(Value),
+    /// The part renders as this template, once the unknown context values are given
+    Residual(SerdeValue),
+}
+
+use Partial::{Known, Residual};
+
+/// The result of partially evaluating an expression
+enum Reduced {
+    /// The expression evaluates to this value
+    Value(Value),
+    /// The expression evaluates as this simplified expression
+    Node(Node),
+}
+
+/// The names bound by `$let` or the `with` of `$include`
+enum Bindings {
+    /// The bindings with known names, split into those with known values and those without
+    Split {
+        known: Vec<(String, Value)>,
+        residual: Map<String, SerdeValue>,
+    },
+    /// Bindings whose names are not all known, as a residual template for the whole object
+    Opaque(SerdeValue),
+}
+
+/// The context values available to part of a template being partially rendered
+struct Scope<'a> {
+    context: &'a Context<'a>,
+    /// The names whose values are not known, in addition to those missing from the context,
+    /// such as those bound by a `$let` to values that are not known
+    unknown: Vec<String>,
+}
+
+impl<'a> Scope<'a> {
+    /// Get the value of the given name, if it is known
+    fn known(&self, name: &str) -> Option<&Value> {
+        if self.unknown.iter().any(|u| u == name) {
+            None
+        } else {
+            self.context.get(name)
+        }
+    }
+
+    /// The unknown names of a scope within this one binding the given names, of which those
+    /// named in `unknown` are not known
+    fn unknown_within(&self, bound: &[&str], unknown: &[&str]) -> Vec<String> {
+        self.unknown
+            .iter()
+            .filter(|u| !bound.contains(&u.as_str()))
+            .cloned()
+            .chain(unknown.iter().map(|u| (*u).to_owned()))
+            .collect()
+    }
+}
+
+/// The environment in which templates are partially rendered
+pub(crate) struct PartialRenderer<'a> {
+    /// The functions and base context available to the template, at the root of every context
+    pub(crate) functions: &'a Context<'a>,
+}
+
+impl<'a> PartialRenderer<'a> {
+    /// Partially render the given template with the known values in the given context.
+    pub(crate) fn render(&self, template: &Template, context: &Context) -> Result<SerdeValue> {
+        let scope = Scope {
+            context,
+            // the current time is not known until the final render
+            unknown: vec!["now".to_owned()],
+        };
+        match self.template(&scope, template)? {
+            // as for a full render, a deletion marker at the top level renders as null
+            Known(Value::DeletionMarker) => Ok(SerdeValue::Null),
+            partial => residual(partial),
+        }
+    }
+
+    /// Whether the given template can be rendered in the given scope, as it depends only on
+    /// known values
+    fn is_known(&self, scope: &Scope, template: &Template) -> bool {
+        // analysis does not report references to functions, which may be shadowed by unknown
+        // values
+        if scope
+            .unknown
+            .iter()
+            .any(|u| self.functions.get(u).is_some())
+        {
+            return false;
+        }
+        let analysis = analyze(template, self.functions);
+        (!analysis.uses_now || scope.known("now").is_some())
+            && !analysis.uses_defined
+            && !analysis.opaque
+            && analysis
+                .free
+                .iter()
+                .all(|v| scope.known(v.name()).is_some())
+    }
+
+    fn template(&self, scope: &Scope, template: &Template) -> Result<Partial> {
+        let _depth = scope.context.enter()?;
+        if self.is_known(scope, template) {
+            match _render(template, scope.context) {
+                Ok(value) => return Ok(Known(value)),
+                Err(e) if e.kind().is_limit_exceeded() => return Err(e),
+                // an array or object leaves only its parts that fail, below
+                Err(_)
+                    if matches!(
+                        template,
+                        Template::Array(_) | Template::Object { .. } | Template::At(..)
+                    ) => {}
+                // as for expressions, the error is left to the final render, as the part may be
+                // in a branch that is never rendered, or if it cannot be, it fails now
+                Err(e) => return self.unrendered(scope, template).map(Residual).ok_or(e),
+            }
+        }
+
+        match template {
+            Template::Literal(v) => Ok(Known(v.clone())),
+            Template::String(_, s) => self.interpolation(scope, s),
+            Template::Array(elements) => {
+                let mut parts = vec![];
+                for (i, e) in elements.iter().enumerate() {
+                    match self.template(scope, e).map_err(|e| e.add_location(i))? {
+                        Known(Value::DeletionMarker) => {}
+                        part => parts.push(part),
+                    }
+                }
+                array(parts)
+            }
+            Template::Object {
+                dynamic_keys,
+                properties,
+            } => {
+                match self.dynamic_keys(scope, dynamic_keys, false) {
+                    Ok(()) => {}
+                    Err(e) if e.kind().is_limit_exceeded() => return Err(e),
+                    Err(e) => return self.unrendered(scope, template).map(Residual).ok_or(e),
+                }
+                let mut parts = vec![];
+                for (raw, k, v) in properties {
+                    let located = |e: Error| e.add_location(raw);
+                    let value = self.template(scope, v).map_err(located)?;
+                    match (self.interpolation(scope, k).map_err(located)?, value) {
+                        (Known(_), Known(Value::DeletionMarker)) => {}
+                        // a key that is not known remains, though its value is deleted, so that
+                        // the final render checks it
+                        (key, value) => parts.push((key, value)),
+                    }
+                }
+                object(parts)
+            }
+            Template::Operator {
+                dynamic_keys,
+                key,
+                operator,
+                ..
+            } => {
+                self.dynamic_keys(scope, dynamic_keys, true)?;
+                match operator {
+                    Ok(operator) => self
+                        .operator(scope, template, key, operator)
+                        .map_err(|e| e.add_location(key)),
+                    Err(e) => Err(e.clone().add_location(key)),
+                }
+            }
+            Template::At(segment, template) => self
+                .template(scope, template)
+                .map_err(|e| e.add_location(segment)),
+            Template::Error(e) => Err(e.clone()),
+        }
+    }
+
+    /// The residual template for a part of the template that fails to render, whose error is
+    /// left to the final render: the part as written, within a `$let` of the known values it
+    /// refers to, if they can be given
+    fn unrendered(&self, scope: &Scope, template: &Template) -> Option<SerdeValue> {
+        let analysis = analyze(template, self.functions);
+        let mut bindings = Map::new();
+        let names = analysis.free.iter().map(|v| v.name());
+        for name in names.chain(Some("now").filter(|_| analysis.uses_now)) {
+            if let Some(value) = scope.known(name) {
+                if !bindings.contains_key(name) {
+                    bindings.insert(name.to_owned(), quote(value).ok()?);
+                }
+            }
+        }
+        let template = source(template).ok()?;
+        residual(with_bindings(bindings, Residual(template)).ok()?).ok()
+    }
+
+    /// Check the keys containing interpolations that appear in an object, or precede an
+    /// operator.  The keys of an object remain in its residual template, to be checked when it is
+    /// rendered, but those preceding an operator do not, so they must be known.
+    fn dynamic_keys(
+        &self,
+        scope: &Scope,
+        dynamic_keys: &[(String, Interpolation)],
+        required: bool,
+    ) -> Result<()> {
+        for (raw, key) in dynamic_keys {
+            match self.interpolation(scope, key) {
+                Ok(Known(Value::String(interpolated))) => check_dynamic_key(raw, &interpolated),
+                Ok(_) if !required => Ok(()),
+                Ok(_) => Err(template_error!(
+                    "cannot partially render key {:?}, as it depends on unknown values",
+                    raw
+                )),
+                Err(e) => Err(e),
+            }
+            .map_err(|e| e.add_location(raw))?;
+        }
+        Ok(())
+    }
+
+    fn interpolation(&self, scope: &Scope, source: &Interpolation) -> Result<Partial> {
+        if let Some(s) = source.as_literal() {
+            return Ok(Known(Value::String(s.into())));
+        }
+
+        // the text rendered so far, and the residual template for the string before it
+        let mut text = String::new();
+        let mut template = String::new();
+        let mut is_residual = false;
+        for fragment in source.fragments() {
+            match fragment {
+                Fragment::Literal(s) => text.push_str(s),
+                Fragment::Expression(code, expr, offset) => {
+                    match self
+                        .reduce_root(scope, code.node())
+                        .map_err(|e| e.add_offset(*offset))?
+                    {
+                        Reduced::Value(v) => interpolate_value(&v, expr, &mut text)
+                            .map_err(|e| e.add_offset(*offset))?,
+                        Reduced::Node(node) => {
+                            push_escaped(&mut template, &text);
+                            text.clear();
+                            template.push_str(&format!("${{{}}}", source_text(&node)?));
+                            is_residual = true;
+                        }
+                    }
+                }
+                Fragment::Error(e) => return Err(e.clone()),
+            }
+            scope.context.check_string(text.len())?;
+        }
+
+        if is_residual {
+            template.push_str(&escape(&text));
+            Ok(Residual(SerdeValue::String(template)))
+        } else {
+            Ok(Known(Value::String(text.into())))
+        }
+    }
+
+    fn operator(
+        &self,
+        scope: &Scope,
+        template: &Template,
+        key: &str,
+        operator: &Operator,
+    ) -> Result<Partial> {
+        match operator {
+            Operator::Eval(expr) => Ok(match self.expression(scope, expr)? {
+                Reduced::Value(v) => Known(v),
+                Reduced::Node(node) => Residual(invocation(
+                    key,
+                    SerdeValue::String(source_text(&node)?),
+                    vec![],
+                )),
+            }),
+            Operator::Flatten(value)
+            | Operator::FlattenDeep(value)
+            | Operator::Json(value)
+            | Operator::Merge(value)
+            | Operator::MergeDeep(value)
+            | Operator::Reverse(value) => match self.template(scope, value)? {
+                Known(v) => {
+                    let value = Template::Literal(v);
+                    let operator = match operator {
+                        Operator::Flatten(_) => Operator::Flatten(value),
+                        Operator::FlattenDeep(_) => Operator::FlattenDeep(value),
+                        Operator::Json(_) => Operator::Json(value),
+                        Operator::Merge(_) => Operator::Merge(value),
+                        Operator::MergeDeep(_) => Operator::MergeDeep(value),
+                        _ => Operator::Reverse(value),
+                    };
+                    Ok(Known(render_operator(&operator, scope.context)?))
+                }
+                partial => Ok(Residual(invocation(key, residual(partial)?, vec![]))),
+            },
+            Operator::FromNow { value, from } => {
+                let value = self.template(scope, value)?;
+                let from = match from {
+                    Some(from) => Some(self.template(scope, from)?),
+                    None => None,
+                };
+                match (value, from) {
+                    (Known(value), Some(Known(from))) => {
+                        let operator = Operator::FromNow {
+                            value: Template::Literal(value),
+                            from: Some(Template::Literal(from)),
+                        };
+                        Ok(Known(render_operator(&operator, scope.context)?))
+                    }
+                    (Known(value), None) if scope.known("now").is_some() => {
+                        let operator = Operator::FromNow {
+                            value: Template::Literal(value),
+                            from: None,
+                        };
+                        Ok(Known(render_operator(&operator, scope.context)?))
+                    }
+                    (value, from) => {
+                        let mut properties = vec![];
+                        if let Some(from) = from {
+                            properties.push(("from", residual(from)?));
+                        }
+                        Ok(Residual(invocation(key, residual(value)?, properties)))
+                    }
+                }
+            }
+            Operator::If {
+                condition,
+                then,
+                else_,
+            } => match self.expression(scope, condition)? {
+                Reduced::Value(condition) => match if condition.into() { then } else { else_ } {
+                    Some(branch) => self.template(scope, branch),
+                    None => Ok(Known(Value::DeletionMarker)),
+                },
+                Reduced::Node(condition) => {
+                    let mut properties = vec![];
+                    if let Some(then) = then {
+                        properties.push(("then", residual(self.template(scope, then)?)?));
+                    }
+                    if let Some(else_) = else_ {
+                        properties.push(("else", residual(self.template(scope, else_)?)?));
+                    }
+                    Ok(Residual(invocation(
+                        key,
+                        SerdeValue::String(source_text(&condition)?),
+                        properties,
+                    )))
+                }
+            },
+            Operator::Include { name, with } => self.include(scope, key, name, with.as_ref()),
+            Operator::Let { bindings, in_ } => {
+                let bindings = self.bindings(
+                    scope,
+                    bindings,
+                    "$let",
+                    "$let value must be an object",
+                    "top level keys of $let must follow /[a-zA-Z_][a-zA-Z0-9_]*/",
+                )?;
+                match bindings {
+                    Bindings::Split { known, residual } => {
+                        let names: Vec<&str> = residual.keys().map(String::as_str).collect();
+                        let partial =
+                            self.bind(scope, known, &names, |scope| self.template(scope, in_))?;
+                        with_bindings(residual, partial)
+                    }
+                    Bindings::Opaque(bindings) => self.opaque(scope, |scope| {
+                        Ok(invocation(
+                            key,
+                            bindings,
+                            vec![("in", residual(self.template(scope, in_)?)?)],
+                        ))
+                    }),
+                }
+            }
+            Operator::Map {
+                value,
+                value_var,
+                index_var,
+                each,
+            } => {
+                let mut vars = vec![value_var.as_str()];
+                vars.extend(index_var.as_deref());
+                match self.template(scope, value)? {
+                    Known(Value::Array(a)) => {
+                        scope.context.check_array(a.len())?;
+                        let mut parts = vec![];
+                        for (i, v) in a.iter().enumerate() {
+                            let mut known = vec![(value_var.clone(), v.clone())];
+                            if let Some(index_var) = index_var {
+                                known.push((index_var.clone(), Value::Number(i.into())));
+                            }
+                            match self
+                                .bind(scope, known, &[], |scope| self.template(scope, each))?
+                            {
+                                Known(Value::DeletionMarker) => {}
+                                part => parts.push(part),
+                            }
+                        }
+                        array(parts)
+                    }
+                    Known(Value::Object(o)) => {
+                        let mut parts = vec![];
+                        for (k, v) in o.iter() {
+                            let known = if let Some(index_var) = index_var {
+                                // if each has two arguments, it gets (val, key)
+                                vec![
+                                    (index_var.clone(), Value::String(k.as_str().into())),
+                                    (value_var.clone(), v.clone()),
+                                ]
+                            } else {
+                                // otherwise, it gets ({val: val, key: key})
+                                let mut arg = Object::new();
+                                arg.insert("key".to_string(), Value::String(k.as_str().into()));
+                                arg.insert("val".to_string(), v.clone());
+                                vec![(value_var.clone(), Value::Object(arg.into()))]
+                            };
+                            match self
+                                .bind(scope, known, &[], |scope| self.template(scope, each))?
+                            {
+                                Known(Value::Object(o)) => parts.push(Known(Value::Object(o))),
+                                Known(v) => {
+                                    return Err(type_error!(
+                                        "$map",
+                                        "object",
+                                        [&v],
+                                        "$map on objects expects each(..) to evaluate to an object"
+                                    ))
+                                }
+                                part => parts.push(part),
+                            }
+                        }
+                        if parts.iter().all(|p| matches!(p, Known(_))) {
+                            let mut result = Object::new();
+                            for part in parts {
+                                if let Known(Value::Object(o)) = part {
+                                    scope.context.add_nodes(o.len())?;
+                                    result.extend(Arc::unwrap_or_clone(o));
+                                }
+                            }
+                            Ok(Known(Value::Object(result.into())))
+                        } else {
+                            let parts = parts.into_iter().map(residual).collect::<Result<_>>()?;
+                            Ok(Residual(invocation(
+                                "$merge",
+                                SerdeValue::Array(parts),
+                                vec![],
+                            )))
+                        }
+                    }
+                    Known(v) => Err(type_error!(
+                        "$map",
+                        "array or object",
+                        [&v],
+                        "$map value must evaluate to an array or object"
+                    )),
+                    value => {
+                        let each =
+                            self.bind(scope, vec![], &vars, |scope| self.template(scope, each))?;
+                        Ok(Residual(invocation(
+                            key,
+                            residual(value)?,
+                            vec![(each_property(&vars).as_str(), residual(each)?)],
+                        )))
+                    }
+                }
+            }
+            Operator::Reduce {
+                value,
+                acc_var,
+                value_var,
+                index_var,
+                each,
+                initial,
+            } => {
+                let mut vars = vec![acc_var.as_str(), value_var.as_str()];
+                vars.extend(index_var.as_deref());
+                let value = match self.template(scope, value)? {
+                    Known(Value::Array(a)) => {
+                        let mut acc = initial.clone();
+                        let mut complete = true;
+                        for (i, v) in a.iter().enumerate() {
+                            let mut known = vec![
+                                (acc_var.clone(), acc.clone()),
+                                (value_var.clone(), v.clone()),
+                            ];
+                            if let Some(index_var) = index_var {
+                                known.push((index_var.clone(), Value::Number(i.into())));
+                            }
+                            match self
+                                .bind(scope, known, &[], |scope| self.template(scope, each))?
+                            {
+                                Known(Value::DeletionMarker) => {}
+                                Known(v) => acc = v,
+                                Residual(_) => {
+                                    complete = false;
+                                    break;
+                                }
+                            }
+                        }
+                        if complete {
+                            return Ok(Known(acc));
+                        }
+                        // the rest of the reduction is left to the final render
+                        quote(&Value::Array(a))?
+                    }
+                    Known(v) => {
+                        return Err(type_error!(
+                            "$reduce",
+                            "array",
+                            [&v],
+                            "$reduce value must evaluate to an array"
+                        ))
+                    }
+                    value => residual(value)?,
+                };
+                let each = self.bind(scope, vec![], &vars, |scope| self.template(scope, each))?;
+                Ok(Residual(invocation(
+                    key,
+                    value,
+                    vec![
+                        ("initial", SerdeValue::try_from(initial)?),
+                        (each_property(&vars).as_str(), residual(each)?),
+                    ],
+                )))
+            }
+            Operator::Find {
+                value,
+                value_var,
+                index_var,
+                each,
+                operators,
+                limits,
+            } => {
+                let mut vars = vec![value_var.as_str()];
+                vars.extend(index_var.as_deref());
+                match self.template(scope, value)? {
+                    Known(Value::Array(a)) => {
+                        // the elements whose conditions are not known, each with its condition
+                        let mut candidates = vec![];
+                        let mut found = Known(Value::DeletionMarker);
+                        for (i, v) in a.iter().enumerate() {
+                            let mut known = vec![(value_var.clone(), v.clone())];
+                            if let Some(index_var) = index_var {
+                                known.push((index_var.clone(), Value::Number(i.into())));
+                            }
+                            let candidate = self.bind(scope, known, &[], |scope| {
+                                let condition = match self.expression(scope, each)? {
+                                    Reduced::Value(ref c) if !bool::from(c) => return Ok(None),
+                                    Reduced::Value(_) => None,
+                                    Reduced::Node(node) => Some(node),
+                                };
+                                // the found value is itself rendered as a template
+                                let budget = Budget::for_compilation(*limits);
+                                let compiler = Compiler {
+                                    budget: scope.context.budget().unwrap_or(&budget),
+                                    operators,
+                                };
+                                let template = Template::compile(v, &compiler);
+                                Ok(Some((condition, self.template(scope, &template)?)))
+                            })?;
+                            match candidate {
+                                None => {}
+                                Some((Some(condition), value)) => {
+                                    candidates.push((condition, value))
+                                }
+                                Some((None, value)) => {
+                                    found = value;
+                                    break;
+                                }
+                            }
+                        }
+                        candidates
+                            .into_iter()
+                            .rev()
+                            .try_fold(found, |found, (condition, value)| {
+                                Ok(Residual(conditional(condition, value, found)?))
+                            })
+                    }
+                    Known(v) => Err(type_error!(
+                        "$find",
+                        "array",
+                        [&v],
+                        "$find value must be an array"
+                    )),
+                    // the found value is rendered as a template, whose references to the context
+                    // are not known
+                    Residual(_) => self.opaque(scope, |scope| {
+                        let each = self.bind(scope, vec![], &vars, |scope| {
+                            self.expression(scope, each)?.into_literal()
+                        })?;
+                        Ok(invocation(
+                            key,
+                            residual(self.template(scope, value)?)?,
+                            vec![(
+                                each_property(&vars).as_str(),
+                                SerdeValue::String(source_text(&each)?),
+                            )],
+                        ))
+                    }),
+                }
+            }
+            Operator::Match(cases) => {
+                let mut parts = vec![];
+                for (condition, value) in cases {
+                    match self.condition(scope, "$match", condition)? {
+                        Reduced::Value(ref c) if !bool::from(c) => {}
+                        Reduced::Value(_) => parts.push((None, self.template(scope, value)?)),
+                        Reduced::Node(condition) => {
+                            parts.push((Some(condition), self.template(scope, value)?))
+                        }
+                    }
+                }
+                scope.context.check_array(parts.len())?;
+                if parts
+                    .iter()
+                    .all(|(c, p)| c.is_none() && matches!(p, Known(_)))
+                {
+                    // unlike other arrays, the result of $match keeps deletion markers
+                    let values = parts
+                        .into_iter()
+                        .filter_map(|(_, p)| match p {
+                            Known(v) => Some(v),
+                            Residual(_) => None,
+                        })
+                        .collect();
+                    return Ok(Known(Value::Array(values)));
+                }
+                // each case becomes an array of its value, or of none, which are concatenated in
+                // order; only $match keeps a deletion marker in an array, so a case that may be
+                // one remains a $match of its own
+                let parts = parts
+                    .into_iter()
+                    .map(|(condition, value)| match (condition, value) {
+                        (None, Known(v)) if !v.is_deletion_marker() => {
+                            Ok(SerdeValue::Array(vec![quote(&v)?]))
+                        }
+                        (condition, value) => {
+                            let condition = match condition {
+                                Some(condition) => source_text(&condition)?,
+                                None => "true".to_owned(),
+                            };
+                            let mut case = Map::new();
+                            case.insert(condition, residual(value)?);
+                            Ok(invocation(key, SerdeValue::Object(case), vec![]))
+                        }
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(Residual(invocation(
+                    "$flatten",
+                    SerdeValue::Array(parts),
+                    vec![],
+                )))
+            }
+            Operator::Switch { cases, default } => {
+                let mut chosen = None;
+                let mut residual_cases = vec![];
+                for (condition, value) in cases {
+                    match self.condition(scope, "$switch", condition)? {
+                        Reduced::Value(ref c) if !bool::from(c) => {}
+                        Reduced::Value(_) => {
+                            if chosen.is_some() {
+                                return Err(operator_error!(
+                                    "$switch",
+                                    "$switch can only have one truthy condition"
+                                ));
+                            }
+                            chosen = Some(value);
+                        }
+                        Reduced::Node(condition) => residual_cases.push((condition, value)),
+                    }
+                }
+
+                if residual_cases.is_empty() {
+                    return match chosen.or(default.as_ref()) {
+                        Some(value) => self.template(scope, value),
+                        None => Ok(Known(Value::DeletionMarker)),
+                    };
+                }
+                let mut conditions = residual_cases
+                    .iter()
+                    .map(|(condition, _)| source_text(condition))
+                    .collect::<Result<Vec<_>>>()?;
+                conditions.extend(chosen.map(|_| "true".to_owned()));
+                let mut distinct = conditions.clone();
+                distinct.sort();
+                distinct.dedup();
+                if distinct.len() < conditions.len() {
+                    // conditions written alike once simplified, such as `x` and `(x)`, cannot be
+                    // separate cases, so the $switch is left as it is written
+                    return self.unrendered(scope, template).map(Residual).ok_or_else(|| {
+                        template_error!(
+                            "cannot partially render $switch, as its conditions are not distinct once simplified"
+                        )
+                    });
+                }
+                let mut cases = Map::new();
+                for ((_, value), condition) in residual_cases.into_iter().zip(conditions) {
+                    cases.insert(condition, residual(self.template(scope, value)?)?);
+                }
+                if let Some(value) = chosen {
+                    cases.insert("true".to_owned(), residual(self.template(scope, value)?)?);
+                }
+                if let Some(default) = default {
+                    cases.insert(
+                        "$default".to_owned(),
+                        residual(self.template(scope, default)?)?,
+                    );
+                }
+                Ok(Residual(invocation(key, SerdeValue::Object(cases), vec![])))
+            }
+            Operator::Sort { value, by } => match self.template(scope, value)? {
+                Known(v) => {
+                    let by_is_known = match by {
+                        Some(Ok((var, expr))) => match expr.node() {
+                            Ok(node) => {
+                                let mut context = scope.context.child();
+                                context.insert(var.as_str(), Value::Null);
+                                let scope = Scope {
+                                    context: &context,
+                                    unknown: scope.unknown_within(&[var], &[]),
+                                };
+                                Knowledge::of(&scope, node).known
+                            }
+                            Err(_) => true,
+                        },
+                        _ => true,
+                    };
+                    if by_is_known {
+                        return Ok(Known(sort_operator(
+                            &Template::Literal(v),
+                            by.as_ref(),
+                            scope.context,
+                        )?));
+                    }
+                    self.sort(scope, key, quote(&v)?, by)
+                }
+                value => self.sort(scope, key, residual(value)?, by),
+            },
+            Operator::Custom {
+                value, properties, ..
+            } => self.opaque(scope, |scope| {
+                let mut rendered = vec![];
+                for (k, template) in properties {
+                    rendered.push((k.as_str(), residual(self.template(scope, template)?)?));
+                }
+                Ok(invocation(
+                    key,
+                    residual(self.template(scope, value)?)?,
+                    rendered,
+                ))
+            }),
+        }
+    }
+
+    fn include(
+        &self,
+        scope: &Scope,
+        key: &str,
+        name_template: &Template,
+        with_template: Option<&Template>,
+    ) -> Result<Partial> {
+        let name = self.template(scope, name_template)?;
+        let with = match with_template {
+            Some(with) => Some(self.bindings(
+                scope,
+                with,
+                "$include",
+                "$include `with` must evaluate to an object",
+                "keys of `with` must follow /[a-zA-Z_][a-zA-Z0-9_]*/",
+            )?),
+            None => None,
+        };
+
+        let name = match name {
+            Known(Value::String(name)) => name,
+            Known(v) => {
+                return Err(type_error!(
+                    "$include",
+                    "string",
+                    [&v],
+                    "$include value must evaluate to a string"
+                ))
+            }
+            // the included template is not known, so neither are the values it refers to
+            Residual(_) => {
+                return self.opaque(scope, |scope| {
+                    let mut properties = vec![];
+                    if let Some(with) = with_template {
+                        properties.push(("with", residual(self.template(scope, with)?)?));
+                    }
+                    Ok(invocation(
+                        key,
+                        residual(self.template(scope, name_template)?)?,
+                        properties,
+                    ))
+                })
+            }
+        };
+
+        let includes = scope.context.includes().ok_or_else(|| {
+            operator_error!("$include", "$include requires a renderer with a loader")
+        })?;
+        includes.check_cycle(&name)?;
+        let template = includes.includes().get(&name)?;
+        let included = includes.push(&name);
+
+        let (known, residual) = match with {
+            None => (vec![], Map::new()),
+            Some(Bindings::Split { known, residual }) => (known, residual),
+            Some(Bindings::Opaque(with)) => {
+                // the included template is inlined, within a $let of the bindings
+                return self.opaque(scope, |scope| {
+                    let mut context = scope.context.child();
+                    context.set_includes(&included);
+                    let scope = Scope {
+                        context: &context,
+                        unknown: scope.unknown.clone(),
+                    };
+                    Ok(invocation(
+                        "$let",
+                        with,
+                        vec![("in", residual(self.template(&scope, &template)?)?)],
+                    ))
+                });
+            }
+        };
+        let mut context = scope.context.child();
+        context.set_includes(&included);
+        let scope = Scope {
+            context: &context,
+            unknown: scope.unknown.clone(),
+        };
+        let names: Vec<&str> = residual.keys().map(String::as_str).collect();
+        let partial = self.bind(&scope, known, &names, |scope| {
+            self.template(scope, &template)
+        })?;
+        with_bindings(residual, partial)
+    }
+
+    /// The residual template for a `$sort` of the given value
+    fn sort(
+        &self,
+        scope: &Scope,
+        key: &str,
+        value: SerdeValue,
+        by: &Option<Result<(String, Expression)>>,
+    ) -> Result<Partial> {
+        match by {
+            Some(Ok((var, expr))) => {
+                let by = self.bind(scope, vec![], &[var], |scope| {
+                    self.expression(scope, expr)?.into_literal()
+                })?;
+                let property = format!("by({})", var);
+                Ok(Residual(invocation(
+                    key,
+                    value,
+                    vec![(&property, SerdeValue::String(source_text(&by)?))],
+                )))
+            }
+            Some(Err(e)) => Err(e.clone()),
+            None => Ok(Residual(invocation(key, value, vec![]))),
+        }
+    }
+
+    /// Partially render a `$let` binding object, or the `with` of `$include`.
+    fn bindings(
+        &self,
+        scope: &Scope,
+        template: &Template,
+        operator: &str,
+        type_message: &str,
+        key_message: &str,
+    ) -> Result<Bindings> {
+        let check_key = |k: &str| {
+            if is_identifier(k) {
+                Ok(())
+            } else {
+                Err(operator_error!(operator, "{}", key_message))
+            }
+        };
+        match template {
+            Template::At(segment, template) => self
+                .bindings(scope, template, operator, type_message, key_message)
+                .map_err(|e| e.add_location(segment)),
+            Template::Object {
+                dynamic_keys,
+                properties,
+            } if !self.is_known(scope, template) => {
+                self.dynamic_keys(scope, dynamic_keys, false)?;
+                let mut known = vec![];
+                let mut residual_values = Map::new();
+                for (raw, k, v) in properties {
+                    let located = |e: Error| e.add_location(raw);
+                    let value = self.template(scope, v).map_err(located)?;
+                    match self.interpolation(scope, k).map_err(located)? {
+                        Known(Value::String(name)) => {
+                            if let Known(Value::DeletionMarker) = value {
+                                continue;
+                            }
+                            check_key(&name)?;
+                            match value {
+                                Known(v) => known.push((name.to_string(), v)),
+                                value => {
+                                    residual_values.insert(name.to_string(), residual(value)?);
+                                }
+                            }
+                        }
+                        _ => {
+                            return Ok(Bindings::Opaque(residual(self.template(scope, template)?)?))
+                        }
+                    }
+                }
+                Ok(Bindings::Split {
+                    known,
+                    residual: residual_values,
+                })
+            }
+            _ => match self.template(scope, template)? {
+                Known(Value::Object(o)) => {
+                    for k in o.keys() {
+                        check_key(k)?;
+                    }
+                    Ok(Bindings::Split {
+                        known: Arc::unwrap_or_clone(o).into_iter().collect(),
+                        residual: Map::new(),
+                    })
+                }
+                Known(v) => Err(type_error!(operator, "object", [&v], "{}", type_message)),
+                partial => Ok(Bindings::Opaque(residual(partial)?)),
+            },
+        }
+    }
+
+    /// Call the given function with a scope within the given one, binding the given known values
+    /// and marking the given names as unknown.
+    fn bind<T, F>(
+        &self,
+        scope: &Scope,
+        known: Vec<(String, Value)>,
+        unknown: &[&str],
+        f: F,
+    ) -> Result<T>
+    where
+        F: FnOnce(&Scope) -> Result<T>,
+    {
+        let mut context = scope.context.child();
+        let mut bound: Vec<&str> = known.iter().map(|(k, _)| k.as_str()).collect();
+        bound.extend(unknown);
+        let unknown = scope.unknown_within(&bound, unknown);
+        for (k, v) in known {
+            context.insert(k, v);
+        }
+        f(&Scope {
+            context: &context,
+            unknown,
+        })
+    }
+
+    /// Partially render part of a template whose references to the context cannot be
+    /// determined, such as a custom operator, which may evaluate expressions of its own, or an
+    /// `$include` of a template that is not known.  The part is rendered with none of the context
+    /// known, and the result is wrapped in a `$let` giving all of the known values.
+    fn opaque<F>(&self, scope: &Scope, f: F) -> Result<Partial>
+    where
+        F: FnOnce(&Scope) -> Result<SerdeValue>,
+    {
+        let mut context = Context::from_value(&Value::Object(Arc::default()), Some(self.functions))?;
+        if let Some(budget) = scope.context.budget() {
+            context.set_budget(budget);
+        }
+        if let Some(includes) = scope.context.includes() {
+            context.set_includes(includes);
+        }
+        let template = f(&Scope {
+            context: &context,
+            unknown: vec!["now".to_owned()],
+        })?;
+
+        let mut bindings = Map::new();
+        for name in scope.context.names_within(self.functions) {
+            match scope.known(name) {
+                // functions bound within the template cannot be given as values, but the
+                // built-in functions remain available
+                Some(Value::Function(_)) | None => {}
+                Some(value) => {
+                    if !bindings.contains_key(name) {
+                        bindings.insert(name.to_owned(), quote(value)?);
+                    }
+                }
+            }
+        }
+        with_bindings(bindings, Residual(template))
+    }
+
+    /// Partially evaluate an expression, as for `$eval` or `$if`.
+    fn expression(&self, scope: &Scope, expression: &Expression) -> Result<Reduced> {
+        let node = match expression.node() {
+            Ok(node) => node,
+            // report the error as a full render would
+            Err(_) => return evaluate(expression, scope.context).map(Reduced::Value),
+        };
+        let knowledge = Knowledge::of(scope, node);
+        if knowledge.known {
+            Ok(Reduced::Value(evaluate(expression, scope.context)?))
+        } else {
+            self.reduce(scope, node, &knowledge)
+        }
+    }
+
+    /// Partially evaluate the condition of a `$match` or `$switch` case.
+    fn condition(&self, scope: &Scope, operator: &str, condition: &Expression) -> Result<Reduced> {
+        match self.expression(scope, condition) {
+            Ok(reduced) => Ok(reduced),
+            Err(e) if e.kind().is_limit_exceeded() => Err(e),
+            Err(_) => Err(condition_error(operator, condition)),
+        }
+    }
+
+    /// Partially evaluate an interpolated expression, whose errors are located by offset.
+    fn reduce_root(&self, scope: &Scope, node: &Node) -> Result<Reduced> {
+        let knowledge = Knowledge::of(scope, node);
+        if knowledge.known {
+            Ok(Reduced::Value(interpreter::evaluate(node, scope.context)?))
+        } else {
+            self.reduce(scope, node, &knowledge)
+        }
+    }
+
+    /// Simplify an expression, evaluating the parts of it that are known.  Errors in those parts
+    /// are left to the final render, as they may be in a branch that is never evaluated.  The
+    /// result refers to no known values, which may not be in the context of the final render.
+    fn reduce(&self, scope: &Scope, node: &Node, knowledge: &Knowledge) -> Result<Reduced> {
+        if knowledge.known {
+            return match interpreter::evaluate(node, scope.context) {
+                Ok(value) => Ok(Reduced::Value(value)),
+                Err(e) if e.kind().is_limit_exceeded() => Err(e),
+                // with the known values written in, the part fails in the same way when it is
+                // evaluated, or if they cannot be, it fails now
+                Err(e) => substitute(scope, node).map(Reduced::Node).ok_or(e),
+            };
+        }
+
+        let operands = &knowledge.operands;
+        let literal = |n: &Node, k: &Knowledge| self.reduce(scope, n, k)?.into_literal();
+        let boxed = |n: &Node, k: &Knowledge| literal(n, k).map(Box::new);
+        let list = |nodes: &[Node], knowledge: &[Knowledge]| {
+            nodes
+                .iter()
+                .zip(knowledge)
+                .map(|(n, k)| literal(n, k))
+                .collect::<Result<_>>()
+        };
+        Ok(Reduced::Node(match node {
+            Node::Array(items) => Node::Array(list(items, operands)?),
+            Node::Object(properties) => Node::Object(
+                properties
+                    .iter()
+                    .zip(operands)
+                    .map(|((k, v), known)| Ok((k.clone(), literal(v, known)?)))
+                    .collect::<Result<_>>()?,
+            ),
+            Node::Un(op, v) => Node::Un(op.clone(), boxed(v, &operands[0])?),
+            Node::Op(l, op, r) => {
+                let left = self.reduce(scope, l, &operands[0])?;
+                // short-circuiting operators may be decided by their left operand alone
+                if let Reduced::Value(v) = &left {
+                    match (op.as_str(), bool::from(v)) {
+                        ("||", true) => return Ok(Reduced::Value(Value::Bool(true))),
+                        ("&&", false) => return Ok(Reduced::Value(Value::Bool(false))),
+                        _ => {}
+                    }
+                }
+                Node::Op(
+                    Box::new(left.into_literal()?),
+                    op.clone(),
+                    boxed(r, &operands[1])?,
+                )
+            }
+            Node::Index(v, i) => Node::Index(boxed(v, &operands[0])?, boxed(i, &operands[1])?),
+            Node::Slice(v, a, b) => {
+                let v = boxed(v, &operands[0])?;
+                // the bounds that are present follow the value
+                let mut bounds = operands[1..].iter();
+                let mut bound = |n: &Option<Box<Node>>| match n {
+                    Some(n) => boxed(n, bounds.next().unwrap()).map(Some),
+                    None => Ok(None),
+                };
+                Node::Slice(v, bound(a)?, bound(b)?)
+            }
+            Node::Dot(v, p) => Node::Dot(boxed(v, &operands[0])?, p.clone()),
+            Node::Func(f, args) => {
+                // a known value is defined whatever the rest of the context is
+                if let (Node::Ident(name), [arg]) = (f.as_ref(), args.as_slice()) {
+                    if name == "defined" {
+                        if let Reduced::Value(Value::String(arg)) =
+                            self.reduce(scope, arg, &operands[1])?
+                        {
+                            if scope.known(&arg).is_some() {
+                                return Ok(Reduced::Value(Value::Bool(true)));
+                            }
+                        }
+                    }
+                }
+                Node::Func(boxed(f, &operands[0])?, list(args, &operands[1..])?)
+            }
+            // anything else is an unknown name
+            _ => node.clone(),
+        }))
+    }
+}
+
+impl Reduced {
+    /// The expression, with a known value given as a literal.  A value that cannot be written as
+    /// one, such as an object with a key containing both kinds of quote, cannot be left for the
+    /// final render.
+    fn into_literal(self) -> Result<Node> {
+        match self {
+            Reduced::Value(value) => literal(&value).ok_or_else(|| {
+                template_error!(
+                    "cannot partially render an expression whose value cannot be written as a literal"
+                )
+            }),
+            Reduced::Node(node) => Ok(node),
+        }
+    }
+}
+
+/// The source text of a residual expression.  Its strings come from the template or are
+/// written by `literal`, so it can always be written.
+fn source_text(node: &Node) -> Result<String> {
+    node.to_source().ok_or_else(|| {
+        template_error!("cannot partially render an expression that cannot be written")
+    })
+}
+
+/// The given expression, which depends only on known values, with those values written in as
+/// literals, if they can be
+fn substitute(scope: &Scope, node: &Node) -> Option<Node> {
+    let sub = |n: &Node| substitute(scope, n);
+    let boxed = |n: &Node| sub(n).map(Box::new);
+    Some(match node {
+        Node::Ident(name) => match scope.known(name) {
+            Some(value) => literal(value)?,
+            None => node.clone(),
+        },
+        Node::Array(items) => Node::Array(items.iter().map(sub).collect::<Option<_>>()?),
+        Node::Object(properties) => Node::Object(
+            properties
+                .iter()
+                .map(|(k, v)| Some((k.clone(), sub(v)?)))
+                .collect::<Option<_>>()?,
+        ),
+        Node::Un(op, v) => Node::Un(op.clone(), boxed(v)?),
+        Node::Op(l, op, r) => Node::Op(boxed(l)?, op.clone(), boxed(r)?),
+        Node::Index(v, i) => Node::Index(boxed(v)?, boxed(i)?),
+        Node::Slice(v, a, b) => Node::Slice(
+            boxed(v)?,
+            match a {
+                Some(a) => Some(boxed(a)?),
+                None => None,
+            },
+            match b {
+                Some(b) => Some(boxed(b)?),
+                None => None,
+            },
+        ),
+        Node::Dot(v, p) => Node::Dot(boxed(v)?, p.clone()),
+        Node::Func(f, args) => Node::Func(boxed(f)?, args.iter().map(sub).collect::<Option<_>>()?),
+        _ => node.clone(),
+    })
+}
+
+/// Whether each part of an expression depends only on known values.  This is found for the
+/// whole expression in a single pass, rather than again for each part as it is simplified.
+struct Knowledge {
+    /// Whether the expression depends only on known values
+    known: bool,
+    /// The knowledge of each operand of the expression, in the order given by
+    /// `Node::for_each_operand`
+    operands: Vec<Knowledge>,
+}
+
+impl Knowledge {
+    fn of(scope: &Scope, node: &Node) -> Knowledge {
+        let mut operands = vec![];
+        node.for_each_operand(|n| operands.push(Knowledge::of(scope, n)));
+        let known = match node {
+            // fromNow depends on the current time, and defined on all of the context
+            Node::Ident(name) if name == "fromNow" => match scope.known(name) {
+                Some(Value::Function(f)) if f.name() == "fromNow" => scope.known("now").is_some(),
+                known => known.is_some(),
+            },
+            Node::Ident(name) if name == "defined" => false,
+            Node::Ident(name) => scope.known(name).is_some(),
+            _ => operands.iter().all(|k| k.known),
+        };
+        Knowledge { known, operands }
+    }
+}
+
+/// The expression literal for the given value, if it can be written as one
+fn literal(value: &Value) -> Option<Node> {
+    Some(match value {
+        Value::Null => Node::Null,
+        Value::Bool(true) => Node::True,
+        Value::Bool(false) => Node::False,
+        Value::Number(n) if n.is_negative() => Node::Un("-".into(), Box::new(number_literal(-*n)?)),
+        Value::Number(n) => number_literal(*n)?,
+        Value::String(s) => string_literal(s),
+        Value::Array(items) => Node::Array(items.iter().map(literal).collect::<Option<_>>()?),
+        Value::Object(o) => Node::Object(
+            o.iter()
+                .map(|(k, v)| {
+                    // keys are written as string literals, which have no escapes
+                    if k.contains('"') && k.contains('\'') {
+                        None
+                    } else {
+                        Some((k.clone(), literal(v)?))
+                    }
+                })
+                .collect::<Option<_>>()?,
+        ),
+        // functions are referred to by name, as they are given to the renderer
+        Value::Function(f) => Node::Ident(f.name().to_owned()),
+        Value::DeletionMarker => return None,
+    })
+}
+
+/// The expression literal for the given string.  Strings have no escapes, so one containing both
+/// kinds of quote is written as a concatenation of literals, split at its double quotes.
+fn string_literal(s: &str) -> Node {
+    if !(s.contains('"') && s.contains('\'')) {
+        return Node::String(s.to_owned());
+    }
+    let mut pieces = vec![];
+    for (i, part) in s.split('"').enumerate() {
+        if i > 0 {
+            pieces.push(Node::String("\"".to_owned()));
+        }
+        if !part.is_empty() {
+            pieces.push(Node::String(part.to_owned()));
+        }
+    }
+    let mut pieces = pieces.into_iter();
+    // there is at least one piece, for the first double quote
+    let first = pieces.next().unwrap();
+    pieces.fold(first, |l, r| {
+        Node::Op(Box::new(l), "+".to_owned(), Box::new(r))
+    })
+}
+
+/// The expression literal for the given non-negative number.  Floats are written with a decimal
+/// point, so that a float with an integer value is not parsed as an integer.
+fn number_literal(n: Number) -> Option<Node> {
+    Some(Node::Number(match n {
+        Number::Integer(i) => i.to_string(),
+        Number::Float(f) if !f.is_finite() => return None,
+        Number::Float(f) => {
+            let s = f.to_string();
+            if s.contains('.') {
+                s
+            } else {
+                format!("{}.0", s)
+            }
+        }
+    }))
+}
+
+/// The template as written, or one that renders as its literal value
+fn source(template: &Template) -> Result<SerdeValue> {
+    Ok(match template {
+        Template::Literal(value) => quote(value)?,
+        Template::String(s, _) => SerdeValue::String(s.to_string()),
+        Template::Array(elements) => {
+            SerdeValue::Array(elements.iter().map(source).collect::<Result<_>>()?)
+        }
+        Template::Object { properties, .. } => SerdeValue::Object(
+            properties
+                .iter()
+                .map(|(raw, _, v)| Ok((raw.clone(), source(v)?)))
+                .collect::<Result<_>>()?,
+        ),
+        Template::Operator { source, .. } => SerdeValue::try_from(&Value::Object(source.clone()))?,
+        Template::At(_, template) => source(template)?,
+        Template::Error(e) => return Err(e.clone()),
+    })
+}
+
+/// The residual template for a partially rendered value
+fn residual(partial: Partial) -> Result<SerdeValue> {
+    match partial {
+        // a deletion marker must remain one, as it may be an array element or object value
+        Known(Value::DeletionMarker) => Ok(invocation("$if", "false".into(), vec![])),
+        Known(value) => quote(&value),
+        Residual(template) => Ok(template),
+    }
+}
+
+/// A template that renders as the given value
+pub(crate) fn quote(value: &Value) -> Result<SerdeValue> {
+    Ok(match value {
+        Value::String(s) => SerdeValue::String(escape(s)),
+        Value::Array(items) => SerdeValue::Array(
+            items
+                .iter()
+                .filter(|v| !v.is_deletion_marker())
+                .map(quote)
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(o) => SerdeValue::Object(
+            o.iter()
+                .filter(|(_, v)| !v.is_deletion_marker())
+                .map(|(k, v)| Ok((escape_key(k), quote(v)?)))
+                .collect::<Result<_>>()?,
+        ),
+        value => SerdeValue::try_from(value)?,
+    })
+}
+
+/// Escape the interpolations in a string, so that it renders as itself
+fn escape(s: &str) -> String {
+    s.replace("${", "$${")
+}
+
+/// Escape a string as an object key, so that it renders as itself and is not an operator
+fn escape_key(k: &str) -> String {
+    let k = escape(k);
+    if k.starts_with('$') {
+        format!("${}", k)
+    } else {
+        k
+    }
+}
+
+/// Append escaped text to an interpolated string, before an interpolation.  A `$` at the end of
+/// the text would escape the interpolation, so trailing `$`s are themselves interpolated.
+fn push_escaped(template: &mut String, text: &str) {
+    let trimmed = text.trim_end_matches('$');
+    template.push_str(&escape(trimmed));
+    if trimmed.len() < text.len() {
+        template.push_str(&format!("${{\"{}\"}}", &text[trimmed.len()..]));
+    }
+}
+
+/// An operator invocation with the given properties
+fn invocation(key: &str, value: SerdeValue, properties: Vec<(&str, SerdeValue)>) -> SerdeValue {
+    let mut object = Map::new();
+    object.insert(key.to_owned(), value);
+    for (k, v) in properties {
+        object.insert(k.to_owned(), v);
+    }
+    SerdeValue::Object(object)
+}
+
+/// The property giving the template for each element in `$map`, `$reduce`, or `$find`
+fn each_property(vars: &[&str]) -> String {
+    format!("each({})", vars.join(","))
+}
+
+/// A `$if` choosing between the given values
+fn conditional(condition: Node, then: Partial, else_: Partial) -> Result<SerdeValue> {
+    let mut properties = vec![("then", residual(then)?)];
+    if !matches!(else_, Known(Value::DeletionMarker)) {
+        properties.push(("else", residual(else_)?));
+    }
+    Ok(invocation(
+        "$if",
+        SerdeValue::String(source_text(&condition)?),
+        properties,
+    ))
+}
+
+/// The given value, within a `$let` of the given bindings if there are any
+fn with_bindings(bindings: Map<String, SerdeValue>, partial: Partial) -> Result<Partial> {
+    if bindings.is_empty() {
+        return Ok(partial);
+    }
+    Ok(Residual(invocation(
+        "$let",
+        SerdeValue::Object(bindings),
+        vec![("in", residual(partial)?)],
+    )))
+}
+
+/// An array of partially rendered elements, from which deletion markers have been removed
+fn array(parts: Vec<Partial>) -> Result<Partial> {
+    if parts.iter().all(|p| matches!(p, Known(_))) {
+        return Ok(Known(Value::Array(
+            parts
+                .into_iter()
+                .filter_map(|p| match p {
+                    Known(v) => Some(v),
+                    Residual(_) => None,
+                })
+                .collect(),
+        )));
+    }
+    Ok(Residual(SerdeValue::Array(
+        parts.into_iter().map(residual).collect::<Result<_>>()?,
+    )))
+}
+
+/// An object of partially rendered keys and values, from which deletion markers have been removed
+/// except those of keys that are not known
+fn object(parts: Vec<(Partial, Partial)>) -> Result<Partial> {
+    if parts
+        .iter()
+        .all(|(k, v)| matches!(k, Known(_)) && matches!(v, Known(_)))
+    {
+        let mut result = Object::new();
+        for (k, v) in parts {
+            if let (Known(Value::String(k)), Known(v)) = (k, v) {
+                result.insert(k.to_string(), v);
+            }
+        }
+        return Ok(Known(Value::Object(result.into())));
+    }
+
+    let mut result = Map::new();
+    for (k, v) in parts {
+        let k = match k {
+            Known(Value::String(k)) => escape_key(&k),
+            Residual(SerdeValue::String(k)) => k,
+            // interpolations always produce strings
+            _ => unreachable!(),
+        };
+        result.insert(k, residual(v)?);
+    }
+    Ok(Residual(SerdeValue::Object(result)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MemoryLoader, Renderer};
+    use serde_json::json;
+
+    /// Check that partially rendering the template with `known`, then rendering the result with
+    /// `rest`, gives the same result as rendering the template with both, returning the residual
+    /// template.
+    fn check(template: SerdeValue, known: SerdeValue, rest: SerdeValue) -> SerdeValue {
+        check_with(&Renderer::new(), template, known, rest)
+    }
+
+    fn check_with(
+        renderer: &Renderer,
+        template: SerdeValue,
+        known: SerdeValue,
+        rest: SerdeValue,
+    ) -> SerdeValue {
+        let mut context = known.as_object().unwrap().clone();
+        context.extend(rest.as_object().unwrap().clone());
+        let expected = renderer.render(&template, &SerdeValue::Object(context));
+
+        let residual = match renderer.partial_render(&template, &known) {
+            Ok(residual) => residual,
+            Err(e) => {
+                assert_eq!(e.to_string(), expected.unwrap_err().to_string());
+                return SerdeValue::Null;
+            }
+        };
+        let actual = renderer.render(&residual, &rest);
+        match (expected, actual) {
+            (Ok(expected), Ok(actual)) => assert_eq!(actual, expected, "residual {}", residual),
+            (Err(expected), Err(actual)) => assert_eq!(actual.to_string(), expected.to_string()),
+            (expected, actual) => panic!(
+                "expected {:?}, got {:?} from {}",
+                expected, actual, residual
+            ),
+        }
+        residual
+    }
+
+    #[test]
+    fn known_values_are_rendered() {
+        let template = json!({"a": "${x}", "b": {"$eval": "[x, x + 1]"}, "$$c": ["$${x}"]});
+        assert_eq!(
+            check(template, json!({"x": 1}), json!({})),
+            json!({"a": "1", "b": [1, 2], "$$c": ["$${x}"]})
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        // floats remain floats, even with integer values
+        assert_eq!(
+            check(
+                json!({"$eval": "a + 1 + c"}),
+                json!({"a": 1e18}),
+                json!({"c": 1})
+            ),
+            json!({"$eval": "1000000000000000000.0 + c"})
+        );
+        check(json!({"$eval": "[a, b, c]"}), json!({"a": 2.0, "b": -0.5}), json!({"c": 1}));
+        check(
+            json!({"$eval": "[a, b, c]"}),
+            json!({"a": 18446744073709551615u64, "b": i64::MIN}),
+            json!({"c": 1}),
+        );
+    }
+
+    #[test]
+    fn unknown_values_are_left() {
+        assert_eq!(
+            check(json!("${x}-${y}-$${z}"), json!({"x": 1}), json!({"y": 2})),
+            json!("1-${y}-$${z}")
+        );
+        assert_eq!(
+            check(json!("${x}${y}"), json!({"x": "$"}), json!({"y": 2})),
+            json!("${\"$\"}${y}")
+        );
+        assert_eq!(
+            check(
+                json!({"$eval": "x + y * 2"}),
+                json!({"y": 3}),
+                json!({"x": 1})
+            ),
+            json!({"$eval": "x + 6"})
+        );
+        assert_eq!(
+            check(
+                json!({"$eval": "a.b[x]"}),
+                json!({"a": {"b": [-1, "x"]}}),
+                json!({"x": 0})
+            ),
+            json!({"$eval": "[-1, \"x\"][x]"})
+        );
+        assert_eq!(
+            check(
+                json!({"$eval": "s + x"}),
+                json!({"s": "it's \"quoted\""}),
+                json!({"x": "!"})
+            ),
+            json!({"$eval": "\"it's \" + '\"' + \"quoted\" + '\"' + x"})
+        );
+        assert_eq!(
+            check(
+                json!({"$eval": "x + s"}),
+                json!({"s": "it's \"quoted\""}),
+                json!({"x": "!"})
+            ),
+            json!({"$eval": "x + (\"it's \" + '\"' + \"quoted\" + '\"')"})
+        );
+    }
+
+    #[test]
+    fn conditions() {
+        let template = json!({"$if": "prod", "then": {"replicas": "${n}"}, "else": "${x}"});
+        assert_eq!(
+            check(template.clone(), json!({"prod": true}), json!({"n": 3})),
+            json!({"replicas": "${n}"})
+        );
+        assert_eq!(
+            check(template, json!({"x": 1}), json!({"prod": false, "n": 3})),
+            json!({"$if": "prod", "then": {"replicas": "${n}"}, "else": "1"})
+        );
+        assert_eq!(
+            check(
+                json!({"$eval": "a && b || c"}),
+                json!({"a": false}),
+                json!({"b": 1, "c": 2})
+            ),
+            json!({"$eval": "false || c"})
+        );
+        assert_eq!(
+            check(
+                json!([{"$if": "a", "then": 1}, 2]),
+                json!({"a": false}),
+                json!({})
+            ),
+            json!([2])
+        );
+    }
+
+    #[test]
+    fn switch_and_match() {
+        let template = json!({
+            "$switch": {"a": "${x}", "b": 2, "c": 3, "$default": 4},
+        });
+        assert_eq!(
+            check(
+                template.clone(),
+                json!({"a": false, "b": true}),
+                json!({"c": false})
+            ),
+            json!({"$switch": {"c": 3, "true": 2, "$default": 4}})
+        );
+        assert_eq!(
+            check(
+                template.clone(),
+                json!({"a": false, "b": false, "c": false}),
+                json!({})
+            ),
+            json!(4)
+        );
+        check(
+            template.clone(),
+            json!({"b": true, "c": true}),
+            json!({"a": false}),
+        );
+        check(template, json!({"b": true}), json!({"a": false, "c": true}));
+
+        // conditions that are alike once simplified are left as they are written
+        let template = json!({"$switch": {"x": 1, "(x)": 2}});
+        assert_eq!(
+            check(template.clone(), json!({}), json!({"x": true})),
+            template
+        );
+        let template = json!({"$switch": {"x": 1, "(x)": 2, "y && x": 3}});
+        assert_eq!(
+            check(template.clone(), json!({"y": false}), json!({"x": false})),
+            json!({"$let": {"y": false}, "in": template})
+        );
+
+        let template = json!({"$match": {"a": 1, "b": 2}});
+        assert_eq!(
+            check(template.clone(), json!({"a": true}), json!({"b": true})),
+            json!({"$flatten": [[1], {"$match": {"b": 2}}]})
+        );
+        check(template, json!({"a": true}), json!({"b": false}));
+
+        // deletion markers are kept, whether or not their case is known
+        let template = json!({"$match": {"x": 1, "y": {"$if": "false"}, "z": {"$if": "w"}}});
+        assert_eq!(
+            check(
+                template.clone(),
+                json!({"y": true}),
+                json!({"x": true, "z": true, "w": false})
+            ),
+            json!({"$flatten": [{"$match": {"x": 1}}, {"$match": {"true": {"$if": "false"}}}, {"$match": {"z": {"$if": "w"}}}]})
+        );
+        check(
+            template,
+            json!({"x": true, "y": true, "z": true}),
+            json!({"w": false}),
+        );
+    }
+
+    #[test]
+    fn bindings() {
+        let template = json!({"$let": {"a": "${x}", "b": "${y}"}, "in": "${a}${b}${c}"});
+        assert_eq!(
+            check(template.clone(), json!({"x": 1, "c": 3}), json!({"y": 2})),
+            json!({"$let": {"b": "${y}"}, "in": "1${b}3"})
+        );
+        check(
+            template,
+            json!({"y": 1, "b": "outer"}),
+            json!({"x": 2, "c": 3}),
+        );
+
+        // a name bound to an unknown value shadows a known one
+        check(
+            json!({"$let": {"x": "${y}"}, "in": "${x}"}),
+            json!({"x": "outer"}),
+            json!({"y": "inner"}),
+        );
+        // keys that are not known remain, even with deleted values, to be checked
+        for k in ["b", "$b"] {
+            check(
+                json!({"${k}": {"$if": "false"}, "a": "${x}"}),
+                json!({"x": 1}),
+                json!({ "k": k }),
+            );
+            check(
+                json!({"$let": {"${k}": {"$if": "false"}, "a": "${x}"}, "in": "${a}"}),
+                json!({}),
+                json!({"k": k, "x": 1}),
+            );
+        }
+        // keys that are not known make the bindings opaque
+        assert_eq!(
+            check(
+                json!({"$let": {"${k}": 1}, "in": "${v}-${a}"}),
+                json!({"v": 2}),
+                json!({"k": "a"})
+            ),
+            json!({"$let": {"v": 2}, "in": {"$let": {"${k}": 1}, "in": "${v}-${a}"}})
+        );
+    }
+
+    #[test]
+    fn map_and_reduce() {
+        let template = json!({"$map": {"$eval": "items"}, "each(x,i)": "${x}${i}${y}"});
+        assert_eq!(
+            check(
+                template.clone(),
+                json!({"items": ["a", "b"]}),
+                json!({"y": "!"})
+            ),
+            json!(["a0${y}", "b1${y}"])
+        );
+        assert_eq!(
+            check(template, json!({"y": "!"}), json!({"items": ["a"]})),
+            json!({"$map": {"$eval": "items"}, "each(x,i)": "${x}${i}!"})
+        );
+        check(
+            json!({"$map": {"a": 1, "b": 2}, "each(y)": {"${y.key}": "${y.val + z}"}}),
+            json!({}),
+            json!({"z": 1}),
+        );
+
+        let template = json!({"$reduce": {"$eval": "items"}, "initial": 0, "each(acc,x)": {"$eval": "acc + x * y"}});
+        assert_eq!(
+            check(
+                template.clone(),
+                json!({"items": [1, 2], "y": 2}),
+                json!({})
+            ),
+            json!(6)
+        );
+        check(template.clone(), json!({"items": [1, 2]}), json!({"y": 2}));
+        check(template, json!({"y": 2}), json!({"items": [1, 2]}));
+    }
+
+    #[test]
+    fn other_operators() {
+        check(
+            json!({"$find": [{"a": "${x}"}, 2, 3], "each(v,i)": "i > n"}),
+            json!({"x": 1}),
+            json!({"n": 0}),
+        );
+        check(
+            json!({"$sort": {"$eval": "items"}, "by(v)": "v.n * d"}),
+            json!({"items": [{"n": 1}, {"n": 2}]}),
+            json!({"d": -1}),
+        );
+        check(
+            json!({"$merge": [{"a": 1}, {"$eval": "b"}]}),
+            json!({}),
+            json!({"b": {"b": 2}}),
+        );
+        assert_eq!(
+            check(
+                json!({"$flatten": [[1], ["${x}"]]}),
+                json!({"x": 2}),
+                json!({})
+            ),
+            json!([1, "2"])
+        );
+        check(
+            json!({"$fromNow": "${d}", "from": "2020-01-01T00:00:00.000Z"}),
+            json!({}),
+            json!({"d": "1 day"}),
+        );
+    }
+
+    #[test]
+    fn now_is_unknown() {
+        let renderer = Renderer::new();
+        assert_eq!(
+            renderer
+                .partial_render(&json!(["${now}", {"$fromNow": "1 day"}]), &json!({}))
+                .unwrap(),
+            json!(["${now}", {"$fromNow": "1 day"}])
+        );
+        assert_eq!(
+            renderer
+                .partial_render(
+                    &json!({"$let": {"now": "2020-01-01T00:00:00.000Z"}, "in": {"$fromNow": "1 day"}}),
+                    &json!({})
+                )
+                .unwrap(),
+            json!("2020-01-02T00:00:00.000Z")
+        );
+    }
+
+    #[test]
+    fn defined() {
+        let template = json!({"$if": "defined('x') && defined('y')", "then": 1, "else": 2});
+        assert_eq!(
+            check(template.clone(), json!({"x": 1}), json!({"y": 2})),
+            json!({"$if": "true && defined(\"y\")", "then": 1, "else": 2})
+        );
+        check(template, json!({"x": 1}), json!({}));
+    }
+
+    #[test]
+    fn includes() {
+        let loader = MemoryLoader::new()
+            .template("greeting", json!("${greeting}, ${name}"))
+            .template("loop", json!({"$include": "loop"}));
+        let renderer = Renderer::new().loader(loader);
+        assert_eq!(
+            check_with(
+                &renderer,
+                json!({"$include": "greeting", "with": {"name": "${n}"}}),
+                json!({"greeting": "hello"}),
+                json!({"n": "world"}),
+            ),
+            json!({"$let": {"name": "${n}"}, "in": "hello, ${name}"})
+        );
+        check_with(
+            &renderer,
+            json!({"$include": "${t}"}),
+            json!({"greeting": "hi", "name": "you"}),
+            json!({"t": "greeting"}),
+        );
+        assert!(renderer
+            .partial_render(&json!({"$include": "loop"}), &json!({}))
+            .is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let renderer = Renderer::new();
+        // errors in parts of the template that might not be rendered are left for the final
+        // render, as are those in expressions
+        for q in [false, true] {
+            check(
+                json!({"$if": "q", "then": {"$eval": "1/0"}}),
+                json!({}),
+                json!({ "q": q }),
+            );
+            check(
+                json!({"$if": "q", "then": [1, {"$reverse": "s"}, {"${'$k'}": 1, "a": "${x}"}]}),
+                json!({"x": 1}),
+                json!({ "q": q }),
+            );
+        }
+        assert_eq!(
+            check(
+                json!([1, {"$reverse": "s"}, {"${'$k'}": 1}, {"a": "${x}", "b": {"$eval": "1/0"}}]),
+                json!({"x": 1}),
+                json!({}),
+            ),
+            json!([1, {"$reverse": "s"}, {"${'$k'}": 1}, {"a": "1", "b": {"$eval": "1/0"}}])
+        );
+        // along with the known values they refer to
+        let residual = renderer
+            .partial_render(&json!({"a": [{"$eval": "x + 1"}]}), &json!({"x": "s"}))
+            .unwrap();
+        assert_eq!(
+            residual,
+            json!({"a": [{"$let": {"x": "s"}, "in": {"$eval": "x + 1"}}]})
+        );
+        let err = renderer.render(&residual, &json!({})).unwrap_err();
+        assert_eq!(err.location().pointer(), "/a/0/$let/in/$eval");
+
+        // errors in expressions that might not be evaluated are left for the final render
+        check(
+            json!({"$eval": "a && x.y"}),
+            json!({"x": 1}),
+            json!({"a": false}),
+        );
+        // where they are reported as in a full render, with the known values written in
+        assert_eq!(
+            check(
+                json!({"$eval": "typeof(c) + str(a)"}),
+                json!({"a": [1, {"k": null}]}),
+                json!({"c": 1}),
+            ),
+            json!({"$eval": "typeof(c) + str([1, {\"k\": null}])"})
+        );
+        assert_eq!(
+            check(
+                json!({"$let": {"x": [1]}, "in": {"$eval": "c && x.y"}}),
+                json!({}),
+                json!({"c": false}),
+            ),
+            json!({"$eval": "c && [1].y"})
+        );
+
+        // known values that cannot be written in cannot be left for the final render
+        let err = renderer
+            .partial_render(&json!({"$eval": "c + x"}), &json!({"x": {"'\"": 1}}))
+            .unwrap_err();
+        assert_eq!(err.location().pointer(), "/$eval");
+        assert!(err.message().starts_with("cannot partially render"));
+    }
+
+    #[test]
+    fn quoting() {
+        let value: Value = (&json!({"$a": ["${b}", "$${c}", 1.5, null], "${d}": true})).into();
+        let quoted = quote(&value).unwrap();
+        assert_eq!(
+            quoted,
+            json!({"$$a": ["$${b}", "$$${c}", 1.5, null], "$$${d}": true})
+        );
+        assert_eq!(
+            Renderer::new().render(&quoted, &json!({})).unwrap(),
+            SerdeValue::try_from(value).unwrap()
+        );
+    }
+}
@@ -1,10 +1,19 @@
 #![allow(unused_variables)]
+use crate::analysis::{analyze, FreeVariable};
 use crate::builtins::BUILTINS;
-use crate::fromnow::{from_now, now};
-use crate::interpreter::{self, Context};
-use crate::op_props::{parse_by, parse_each, parse_each_three};
-use crate::value::{Object, Value};
-use anyhow::{bail, Result};
+use crate::errors::{json_pointer, Error, ErrorKind, Result};
+use crate::expression::ParsedExpression;
+use crate::fromnow::{format_time, from_now, Clock, DefaultClock};
+#[cfg(feature = "incremental")]
+use crate::incremental::IncrementalRender;
+use crate::include::{IncludeStack, Includes, Loader};
+use crate::interpreter::{Context, Reads};
+use crate::limits::{value_depth, Budget, Limits};
+use crate::operators::{CustomOperator, Invocation, Operators, RegisteredOperator};
+use crate::partial::PartialRenderer;
+use crate::stream;
+use crate::template::{Compiler, Expression, Fragment, Interpolation, Operator, Template};
+use crate::value::{Function, Object, Value};
 use nom::{
     branch::alt,
     bytes::complete::tag,
@@ -13,304 +22,894 @@ use nom::{
     multi::many0,
     sequence::pair,
 };
+use serde::de::DeserializeOwned;
+use serde::{Serialize, Serializer};
+use serde_path_to_error::Segment;
 use serde_json::Value as SerdeValue;
 use std::borrow::Cow;
-use std::convert::TryInto;
-use std::fmt::Write;
+use std::convert::{TryFrom, TryInto};
+use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
 
 /// Render the given JSON-e template with the given context.
-pub fn render(template: &SerdeValue, context: &SerdeValue) -> Result<SerdeValue> {
-    let template: Value = template.into();
-    let context = Context::from_serde_value(context, Some(&BUILTINS))?;
+pub fn render(template: &SerdeValue, context: &SerdeValue) -> Result<SerdeValue, Error> {
+    Renderer::new().render(template, context)
+}
 
-    // set "now" in context to a single current time for the duration of the render
-    let mut context = context.child();
-    context.insert("now", Value::String(now()));
+/// Render the given JSON-e template with the given context, writing the result to the given
+/// writer as compact JSON as it is produced.  See [`CompiledTemplate::render_to_serializer`].
+pub fn render_to_writer<W: io::Write>(
+    template: &SerdeValue,
+    context: &SerdeValue,
+    writer: W,
+) -> Result<(), Error> {
+    Renderer::new().render_to_writer(template, context, writer)
+}
 
-    match _render(&template, &context) {
-        // note that this will convert DeletionMarker into Null
-        Ok(v) => Ok(v.try_into()?),
-        Err(e) => Err(e),
-    }
+/// A Renderer renders JSON-e templates, making available any custom functions and operators that
+/// have been added to it in addition to the built-ins, along with its base context.
+///
+/// A renderer is `Send + Sync`, and cloning it is cheap, as its clones share its functions,
+/// operators, base context, and loaded templates.  It can be built once and then used for every
+/// render, from any number of threads.
+#[derive(Clone)]
+pub struct Renderer {
+    functions: Arc<Context<'static>>,
+    /// Values available to every render, behind the values of the context given to the render
+    base: Option<Arc<Object>>,
+    /// The nesting depth of the base context, which is checked against the limits of each
+    /// render, as they may be changed after the base context is given
+    base_depth: usize,
+    operators: Arc<Operators>,
+    limits: Limits,
+    clock: Arc<dyn Clock>,
+    includes: Option<Arc<Includes>>,
 }
 
-/// Inner, recursive render function.
-fn _render(template: &Value, context: &Context) -> Result<Value> {
-    /// render a value, shaping the result such that it can be used with
-    /// `.filter_map(..).colect::<Result<_>>`.
-    fn render_or_deletion_marker(v: &Value, context: &Context) -> Option<Result<Value>> {
-        match _render(v, context) {
-            Ok(Value::DeletionMarker) => None,
-            Ok(rendered) => Some(Ok(rendered)),
-            Err(e) => Some(Err(e)),
+impl Default for Renderer {
+    fn default() -> Self {
+        Renderer {
+            functions: Arc::new(BUILTINS.child()),
+            base: None,
+            base_depth: 0,
+            operators: Arc::default(),
+            limits: Limits::default(),
+            clock: Arc::new(DefaultClock),
+            includes: None,
         }
     }
+}
 
-    Ok(match template {
-        Value::Number(_) | Value::Bool(_) | Value::Null => (*template).clone(),
-        Value::String(s) => Value::String(interpolate(s, context)?),
-        Value::Array(elements) => Value::Array(
-            elements
+impl Renderer {
+    /// Create a new Renderer with only the built-in functions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a function that templates can call by the given name, just like a built-in.  The
+    /// function is called with the evaluated arguments as JSON values.  A function with the same
+    /// name as a built-in replaces it, and a context value with the same name as the function
+    /// takes precedence over it.
+    ///
+    /// An error returned from the function is reported as an [`ErrorKind::Function`] error, with
+    /// the error's string form as its message.
+    ///
+    /// # Panics
+    ///
+    /// If `name` is not a valid identifier (`/[a-zA-Z_][a-zA-Z0-9_]*/`).
+    pub fn function<F>(mut self, name: &str, f: F) -> Self
+    where
+        F: Fn(&[SerdeValue]) -> Result<SerdeValue, Box<dyn std::error::Error + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        assert!(
+            is_identifier(name),
+            "function name {:?} is not a valid identifier",
+            name
+        );
+        let function_name = name.to_owned();
+        let function = Function::new(name.to_owned(), move |_: &Context, args: &[Value]| {
+            let args = args
                 .iter()
-                .filter_map(|e| render_or_deletion_marker(e, context))
-                .collect::<Result<Vec<Value>>>()?,
-        ),
-        Value::Object(o) => {
-            // first, see if this is a operator invocation
-            for (k, v) in o.iter() {
-                // apply interpolation to key
-                // this allows keys that start with an interpolation to work
-                let interpolated = interpolate(k, context)?;
-                let mut chars = interpolated.chars();
-                if chars.next() == Some('$') && chars.next() != Some('$') {
-                    if let Some(rendered) = maybe_operator(k, v, o, context)? {
-                        return Ok(rendered);
-                    }
-                }
+                .map(SerdeValue::try_from)
+                .collect::<Result<Vec<_>>>()?;
+            match f(&args) {
+                Ok(v) => Ok(v.into()),
+                Err(e) => Err(Error::new(
+                    ErrorKind::Function {
+                        name: function_name.clone(),
+                    },
+                    e.to_string(),
+                )),
             }
+        });
+        Arc::make_mut(&mut self.functions).insert(name.to_owned(), Value::Function(function));
+        self
+    }
 
-            // apparently not, so recursively render the content
-            let mut result = Object::new();
-            for (k, v) in o.iter() {
-                // un-escape escaped operators
-                let k = if k.starts_with("$$") { &k[1..] } else { &k[..] };
-                match _render(v, context)? {
-                    Value::DeletionMarker => {}
-                    v => {
-                        result.insert(interpolate(k, context)?, v);
-                    }
-                };
-            }
-            Value::Object(result)
+    /// Make the properties of the given object available to every render, as if they were part
+    /// of the context given to each, such as defaults shared by all renders.  A value in the
+    /// context given to a render takes precedence over a value of the same name in the base
+    /// context, which in turn takes precedence over a function of that name.  This replaces any
+    /// existing base context.
+    ///
+    /// The base context is converted once, and shared by all renders with this renderer and its
+    /// clones rather than copied into each.  Like the context given to a render, it must be an
+    /// object whose keys are identifiers, or this fails with [`ErrorKind::InvalidContext`].
+    ///
+    /// ```
+    /// # use json_e::Renderer;
+    /// # use serde_json::json;
+    /// let renderer = Renderer::new()
+    ///     .base_context(&json!({"region": "us-east-1", "replicas": 1}))
+    ///     .unwrap();
+    /// let template = json!({"region": "${region}", "replicas": {"$eval": "replicas"}});
+    /// assert_eq!(
+    ///     renderer.render(&template, &json!({"replicas": 3})).unwrap(),
+    ///     json!({"region": "us-east-1", "replicas": 3}),
+    /// );
+    /// ```
+    pub fn base_context(mut self, context: &SerdeValue) -> Result<Self, Error> {
+        let depth = value_depth(context);
+        Budget::new(self.limits).check_context_nesting(depth)?;
+        self.base = Some(Context::object_of(&context.into())?);
+        self.base_depth = depth;
+        Ok(self)
+    }
+
+    /// Check that the given context, and the base context, are not nested more deeply than the
+    /// given budget allows.
+    fn check_depth(&self, budget: &Budget, context: &SerdeValue) -> Result<()> {
+        budget.check_context_nesting(self.base_depth)?;
+        budget.check_context_depth(context)
+    }
+
+    /// Call the given function with the context on which the context of each render is layered:
+    /// this renderer's base context, if any, in front of its functions.
+    fn with_root<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&Context) -> T,
+    {
+        let mut root = self.functions.child();
+        if let Some(base) = &self.base {
+            root.bind_object(base.clone());
         }
+        f(&root)
+    }
 
-        // `template` has been converted from JSON and cannot contain DeletionMarker or Function
-        Value::DeletionMarker | Value::Function(_) => unreachable!(),
-    })
+    /// Add an operator that templates can invoke by the given name, just like a built-in operator
+    /// such as `$let`.  An operator with the same name as a built-in replaces it.  See
+    /// [`CustomOperator`] for details.
+    ///
+    /// # Panics
+    ///
+    /// If `name` is not `$` followed by a valid identifier (`/[a-zA-Z_][a-zA-Z0-9_]*/`).
+    pub fn operator<O: CustomOperator + 'static>(mut self, name: &str, operator: O) -> Self {
+        assert!(
+            name.starts_with('$') && is_identifier(&name[1..]),
+            "operator name {:?} is not `$` followed by a valid identifier",
+            name
+        );
+        Arc::make_mut(&mut self.operators)
+            .insert(name.to_owned(), RegisteredOperator(Arc::new(operator)));
+        self.reset_includes();
+        self
+    }
+
+    /// Limit the resources used by each render, such as when rendering untrusted templates.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self.reset_includes();
+        self
+    }
+
+    /// Load the templates named by `$include` with the given loader, replacing any existing
+    /// loader.  Each template is loaded and compiled once, when it is first included, and then
+    /// cached for use by all renders with this renderer and its clones.
+    pub fn loader<L: Loader + 'static>(mut self, loader: L) -> Self {
+        self.includes = Some(Arc::new(Includes::new(
+            Arc::new(loader),
+            self.operators.clone(),
+            self.limits,
+        )));
+        self
+    }
+
+    /// Discard any cached included templates, which were compiled with the operators and limits
+    /// that have since changed.
+    fn reset_includes(&mut self) {
+        if let Some(includes) = &self.includes {
+            self.includes = Some(Arc::new(Includes::new(
+                includes.loader().clone(),
+                self.operators.clone(),
+                self.limits,
+            )));
+        }
+    }
+
+    /// Use the given clock for the current time in each render, in place of the system clock.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Use the given time as the current time in every render.
+    pub fn now(self, now: SystemTime) -> Self {
+        self.clock(move || now)
+    }
+
+    /// Compile the given JSON-e template, so that it can be rendered repeatedly without parsing
+    /// it again.  Compilation never fails: errors in the template are reported when the part of
+    /// the template containing them is rendered, just as for [`Renderer::render`].
+    pub fn compile(&self, template: &SerdeValue) -> CompiledTemplate {
+        CompiledTemplate {
+            renderer: self.clone(),
+            template: Template::compile_root(template, self.limits, &self.operators),
+        }
+    }
+
+    /// Render the given JSON-e template with the given context.
+    pub fn render(&self, template: &SerdeValue, context: &SerdeValue) -> Result<SerdeValue, Error> {
+        self.compile(template).render(context)
+    }
+
+    /// Render the given JSON-e template with a context of any serializable type, deserializing the
+    /// result into the requested type.  See [`CompiledTemplate::render_as`].
+    pub fn render_as<T, U>(&self, template: &SerdeValue, context: &T) -> Result<U, Error>
+    where
+        T: Serialize + ?Sized,
+        U: DeserializeOwned,
+    {
+        self.compile(template).render_as(context)
+    }
+
+    /// Render the given JSON-e template with the given context, writing the result to the given
+    /// writer as compact JSON as it is produced.  See [`CompiledTemplate::render_to_serializer`].
+    pub fn render_to_writer<W: io::Write>(
+        &self,
+        template: &SerdeValue,
+        context: &SerdeValue,
+        writer: W,
+    ) -> Result<(), Error> {
+        self.compile(template).render_to_writer(context, writer)
+    }
+
+    /// Evaluate the given expression with the given context, which must be an object, just as
+    /// `$eval` would in a template rendered by this renderer: with its functions and limits, and
+    /// with `now` given by its clock.
+    pub fn evaluate(
+        &self,
+        expression: &ParsedExpression,
+        context: &SerdeValue,
+    ) -> Result<SerdeValue, Error> {
+        let budget = Budget::new(self.limits);
+        self.check_depth(&budget, context)?;
+        self.with_root(|root| {
+            let context = Context::from_serde_value(context, Some(root))?;
+
+            let mut context = context.child();
+            context.set_budget(&budget);
+            context.insert("now", Value::String(format_time(self.clock.now()).into()));
+
+            let value =
+                expression.code().evaluate(&context).map_err(|e| e.add_offset(0))?;
+            value.try_into()
+        })
+    }
+
+    /// Partially render the given JSON-e template with the given incomplete context, producing a
+    /// residual template.  See [`CompiledTemplate::partial_render`].
+    pub fn partial_render(
+        &self,
+        template: &SerdeValue,
+        context: &SerdeValue,
+    ) -> Result<SerdeValue, Error> {
+        self.compile(template).partial_render(context)
+    }
 }
 
-/// Perform string interpolation on the given string.
-fn interpolate(mut source: &str, context: &Context) -> Result<String> {
-    // shortcut the common no-interpolation case
-    if source.find('$').is_none() {
-        return Ok(source.into());
+/// A CompiledTemplate is a JSON-e template that has been parsed by [`Renderer::compile`], and
+/// can be rendered any number of times with different contexts.
+pub struct CompiledTemplate {
+    renderer: Renderer,
+    template: Template,
+}
+
+impl CompiledTemplate {
+    /// Render this template with the given context, at the current time given by the
+    /// renderer's clock.
+    pub fn render(&self, context: &SerdeValue) -> Result<SerdeValue, Error> {
+        self.render_at(context, self.renderer.clock.now())
     }
 
-    let mut result = String::new();
+    /// Find the context values that this template may refer to: the names in its expressions
+    /// that are not bound by an operator within the template, such as `$let` or `each(..)`, and
+    /// are not functions or `now`.  Each reference is returned along with its location, in the
+    /// order in which it appears in the template.
+    ///
+    /// This is necessarily approximate: names bound by `$let` with keys containing
+    /// interpolations are not known, nor are the contents of templates given to `$include` or
+    /// found by `$find`, nor the names given to `defined` other than as a string literal.  Parts
+    /// of the template that fail to compile are skipped.
+    ///
+    /// ```
+    /// # use json_e::Renderer;
+    /// # use serde_json::json;
+    /// let template = json!({"$map": {"$eval": "items"}, "each(x)": "${x}-${suffix}"});
+    /// let free = Renderer::new().compile(&template).free_variables();
+    /// let names: Vec<_> = free.iter().map(|v| v.name()).collect();
+    /// assert_eq!(names, vec!["items", "suffix"]);
+    /// assert_eq!(free[1].location().pointer(), "/$map/each(x)");
+    /// ```
+    pub fn free_variables(&self) -> Vec<FreeVariable> {
+        self.renderer
+            .with_root(|root| analyze(&self.template, root).free)
+    }
 
-    while !source.is_empty() {
-        if let Some(offset) = source.find('$') {
-            // If this is an un-escaped `${`, interpolate..
-            if let Some(s) = source.get(offset..offset + 2) {
-                if s == "${" {
-                    result.push_str(source.get(..offset).unwrap());
-                    let expr = source.get(offset + 2..).unwrap();
-                    let (parsed, remainder) = interpreter::parse_partial(expr)?;
-                    if remainder.get(0..1) != Some("}") {
-                        // Hide '{' in this error message from the formatting machinery in bail macro
-                        let msg = "unterminated ${..} expression";
-                        bail!(msg);
-                    }
-                    let eval_result = interpreter::evaluate(&parsed, context)?;
-
-                    match eval_result {
-                        Value::Number(n) => write!(&mut result, "{}", n)?,
-                        Value::Bool(true) => result.push_str("true"),
-                        Value::Bool(false) => result.push_str("false"),
-                        // null interpolates to an empty string
-                        Value::Null => {}
-                        Value::String(s) => result.push_str(&s),
-                        _ => bail!("interpolation of '{}' produced an array or object", expr),
-                    }
+    /// Check this template for errors that do not depend on the context, returning all of them
+    /// at once.  These are the errors that would occur when rendering any part of the template
+    /// that contains them, such as unknown operators, undefined operator properties, invalid
+    /// `each(..)` or `by(..)` properties, and syntax errors in expressions, each with the
+    /// location at which it would occur.
+    ///
+    /// ```
+    /// # use json_e::Renderer;
+    /// # use serde_json::json;
+    /// let template = json!({"$if": "x", "then": 1, "else": {"$eval": "x +"}});
+    /// let errors = Renderer::new().compile(&template).validate().unwrap_err();
+    /// assert_eq!(errors[0].location().pointer(), "/$if/else/$eval");
+    /// ```
+    pub fn validate(&self) -> Result<(), Vec<Error>> {
+        let errors = self
+            .renderer
+            .with_root(|root| analyze(&self.template, root).errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
 
-                    source = &remainder[1..];
-                    continue;
-                }
+    /// Render this template with a context that gives only some of the values it refers to,
+    /// producing a residual template.  Rendering the residual template with the remaining values
+    /// gives the same result as rendering this template with all of them.
+    ///
+    /// Everything that can be determined from the given values is rendered ahead of time:
+    /// expressions are simplified, `$if` and `$switch` with known conditions are reduced to the
+    /// chosen branch, `$map` over a known array is expanded, and templates named by `$include`
+    /// are inlined.  Anything that depends on a missing value, or on the current time, is left
+    /// for the final render.
+    ///
+    /// The values given to the final render must not shadow functions that the template calls,
+    /// as those calls may already have been evaluated.  A part of the template that fails to
+    /// render ahead of time is left for the final render, as it may be in a branch that is never
+    /// taken, within a `$let` giving the known values it refers to.  Only errors that cannot be
+    /// left, such as exceeding a limit, are returned.
+    ///
+    /// ```
+    /// # use json_e::Renderer;
+    /// # use serde_json::json;
+    /// let template = json!({
+    ///     "$if": "env == 'prod'",
+    ///     "then": {"replicas": "${replicas}", "image": "${image}:${tag}"},
+    ///     "else": {"replicas": 1},
+    /// });
+    /// let renderer = Renderer::new();
+    /// let residual = renderer
+    ///     .compile(&template)
+    ///     .partial_render(&json!({"env": "prod", "image": "app"}))
+    ///     .unwrap();
+    /// assert_eq!(residual, json!({"replicas": "${replicas}", "image": "app:${tag}"}));
+    /// assert_eq!(
+    ///     renderer.render(&residual, &json!({"replicas": 3, "tag": "v2"})).unwrap(),
+    ///     json!({"replicas": "3", "image": "app:v2"}),
+    /// );
+    /// ```
+    pub fn partial_render(&self, context: &SerdeValue) -> Result<SerdeValue, Error> {
+        let budget = Budget::new(self.renderer.limits);
+        self.renderer.check_depth(&budget, context)?;
+        self.renderer.with_root(|root| {
+            let context = Context::from_serde_value(context, Some(root))?;
+
+            let mut context = context.child();
+            context.set_budget(&budget);
+            let includes = self.renderer.includes.as_deref().map(IncludeStack::new);
+            if let Some(includes) = &includes {
+                context.set_includes(includes);
             }
 
-            // If this is an escape (`$${`), un-escape it
-            if let Some(s) = source.get(offset..offset + 3) {
-                if s == "$${" {
-                    result.push_str(source.get(..offset + 1).unwrap());
-                    source = source.get(offset + 2..).unwrap();
-                    continue;
-                }
+            let renderer = PartialRenderer { functions: root };
+            renderer.render(&self.template, &context)
+        })
+    }
+
+    /// Render this template with a context of any serializable type, which must serialize to an
+    /// object, deserializing the result into the requested type.  If the result cannot be
+    /// deserialized, the error is an [`ErrorKind::Deserialize`] giving the location within the
+    /// result at which that failed.
+    ///
+    /// ```
+    /// # use json_e::Renderer;
+    /// # use serde_json::json;
+    /// # use std::collections::HashMap;
+    /// let mut context = HashMap::new();
+    /// context.insert("replicas", 3);
+    /// let template = json!({"$eval": "[replicas, replicas * 2]"});
+    /// let result: (u32, u32) = Renderer::new().render_as(&template, &context).unwrap();
+    /// assert_eq!(result, (3, 6));
+    /// ```
+    pub fn render_as<T, U>(&self, context: &T) -> Result<U, Error>
+    where
+        T: Serialize + ?Sized,
+        U: DeserializeOwned,
+    {
+        let context = serde_json::to_value(context).map_err(|e| {
+            error_of_kind!(
+                ErrorKind::InvalidContext,
+                "context could not be serialized: {}",
+                e
+            )
+        })?;
+        deserialize_output(self.render(&context)?)
+    }
+
+    /// Render this template with the given context, using the given time as the current time.
+    pub fn render_at(&self, context: &SerdeValue, now: SystemTime) -> Result<SerdeValue, Error> {
+        self.with_context(context, now, |context| {
+            match _render(&self.template, context) {
+                // note that this will convert DeletionMarker into Null
+                Ok(v) => Ok(v.try_into()?),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Render this template with the given context, writing the result to the given writer as
+    /// compact JSON.  See [`CompiledTemplate::render_to_serializer`].
+    pub fn render_to_writer<W: io::Write>(
+        &self,
+        context: &SerdeValue,
+        writer: W,
+    ) -> Result<(), Error> {
+        self.render_to_serializer(context, &mut serde_json::Serializer::new(writer))
+    }
+
+    /// Render this template with the given context, passing the result to the given serializer
+    /// as it is produced.  Arrays, objects whose keys contain no interpolations, and `$map` over
+    /// arrays are rendered and serialized one element at a time, so a large result is never held
+    /// in memory all at once.  The result is the same as from [`render`](Self::render), as are
+    /// any errors, except that an error partway through leaves the output incomplete.  If the
+    /// serializer fails, as when writing to it fails, the error is an [`ErrorKind::Output`].
+    ///
+    /// ```
+    /// # use json_e::Renderer;
+    /// # use serde_json::json;
+    /// let template = json!({"$map": {"$eval": "range(0, 3)"}, "each(i)": {"id": "${i}"}});
+    /// let mut output = Vec::new();
+    /// Renderer::new()
+    ///     .compile(&template)
+    ///     .render_to_serializer(&json!({}), &mut serde_json::Serializer::pretty(&mut output))
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     serde_json::from_slice::<serde_json::Value>(&output).unwrap(),
+    ///     json!([{"id": "0"}, {"id": "1"}, {"id": "2"}]),
+    /// );
+    /// ```
+    pub fn render_to_serializer<S: Serializer>(
+        &self,
+        context: &SerdeValue,
+        serializer: S,
+    ) -> Result<S::Ok, Error> {
+        self.with_context(context, self.renderer.clock.now(), |context| {
+            stream::render_to_serializer(&self.template, context, serializer)
+        })
+    }
+
+    /// Render this template with the given context, keeping track of the context values on which
+    /// each part of the result depends, so that the result can be updated when the context
+    /// changes by rendering again only the parts that the change affects.  See
+    /// [`IncrementalRender`].
+    #[cfg(feature = "incremental")]
+    pub fn render_incremental(&self, context: &SerdeValue) -> Result<IncrementalRender<'_>, Error> {
+        IncrementalRender::new(self, &self.template, context, self.renderer.clock.now())
+    }
+
+    /// Call the given function with the context for a render with the given context, which
+    /// has the renderer's functions, budget, and includes, and `now` set to a single current time
+    /// for the duration of the render.
+    fn with_context<T, F>(&self, context: &SerdeValue, now: SystemTime, f: F) -> Result<T>
+    where
+        F: FnOnce(&Context) -> Result<T>,
+    {
+        self.check_depth(context)?;
+        self.with_values(&context.into(), now, None, f)
+    }
+
+    /// Check that the given context, and the renderer's base context, are not nested more deeply
+    /// than this template's limits allow.
+    pub(crate) fn check_depth(&self, context: &SerdeValue) -> Result<()> {
+        let budget = Budget::new(self.renderer.limits);
+        self.renderer.check_depth(&budget, context)
+    }
+
+    /// Call the given function with the context for a render with the given values, as for
+    /// `with_context`, recording the names looked up among the values in `reads`, if given.  The
+    /// caller checks the depth of the values.
+    pub(crate) fn with_values<T, F>(
+        &self,
+        values: &Value,
+        now: SystemTime,
+        reads: Option<&Reads>,
+        f: F,
+    ) -> Result<T>
+    where
+        F: FnOnce(&Context) -> Result<T>,
+    {
+        let budget = Budget::new(self.renderer.limits);
+        self.renderer.with_root(|root| {
+            let mut context = Context::from_value(values, Some(root))?;
+            if let Some(reads) = reads {
+                context.set_reads(reads);
             }
 
-            // otherwise, carry on..
-            result.push_str(source.get(..offset + 1).unwrap());
-            source = source.get(offset + 1..).unwrap();
+            let mut context = context.child();
+            context.set_budget(&budget);
+            context.insert("now", Value::String(format_time(now).into()));
+            let includes = self.renderer.includes.as_deref().map(IncludeStack::new);
+            if let Some(includes) = &includes {
+                context.set_includes(includes);
+            }
+            f(&context)
+        })
+    }
+}
+
+/// Deserialize a rendered value into the requested type, reporting where in the value this failed.
+/// The location is not reported if any part of it is unknown, such as a map key that is not
+/// deserialized as a string, as the path without that part would point elsewhere.
+fn deserialize_output<U: DeserializeOwned>(value: SerdeValue) -> Result<U> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let segments: Vec<String> = e
+            .path()
+            .iter()
+            .map(|segment| match segment {
+                Segment::Seq { index } => Some(index.to_string()),
+                Segment::Map { key } => Some(key.clone()),
+                Segment::Enum { variant } => Some(variant.clone()),
+                _ => None,
+            })
+            .collect::<Option<_>>()
+            .unwrap_or_default();
+        let path = json_pointer(&segments);
+        let message = if path.is_empty() {
+            format!("rendered value could not be deserialized: {}", e.inner())
         } else {
-            // remainder of the string contains no interpolations..
-            result.push_str(source);
-            source = "";
+            format!(
+                "rendered value could not be deserialized at {}: {}",
+                path,
+                e.inner()
+            )
+        };
+        Error::new(ErrorKind::Deserialize { path }, message)
+    })
+}
+
+/// Inner, recursive render function.
+pub(crate) fn _render(template: &Template, context: &Context) -> Result<Value> {
+    /// render a value, shaping the result such that it can be used with
+    /// `.filter_map(..).colect::<Result<_>>`.
+    fn render_or_deletion_marker(v: &Template, context: &Context) -> Option<Result<Value>> {
+        match _render(v, context) {
+            Ok(Value::DeletionMarker) => None,
+            Ok(rendered) => Some(Ok(rendered)),
+            Err(e) => Some(Err(e)),
         }
     }
 
-    Ok(result)
-}
+    let _depth = context.enter()?;
+    Ok(match template {
+        Template::Literal(v) => v.clone(),
+        Template::String(_, s) => Value::String(interpolate(s, context)?.into()),
+        Template::Array(elements) => {
+            context.check_array(elements.len())?;
+            Value::Array(
+                elements
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, e)| {
+                        render_or_deletion_marker(e, context)
+                            .map(|r| r.map_err(|e| e.add_location(i)))
+                    })
+                    .collect::<Result<_>>()?,
+            )
+        }
+        Template::Object {
+            dynamic_keys,
+            properties,
+        } => {
+            check_dynamic_keys(dynamic_keys, context)?;
+            context.add_nodes(properties.len())?;
+
+            #[cfg(feature = "parallel")]
+            {
+                let rendered = crate::parallel::map_in_order(
+                    properties,
+                    context,
+                    |_, (raw, k, v), context| {
+                        let located = |e: Error| e.add_location(raw);
+                        Ok(match _render(v, context).map_err(located)? {
+                            Value::DeletionMarker => None,
+                            v => Some((interpolate(k, context).map_err(located)?, v)),
+                        })
+                    },
+                )?;
+                Value::Object(Arc::new(rendered.into_iter().flatten().collect()))
+            }
 
-/// Evaluate the given expression and return the resulting Value
-fn evaluate(expression: &str, context: &Context) -> Result<Value> {
-    let parsed = interpreter::parse_all(expression)?;
-    interpreter::evaluate(&parsed, context)
+            #[cfg(not(feature = "parallel"))]
+            {
+                let mut result = Object::new();
+                for (raw, k, v) in properties.iter() {
+                    let located = |e: Error| e.add_location(raw);
+                    match _render(v, context).map_err(located)? {
+                        Value::DeletionMarker => {}
+                        v => {
+                            result.insert(interpolate(k, context).map_err(located)?, v);
+                        }
+                    };
+                }
+                Value::Object(result.into())
+            }
+        }
+        Template::Operator {
+            dynamic_keys,
+            key,
+            operator,
+            ..
+        } => {
+            check_dynamic_keys(dynamic_keys, context)?;
+            match operator {
+                Ok(operator) => {
+                    render_operator(operator, context).map_err(|e| e.add_location(key))?
+                }
+                Err(e) => return Err(e.clone().add_location(key)),
+            }
+        }
+        Template::At(segment, template) => {
+            _render(template, context).map_err(|e| e.add_location(segment))?
+        }
+        Template::Error(e) => return Err(e.clone()),
+    })
 }
 
-/// The given object may be an operator: it has the given key that starts with `$`.  If so,
-/// this function evaluates the operator and return Ok(Some(result)) or an error in
-/// evaluation.  Otherwise, it returns Ok(None) indicating that this is a "normal" object.
-fn maybe_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
+/// Check that none of the given keys, which contain interpolations, are operators once
+/// interpolated.  Such operators are never recognized, so this is always an error.
+pub(crate) fn check_dynamic_keys(
+    dynamic_keys: &[(String, Interpolation)],
     context: &Context,
-) -> Result<Option<Value>> {
-    match operator {
-        "$eval" => Ok(Some(eval_operator(operator, value, object, context)?)),
-        "$flatten" => Ok(Some(flatten_operator(operator, value, object, context)?)),
-        "$flattenDeep" => Ok(Some(flatten_deep_operator(
-            operator, value, object, context,
-        )?)),
-        "$fromNow" => Ok(Some(from_now_operator(operator, value, object, context)?)),
-        "$if" => Ok(Some(if_operator(operator, value, object, context)?)),
-        "$json" => Ok(Some(json_operator(operator, value, object, context)?)),
-        "$let" => Ok(Some(let_operator(operator, value, object, context)?)),
-        "$map" => Ok(Some(map_operator(operator, value, object, context)?)),
-        "$reduce" => Ok(Some(reduce_operator(operator, value, object, context)?)),
-        "$find" => Ok(Some(find_operator(operator, value, object, context)?)),
-        "$match" => Ok(Some(match_operator(operator, value, object, context)?)),
-        "$switch" => Ok(Some(switch_operator(operator, value, object, context)?)),
-        "$merge" => Ok(Some(merge_operator(operator, value, object, context)?)),
-        "$mergeDeep" => Ok(Some(merge_deep_operator(operator, value, object, context)?)),
-        "$reverse" => Ok(Some(reverse_operator(operator, value, object, context)?)),
-        "$sort" => Ok(Some(sort_operator(operator, value, object, context)?)),
-
-        // if the operator isn't recognized, then it should be escaped
-        _ => Err(template_error!(
+) -> Result<()> {
+    for (k, key) in dynamic_keys {
+        let interpolated = interpolate(key, context).map_err(|e| e.add_location(k))?;
+        check_dynamic_key(k, &interpolated).map_err(|e| e.add_location(k))?;
+    }
+    Ok(())
+}
+
+/// Check that the given key, containing interpolations, is not an operator once interpolated.
+pub(crate) fn check_dynamic_key(raw: &str, interpolated: &str) -> Result<()> {
+    let mut chars = interpolated.chars();
+    if chars.next() == Some('$') && chars.next() != Some('$') {
+        return Err(operator_error!(
+            interpolated,
             "$<identifier> is reserved; use $$<identifier> ({})",
-            operator
-        )),
+            raw
+        ));
     }
+    Ok(())
 }
 
-/// Check for undefined properties for an operator, returning an appropriate error message if
-/// found; the check function is called for each value other than the operator.
-#[inline(always)]
-fn check_operator_properties<F>(operator: &str, object: &Object, check: F) -> Result<()>
-where
-    F: Fn(&str) -> bool,
-{
-    // if the object only has one key, we already have it (the operator)
-    if object.len() == 1 {
-        return Ok(());
+/// Perform string interpolation on the given string.
+fn interpolate(source: &Interpolation, context: &Context) -> Result<String> {
+    // shortcut the common no-interpolation case
+    if let Some(s) = source.as_literal() {
+        return Ok(s.into());
     }
 
-    // TODO: avoid this allocation unless necessary
-    let mut unknown = Vec::new();
+    let mut result = String::new();
 
-    for (k, _) in object.iter() {
-        if k == operator {
-            continue;
-        }
-        if !check(k) {
-            unknown.push(k.as_ref());
+    for fragment in source.fragments() {
+        match fragment {
+            Fragment::Literal(s) => result.push_str(s),
+            Fragment::Expression(code, expr, offset) => {
+                let eval_result = code.evaluate(context).map_err(|e| e.add_offset(*offset))?;
+                interpolate_value(&eval_result, expr, &mut result)
+                    .map_err(|e| e.add_offset(*offset))?;
+            }
+            Fragment::Error(e) => return Err(e.clone()),
         }
+        context.check_string(result.len())?;
     }
 
-    if !unknown.is_empty() {
-        unknown.sort();
-        Err(template_error!(
-            "{} has undefined properties: {}",
-            operator,
-            unknown.join(" ")
-        ))?;
-    }
+    Ok(result)
+}
 
+/// Append the result of the given interpolated expression to a string.
+pub(crate) fn interpolate_value(value: &Value, expr: &str, result: &mut String) -> Result<()> {
+    match value {
+        Value::Number(n) => result.push_str(&n.to_string()),
+        Value::Bool(true) => result.push_str("true"),
+        Value::Bool(false) => result.push_str("false"),
+        // null interpolates to an empty string
+        Value::Null => {}
+        Value::String(s) => result.push_str(s),
+        v => {
+            return Err(type_error!(
+                "interpolation",
+                "string, number, boolean, or null",
+                [v],
+                "interpolation of '{}' produced an array or object",
+                expr
+            ))
+        }
+    }
     Ok(())
 }
 
-fn eval_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
-    context: &Context,
-) -> Result<Value> {
-    check_operator_properties(operator, object, |_| false)?;
-    if let Value::String(expr) = value {
-        Ok(evaluate(expr, context)?)
-    } else {
-        Err(template_error!("$eval must be given a string expression"))
+/// Evaluate the given expression and return the resulting Value
+pub(crate) fn evaluate(expression: &Expression, context: &Context) -> Result<Value> {
+    let located = |e: Error| match expression.segment() {
+        Some(segment) => e.add_location(segment),
+        None => e,
+    };
+    let code = expression.code().map_err(located)?;
+    code.evaluate(context).map_err(|e| located(e.add_offset(0)))
+}
+
+/// Render the given operator invocation.
+pub(crate) fn render_operator(operator: &Operator, context: &Context) -> Result<Value> {
+    match operator {
+        Operator::Eval(expr) => eval_operator(expr, context),
+        Operator::Flatten(value) => flatten_operator(value, context),
+        Operator::FlattenDeep(value) => flatten_deep_operator(value, context),
+        Operator::FromNow { value, from } => from_now_operator(value, from.as_ref(), context),
+        Operator::If {
+            condition,
+            then,
+            else_,
+        } => if_operator(condition, then.as_ref(), else_.as_ref(), context),
+        Operator::Include { name, with } => include_operator(name, with.as_ref(), context),
+        Operator::Json(value) => json_operator(value, context),
+        Operator::Let { bindings, in_ } => let_operator(bindings, in_, context),
+        Operator::Map {
+            value,
+            value_var,
+            index_var,
+            each,
+        } => map_operator(value, value_var, index_var.as_deref(), each, context),
+        Operator::Reduce {
+            value,
+            acc_var,
+            value_var,
+            index_var,
+            each,
+            initial,
+        } => reduce_operator(
+            value,
+            acc_var,
+            value_var,
+            index_var.as_deref(),
+            each,
+            initial,
+            context,
+        ),
+        Operator::Find {
+            value,
+            value_var,
+            index_var,
+            each,
+            operators,
+            limits,
+        } => find_operator(
+            value,
+            value_var,
+            index_var.as_deref(),
+            each,
+            operators,
+            *limits,
+            context,
+        ),
+        Operator::Match(cases) => match_operator(cases, context),
+        Operator::Switch { cases, default } => switch_operator(cases, default.as_ref(), context),
+        Operator::Merge(value) => merge_operator(value, context),
+        Operator::MergeDeep(value) => merge_deep_operator(value, context),
+        Operator::Reverse(value) => reverse_operator(value, context),
+        Operator::Sort { value, by } => sort_operator(value, by.as_ref(), context),
+        Operator::Custom {
+            name,
+            operator,
+            value,
+            properties,
+        } => {
+            let invocation = Invocation::new(name, value, properties, context);
+            match operator.0.render(&invocation)? {
+                Some(v) => Ok(v.into()),
+                None => Ok(Value::DeletionMarker),
+            }
+        }
     }
 }
 
-fn flatten_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
-    context: &Context,
-) -> Result<Value> {
-    check_operator_properties(operator, object, |_| false)?;
-    if let Value::Array(ref mut items) = _render(value, context)? {
+fn eval_operator(expr: &Expression, context: &Context) -> Result<Value> {
+    evaluate(expr, context)
+}
+
+fn flatten_operator(value: &Template, context: &Context) -> Result<Value> {
+    let rendered = _render(value, context)?;
+    if let Value::Array(ref items) = rendered {
         let mut resitems = Vec::new();
-        for mut item in items.drain(..) {
-            if let Value::Array(ref mut subitems) = item {
-                for subitem in subitems.drain(..) {
-                    resitems.push(subitem);
-                }
+        for item in items.iter() {
+            if let Value::Array(ref subitems) = item {
+                resitems.extend(subitems.iter().cloned());
             } else {
-                resitems.push(item);
+                resitems.push(item.clone());
             }
         }
-        Ok(Value::Array(resitems))
+        context.check_array(resitems.len())?;
+        Ok(Value::Array(resitems.into()))
     } else {
-        Err(template_error!("$flatten value must evaluate to an array"))
+        Err(type_error!(
+            "$flatten",
+            "array",
+            [&rendered],
+            "$flatten value must evaluate to an array"
+        ))
     }
 }
 
-fn flatten_deep_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
-    context: &Context,
-) -> Result<Value> {
-    check_operator_properties(operator, object, |_| false)?;
-
-    fn flatten_deep(mut value: Value, accumulator: &mut Vec<Value>) {
-        if let Value::Array(ref mut items) = value {
-            for item in items.drain(..) {
+fn flatten_deep_operator(value: &Template, context: &Context) -> Result<Value> {
+    fn flatten_deep(value: &Value, accumulator: &mut Vec<Value>) {
+        if let Value::Array(ref items) = value {
+            for item in items.iter() {
                 flatten_deep(item, accumulator);
             }
         } else {
-            accumulator.push(value);
+            accumulator.push(value.clone());
         }
     }
 
-    if let value @ Value::Array(_) = _render(value, context)? {
+    let rendered = _render(value, context)?;
+    if rendered.is_array() {
         let mut resitems = Vec::new();
-        flatten_deep(value, &mut resitems);
-        Ok(Value::Array(resitems))
+        flatten_deep(&rendered, &mut resitems);
+        context.check_array(resitems.len())?;
+        Ok(Value::Array(resitems.into()))
     } else {
-        Err(template_error!("$flatten value must evaluate to an array"))
+        Err(type_error!(
+            "$flattenDeep",
+            "array",
+            [&rendered],
+            "$flatten value must evaluate to an array"
+        ))
     }
 }
 
-fn from_now_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
-    context: &Context,
-) -> Result<Value> {
-    check_operator_properties(operator, object, |prop| prop == "from")?;
+fn from_now_operator(value: &Template, from: Option<&Template>, context: &Context) -> Result<Value> {
     let reference: Cow<str>;
 
     // if "from" is specified, use that as the reference time
-    if let Some(val) = object.get("from") {
+    if let Some(val) = from {
         match _render(val, context)? {
             Value::String(ref s) => {
                 reference = Cow::Owned(s.to_string());
             }
-            _ => {
-                return Err(template_error!("$fromNow expects a string"));
+            ref v => {
+                return Err(
+                    type_error!("$fromNow", "string", [v], "$fromNow expects a string")
+                        .add_location("from"),
+                );
             }
         };
     } else {
@@ -318,187 +917,273 @@ fn from_now_operator(
         match context.get("now") {
             None => unreachable!(), // this is set in render()
             Some(Value::String(ref s)) => reference = Cow::Borrowed(s),
-            _ => return Err(template_error!("context value `now` must be a string")),
+            _ => {
+                return Err(error_of_kind!(
+                    ErrorKind::InvalidContext,
+                    "context value `now` must be a string"
+                ))
+            }
         };
     }
 
     match _render(value, context)? {
-        Value::String(s) => Ok(Value::String(from_now(&s, reference.as_ref())?)),
-        _ => Err(template_error!("$fromNow expects a string")),
+        Value::String(s) => Ok(Value::String(from_now(&s, reference.as_ref())?.into())),
+        ref v => Err(type_error!(
+            "$fromNow",
+            "string",
+            [v],
+            "$fromNow expects a string"
+        )),
     }
 }
 
-fn if_operator(operator: &str, value: &Value, object: &Object, context: &Context) -> Result<Value> {
-    check_operator_properties(operator, object, |prop| prop == "then" || prop == "else")?;
-
-    let eval_result = match value {
-        Value::String(s) => evaluate(s, context)?,
-        _ => return Err(template_error!("$if can evaluate string expressions only")),
-    };
+fn if_operator(
+    condition: &Expression,
+    then: Option<&Template>,
+    else_: Option<&Template>,
+    context: &Context,
+) -> Result<Value> {
+    let eval_result = evaluate(condition, context)?;
 
-    let prop = if eval_result.into() { "then" } else { "else" };
-    match object.get(prop) {
+    let branch = if eval_result.into() { then } else { else_ };
+    match branch {
         None => Ok(Value::DeletionMarker),
         Some(val) => Ok(_render(val, context)?),
     }
 }
 
-fn json_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
-    context: &Context,
-) -> Result<Value> {
-    check_operator_properties(operator, object, |_| false)?;
-    let v = _render(value, context)?;
-    Ok(Value::String(v.to_json()?))
+fn include_operator(name: &Template, with: Option<&Template>, context: &Context) -> Result<Value> {
+    let name = match _render(name, context)? {
+        Value::String(name) => name,
+        v => {
+            return Err(type_error!(
+                "$include",
+                "string",
+                [&v],
+                "$include value must evaluate to a string"
+            ))
+        }
+    };
+    let includes = context.includes().ok_or_else(|| {
+        operator_error!("$include", "$include requires a renderer with a loader")
+    })?;
+    includes.check_cycle(&name)?;
+    let template = includes.includes().get(&name)?;
+
+    let mut child_context = context.child();
+    if let Some(with) = with {
+        match _render(with, context)? {
+            Value::Object(o) => {
+                if !o.keys().all(|k| is_identifier(k)) {
+                    return Err(operator_error!(
+                        "$include",
+                        "keys of `with` must follow /[a-zA-Z_][a-zA-Z0-9_]*/"
+                    ));
+                }
+                child_context.bind_object(o);
+            }
+            v => {
+                return Err(type_error!(
+                    "$include",
+                    "object",
+                    [&v],
+                    "$include `with` must evaluate to an object"
+                ))
+            }
+        }
+    }
+    let included = includes.push(&name);
+    child_context.set_includes(&included);
+    _render(&template, &child_context)
 }
 
-fn let_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
-    context: &Context,
-) -> Result<Value> {
-    check_operator_properties(operator, object, |p| p == "in")?;
-
-    if !value.is_object() {
-        return Err(template_error!("$let value must be an object"));
-    }
+fn json_operator(value: &Template, context: &Context) -> Result<Value> {
+    let v = _render(value, context)?;
+    let json = v.to_json()?;
+    context.check_string(json.len())?;
+    Ok(Value::String(json.into()))
+}
 
-    let value = _render(value, context)?;
+fn let_operator(bindings: &Template, in_: &Template, context: &Context) -> Result<Value> {
+    let value = _render(bindings, context)?;
 
     if let Value::Object(o) = value {
-        let mut child_context = context.child();
-        for (k, v) in o.iter() {
-            if !is_identifier(k) {
-                return Err(template_error!(
-                    "top level keys of $let must follow /[a-zA-Z_][a-zA-Z0-9_]*/"
-                ));
-            }
-            child_context.insert(k, v.clone());
+        if !o.keys().all(|k| is_identifier(k)) {
+            return Err(operator_error!(
+                "$let",
+                "top level keys of $let must follow /[a-zA-Z_][a-zA-Z0-9_]*/"
+            ));
         }
+        let mut child_context = context.child();
+        child_context.bind_object(o);
 
-        if let Some(in_tpl) = object.get("in") {
-            Ok(_render(in_tpl, &child_context)?)
-        } else {
-            Err(template_error!("$let operator requires an `in` clause"))
-        }
+        _render(in_, &child_context)
     } else {
-        Err(template_error!("$let value must be an object"))
+        Err(type_error!(
+            "$let",
+            "object",
+            [&value],
+            "$let value must be an object"
+        ))
     }
 }
 
 fn map_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
+    value: &Template,
+    value_var: &str,
+    index_var: Option<&str>,
+    each_tpl: &Template,
     context: &Context,
 ) -> Result<Value> {
-    check_operator_properties(operator, object, |p| parse_each(p).is_some())?;
-    if object.len() != 2 {
-        return Err(template_error!("$map must have exactly two properties"));
-    }
-
-    // Unwraps here are safe because the presence of the `each(..)` is checked above.
-    let each_prop = object.keys().find(|k| k != &"$map").unwrap();
-
-    let (value_var, index_var) = parse_each(each_prop)
-        .ok_or_else(|| template_error!("$map requires each(identifier[,identifier]) syntax"))?;
-
-    let each_tpl = object.get(each_prop).unwrap();
+    let value = _render(value, context)?;
+    map_value(value, value_var, index_var, each_tpl, context)
+}
 
-    let mut value = _render(value, context)?;
+/// Apply `$map` to its rendered value.
+pub(crate) fn map_value(
+    value: Value,
+    value_var: &str,
+    index_var: Option<&str>,
+    each_tpl: &Template,
+    context: &Context,
+) -> Result<Value> {
+    let render_property = |subcontext: &Context| match _render(each_tpl, subcontext)? {
+        Value::Object(r) => Ok(r),
+        rendered => Err(type_error!(
+            "$map",
+            "object",
+            [&rendered],
+            "$map on objects expects each(..) to evaluate to an object"
+        )),
+    };
 
     match value {
+        #[cfg(feature = "parallel")]
+        Value::Object(ref o) => {
+            let properties: Vec<_> = o.iter().collect();
+            let rendered =
+                crate::parallel::map_in_order(&properties, context, |_, (k, v), context| {
+                    let mut subcontext = context.child();
+                    bind_property(&mut subcontext, value_var, index_var, k, v);
+                    render_property(&subcontext)
+                })?;
+            let mut result = Object::new();
+            for r in rendered {
+                context.add_nodes(r.len())?;
+                result.extend(Arc::unwrap_or_clone(r));
+            }
+            Ok(Value::Object(result.into()))
+        }
+        #[cfg(not(feature = "parallel"))]
         Value::Object(ref o) => {
             let mut result = Object::new();
 
+            // a single child context serves every iteration, each replacing the last's values
+            let mut subcontext = context.child();
             for (k, v) in o.iter() {
-                let mut subcontext = context.child();
-
-                if let Some(index_var) = index_var {
-                    // if each has two arguments, it gets (val, key)
-                    subcontext.insert(index_var, Value::String(k.to_string()));
-                    subcontext.insert(value_var, v.clone());
-                } else {
-                    // otherwise, it gets ({val: val, key: key})
-                    let mut arg = Object::new();
-                    arg.insert("key".to_string(), Value::String(k.to_string()));
-                    arg.insert("val".to_string(), v.clone());
-                    subcontext.insert(value_var, Value::Object(arg));
-                }
-
-                let rendered = _render(each_tpl, &subcontext)?;
-
-                if let Value::Object(r) = rendered {
-                    for (rk, rv) in r {
-                        result.insert(rk, rv);
-                    }
-                } else {
-                    return Err(template_error!(
-                        "$map on objects expects each(..) to evaluate to an object"
-                    ));
-                }
+                bind_property(&mut subcontext, value_var, index_var, k, v);
+                let r = render_property(&subcontext)?;
+                context.add_nodes(r.len())?;
+                result.extend(Arc::unwrap_or_clone(r));
             }
-            Ok(Value::Object(result))
+            Ok(Value::Object(result.into()))
+        }
+        #[cfg(feature = "parallel")]
+        Value::Array(ref a) => {
+            context.check_array(a.len())?;
+            let mapped = crate::parallel::map_in_order(a, context, |i, v, context| {
+                let mut subcontext = context.child();
+                bind_element(&mut subcontext, value_var, index_var, i, v);
+                _render(each_tpl, &subcontext)
+            })?;
+            Ok(Value::Array(
+                mapped
+                    .into_iter()
+                    .filter(|v| !matches!(v, Value::DeletionMarker))
+                    .collect(),
+            ))
         }
-        Value::Array(ref mut a) => {
+        #[cfg(not(feature = "parallel"))]
+        Value::Array(ref a) => {
+            context.check_array(a.len())?;
+            let mut subcontext = context.child();
             let mapped = a
-                .drain(..)
+                .iter()
                 .enumerate()
                 .map(|(i, v)| {
-                    let mut subcontext = context.child();
-                    subcontext.insert(value_var, v);
-                    if let Some(index_var) = index_var {
-                        subcontext.insert(index_var, Value::Number(i as f64));
-                    }
+                    bind_element(&mut subcontext, value_var, index_var, i, v);
                     _render(each_tpl, &subcontext)
                 })
                 .filter(|v| !matches!(v, Ok(Value::DeletionMarker)))
-                .collect::<Result<Vec<_>>>()?;
+                .collect::<Result<_>>()?;
             Ok(Value::Array(mapped))
         }
-        _ => Err(template_error!(
+        ref v => Err(type_error!(
+            "$map",
+            "array or object",
+            [v],
             "$map value must evaluate to an array or object"
         )),
     }
 }
 
-fn reduce_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
-    context: &Context,
-) -> Result<Value> {
-    check_operator_properties(operator, object, |p| p == "initial" || parse_each_three(p).is_some())?;
-    if object.len() != 3 {
-        return Err(template_error!("$reduce must have exactly three properties"));
+/// Bind the variables of `$map`'s `each` for the given element of an array.
+fn bind_element<'a>(
+    subcontext: &mut Context<'a>,
+    value_var: &'a str,
+    index_var: Option<&'a str>,
+    i: usize,
+    v: &Value,
+) {
+    subcontext.insert(value_var, v.clone());
+    if let Some(index_var) = index_var {
+        subcontext.insert(index_var, Value::Number(i.into()));
     }
+}
 
-    // Unwraps here are safe because the presence of the `each(..)` is checked above.
-    let each_prop = object.keys().find(|k| k != &"$reduce" && k != &"initial").unwrap();
-
-    let (acc_var, value_var, index_var) = parse_each_three(each_prop)
-        .ok_or_else(|| template_error!("$reduce requires each(identifier,identifier[,identifier]) syntax"))?;
-
-    let each_tpl = object.get(each_prop).unwrap();
+/// Bind the variables of `$map`'s `each` for the given property of an object.
+fn bind_property<'a>(
+    subcontext: &mut Context<'a>,
+    value_var: &'a str,
+    index_var: Option<&'a str>,
+    k: &str,
+    v: &Value,
+) {
+    if let Some(index_var) = index_var {
+        // if each has two arguments, it gets (val, key)
+        subcontext.insert(index_var, Value::String(k.into()));
+        subcontext.insert(value_var, v.clone());
+    } else {
+        // otherwise, it gets ({val: val, key: key})
+        let mut arg = Object::new();
+        arg.insert("key".to_string(), Value::String(k.into()));
+        arg.insert("val".to_string(), v.clone());
+        subcontext.insert(value_var, Value::Object(arg.into()));
+    }
+}
 
-    let mut value = _render(value, context)?;
-    // Need to get the initial value from the object.
-    let initial = object.get("initial").unwrap();
+fn reduce_operator(
+    value: &Template,
+    acc_var: &str,
+    value_var: &str,
+    index_var: Option<&str>,
+    each_tpl: &Template,
+    initial: &Value,
+    context: &Context,
+) -> Result<Value> {
+    let value = _render(value, context)?;
 
     match value {
-        Value::Array(ref mut a) => {
+        Value::Array(ref a) => {
+            let mut subcontext = context.child();
             let mapped = a
-                .drain(..)
+                .iter()
                 .enumerate()
                 .try_fold(initial.clone(), |acc, (i, v)| {
-                    let mut subcontext = context.child();
                     subcontext.insert(acc_var, acc.clone());
-                    subcontext.insert(value_var, v);
+                    subcontext.insert(value_var, v.clone());
                     if let Some(index_var) = index_var {
-                        subcontext.insert(index_var, Value::Number(i as f64));
+                        subcontext.insert(index_var, Value::Number(i.into()));
                     }
                     let rendered = _render(each_tpl, &subcontext);
                     match rendered {
@@ -509,251 +1194,228 @@ fn reduce_operator(
                 });
             mapped
         }
-        _ => Err(template_error!(
+        ref v => Err(type_error!(
+            "$reduce",
+            "array",
+            [v],
             "$reduce value must evaluate to an array"
         )),
     }
 }
 
 fn find_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
+    value: &Template,
+    value_var: &str,
+    index_var: Option<&str>,
+    each: &Expression,
+    operators: &Arc<Operators>,
+    limits: Limits,
     context: &Context,
 ) -> Result<Value> {
-    check_operator_properties(operator, object, |p| parse_each(p).is_some())?;
-    if object.len() != 2 {
-        return Err(template_error!("$find must have exactly two properties"));
-    }
-
-    // Unwraps here are safe because the presence of the `each(..)` is checked above.
-    let each_prop = object.keys().find(|k| k != &"$find").unwrap();
-
-    let (value_var, index_var) = parse_each(each_prop)
-        .ok_or_else(|| template_error!("$find requires each(identifier[,identifier]) syntax"))?;
-
-    let each_tpl = object.get(each_prop).unwrap();
-
-    let mut value = _render(value, context)?;
+    let value = _render(value, context)?;
 
-    if let Value::Array(ref mut a) = value {
+    if let Value::Array(ref a) = value {
+        let mut subcontext = context.child();
         for (i, v) in a.iter().enumerate() {
-            let mut subcontext = context.child();
             subcontext.insert(value_var, v.clone());
             if let Some(index_var) = index_var {
-                subcontext.insert(index_var, Value::Number(i as f64));
+                subcontext.insert(index_var, Value::Number(i.into()));
             }
 
-            if let Value::String(ref s) = each_tpl {
-                let eval_result = evaluate(s, &subcontext)?;
-                if bool::from(eval_result) {
-                    return _render(v, &subcontext);
-                }
-            } else {
-                return Err(template_error!("$find can evaluate string expressions only"));
+            let eval_result = evaluate(each, &subcontext)?;
+            if bool::from(eval_result) {
+                // the found value is itself rendered as a template
+                let budget = Budget::for_compilation(limits);
+                let compiler = Compiler {
+                    budget: context.budget().unwrap_or(&budget),
+                    operators,
+                };
+                return _render(&Template::compile(v, &compiler), &subcontext);
             }
-        }   
+        }
         Ok(Value::DeletionMarker)
     } else {
-        Err(template_error!("$find value must be an array"))
+        Err(type_error!(
+            "$find",
+            "array",
+            [&value],
+            "$find value must be an array"
+        ))
     }
 }
 
-fn match_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
-    context: &Context,
-) -> Result<Value> {
-    check_operator_properties(operator, object, |_| false)?;
-    if let Value::Object(ref obj) = value {
-        let mut res = vec![];
-        for (cond, val) in obj {
-            if let Ok(cond) = evaluate(cond, context) {
+fn match_operator(cases: &[(Expression, Template)], context: &Context) -> Result<Value> {
+    let mut res = vec![];
+    for (cond, val) in cases {
+        match evaluate(cond, context) {
+            Ok(cond) => {
                 if !bool::from(cond) {
                     continue;
                 }
                 res.push(_render(val, context)?);
-            } else {
-                bail!(template_error!("parsing error in condition"));
             }
+            Err(e) if e.kind().is_limit_exceeded() => return Err(e),
+            Err(_) => return Err(condition_error("$match", cond)),
         }
-        Ok(Value::Array(res))
-    } else {
-        Err(template_error!("$match can evaluate objects only"))
+    }
+    context.check_array(res.len())?;
+    Ok(Value::Array(res.into()))
+}
+
+/// The error for a `$match` or `$switch` condition that cannot be evaluated
+pub(crate) fn condition_error(operator: &str, cond: &Expression) -> Error {
+    let err = operator_error!(operator, "parsing error in condition");
+    match cond.segment() {
+        Some(segment) => err.add_location(segment),
+        None => err,
     }
 }
 
 fn switch_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
+    cases: &[(Expression, Template)],
+    default: Option<&Template>,
     context: &Context,
 ) -> Result<Value> {
-    if let Value::Object(ref obj) = value {
-        let mut res = None;
-        let mut unrendered_default = None;
-        for (cond, val) in obj {
-            // if the condition is `$default`, stash it for later
-            if cond == "$default" {
-                unrendered_default = Some(val);
-                continue;
-            }
-            // try to evaluate the condition
-            if let Ok(cond) = evaluate(cond, context) {
+    let mut res = None;
+    for (cond, val) in cases {
+        // try to evaluate the condition
+        match evaluate(cond, context) {
+            Ok(cond) => {
                 if !bool::from(cond) {
                     continue;
                 }
                 if res.is_some() {
-                    bail!(template_error!(
+                    return Err(operator_error!(
+                        "$switch",
                         "$switch can only have one truthy condition"
-                    ))
+                    ));
                 }
                 res = Some(val);
-            } else {
-                bail!(template_error!("parsing error in condition"));
             }
+            Err(e) if e.kind().is_limit_exceeded() => return Err(e),
+            Err(_) => return Err(condition_error("$switch", cond)),
         }
+    }
 
-        if let Some(res) = res {
-            _render(res, context)
-        } else if let Some(unrendered_default) = unrendered_default {
-            _render(unrendered_default, context)
-        } else {
-            Ok(Value::DeletionMarker)
-        }
+    if let Some(res) = res {
+        _render(res, context)
+    } else if let Some(default) = default {
+        _render(default, context)
     } else {
-        Err(template_error!("$switch can evaluate objects only"))
+        Ok(Value::DeletionMarker)
     }
 }
 
-fn merge_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
-    context: &Context,
-) -> Result<Value> {
-    check_operator_properties(operator, object, |_| false)?;
-    if let Value::Array(items) = _render(value, context)? {
-        let mut new_obj = std::collections::BTreeMap::new();
-        for item in items {
-            if let Value::Object(mut obj) = item {
-                new_obj.append(&mut obj);
+fn merge_operator(value: &Template, context: &Context) -> Result<Value> {
+    let make_err = |v: &Value| {
+        type_error!(
+            "$merge",
+            "array of objects",
+            [v],
+            "$merge value must evaluate to an array of objects"
+        )
+    };
+
+    let rendered = _render(value, context)?;
+    if let Value::Array(items) = rendered {
+        let mut new_obj = Object::new();
+        for item in items.iter() {
+            if let Value::Object(obj) = item {
+                context.add_nodes(obj.len())?;
+                new_obj.extend(obj.iter().map(|(k, v)| (k.clone(), v.clone())));
             } else {
-                return Err(template_error!(
-                    "$merge value must evaluate to an array of objects"
-                ));
+                return Err(make_err(item));
             }
         }
-        Ok(Value::Object(new_obj))
+        Ok(Value::Object(new_obj.into()))
     } else {
-        Err(template_error!(
-            "$merge value must evaluate to an array of objects"
-        ))
+        Err(make_err(&rendered))
     }
 }
 
-fn merge_deep_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
-    context: &Context,
-) -> Result<Value> {
-    fn merge_deep(a: &Value, b: &Value) -> Value {
-        match (a, b) {
+fn merge_deep_operator(value: &Template, context: &Context) -> Result<Value> {
+    /// Merge `b` into `a`, modifying `a` in place where it is not shared
+    fn merge_deep(a: Value, b: &Value, context: &Context) -> Result<Value> {
+        Ok(match (a, b) {
             (Value::Array(a), Value::Array(b)) => {
-                let mut a = a.clone();
-                a.append(&mut b.clone());
-                Value::Array(a)
+                context.check_array(a.len() + b.len())?;
+                Value::Array(a.iter().chain(b.iter()).cloned().collect())
             }
-            (Value::Object(a), Value::Object(b)) => {
-                let mut a = a.clone();
-                let b = b.clone();
-                for (k, v) in b {
-                    if a.contains_key(&k) {
-                        a.insert(k.to_string(), merge_deep(a.get(&k).unwrap(), &v));
-                    } else {
-                        a.insert(k.to_string(), v);
+            (Value::Object(mut a), Value::Object(b)) => {
+                context.add_nodes(b.len())?;
+                let entries = Arc::make_mut(&mut a);
+                for (k, v) in b.iter() {
+                    match entries.get_mut(k) {
+                        Some(existing) => {
+                            let existing_value = std::mem::replace(existing, Value::Null);
+                            *existing = merge_deep(existing_value, v, context)?;
+                        }
+                        None => {
+                            entries.insert(k.clone(), v.clone());
+                        }
                     }
                 }
                 Value::Object(a)
             }
             _ => b.clone(),
-        }
+        })
     }
 
-    check_operator_properties(operator, object, |_| false)?;
-    if let Value::Array(items) = _render(value, context)? {
-        let mut new_obj = Value::Object(std::collections::BTreeMap::new());
-        for item in items {
+    let make_err = |v: &Value| {
+        type_error!(
+            "$mergeDeep",
+            "array of objects",
+            [v],
+            "$mergeDeep value must evaluate to an array of objects"
+        )
+    };
+
+    let rendered = _render(value, context)?;
+    if let Value::Array(items) = rendered {
+        let mut new_obj = Value::Object(Arc::default());
+        for item in items.iter() {
             if let Value::Object(_) = item {
-                new_obj = merge_deep(&new_obj, &item);
+                new_obj = merge_deep(new_obj, item, context)?;
             } else {
-                return Err(template_error!(
-                    "$mergeDeep value must evaluate to an array of objects"
-                ));
+                return Err(make_err(item));
             }
         }
         Ok(new_obj)
     } else {
-        Err(template_error!(
-            "$mergeDeep value must evaluate to an array of objects"
-        ))
+        Err(make_err(&rendered))
     }
 }
 
-fn reverse_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
-    context: &Context,
-) -> Result<Value> {
-    check_operator_properties(operator, object, |_| false)?;
-    if let Value::Array(items) = _render(value, context)? {
-        Ok(Value::Array(items.into_iter().rev().collect()))
+fn reverse_operator(value: &Template, context: &Context) -> Result<Value> {
+    let rendered = _render(value, context)?;
+    if let Value::Array(items) = rendered {
+        Ok(Value::Array(items.iter().rev().cloned().collect()))
     } else {
-        Err(template_error!("$reverse value must evaluate to an array"))
+        Err(type_error!(
+            "$reverse",
+            "array",
+            [&rendered],
+            "$reverse value must evaluate to an array"
+        ))
     }
 }
 
-fn sort_operator(
-    operator: &str,
-    value: &Value,
-    object: &Object,
+pub(crate) fn sort_operator(
+    value: &Template,
+    by: Option<&Result<(String, Expression)>>,
     context: &Context,
 ) -> Result<Value> {
-    check_operator_properties(operator, object, |p| parse_by(p).is_some())?;
-
-    let make_err = || {
-        Err(template_error!(
-            "$sorted values to be sorted must have the same type"
-        ))
-    };
-
-    if let Value::Array(arr) = _render(value, context)? {
+    let rendered = _render(value, context)?;
+    if let Value::Array(arr) = rendered {
         // short-circuit a zero-length array, so we can later assume at least one item
         if arr.is_empty() {
             return Ok(Value::Array(arr));
         }
 
-        if object.len() == 1 {
-            return sort_operator_without_by(operator, arr, object, context);
-        }
-
-        // sort by
-        // Unwraps here are safe because the presence of the `by(..)` is checked above.
-        let by_props: Vec<_> = object.keys().filter(|k| k != &"$sort").collect();
-        if by_props.len() > 1 {
-            return Err(template_error!("only one by(..) is allowed"));
-        }
-
-        let by_var = parse_by(by_props[0])
-            .ok_or_else(|| template_error!("$sort requires by(identifier) syntax"))?;
-
-        let by_expr = if let Value::String(expr) = object.get(by_props[0]).unwrap() {
-            expr
-        } else {
-            return Err(interpreter_error!("invalid expression in $sorted by"));
+        let (by_var, by_expr) = match by {
+            None => return sort_operator_without_by(arr.to_vec()),
+            Some(Ok((by_var, by_expr))) => (by_var, by_expr),
+            Some(Err(e)) => return Err(e.clone()),
         };
 
         let mut subcontext = context.child();
@@ -766,7 +1428,7 @@ fn sort_operator(
         let mut eval_pairs: Vec<(Value, Value)> = arr
             .iter()
             .map(|item| {
-                subcontext.insert(by_var, item.clone());
+                subcontext.insert(by_var.as_str(), item.clone());
                 Ok((evaluate(by_expr, &subcontext)?, item.clone()))
             })
             .collect::<Result<_>>()?;
@@ -783,14 +1445,14 @@ fn sort_operator(
             // sort numbers
             eval_pairs.sort_by(|a, b| {
                 // unwraps are ok because we checked the types above
-                let a = a.0.as_f64().unwrap();
-                let b = b.0.as_f64().unwrap();
+                let a = a.0.as_number().unwrap();
+                let b = b.0.as_number().unwrap();
                 // unwrap is ok because we do not deal with NaN
-                a.partial_cmp(b).unwrap()
+                a.partial_cmp(&b).unwrap()
             });
         } else {
             // either a mix of types or unsortable values
-            return make_err();
+            return Err(sort_error(eval_pairs.iter().map(|(e, _v)| e)));
         }
         let result = eval_pairs
             .into_iter()
@@ -798,26 +1460,35 @@ fn sort_operator(
             .collect();
         Ok(Value::Array(result))
     } else {
-        make_err()
+        Err(sort_error(std::iter::once(&rendered)))
     }
 }
 
-fn sort_operator_without_by(
-    operator: &str,
-    mut arr: Vec<Value>,
-    object: &Object,
-    context: &Context,
-) -> Result<Value> {
-    let make_err = || {
-        Err(template_error!(
-            "$sorted values to be sorted must have the same type"
-        ))
-    };
+/// The error for a `$sort` of the given values, which cannot be sorted together
+fn sort_error<'a>(values: impl Iterator<Item = &'a Value>) -> Error {
+    let mut actual: Vec<String> = Vec::new();
+    for v in values {
+        if !actual.iter().any(|t| t == v.type_name()) {
+            actual.push(v.type_name().to_owned());
+        }
+    }
+    error_of_kind!(
+        ErrorKind::Type {
+            operation: "$sort".into(),
+            expected: "array of strings or array of numbers".into(),
+            actual,
+        },
+        "$sorted values to be sorted must have the same type"
+    )
+}
+
+fn sort_operator_without_by(mut arr: Vec<Value>) -> Result<Value> {
+    let make_err = |arr: &[Value]| Err(sort_error(arr.iter()));
     match arr[0] {
         Value::String(_) => {
             for i in &arr {
                 if !i.is_string() {
-                    return make_err();
+                    return make_err(&arr);
                 }
             }
 
@@ -827,25 +1498,25 @@ fn sort_operator_without_by(
                 let b = b.as_str().unwrap();
                 a.cmp(b)
             });
-            Ok(Value::Array(arr))
+            Ok(Value::Array(arr.into()))
         }
         Value::Number(_) => {
             for i in &arr {
                 if !i.is_number() {
-                    return make_err();
+                    return make_err(&arr);
                 }
             }
 
             arr.sort_by(|a, b| {
                 // unwraps are ok because we checked the types above
-                let a = a.as_f64().unwrap();
-                let b = b.as_f64().unwrap();
+                let a = a.as_number().unwrap();
+                let b = b.as_number().unwrap();
                 // unwrap is ok because we do not deal with NaN
-                a.partial_cmp(b).unwrap()
+                a.partial_cmp(&b).unwrap()
             });
-            Ok(Value::Array(arr))
+            Ok(Value::Array(arr.into()))
         }
-        _ => make_err(),
+        _ => make_err(&arr),
     }
 }
 
@@ -947,57 +1618,1058 @@ mod tests {
         )
     }
 
-    mod check_operator_properties {
-        use super::super::{check_operator_properties, Object};
-        use crate::value::Value;
+    mod functions {
+        use crate::{ErrorKind, Renderer};
+        use serde_json::{json, Value};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        #[test]
+        fn call_custom_function() {
+            let renderer = Renderer::new().function("double", |args: &[Value]| {
+                Ok(json!(args[0].as_f64().unwrap() * 2.0))
+            });
+            let template = json!({"$eval": "double(x) + 1"});
+            assert_eq!(
+                renderer.render(&template, &json!({"x": 4})).unwrap(),
+                json!(9)
+            );
+        }
+
+        #[test]
+        fn custom_function_gets_json_arguments() {
+            let renderer = Renderer::new().function("args", |args: &[Value]| Ok(json!(args)));
+            let template = json!({"$eval": "args(1, 'two', [3], {four: 4}, null)"});
+            assert_eq!(
+                renderer.render(&template, &json!({})).unwrap(),
+                json!([1, "two", [3], {"four": 4}, null])
+            );
+        }
+
+        #[test]
+        fn custom_function_captures_state() {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let counter = calls.clone();
+            let renderer = Renderer::new().function("count", move |_: &[Value]| {
+                Ok(json!(counter.fetch_add(1, Ordering::SeqCst)))
+            });
+            let template = json!(["${count()}", "${count()}"]);
+            assert_eq!(
+                renderer.render(&template, &json!({})).unwrap(),
+                json!(["0", "1"])
+            );
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+        }
+
+        #[test]
+        fn custom_function_is_a_function() {
+            let renderer = Renderer::new().function("f", |_: &[Value]| Ok(json!(null)));
+            let template = json!({"$eval": "typeof(f)"});
+            assert_eq!(
+                renderer.render(&template, &json!({})).unwrap(),
+                json!("function")
+            );
+        }
+
+        #[test]
+        fn custom_function_overrides_builtin() {
+            let renderer = Renderer::new().function("len", |_: &[Value]| Ok(json!(42)));
+            let template = json!({"$eval": "len('abc')"});
+            assert_eq!(renderer.render(&template, &json!({})).unwrap(), json!(42));
+        }
+
+        #[test]
+        fn context_overrides_custom_function() {
+            let renderer = Renderer::new().function("f", |_: &[Value]| Ok(json!(null)));
+            let template = json!({"$eval": "f"});
+            assert_eq!(
+                renderer.render(&template, &json!({"f": 10})).unwrap(),
+                json!(10)
+            );
+        }
+
+        #[test]
+        fn custom_function_error() {
+            let renderer =
+                Renderer::new().function("fail", |_: &[Value]| Err("oh no".into()));
+            let template = json!({"$eval": "fail()"});
+            let err = renderer.render(&template, &json!({})).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::Function {
+                    name: "fail".into()
+                }
+            );
+            assert_eq!(err.message(), "oh no");
+        }
+
+        #[test]
+        fn function_arguments_cannot_be_functions() {
+            let renderer = Renderer::new().function("f", |_: &[Value]| Ok(json!(null)));
+            let template = json!({"$eval": "f(f)"});
+            assert!(renderer.render(&template, &json!({})).is_err());
+        }
+
+        #[test]
+        #[should_panic]
+        fn invalid_function_name() {
+            Renderer::new().function("not-an-identifier", |_: &[Value]| Ok(json!(null)));
+        }
+    }
+
+    mod compiled {
+        use crate::Renderer;
+        use serde_json::json;
+
+        #[test]
+        fn render_compiled_template_repeatedly() {
+            let compiled = Renderer::new().compile(&json!({
+                "greeting": "hello ${name}",
+                "$$escaped": {"$eval": "name"},
+                "items": {"$map": {"$eval": "items"}, "each(x)": {"$eval": "x * 2"}},
+            }));
+            assert_eq!(
+                compiled
+                    .render(&json!({"name": "world", "items": [1, 2]}))
+                    .unwrap(),
+                json!({"greeting": "hello world", "$escaped": "world", "items": [2, 4]})
+            );
+            assert_eq!(
+                compiled
+                    .render(&json!({"name": "again", "items": []}))
+                    .unwrap(),
+                json!({"greeting": "hello again", "$escaped": "again", "items": []})
+            );
+        }
+
+        #[test]
+        fn compiled_errors_are_raised_on_render() {
+            let compiled = Renderer::new().compile(&json!({"$eval": "a +"}));
+            assert!(compiled.render(&json!({})).is_err());
+        }
+
+        #[test]
+        fn compiled_errors_in_untaken_branch_are_ignored() {
+            let compiled = Renderer::new().compile(&json!({
+                "$if": "x",
+                "then": {"$unknown": 1},
+                "else": "ok",
+            }));
+            assert_eq!(compiled.render(&json!({"x": false})).unwrap(), json!("ok"));
+            assert!(compiled.render(&json!({"x": true})).is_err());
+        }
+
+        #[test]
+        fn compiled_template_keeps_functions() {
+            let renderer = Renderer::new().function("one", |_| Ok(json!(1)));
+            let compiled = renderer.compile(&json!({"$eval": "one()"}));
+            drop(renderer);
+            assert_eq!(compiled.render(&json!({})).unwrap(), json!(1));
+        }
+    }
+
+    mod operators {
+        use crate::{CustomOperator, Error, ErrorKind, Invocation, Renderer};
+        use serde_json::{json, Map, Value};
+
+        /// `{"$repeat": <value>, "times": <number>}` renders to an array of copies of the value
+        struct Repeat;
+
+        impl CustomOperator for Repeat {
+            fn allows_property(&self, property: &str) -> bool {
+                property == "times"
+            }
+
+            fn render(&self, invocation: &Invocation) -> Result<Option<Value>, Error> {
+                let times = match invocation.render_property("times")? {
+                    Some(Value::Number(n)) if n.is_u64() => n.as_u64().unwrap(),
+                    _ => return Err(invocation.error("times must be a non-negative integer")),
+                };
+                Ok(invocation
+                    .render_value()?
+                    .map(|value| Value::Array(vec![value; times as usize])))
+            }
+        }
+
+        /// `{"$with": <expression>, "as(x)": <template>}` renders the template with `x` bound to
+        /// the value of the expression
+        fn with(invocation: &Invocation) -> Result<Option<Value>, Error> {
+            let expression = match invocation.render_value()? {
+                Some(Value::String(s)) => s,
+                _ => return Err(invocation.error("expected an expression")),
+            };
+            let value = invocation.evaluate(&expression)?;
+            let mut bindings = Map::new();
+            bindings.insert("x".into(), value);
+            invocation.render_property_with("as(x)", &bindings)
+        }
+
+        struct With;
 
-        fn map(mut keys: Vec<&str>) -> Object {
-            let mut map = Object::new();
-            for key in keys.drain(..) {
-                map.insert(key.into(), Value::Null);
+        impl CustomOperator for With {
+            fn allows_property(&self, property: &str) -> bool {
+                property == "as(x)"
             }
-            map
+
+            fn render(&self, invocation: &Invocation) -> Result<Option<Value>, Error> {
+                with(invocation)
+            }
+        }
+
+        fn renderer() -> Renderer {
+            Renderer::new()
+                .operator("$repeat", Repeat)
+                .operator("$with", With)
+                .operator("$nothing", |_: &Invocation| Ok(None))
         }
 
         #[test]
-        fn single_property_is_ok() -> anyhow::Result<()> {
-            check_operator_properties("$foo", &map(vec!["$foo"]), |_| false)
+        fn operator_with_properties() {
+            let template = json!({"a": {"$repeat": "${x}", "times": {"$eval": "1 + 1"}}});
+            assert_eq!(
+                renderer().render(&template, &json!({"x": 1})).unwrap(),
+                json!({"a": ["1", "1"]})
+            );
         }
 
         #[test]
-        fn allowed_properties_are_ok() -> anyhow::Result<()> {
-            check_operator_properties("$foo", &map(vec!["$foo", "a", "b"]), |k| {
-                k == "a" || k == "b"
-            })
+        fn child_context() {
+            let template = json!({"$with": "a.b", "as(x)": {"$eval": "x + y"}});
+            assert_eq!(
+                renderer()
+                    .render(&template, &json!({"a": {"b": 1}, "y": 2}))
+                    .unwrap(),
+                json!(3)
+            );
+        }
+
+        #[test]
+        fn deletion_marker() {
+            let template = json!([1, {"$nothing": null}, 2]);
+            assert_eq!(
+                renderer().render(&template, &json!({})).unwrap(),
+                json!([1, 2])
+            );
+        }
+
+        #[test]
+        fn undefined_properties() {
+            let template = json!({"$repeat": 1, "times": 2, "twice": true});
+            let err = renderer().render(&template, &json!({})).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::Template {
+                    operator: Some("$repeat".into())
+                }
+            );
+            assert_eq!(err.message(), "$repeat has undefined properties: twice");
         }
 
         #[test]
-        fn missing_allowed_properties_are_ok() -> anyhow::Result<()> {
-            check_operator_properties("$foo", &map(vec!["$foo", "b"]), |k| k == "a" || k == "b")
+        fn operator_error() {
+            let template = json!({"a": {"$repeat": 1, "times": -1}});
+            let err = renderer().render(&template, &json!({})).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::Operator {
+                    name: "$repeat".into()
+                }
+            );
+            assert_eq!(err.location().pointer(), "/a/$repeat");
         }
 
         #[test]
-        fn disalloewd_properties_not_ok() {
-            assert_template_error!(
-                check_operator_properties("$foo", &map(vec!["$foo", "nosuch"]), |k| k == "a"),
-                "$foo has undefined properties: nosuch",
+        fn error_location_in_property() {
+            let template = json!({"$with": "1", "as(x)": {"$eval": "x + z"}});
+            let err = renderer().render(&template, &json!({})).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::UnknownContextValue { name: "z".into() }
             );
+            assert_eq!(err.location().pointer(), "/$with/as(x)/$eval");
         }
 
         #[test]
-        fn disalloewd_properties_sorted() {
-            assert_template_error!(
-                check_operator_properties("$foo", &map(vec!["$foo", "a", "b", "c", "d"]), |k| k
-                    == "a"),
-                "$foo has undefined properties: b c d",
+        fn unregistered_operator_is_reserved() {
+            let template = json!({"$repeat": 1, "times": 2});
+            assert!(Renderer::new().render(&template, &json!({})).is_err());
+        }
+
+        #[test]
+        fn replaces_builtin() {
+            let renderer = Renderer::new().operator("$reverse", |_: &Invocation| Ok(Some(json!("no"))));
+            let template = json!({"$reverse": [1, 2]});
+            assert_eq!(renderer.render(&template, &json!({})).unwrap(), json!("no"));
+        }
+
+        #[test]
+        fn in_found_value() {
+            let template = json!({"$find": {"$eval": "items"}, "each(i)": "true"});
+            let context = json!({"items": [{"$repeat": "x", "times": 2}]});
+            assert_eq!(
+                renderer().render(&template, &context).unwrap(),
+                json!(["x", "x"])
             );
         }
+
+        #[test]
+        #[should_panic]
+        fn invalid_name() {
+            Renderer::new().operator("repeat", Repeat);
+        }
     }
 
-    mod interpolate {
-        use super::super::interpolate;
+    mod includes {
+        use crate::{ErrorKind, Invocation, Loader, MemoryLoader, Renderer};
+        use serde_json::{json, Value};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        fn renderer() -> Renderer {
+            Renderer::new().loader(
+                MemoryLoader::new()
+                    .template("greeting", json!("hello, ${name}"))
+                    .template("labels", json!({"app": "${app}", "tier": {"$include": "tier"}}))
+                    .template("tier", json!("${tier}"))
+                    .template("a", json!({"$include": "b"}))
+                    .template("b", json!([{"$include": "a"}]))
+                    .template("self", json!({"x": {"$include": "self"}}))
+                    .template("twice", json!([{"$twice": 1}]))
+                    .template("bad", json!({"a": {"$eval": "x +"}})),
+            )
+        }
+
+        #[test]
+        fn include() {
+            let template = json!({"message": {"$include": "greeting"}});
+            assert_eq!(
+                renderer().render(&template, &json!({"name": "world"})).unwrap(),
+                json!({"message": "hello, world"})
+            );
+        }
+
+        #[test]
+        fn include_with() {
+            let template = json!({"$include": "labels", "with": {"app": "web", "tier": "${t}"}});
+            assert_eq!(
+                renderer()
+                    .render(&template, &json!({"t": "front", "app": "ignored"}))
+                    .unwrap(),
+                json!({"app": "web", "tier": "front"})
+            );
+        }
+
+        #[test]
+        fn dynamic_name() {
+            let template = json!({"$include": "${n}", "with": {"tier": 3}});
+            assert_eq!(
+                renderer().render(&template, &json!({"n": "tier"})).unwrap(),
+                json!("3")
+            );
+        }
+
+        #[test]
+        fn same_template_twice() {
+            let template = json!([{"$include": "tier"}, {"$include": "tier", "with": {"tier": 2}}]);
+            assert_eq!(
+                renderer().render(&template, &json!({"tier": 1})).unwrap(),
+                json!(["1", "2"])
+            );
+        }
 
+        #[test]
+        fn not_found() {
+            let template = json!({"x": {"$include": "missing"}});
+            let err = renderer().render(&template, &json!({})).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::Include {
+                    name: "missing".into()
+                }
+            );
+            assert_eq!(err.location().pointer(), "/x/$include");
+        }
+
+        #[test]
+        fn cycle() {
+            let template = json!({"$include": "a"});
+            let err = renderer().render(&template, &json!({})).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::IncludeCycle {
+                    names: vec!["a".into(), "b".into(), "a".into()]
+                }
+            );
+            assert_eq!(err.message(), "$include cycle: a -> b -> a");
+        }
+
+        #[test]
+        fn self_cycle() {
+            let template = json!({"$include": "self"});
+            let err = renderer().render(&template, &json!({})).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::IncludeCycle {
+                    names: vec!["self".into(), "self".into()]
+                }
+            );
+        }
+
+        #[test]
+        fn error_in_included_template() {
+            let template = json!({"$include": "bad"});
+            let err = renderer().render(&template, &json!({})).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::Syntax);
+            assert_eq!(err.location().pointer(), "/$include/a/$eval");
+        }
+
+        #[test]
+        fn invalid_values() {
+            let err = renderer()
+                .render(&json!({"$include": 1}), &json!({}))
+                .unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::Type { .. }));
+            let err = renderer()
+                .render(&json!({"$include": "tier", "with": [1]}), &json!({}))
+                .unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::Type { .. }));
+            let err = renderer()
+                .render(&json!({"$include": "tier", "also": 1}), &json!({}))
+                .unwrap_err();
+            assert_eq!(err.message(), "$include has undefined properties: also");
+        }
+
+        #[test]
+        fn without_loader() {
+            let err = Renderer::new()
+                .render(&json!({"$include": "tier"}), &json!({}))
+                .unwrap_err();
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::Template {
+                    operator: Some("$include".into())
+                }
+            );
+        }
+
+        #[test]
+        fn custom_operators_in_included_template() {
+            // the operator is added after the loader
+            let renderer = renderer().operator("$twice", |inv: &Invocation| {
+                Ok(inv.render_value()?.map(|v| json!([v.clone(), v])))
+            });
+            assert_eq!(
+                renderer
+                    .render(&json!({"$include": "twice"}), &json!({}))
+                    .unwrap(),
+                json!([[1, 1]])
+            );
+        }
+
+        /// A loader that counts the templates it loads
+        struct Counting(Arc<AtomicUsize>);
+
+        impl Loader for Counting {
+            fn load(
+                &self,
+                name: &str,
+            ) -> Result<Option<Value>, Box<dyn std::error::Error + Send + Sync>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Ok(Some(json!(name)))
+            }
+        }
+
+        #[test]
+        fn templates_are_cached() {
+            let loads = Arc::new(AtomicUsize::new(0));
+            let renderer = Renderer::new().loader(Counting(loads.clone()));
+            let compiled = renderer.compile(&json!([{"$include": "x"}, {"$include": "x"}]));
+            for _ in 0..3 {
+                assert_eq!(compiled.render(&json!({})).unwrap(), json!(["x", "x"]));
+            }
+            renderer
+                .render(&json!({"$include": "x"}), &json!({}))
+                .unwrap();
+            assert_eq!(loads.load(Ordering::SeqCst), 1);
+        }
+
+        #[test]
+        fn loader_errors() {
+            struct Failing;
+            impl Loader for Failing {
+                fn load(
+                    &self,
+                    _: &str,
+                ) -> Result<Option<Value>, Box<dyn std::error::Error + Send + Sync>> {
+                    Err("uhoh".into())
+                }
+            }
+            let err = Renderer::new()
+                .loader(Failing)
+                .render(&json!({"$include": "x"}), &json!({}))
+                .unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::Include { name: "x".into() });
+            assert_eq!(
+                err.message(),
+                "could not load template \"x\" to include: uhoh"
+            );
+        }
+    }
+
+    mod typed {
+        use crate::{ErrorKind, Renderer};
+        use serde::{Deserialize, Serialize};
+        use serde_json::json;
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize)]
+        struct Config {
+            name: String,
+            replicas: u32,
+            ports: Vec<u16>,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Deployment {
+            metadata: Metadata,
+            spec: Spec,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Metadata {
+            name: String,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Spec {
+            replicas: u32,
+            containers: Vec<Container>,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Container {
+            port: u16,
+        }
+
+        fn config() -> Config {
+            Config {
+                name: "web".into(),
+                replicas: 2,
+                ports: vec![80, 443],
+            }
+        }
+
+        #[test]
+        fn render_as() {
+            let template = json!({
+                "metadata": {"name": "${name}"},
+                "spec": {
+                    "replicas": {"$eval": "replicas"},
+                    "containers": {"$map": {"$eval": "ports"}, "each(p)": {"port": {"$eval": "p"}}},
+                },
+            });
+            let deployment: Deployment = Renderer::new().render_as(&template, &config()).unwrap();
+            assert_eq!(
+                deployment,
+                Deployment {
+                    metadata: Metadata { name: "web".into() },
+                    spec: Spec {
+                        replicas: 2,
+                        containers: vec![Container { port: 80 }, Container { port: 443 }],
+                    },
+                }
+            );
+        }
+
+        #[test]
+        fn deserialize_error_path() {
+            let template = json!({
+                "metadata": {"name": "${name}"},
+                "spec": {
+                    "replicas": {"$eval": "replicas"},
+                    "containers": [{"port": 80}, {"port": "${name}"}],
+                },
+            });
+            let err = Renderer::new()
+                .render_as::<_, Deployment>(&template, &config())
+                .unwrap_err();
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::Deserialize {
+                    path: "/spec/containers/1/port".into()
+                }
+            );
+            assert!(err
+                .message()
+                .starts_with("rendered value could not be deserialized at /spec/containers/1/port: "));
+        }
+
+        #[test]
+        fn deserialize_error_at_root() {
+            let err = Renderer::new()
+                .render_as::<_, u32>(&json!("x"), &json!({}))
+                .unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::Deserialize { path: "".into() });
+        }
+
+        #[test]
+        fn deserialize_error_at_unknown_location() {
+            // a key that cannot be deserialized is not known to the path, so no location is
+            // given rather than that of its map
+            let err = Renderer::new()
+                .render_as::<_, BTreeMap<String, BTreeMap<u32, u32>>>(
+                    &json!({"a": {"1": 1, "x": 2}}),
+                    &json!({}),
+                )
+                .unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::Deserialize { path: "".into() });
+            assert!(err
+                .message()
+                .starts_with("rendered value could not be deserialized: "));
+        }
+
+        #[test]
+        fn context_must_be_an_object() {
+            let err = Renderer::new()
+                .render_as::<_, u32>(&json!(1), &[1, 2])
+                .unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::InvalidContext);
+        }
+
+        #[test]
+        fn unserializable_context() {
+            let mut context = BTreeMap::new();
+            context.insert(vec![1], 1);
+            let err = Renderer::new()
+                .render_as::<_, u32>(&json!(1), &context)
+                .unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::InvalidContext);
+        }
+    }
+
+    mod clock {
+        use crate::Renderer;
+        use serde_json::json;
+        use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+        fn at_millis(millis: u64) -> SystemTime {
+            UNIX_EPOCH + Duration::from_millis(millis)
+        }
+
+        #[test]
+        fn fixed_now() {
+            let renderer = Renderer::new().now(at_millis(1484843240974));
+            let template = json!({
+                "now": {"$eval": "now"},
+                "op": {"$fromNow": "1 day"},
+                "builtin": {"$eval": "fromNow('2 hours')"},
+            });
+            assert_eq!(
+                renderer.render(&template, &json!({})).unwrap(),
+                json!({
+                    "now": "2017-01-19T16:27:20.974Z",
+                    "op": "2017-01-20T16:27:20.974Z",
+                    "builtin": "2017-01-19T18:27:20.974Z",
+                })
+            );
+        }
+
+        #[test]
+        fn clocks_are_per_renderer() {
+            let template = json!({"$eval": "now"});
+            let pinned = Renderer::new().now(UNIX_EPOCH);
+            let real = Renderer::new();
+            assert_eq!(
+                pinned.render(&template, &json!({})).unwrap(),
+                json!("1970-01-01T00:00:00.000Z")
+            );
+            assert_ne!(
+                real.render(&template, &json!({})).unwrap(),
+                json!("1970-01-01T00:00:00.000Z")
+            );
+        }
+
+        #[test]
+        fn render_at() {
+            let compiled = Renderer::new()
+                .now(UNIX_EPOCH)
+                .compile(&json!({"$fromNow": "5 minutes"}));
+            assert_eq!(
+                compiled.render_at(&json!({}), at_millis(1000)).unwrap(),
+                json!("1970-01-01T00:05:01.000Z")
+            );
+            assert_eq!(
+                compiled.render(&json!({})).unwrap(),
+                json!("1970-01-01T00:05:00.000Z")
+            );
+        }
+    }
+
+    mod locations {
+        use crate::{Error, ErrorKind, Renderer};
+        use serde_json::{json, Value};
+
+        fn render_err(template: Value, context: Value) -> Error {
+            Renderer::new().render(&template, &context).unwrap_err()
+        }
+
+        #[test]
+        fn nested_path() {
+            let err = render_err(
+                json!({"spec": {"containers": [1, 2, 3, {
+                    "$map": [1],
+                    "each(x)": {"$eval": "x.y"},
+                }]}}),
+                json!({}),
+            );
+            assert_eq!(err.message(), "dot operator expects an object");
+            assert_eq!(
+                err.location().pointer(),
+                "/spec/containers/3/$map/each(x)/$eval"
+            );
+            assert_eq!(err.location().offset(), Some(0));
+        }
+
+        #[test]
+        fn operator_error_at_operator() {
+            let err = render_err(json!({"a": {"$map": 1, "each(x)": 2}}), json!({}));
+            assert_eq!(err.location().path(), &["a", "$map"]);
+            assert_eq!(err.location().offset(), None);
+        }
+
+        #[test]
+        fn deferred_error_at_operator() {
+            let err = render_err(json!([{"$if": "true", "then": {"$bogus": 1}}]), json!({}));
+            assert_eq!(err.location().pointer(), "/0/$if/then/$bogus");
+        }
+
+        #[test]
+        fn interpolation_offset() {
+            let err = render_err(json!({"a": "abc ${x} ${y}"}), json!({"x": 1}));
+            assert_eq!(err.message(), "unknown context value y");
+            assert_eq!(err.location().pointer(), "/a");
+            assert_eq!(err.location().offset(), Some(11));
+        }
+
+        #[test]
+        fn interpolation_syntax_error_offset() {
+            let err = render_err(json!(["${1 +}"]), json!({}));
+            assert_eq!(err.kind(), &ErrorKind::Syntax);
+            assert_eq!(err.location().pointer(), "/0");
+            assert_eq!(err.location().offset(), Some(4));
+        }
+
+        #[test]
+        fn eval_syntax_error_offset() {
+            let err = render_err(json!({"$eval": "a b"}), json!({}));
+            assert_eq!(err.kind(), &ErrorKind::Syntax);
+            assert_eq!(err.message(), "Unexpected trailing characters b");
+            assert_eq!(err.location().pointer(), "/$eval");
+            assert_eq!(err.location().offset(), Some(2));
+        }
+
+        #[test]
+        fn match_condition_location() {
+            let err = render_err(json!({"$match": {"x +": 1}}), json!({}));
+            assert_eq!(err.location().path(), &["$match", "x +"]);
+        }
+
+        #[test]
+        fn display_includes_location() {
+            let err = render_err(json!({"a": ["${x}"]}), json!({}));
+            assert_eq!(
+                format!("{}", err),
+                "unknown context value x at /a/0 (offset 2)"
+            );
+        }
+    }
+
+    mod error_kinds {
+        use crate::{Error, ErrorKind, Renderer};
+        use serde_json::{json, Value};
+
+        fn render_err(template: Value, context: Value) -> Error {
+            Renderer::new().render(&template, &context).unwrap_err()
+        }
+
+        #[test]
+        fn unknown_context_value() {
+            let err = render_err(json!({"$eval": "a + 1"}), json!({}));
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::UnknownContextValue { name: "a".into() }
+            );
+        }
+
+        #[test]
+        fn operator_type() {
+            let err = render_err(json!({"$eval": "a + 1"}), json!({"a": [1]}));
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::Type {
+                    operation: "+".into(),
+                    expected: "numbers or strings".into(),
+                    actual: vec!["array".into(), "number".into()],
+                }
+            );
+        }
+
+        #[test]
+        fn template_operator_type() {
+            let err = render_err(json!({"$reverse": {"a": 1}}), json!({}));
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::Type {
+                    operation: "$reverse".into(),
+                    expected: "array".into(),
+                    actual: vec!["object".into()],
+                }
+            );
+        }
+
+        #[test]
+        fn sort_mixed_types() {
+            let err = render_err(json!({"$sort": [1, "two", 3, true]}), json!({}));
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::Type {
+                    operation: "$sort".into(),
+                    expected: "array of strings or array of numbers".into(),
+                    actual: vec!["number".into(), "string".into(), "boolean".into()],
+                }
+            );
+        }
+
+        #[test]
+        fn builtin_argument() {
+            let err = render_err(json!({"$eval": "lowercase(1)"}), json!({}));
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::BuiltinArgument {
+                    function: "lowercase".into()
+                }
+            );
+        }
+
+        #[test]
+        fn template_structure() {
+            let err = render_err(json!({"$map": [], "each(x)": 1, "x": 2}), json!({}));
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::Template {
+                    operator: Some("$map".into())
+                }
+            );
+            assert_eq!(err.message(), "$map has undefined properties: x");
+        }
+
+        #[test]
+        fn reserved_operator() {
+            let err = render_err(json!({"$nosuch": 1}), json!({}));
+            assert_eq!(
+                err.kind(),
+                &ErrorKind::Template {
+                    operator: Some("$nosuch".into())
+                }
+            );
+        }
+
+        #[test]
+        fn invalid_context() {
+            let err = render_err(json!(1), json!({"not an identifier": 1}));
+            assert_eq!(err.kind(), &ErrorKind::InvalidContext);
+        }
+    }
+
+    mod limits {
         use crate::interpreter::Context;
+        use crate::render::_render;
+        use crate::template::Template;
+        use crate::{ErrorKind, Limits, Renderer};
+        use serde_json::{json, Value};
+        use std::sync::Arc;
+
+        fn render_limited(limits: Limits, template: Value) -> Result<Value, crate::Error> {
+            Renderer::new()
+                .limits(limits)
+                .render(&template, &json!({}))
+        }
+
+        fn nested(depth: usize) -> Value {
+            let mut template = json!(1);
+            for _ in 0..depth {
+                // (the json! macro would recurse into the template)
+                let mut object = serde_json::Map::new();
+                object.insert("$let".into(), json!({}));
+                object.insert("in".into(), Value::Array(vec![template]));
+                template = Value::Object(object);
+            }
+            template
+        }
+
+        fn nested_arrays(depth: usize) -> Value {
+            (0..depth).fold(json!(1), |v, _| Value::Array(vec![v]))
+        }
+
+        #[test]
+        fn default_limits() {
+            // templates and expressions deeper than any in practice render with the defaults
+            assert!(render_limited(Limits::default(), nested(40)).is_ok());
+            assert!(render_limited(Limits::default(), nested_arrays(150)).is_ok());
+            let expression = format!("{}1{}", "[".repeat(50), "]".repeat(50));
+            assert!(render_limited(Limits::default(), json!({"$eval": expression})).is_ok());
+            let chain = vec!["1"; 200].join(" + ");
+            let result = render_limited(Limits::default(), json!({"$eval": chain})).unwrap();
+            assert_eq!(result, json!(200));
+        }
+
+        #[test]
+        fn default_stack() {
+            // the stack limit is reached before the stack overflows
+            let limit = ErrorKind::StackLimitExceeded { limit: 1 << 20 };
+            let err = render_limited(Limits::default(), nested(1000)).unwrap_err();
+            assert_eq!(err.kind(), &limit);
+
+            let expression = format!("{}1{}", "[".repeat(1000), "]".repeat(1000));
+            let err = render_limited(Limits::default(), json!({"$eval": expression})).unwrap_err();
+            assert_eq!(err.kind(), &limit);
+            assert_eq!(err.location().pointer(), "/$eval");
+
+            // a value too deep to convert is rejected, at its location
+            let err = render_limited(Limits::default(), nested_arrays(2000)).unwrap_err();
+            assert_eq!(err.kind(), &limit);
+            assert_eq!(err.location().pointer(), "/0".repeat(256));
+
+            // as is a long chain of operations, even with a low depth limit
+            let chain = vec!["1"; 100_000].join("+");
+            let limits = Limits::default().max_depth(64).max_steps(1000);
+            let err = render_limited(limits, json!({"$eval": chain})).unwrap_err();
+            assert_eq!(err.kind(), &limit);
+        }
+
+        #[test]
+        fn max_depth() {
+            let err = render_limited(Limits::default().max_depth(3), json!([[[1]]])).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::DepthLimitExceeded { limit: 3 });
+            assert!(render_limited(Limits::default().max_depth(4), json!([[[1]]])).is_ok());
+
+            let template = json!({"a": {"$eval": "[[[1]]]"}});
+            let err = render_limited(Limits::default().max_depth(4), template).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::DepthLimitExceeded { limit: 4 });
+            assert_eq!(err.location().pointer(), "/a/$eval");
+
+            // a chain of operations counts as a single level
+            let template = json!({"a": {"$eval": "x.y.z + 1 + 2 + 3"}});
+            let context = json!({"x": {"y": {"z": 0}}});
+            let renderer = Renderer::new().limits(Limits::default().max_depth(4));
+            assert_eq!(renderer.render(&template, &context).unwrap(), json!({"a": 6}));
+        }
+
+        #[test]
+        fn context_depth() {
+            // the default stack limit does not apply to context values
+            let context = json!({"x": nested_arrays(300)});
+            let template = json!({"$eval": "x"});
+            let renderer = Renderer::new().base_context(&context).unwrap();
+            assert_eq!(renderer.render(&template, &context).unwrap(), context["x"]);
+
+            // while a given one does
+            let limits = Limits::default().max_stack(1 << 20);
+            let err = Renderer::new().limits(limits).render(&template, &context).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::StackLimitExceeded { limit: 1 << 20 });
+            let err = renderer.limits(limits).render(&template, &json!({})).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::StackLimitExceeded { limit: 1 << 20 });
+        }
+
+        #[test]
+        fn max_steps() {
+            let template = json!({"$map": {"$eval": "range(0, 1000)"}, "each(x)": {"$map": {"$eval": "range(0, 1000)"}, "each(y)": "${x * y}"}});
+            let err = render_limited(Limits::default().max_steps(10_000), template).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::StepLimitExceeded { limit: 10_000 });
+        }
+
+        #[test]
+        fn max_output_nodes() {
+            let template = json!({"$map": {"$eval": "range(0, 100)"}, "each(x)": {"$eval": "range(0, 100)"}});
+            let err = render_limited(Limits::default().max_output_nodes(5000), template).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::OutputNodeLimitExceeded { limit: 5000 });
+        }
+
+        #[test]
+        fn max_string_length() {
+            let template = json!({"$reduce": {"$eval": "range(0, 64)"}, "initial": "x", "each(acc, i)": {"$eval": "acc + acc"}});
+            let err = render_limited(Limits::default().max_string_length(1000), template).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::StringLengthLimitExceeded { limit: 1000 });
+        }
+
+        #[test]
+        fn max_string_length_interpolation() {
+            let template = json!({"$let": {"s": "0123456789"}, "in": "${s}${s}${s}"});
+            let err = render_limited(Limits::default().max_string_length(25), template).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::StringLengthLimitExceeded { limit: 25 });
+        }
+
+        #[test]
+        fn max_array_length() {
+            let template = json!({"$eval": "range(0, 1000000000)"});
+            let err = render_limited(Limits::default().max_array_length(1000), template).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::ArrayLengthLimitExceeded { limit: 1000 });
+            let template = json!({"$eval": "range(0, 1000, 1)"});
+            assert!(render_limited(Limits::default().max_array_length(1000), template).is_ok());
+        }
+
+        #[test]
+        fn find_without_budget() {
+            // the found value is compiled with the renderer's limits, even without a budget
+            let template = json!({"$find": [[[[1]]]], "each(x)": "true"});
+            let limits = Limits::default().max_depth(3);
+            let compiled = Template::compile_root(&template, limits, &Arc::default());
+            let err = _render(&compiled, &Context::new()).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::DepthLimitExceeded { limit: 3 });
+        }
+
+        #[test]
+        fn range_extremes() {
+            let (min, max) = (i64::MIN, i64::MAX);
+            let range = |args: String, limits: Limits| {
+                let template = json!({"$eval": format!("range({})", args)});
+                render_limited(limits.max_array_length(1000), template)
+            };
+            for args in [format!("{}, {}", min, max), format!("{}, {}, -1", max, min)] {
+                let err = range(args, Limits::default()).unwrap_err();
+                assert_eq!(err.kind(), &ErrorKind::ArrayLengthLimitExceeded { limit: 1000 });
+            }
+            let cases = [
+                (format!("{}, {}", max - 2, max), json!([max - 2, max - 1])),
+                (format!("{}, {}, -1", min + 2, min), json!([min + 2, min + 1])),
+                (format!("{}, {}, {}", min, max, max), json!([min, -1, max - 1])),
+                (format!("{}, {}, {}", max, min, min), json!([max, -1])),
+                (format!("0, {}, {}", max, min), json!([])),
+                (format!("0, {}, {}", min, max), json!([])),
+            ];
+            for (args, expected) in cases {
+                assert_eq!(range(args, Limits::default()).unwrap(), expected);
+            }
+        }
+
+        #[test]
+        fn limit_in_condition() {
+            let template = json!({"$switch": {"x == 1": 1}});
+            let err = render_limited(Limits::default().max_steps(2), template).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::StepLimitExceeded { limit: 2 });
+        }
+    }
+
+    mod interpolate {
+        use crate::interpreter::Context;
+        use crate::limits::{Budget, Limits};
+        use crate::template::Interpolation;
+
+        fn interpolate(source: &str, context: &Context) -> crate::errors::Result<String> {
+            let budget = Budget::new(Limits::default());
+            super::super::interpolate(&Interpolation::compile(source, &budget), context)
+        }
+
         #[test]
         fn plain_string() {
             let context = Context::new();
@@ -1047,6 +2719,254 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "preserve_order")]
+    mod key_order {
+        use crate::render;
+        use serde_json::{json, Value};
+
+        /// Render the template, giving the compact JSON of the result, which shows its key order
+        fn render_json(template: &str, context: &str) -> String {
+            let template: Value = serde_json::from_str(template).unwrap();
+            let context: Value = serde_json::from_str(context).unwrap();
+            serde_json::to_string(&render(&template, &context).unwrap()).unwrap()
+        }
+
+        #[test]
+        fn template_and_context_order() {
+            assert_eq!(
+                render_json(
+                    r#"{"kind": "Pod", "${k}": 1, "apiVersion": "v1", "spec": {"$eval": "spec"}}"#,
+                    r#"{"k": "metadata", "spec": {"z": 1, "a": 2}}"#
+                ),
+                r#"{"kind":"Pod","metadata":1,"apiVersion":"v1","spec":{"z":1,"a":2}}"#
+            );
+        }
+
+        #[test]
+        fn merge_order() {
+            // keys are in the order they first appear, with later values replacing earlier ones
+            assert_eq!(
+                render_json(r#"{"$merge": [{"z": 1, "a": 1}, {"m": 2, "z": 2}]}"#, "{}"),
+                r#"{"z":2,"a":1,"m":2}"#
+            );
+            assert_eq!(
+                render_json(
+                    r#"{"$mergeDeep": [{"z": {"y": 1, "b": 1}, "a": 1}, {"z": {"c": 2, "y": 2}}]}"#,
+                    "{}"
+                ),
+                r#"{"z":{"y":2,"b":1,"c":2},"a":1}"#
+            );
+        }
+
+        #[test]
+        fn map_order() {
+            assert_eq!(
+                render_json(
+                    r#"{"$map": {"z": 1, "a": 2}, "each(v,k)": {"${k}2": "${v}", "${k}1": "${v}"}}"#,
+                    "{}"
+                ),
+                r#"{"z2":"1","z1":"1","a2":"2","a1":"2"}"#
+            );
+        }
+
+        #[test]
+        fn json_and_match_are_sorted() {
+            let template = json!({"$json": {"z": 1, "a": {"y": 2, "b": 3}}});
+            assert_eq!(
+                render(&template, &json!({})).unwrap(),
+                json!(r#"{"a":{"b":3,"y":2},"z":1}"#)
+            );
+            assert_eq!(
+                render_json(r#"{"$match": {"true": "t", "1 == 1": "one"}}"#, "{}"),
+                r#"["one","t"]"#
+            );
+        }
+    }
+
+    mod numbers {
+        use crate::render;
+        use serde_json::json;
+
+        #[test]
+        fn large_integers_are_exact() {
+            let context = json!({"id": 9007199254740993u64, "max": u64::MAX, "min": i64::MIN});
+            let template = json!({
+                "id": {"$eval": "id"},
+                "label": "task-${id}",
+                "next": {"$eval": "id + 1"},
+                "same": {"$eval": "id == 9007199254740992"},
+                "greater": {"$eval": "id > 9007199254740992"},
+                "values": {"$eval": "[max, min, max - 1, -(min + 1), max / 5]"},
+                "sorted": {"$sort": [{"$eval": "id"}, 9007199254740992u64]},
+            });
+            assert_eq!(
+                render(&template, &context).unwrap(),
+                json!({
+                    "id": 9007199254740993u64,
+                    "label": "task-9007199254740993",
+                    "next": 9007199254740994u64,
+                    "same": false,
+                    "greater": true,
+                    "values": [
+                        u64::MAX,
+                        i64::MIN,
+                        u64::MAX - 1,
+                        i64::MAX,
+                        u64::MAX / 5,
+                    ],
+                    "sorted": [9007199254740992u64, 9007199254740993u64],
+                })
+            );
+        }
+
+        #[test]
+        fn arithmetic_falls_back_to_float() {
+            let context = json!({"max": u64::MAX, "id": 9007199254740993u64});
+            let template = json!({"$eval": "[max + 1, id / 2, id * 0.5, 2 ** 64]"});
+            assert_eq!(
+                render(&template, &context).unwrap(),
+                json!([
+                    18446744073709551616.0,
+                    4503599627370496.0,
+                    4503599627370496.0,
+                    18446744073709551616.0,
+                ])
+            );
+        }
+    }
+
+    mod base_context {
+        use crate::{parse_expression, ErrorKind, Limits, Renderer};
+        use serde_json::json;
+        use std::sync::Arc;
+        use std::thread;
+
+        fn renderer() -> Renderer {
+            Renderer::new()
+                .function("region", |_| Ok(json!("from function")))
+                .base_context(&json!({"region": "base", "replicas": 1, "tags": ["a"]}))
+                .unwrap()
+        }
+
+        #[test]
+        fn render_context_takes_precedence() {
+            let template = json!(["${region}", {"$eval": "replicas"}, {"$eval": "tags"}]);
+            assert_eq!(
+                renderer().render(&template, &json!({"replicas": 3})).unwrap(),
+                json!(["base", 3, ["a"]])
+            );
+            assert_eq!(
+                renderer().render(&template, &json!({"region": "eu"})).unwrap(),
+                json!(["eu", 1, ["a"]])
+            );
+            assert_eq!(
+                Renderer::new()
+                    .function("region", |_| Ok(json!("from function")))
+                    .render(&json!({"$eval": "region()"}), &json!({}))
+                    .unwrap(),
+                json!("from function")
+            );
+        }
+
+        #[test]
+        fn base_context_is_replaced() {
+            let renderer = renderer().base_context(&json!({"replicas": 2})).unwrap();
+            assert_eq!(
+                renderer.render(&json!({"$eval": "replicas"}), &json!({})).unwrap(),
+                json!(2)
+            );
+            assert!(renderer.render(&json!({"$eval": "tags"}), &json!({})).is_err());
+        }
+
+        #[test]
+        fn invalid_base_context() {
+            let err = Renderer::new().base_context(&json!([1])).err().unwrap();
+            assert_eq!(err.kind(), &ErrorKind::InvalidContext);
+            let err = Renderer::new().base_context(&json!({"a-b": 1})).err().unwrap();
+            assert_eq!(err.kind(), &ErrorKind::InvalidContext);
+        }
+
+        #[test]
+        fn base_context_depth_is_checked_on_render() {
+            let renderer = Renderer::new()
+                .base_context(&json!({"a": [[1]]}))
+                .unwrap()
+                .limits(Limits::default().max_depth(3));
+            let template = json!({"$eval": "1"});
+            let expected = ErrorKind::DepthLimitExceeded { limit: 3 };
+            let err = renderer.render(&template, &json!({})).unwrap_err();
+            assert_eq!(err.kind(), &expected);
+            let err = renderer.compile(&template).render(&json!({})).unwrap_err();
+            assert_eq!(err.kind(), &expected);
+            let err = renderer.partial_render(&template, &json!({})).unwrap_err();
+            assert_eq!(err.kind(), &expected);
+            let expression = parse_expression("1").unwrap();
+            let err = renderer.evaluate(&expression, &json!({})).unwrap_err();
+            assert_eq!(err.kind(), &expected);
+
+            let renderer = renderer.limits(Limits::default().max_depth(4));
+            assert_eq!(renderer.render(&template, &json!({})).unwrap(), json!(1));
+            let err = Renderer::new()
+                .limits(Limits::default().max_depth(3))
+                .base_context(&json!({"a": [[1]]}))
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), &expected);
+        }
+
+        #[test]
+        fn clones_share_base_context() {
+            let renderer = renderer();
+            let clone = renderer.clone();
+            let compiled = clone.compile(&json!("${region}"));
+            assert!(Arc::ptr_eq(
+                renderer.base.as_ref().unwrap(),
+                compiled.renderer.base.as_ref().unwrap()
+            ));
+            assert_eq!(compiled.render(&json!({})).unwrap(), json!("base"));
+        }
+
+        #[test]
+        fn evaluate_and_analysis_use_base_context() {
+            let renderer = renderer();
+            let expression = parse_expression("replicas + len(tags)").unwrap();
+            assert_eq!(renderer.evaluate(&expression, &json!({})).unwrap(), json!(2));
+
+            let compiled = renderer.compile(&json!(["${region}", {"$eval": "replicas + x"}]));
+            let free = compiled.free_variables();
+            let names: Vec<_> = free.iter().map(|v| v.name()).collect();
+            assert_eq!(names, vec!["x"]);
+
+            let residual = compiled.partial_render(&json!({})).unwrap();
+            assert_eq!(residual, json!(["base", {"$eval": "1 + x"}]));
+            assert_eq!(renderer.render(&residual, &json!({"x": 1})).unwrap(), json!(["base", 2]));
+        }
+
+        #[test]
+        fn render_from_many_threads() {
+            fn send_sync<T: Send + Sync>(_: &T) {}
+            let renderer = renderer();
+            send_sync(&renderer);
+            let compiled = Arc::new(renderer.compile(&json!("${region}-${n}")));
+            send_sync(&compiled);
+            let threads: Vec<_> = (0..8)
+                .map(|n| {
+                    let renderer = renderer.clone();
+                    let compiled = compiled.clone();
+                    thread::spawn(move || {
+                        let template = json!({"$eval": "replicas + n"});
+                        let result = renderer.render(&template, &json!({"n": n})).unwrap();
+                        assert_eq!(result, json!(1 + n));
+                        compiled.render(&json!({"n": n})).unwrap()
+                    })
+                })
+                .collect();
+            for (n, thread) in threads.into_iter().enumerate() {
+                assert_eq!(thread.join().unwrap(), json!(format!("base-{}", n)));
+            }
+        }
+    }
+
     #[test]
     fn test_is_identifier() {
         assert!(!is_identifier(""));
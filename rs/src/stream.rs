@@ -0,0 +1,378 @@
+//! Rendering directly to a serializer, as the output is produced.
+//!
+//! Arrays, objects whose keys contain no interpolations, and `$map` over an array are rendered
+//! one element at a time, with each element serialized before the next is rendered, so that the
+//! whole result is never held in memory.  Any other part of the template is rendered as usual,
+//! and serialized from its rendered value without first converting it to a `serde_json::Value`.
+
+use crate::errors::{Error, ErrorKind, Result};
+use crate::interpreter::Context;
+use crate::render::{_render, check_dynamic_keys, map_value};
+use crate::template::{Operator, Template};
+use crate::value::{number_to_serde_number, Value};
+use serde::ser::{self, SerializeMap, SerializeSeq, Serializer};
+use serde::Serialize;
+use std::cell::RefCell;
+
+/// Render the given template with the given context to the given serializer.
+pub(crate) fn render_to_serializer<S: Serializer>(
+    template: &Template,
+    context: &Context,
+    serializer: S,
+) -> Result<S::Ok> {
+    let stream = Stream::default();
+    let streamed = Streamed {
+        stream: &stream,
+        template,
+        context,
+    };
+    streamed.serialize(serializer).map_err(|e| {
+        stream.error.into_inner().map(|(e, _)| e).unwrap_or_else(|| {
+            error_of_kind!(
+                ErrorKind::Output,
+                "could not write rendered value: {}",
+                e
+            )
+        })
+    })
+}
+
+/// The state of a streaming render, shared by all of its parts
+#[derive(Default)]
+struct Stream {
+    /// The error that stopped the render, if any, and whether it occurred while rendering (as
+    /// opposed to while serializing a rendered value), and so should be located in the template
+    error: RefCell<Option<(Error, bool)>>,
+}
+
+impl Stream {
+    /// Stop the render with the given error, which the serializer cannot carry, so that it is
+    /// returned from `render_to_serializer` instead of the serializer's error.
+    fn fail<E: ser::Error>(&self, error: Error, located: bool) -> E {
+        *self.error.borrow_mut() = Some((error, located));
+        E::custom("render failed")
+    }
+
+    fn check<T, E: ser::Error>(&self, result: Result<T>) -> Result<T, E> {
+        result.map_err(|e| self.fail(e, true))
+    }
+
+    /// Add the given segment to the location of the render error, if any, as the error passes
+    /// out of the part of the template at that segment.
+    fn locate<E, S: ToString>(&self, e: E, segment: S) -> E {
+        if let Some((error, true)) = self.error.borrow_mut().as_mut() {
+            *error = error.clone().add_location(segment);
+        }
+        e
+    }
+}
+
+/// True if the given template renders as an array or object that can be streamed, rather than
+/// possibly as a deletion marker.
+fn is_streamed(template: &Template) -> bool {
+    match template {
+        Template::Array(_) => true,
+        Template::Object { dynamic_keys, .. } => dynamic_keys.is_empty(),
+        Template::Operator {
+            operator: Ok(operator),
+            ..
+        } => matches!(**operator, Operator::Map { .. }),
+        Template::At(_, template) => is_streamed(template),
+        _ => false,
+    }
+}
+
+/// A part of a template, which renders as it is serialized
+struct Streamed<'a, 'c> {
+    stream: &'a Stream,
+    template: &'a Template,
+    context: &'a Context<'c>,
+}
+
+impl Streamed<'_, '_> {
+    /// Serialize an element of an array being rendered, or nothing if it is a deletion marker.
+    fn element<S: SerializeSeq>(
+        &self,
+        seq: &mut S,
+        template: &Template,
+        context: &Context,
+    ) -> Result<(), S::Error> {
+        if is_streamed(template) {
+            seq.serialize_element(&Streamed {
+                stream: self.stream,
+                template,
+                context,
+            })
+        } else {
+            match self.stream.check(_render(template, context))? {
+                Value::DeletionMarker => Ok(()),
+                value => seq.serialize_element(&Rendered {
+                    stream: self.stream,
+                    value: &value,
+                }),
+            }
+        }
+    }
+
+    fn map<S: Serializer>(
+        &self,
+        serializer: S,
+        value: &Template,
+        value_var: &str,
+        index_var: Option<&str>,
+        each: &Template,
+    ) -> Result<S::Ok, S::Error> {
+        let stream = self.stream;
+        let context = self.context;
+        let items = match stream.check(_render(value, context))? {
+            Value::Array(items) => items,
+            // objects are mapped as usual, since the results of `each` may share keys
+            value => {
+                let value = stream.check(map_value(value, value_var, index_var, each, context))?;
+                return Rendered {
+                    stream,
+                    value: &value,
+                }
+                .serialize(serializer);
+            }
+        };
+
+        stream.check(context.check_array(items.len()))?;
+        let mut seq = serializer.serialize_seq(None)?;
+        let mut subcontext = context.child();
+        for (i, item) in items.iter().enumerate() {
+            subcontext.insert(value_var, item.clone());
+            if let Some(index_var) = index_var {
+                subcontext.insert(index_var, Value::Number(i.into()));
+            }
+            self.element(&mut seq, each, &subcontext)?;
+        }
+        seq.end()
+    }
+}
+
+impl Serialize for Streamed<'_, '_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let stream = self.stream;
+        let context = self.context;
+        if !is_streamed(self.template) {
+            return match self.template {
+                // literals are serialized in place, rather than copied as they are when rendered
+                Template::Literal(value) => {
+                    let _depth = stream.check(context.enter())?;
+                    Rendered { stream, value }.serialize(serializer)
+                }
+                template => {
+                    let value = stream.check(_render(template, context))?;
+                    Rendered {
+                        stream,
+                        value: &value,
+                    }
+                    .serialize(serializer)
+                }
+            };
+        }
+
+        // the checks and their order here follow those of `_render`
+        let _depth = stream.check(context.enter())?;
+        match self.template {
+            Template::Array(elements) => {
+                stream.check(context.check_array(elements.len()))?;
+                let mut seq = serializer.serialize_seq(None)?;
+                for (i, element) in elements.iter().enumerate() {
+                    self.element(&mut seq, element, context)
+                        .map_err(|e| stream.locate(e, i))?;
+                }
+                seq.end()
+            }
+            Template::Object { properties, .. } => {
+                stream.check(context.add_nodes(properties.len()))?;
+                let mut map = serializer.serialize_map(None)?;
+                // with no dynamic keys, every key is a literal, so the keys are distinct and
+                // already in the order they will have in the result
+                for (raw, k, v) in properties {
+                    let key = k.as_literal().expect("key of a streamed object is not literal");
+                    if is_streamed(v) {
+                        let streamed = Streamed {
+                            stream,
+                            template: v,
+                            context,
+                        };
+                        map.serialize_entry(key, &streamed)
+                            .map_err(|e| stream.locate(e, raw))?;
+                    } else {
+                        match stream.check(_render(v, context).map_err(|e| e.add_location(raw)))? {
+                            Value::DeletionMarker => {}
+                            value => map.serialize_entry(
+                                key,
+                                &Rendered {
+                                    stream,
+                                    value: &value,
+                                },
+                            )?,
+                        }
+                    }
+                }
+                map.end()
+            }
+            Template::Operator {
+                dynamic_keys,
+                key,
+                operator: Ok(operator),
+                ..
+            } => {
+                stream.check(check_dynamic_keys(dynamic_keys, context))?;
+                match **operator {
+                    Operator::Map {
+                        ref value,
+                        ref value_var,
+                        ref index_var,
+                        ref each,
+                    } => self.map(serializer, value, value_var, index_var.as_deref(), each),
+                    _ => unreachable!(),
+                }
+                .map_err(|e| stream.locate(e, key))
+            }
+            Template::At(segment, template) => Streamed {
+                stream,
+                template,
+                context,
+            }
+            .serialize(serializer)
+            .map_err(|e| stream.locate(e, segment)),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A rendered value, serialized as it would be converted to JSON
+struct Rendered<'a> {
+    stream: &'a Stream,
+    value: &'a Value,
+}
+
+impl Serialize for Rendered<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let stream = self.stream;
+        match self.value {
+            Value::Null | Value::DeletionMarker => serializer.serialize_unit(),
+            Value::String(s) => serializer.serialize_str(s),
+            Value::Number(n) => match number_to_serde_number(*n) {
+                Ok(n) => n.serialize(serializer),
+                Err(e) => Err(stream.fail(e, false)),
+            },
+            Value::Bool(b) => serializer.serialize_bool(*b),
+            Value::Object(o) => {
+                let mut map = serializer.serialize_map(Some(o.len()))?;
+                for (k, value) in o.iter() {
+                    map.serialize_entry(k, &Rendered { stream, value })?;
+                }
+                map.end()
+            }
+            Value::Array(a) => {
+                let mut seq = serializer.serialize_seq(Some(a.len()))?;
+                for value in a.iter() {
+                    seq.serialize_element(&Rendered { stream, value })?;
+                }
+                seq.end()
+            }
+            Value::Function(_) => Err(stream.fail(
+                interpreter_error!("cannot represent JSON-e functions as JSON"),
+                false,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{render, render_to_writer, ErrorKind, Renderer};
+    use serde_json::{json, Value as SerdeValue};
+    use std::io;
+
+    fn streamed(template: SerdeValue, context: SerdeValue) -> String {
+        let mut output = Vec::new();
+        render_to_writer(&template, &context, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn same_as_render() {
+        let context = json!({"items": [1, 2, 3], "obj": {"a": 1, "b": 2}, "big": 9007199254740993u64});
+        for template in [
+            json!({"$map": {"$eval": "items"}, "each(x,i)": {"$if": "x != 2", "then": ["${x}", {"$eval": "i"}]}}),
+            json!({"$map": {"$eval": "obj"}, "each(v,k)": {"${k}${k}": "${v}"}}),
+            json!({"a": {"$if": "false", "then": 1}, "b": [{"$eval": "big"}, {"$if": "false"}]}),
+            json!({"nested": {"$map": [[1], [2]], "each(x)": {"$map": {"$eval": "x"}, "each(y)": {"$eval": "y * 1.5"}}}}),
+            json!({"${'k'}": "dynamic", "$$escaped": true}),
+            json!({"$let": {"x": 1}, "in": [{"$eval": "x"}]}),
+            json!("${items[0]}"),
+        ] {
+            assert_eq!(
+                serde_json::from_str::<SerdeValue>(&streamed(template.clone(), context.clone())).unwrap(),
+                render(&template, &context).unwrap(),
+                "{}",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn compact_output() {
+        assert_eq!(
+            streamed(json!({"a": [1, {"$eval": "2.5"}], "b": {"$if": "false"}}), json!({})),
+            r#"{"a":[1,2.5]}"#
+        );
+    }
+
+    #[test]
+    fn pretty_output() {
+        let mut output = Vec::new();
+        Renderer::new()
+            .compile(&json!({"$map": [1, 2], "each(x)": {"x": {"$eval": "x"}}}))
+            .render_to_serializer(&json!({}), &mut serde_json::Serializer::pretty(&mut output))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[\n  {\n    \"x\": 1\n  },\n  {\n    \"x\": 2\n  }\n]"
+        );
+    }
+
+    #[test]
+    fn errors_are_located() {
+        let template = json!({"a": {"$map": [1, 2], "each(x)": [{"$eval": "x * y[x - 1]"}]}});
+        let context = json!({"y": [1]});
+        let mut output = Vec::new();
+        let err = render_to_writer(&template, &context, &mut output).unwrap_err();
+        assert_eq!(err, render(&template, &context).unwrap_err());
+        assert_eq!(err.location().pointer(), "/a/$map/each(x)/0/$eval");
+        // elements before the error have been written already
+        assert_eq!(String::from_utf8(output).unwrap(), r#"{"a":[[1],["#);
+    }
+
+    #[test]
+    fn unrepresentable_values() {
+        let template = json!({"a": [{"$eval": "max"}]});
+        let err = render_to_writer(&template, &json!({}), io::sink()).unwrap_err();
+        assert_eq!(err, render(&template, &json!({})).unwrap_err());
+    }
+
+    struct FailingWriter;
+
+    impl io::Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_errors() {
+        let err = render_to_writer(&json!([1, 2]), &json!({}), FailingWriter).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Output);
+        assert_eq!(err.to_string(), "could not write rendered value: disk full");
+    }
+}
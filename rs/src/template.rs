@@ -0,0 +1,894 @@
+//! Compilation of templates.  Compiling a template parses its operators and expressions ahead of
+//! time, so that a compiled template can be rendered many times without repeating that work.
+
+use crate::errors::{char_offset, Error, Result};
+use crate::interpreter::{self, Compiled, Node};
+use crate::limits::{Budget, Limits};
+use crate::op_props::{parse_by, parse_each, parse_each_three};
+use crate::operators::{Operators, RegisteredOperator};
+use crate::value::{Object, Value};
+use serde_json::Value as SerdeValue;
+use std::sync::Arc;
+
+/// The environment in which templates are compiled.
+pub(crate) struct Compiler<'a> {
+    /// The budget limiting the depth of compilation
+    pub(crate) budget: &'a Budget,
+    /// The custom operators that templates may invoke
+    pub(crate) operators: &'a Arc<Operators>,
+}
+
+/// A compiled template.
+#[derive(Debug)]
+pub(crate) enum Template {
+    /// A value that renders as itself
+    Literal(Value),
+
+    /// A string containing `${..}` interpolations, along with the string as written, which a
+    /// partial render may leave for the final render
+    String(Arc<str>, Interpolation),
+
+    /// An array of templates
+    Array(Vec<Template>),
+
+    /// An object that is not an operator invocation
+    Object {
+        /// Keys containing interpolations, which may only be recognized as (reserved) operators
+        /// at render time, along with their raw form.
+        dynamic_keys: Vec<(String, Interpolation)>,
+        /// The object's properties, with their raw keys and with escaped operators (`$$..`)
+        /// un-escaped.
+        properties: Vec<(String, Interpolation, Template)>,
+    },
+
+    /// An operator invocation
+    Operator {
+        /// Keys containing interpolations that precede the operator, as for `Object`.
+        dynamic_keys: Vec<(String, Interpolation)>,
+        /// The operator's key, such as `$map`
+        key: String,
+        /// The operator, or the error that will occur when it is rendered.
+        operator: Result<Box<Operator>, Error>,
+        /// The invoking object as written, which a partial render may leave for the final render
+        source: Arc<Object>,
+    },
+
+    /// A property of an operator other than the operator itself, such as `each(x)`, which
+    /// appears as a segment in the location of any errors within it
+    At(String, Box<Template>),
+
+    /// A template nested too deeply to compile, which fails with the given error when rendered
+    Error(Error),
+}
+
+impl Template {
+    /// Compile the given template.  This cannot fail: any errors, including exceeding the depth
+    /// limit of the compiler's budget, are deferred until rendering.
+    pub(crate) fn compile(template: &Value, compiler: &Compiler) -> Template {
+        let _depth = match compiler.budget.enter() {
+            Ok(depth) => depth,
+            Err(e) => return Template::Error(e),
+        };
+        match template {
+            Value::String(s) => {
+                let interpolation = Interpolation::compile(s, compiler.budget);
+                if let Some(s) = interpolation.as_literal() {
+                    Template::Literal(Value::String(s.into()))
+                } else {
+                    Template::String(s.clone(), interpolation)
+                }
+            }
+            Value::Array(elements) => {
+                let elements: Vec<Template> = elements
+                    .iter()
+                    .map(|e| Template::compile(e, compiler))
+                    .collect();
+                if elements.iter().all(Template::is_literal) {
+                    Template::Literal(Value::Array(
+                        elements.into_iter().map(Template::into_literal).collect(),
+                    ))
+                } else {
+                    Template::Array(elements)
+                }
+            }
+            Value::Object(o) => Template::compile_object(o, compiler),
+            // numbers, booleans, and null render as themselves, as do functions (which can only
+            // appear in templates drawn from the context, such as in `$find`)
+            _ => Template::Literal(template.clone()),
+        }
+    }
+
+    /// Compile the given template value with the given limits and custom operators, first
+    /// checking that it is not nested too deeply to convert.
+    pub(crate) fn compile_root(
+        template: &SerdeValue,
+        limits: Limits,
+        operators: &Arc<Operators>,
+    ) -> Template {
+        let budget = Budget::for_compilation(limits);
+        let compiler = Compiler {
+            budget: &budget,
+            operators,
+        };
+        match budget.check_template_depth(template) {
+            Ok(()) => Template::compile(&template.into(), &compiler),
+            Err(e) => Template::Error(e),
+        }
+    }
+
+    fn compile_object(object: &Arc<Object>, compiler: &Compiler) -> Template {
+        // first, see if this is an operator invocation.  Keys containing interpolations must be
+        // checked at render time.
+        let mut dynamic_keys = Vec::new();
+        for (k, v) in object.iter() {
+            let key = Interpolation::compile(k, compiler.budget);
+            if let Some(interpolated) = key.as_literal() {
+                let mut chars = interpolated.chars();
+                if chars.next() == Some('$') && chars.next() != Some('$') {
+                    return Template::Operator {
+                        dynamic_keys,
+                        key: k.to_owned(),
+                        operator: Operator::compile(k, v, object, compiler).map(Box::new),
+                        source: object.clone(),
+                    };
+                }
+            } else {
+                dynamic_keys.push((k.to_owned(), key));
+            }
+        }
+
+        // apparently not, so compile the content
+        let properties: Vec<(String, Interpolation, Template)> = object
+            .iter()
+            .map(|(raw, v)| {
+                // un-escape escaped operators
+                let k = if raw.starts_with("$$") { &raw[1..] } else { &raw[..] };
+                (
+                    raw.to_owned(),
+                    Interpolation::compile(k, compiler.budget),
+                    Template::compile(v, compiler),
+                )
+            })
+            .collect();
+
+        if dynamic_keys.is_empty()
+            && properties
+                .iter()
+                .all(|(_, k, v)| k.as_literal().is_some() && v.is_literal())
+        {
+            Template::Literal(Value::Object(
+                properties
+                    .into_iter()
+                    .map(|(_, k, v)| (k.as_literal().unwrap().to_owned(), v.into_literal()))
+                    .collect::<Object>()
+                    .into(),
+            ))
+        } else {
+            Template::Object {
+                dynamic_keys,
+                properties,
+            }
+        }
+    }
+
+    /// Compile the given operator property, located at the given segment.
+    fn compile_at(segment: &str, template: &Value, compiler: &Compiler) -> Template {
+        match Template::compile(template, compiler) {
+            // literals cannot fail to render, so need no location
+            t @ Template::Literal(_) => t,
+            t => Template::At(segment.to_owned(), Box::new(t)),
+        }
+    }
+
+    fn is_literal(&self) -> bool {
+        matches!(self, Template::Literal(_))
+    }
+
+    fn into_literal(self) -> Value {
+        match self {
+            Template::Literal(v) => v,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A compiled string, which may contain `${..}` interpolations.
+#[derive(Debug)]
+pub(crate) struct Interpolation(Vec<Fragment>);
+
+#[derive(Debug)]
+pub(crate) enum Fragment {
+    /// Literal text
+    Literal(String),
+    /// An expression to be interpolated, along with the remainder of the string beginning with
+    /// that expression (used in error messages) and the character offset of the expression
+    /// within the string
+    Expression(Compiled, String, usize),
+    /// An error in the string, which occurs when it is reached during interpolation
+    Error(Error),
+}
+
+impl Interpolation {
+    /// Compile the given string.
+    pub(crate) fn compile(string: &str, budget: &Budget) -> Interpolation {
+        let mut source = string;
+        let mut fragments = Vec::new();
+        let mut literal = String::new();
+
+        // shortcut the common no-interpolation case
+        if source.find('$').is_none() {
+            return Interpolation(vec![Fragment::Literal(source.into())]);
+        }
+
+        while !source.is_empty() {
+            if let Some(offset) = source.find('$') {
+                // If this is an un-escaped `${`, parse the expression..
+                if let Some(s) = source.get(offset..offset + 2) {
+                    if s == "${" {
+                        literal.push_str(source.get(..offset).unwrap());
+                        if !literal.is_empty() {
+                            fragments.push(Fragment::Literal(std::mem::take(&mut literal)));
+                        }
+                        let expr = source.get(offset + 2..).unwrap();
+                        let expr_offset = char_offset(string, expr);
+                        match interpreter::parse_partial(expr, budget) {
+                            Ok((parsed, remainder)) if remainder.get(0..1) == Some("}") => {
+                                fragments.push(match Compiled::new(parsed, budget) {
+                                    Ok(code) => {
+                                        Fragment::Expression(code, expr.to_owned(), expr_offset)
+                                    }
+                                    Err(e) => Fragment::Error(e.add_offset(expr_offset)),
+                                });
+                                source = &remainder[1..];
+                                continue;
+                            }
+                            Ok((_, remainder)) => {
+                                let err = syntax_error!("unterminated ${..} expression");
+                                let err = err.add_offset(char_offset(string, remainder));
+                                fragments.push(Fragment::Error(err));
+                            }
+                            Err(e) => fragments.push(Fragment::Error(e.add_offset(expr_offset))),
+                        }
+                        return Interpolation(fragments);
+                    }
+                }
+
+                // If this is an escape (`$${`), un-escape it
+                if let Some(s) = source.get(offset..offset + 3) {
+                    if s == "$${" {
+                        literal.push_str(source.get(..offset + 1).unwrap());
+                        source = source.get(offset + 2..).unwrap();
+                        continue;
+                    }
+                }
+
+                // otherwise, carry on..
+                literal.push_str(source.get(..offset + 1).unwrap());
+                source = source.get(offset + 1..).unwrap();
+            } else {
+                // remainder of the string contains no interpolations..
+                literal.push_str(source);
+                source = "";
+            }
+        }
+
+        if !literal.is_empty() || fragments.is_empty() {
+            fragments.push(Fragment::Literal(literal));
+        }
+        Interpolation(fragments)
+    }
+
+    /// If this string contains no interpolations (or errors), the string itself
+    pub(crate) fn as_literal(&self) -> Option<&str> {
+        match &self.0[..] {
+            [Fragment::Literal(s)] => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn fragments(&self) -> &[Fragment] {
+        &self.0[..]
+    }
+}
+
+/// A compiled expression, as given to `$eval`, `$if`, and so on.
+#[derive(Debug)]
+pub(crate) struct Expression {
+    code: Result<Compiled, Error>,
+    /// The segment at which errors in this expression are located, if it is not the operator's
+    /// own value
+    segment: Option<String>,
+}
+
+impl Expression {
+    /// Compile the given expression.
+    pub(crate) fn compile(source: &str, budget: &Budget) -> Expression {
+        Expression {
+            code: interpreter::parse_all(source, budget).and_then(|n| Compiled::new(n, budget)),
+            segment: None,
+        }
+    }
+
+    /// Compile the given template value, which must be a string expression, returning the given
+    /// error when the expression is evaluated if not.
+    fn compile_value(value: &Value, err: Error, budget: &Budget) -> Expression {
+        match value {
+            Value::String(s) => Expression::compile(s, budget),
+            _ => Expression {
+                code: Err(err),
+                segment: None,
+            },
+        }
+    }
+
+    /// Locate errors in this expression at the given segment.
+    fn at(mut self, segment: &str) -> Expression {
+        self.segment = Some(segment.to_owned());
+        self
+    }
+
+    pub(crate) fn node(&self) -> Result<&Node> {
+        self.code().map(Compiled::node)
+    }
+
+    pub(crate) fn code(&self) -> Result<&Compiled> {
+        self.code.as_ref().map_err(Clone::clone)
+    }
+
+    pub(crate) fn segment(&self) -> Option<&str> {
+        self.segment.as_deref()
+    }
+}
+
+/// A compiled operator invocation.
+#[derive(Debug)]
+pub(crate) enum Operator {
+    Eval(Expression),
+    Flatten(Template),
+    FlattenDeep(Template),
+    FromNow {
+        value: Template,
+        from: Option<Template>,
+    },
+    If {
+        condition: Expression,
+        then: Option<Template>,
+        else_: Option<Template>,
+    },
+    Include {
+        name: Template,
+        /// The `with` bindings, if given
+        with: Option<Template>,
+    },
+    Json(Template),
+    Let {
+        bindings: Template,
+        in_: Template,
+    },
+    Map {
+        value: Template,
+        value_var: String,
+        index_var: Option<String>,
+        each: Template,
+    },
+    Reduce {
+        value: Template,
+        acc_var: String,
+        value_var: String,
+        index_var: Option<String>,
+        each: Template,
+        initial: Value,
+    },
+    Find {
+        value: Template,
+        value_var: String,
+        index_var: Option<String>,
+        each: Expression,
+        /// The custom operators available to the found value, which is rendered as a template
+        operators: Arc<Operators>,
+        /// The limits with which the found value is compiled, if it is rendered without a budget
+        limits: Limits,
+    },
+    Match(Vec<(Expression, Template)>),
+    Switch {
+        cases: Vec<(Expression, Template)>,
+        default: Option<Template>,
+    },
+    Merge(Template),
+    MergeDeep(Template),
+    Reverse(Template),
+    Sort {
+        value: Template,
+        /// The `by(..)` variable and expression, if given
+        by: Option<Result<(String, Expression), Error>>,
+    },
+    Custom {
+        name: String,
+        operator: RegisteredOperator,
+        value: Template,
+        /// The other properties of the invoking object
+        properties: Vec<(String, Template)>,
+    },
+}
+
+impl Operator {
+    /// Compile an operator invocation: the given object has the given key that starts with `$`.
+    fn compile(
+        operator: &str,
+        value: &Value,
+        object: &Object,
+        compiler: &Compiler,
+    ) -> Result<Operator, Error> {
+        let compile_prop =
+            |prop: &str| object.get(prop).map(|v| Template::compile_at(prop, v, compiler));
+
+        // custom operators take precedence over the built-in operators
+        if let Some(custom) = compiler.operators.get(operator) {
+            check_operator_properties(operator, object, |p| custom.0.allows_property(p))?;
+            return Ok(Operator::Custom {
+                name: operator.to_owned(),
+                operator: custom.clone(),
+                value: Template::compile(value, compiler),
+                properties: object
+                    .iter()
+                    .filter(|(k, _)| k != &operator)
+                    .map(|(k, v)| (k.to_owned(), Template::compile_at(k, v, compiler)))
+                    .collect(),
+            });
+        }
+
+        Ok(match operator {
+            "$eval" => {
+                check_operator_properties(operator, object, |_| false)?;
+                Operator::Eval(Expression::compile_value(
+                    value,
+                    type_error!(
+                        operator,
+                        "string",
+                        [value],
+                        "$eval must be given a string expression"
+                    ),
+                    compiler.budget,
+                ))
+            }
+            "$flatten" => {
+                check_operator_properties(operator, object, |_| false)?;
+                Operator::Flatten(Template::compile(value, compiler))
+            }
+            "$flattenDeep" => {
+                check_operator_properties(operator, object, |_| false)?;
+                Operator::FlattenDeep(Template::compile(value, compiler))
+            }
+            "$fromNow" => {
+                check_operator_properties(operator, object, |prop| prop == "from")?;
+                Operator::FromNow {
+                    value: Template::compile(value, compiler),
+                    from: compile_prop("from"),
+                }
+            }
+            "$if" => {
+                check_operator_properties(operator, object, |prop| {
+                    prop == "then" || prop == "else"
+                })?;
+                Operator::If {
+                    condition: Expression::compile_value(
+                        value,
+                        type_error!(
+                            operator,
+                            "string",
+                            [value],
+                            "$if can evaluate string expressions only"
+                        ),
+                        compiler.budget,
+                    ),
+                    then: compile_prop("then"),
+                    else_: compile_prop("else"),
+                }
+            }
+            "$include" => {
+                check_operator_properties(operator, object, |p| p == "with")?;
+                Operator::Include {
+                    name: Template::compile(value, compiler),
+                    with: compile_prop("with"),
+                }
+            }
+            "$json" => {
+                check_operator_properties(operator, object, |_| false)?;
+                Operator::Json(Template::compile(value, compiler))
+            }
+            "$let" => {
+                check_operator_properties(operator, object, |p| p == "in")?;
+                if !value.is_object() {
+                    return Err(type_error!(
+                        operator,
+                        "object",
+                        [value],
+                        "$let value must be an object"
+                    ));
+                }
+                let in_ = compile_prop("in").ok_or_else(|| {
+                    operator_error!(operator, "$let operator requires an `in` clause")
+                })?;
+                Operator::Let {
+                    bindings: Template::compile(value, compiler),
+                    in_,
+                }
+            }
+            "$map" => {
+                check_operator_properties(operator, object, |p| parse_each(p).is_some())?;
+                if object.len() != 2 {
+                    return Err(operator_error!(
+                        operator,
+                        "$map must have exactly two properties"
+                    ));
+                }
+
+                // Unwraps here are safe because the presence of the `each(..)` is checked above.
+                let each_prop = object.keys().find(|k| k != &"$map").unwrap();
+                let (value_var, index_var) = parse_each(each_prop).ok_or_else(|| {
+                    operator_error!(
+                        operator,
+                        "$map requires each(identifier[,identifier]) syntax"
+                    )
+                })?;
+
+                Operator::Map {
+                    value: Template::compile(value, compiler),
+                    value_var: value_var.to_owned(),
+                    index_var: index_var.map(str::to_owned),
+                    each: Template::compile_at(each_prop, object.get(each_prop).unwrap(), compiler),
+                }
+            }
+            "$reduce" => {
+                check_operator_properties(operator, object, |p| {
+                    p == "initial" || parse_each_three(p).is_some()
+                })?;
+                if object.len() != 3 {
+                    return Err(operator_error!(
+                        operator,
+                        "$reduce must have exactly three properties"
+                    ));
+                }
+
+                let initial = object
+                    .get("initial")
+                    .ok_or_else(|| {
+                        operator_error!(operator, "$reduce requires an `initial` property")
+                    })?;
+
+                // Unwraps here are safe because the presence of the `each(..)` is checked above.
+                let each_prop = object
+                    .keys()
+                    .find(|k| k != &"$reduce" && k != &"initial")
+                    .unwrap();
+                let (acc_var, value_var, index_var) =
+                    parse_each_three(each_prop).ok_or_else(|| {
+                        operator_error!(
+                            operator,
+                            "$reduce requires each(identifier,identifier[,identifier]) syntax"
+                        )
+                    })?;
+
+                Operator::Reduce {
+                    value: Template::compile(value, compiler),
+                    acc_var: acc_var.to_owned(),
+                    value_var: value_var.to_owned(),
+                    index_var: index_var.map(str::to_owned),
+                    each: Template::compile_at(each_prop, object.get(each_prop).unwrap(), compiler),
+                    // note that the initial value is not rendered
+                    initial: initial.clone(),
+                }
+            }
+            "$find" => {
+                check_operator_properties(operator, object, |p| parse_each(p).is_some())?;
+                if object.len() != 2 {
+                    return Err(operator_error!(
+                        operator,
+                        "$find must have exactly two properties"
+                    ));
+                }
+
+                // Unwraps here are safe because the presence of the `each(..)` is checked above.
+                let each_prop = object.keys().find(|k| k != &"$find").unwrap();
+                let (value_var, index_var) = parse_each(each_prop).ok_or_else(|| {
+                    operator_error!(
+                        operator,
+                        "$find requires each(identifier[,identifier]) syntax"
+                    )
+                })?;
+
+                Operator::Find {
+                    value: Template::compile(value, compiler),
+                    value_var: value_var.to_owned(),
+                    index_var: index_var.map(str::to_owned),
+                    each: Expression::compile_value(
+                        object.get(each_prop).unwrap(),
+                        type_error!(
+                            operator,
+                            "string",
+                            [object.get(each_prop).unwrap()],
+                            "$find can evaluate string expressions only"
+                        ),
+                        compiler.budget,
+                    )
+                    .at(each_prop),
+                    operators: compiler.operators.clone(),
+                    limits: compiler.budget.limits(),
+                }
+            }
+            "$match" => {
+                check_operator_properties(operator, object, |_| false)?;
+                if let Value::Object(ref obj) = value {
+                    // matches are in the order of their conditions, whatever the key order
+                    let mut cases: Vec<_> = obj.iter().collect();
+                    cases.sort_by_key(|(cond, _)| *cond);
+                    Operator::Match(
+                        cases
+                            .into_iter()
+                            .map(|(cond, val)| {
+                                (
+                                    Expression::compile(cond, compiler.budget).at(cond),
+                                    Template::compile_at(cond, val, compiler),
+                                )
+                            })
+                            .collect(),
+                    )
+                } else {
+                    return Err(type_error!(
+                        operator,
+                        "object",
+                        [value],
+                        "$match can evaluate objects only"
+                    ));
+                }
+            }
+            "$switch" => {
+                if let Value::Object(ref obj) = value {
+                    let mut cases = Vec::new();
+                    let mut default = None;
+                    for (cond, val) in obj.iter() {
+                        // if the condition is `$default`, stash it for later
+                        if cond == "$default" {
+                            default = Some(Template::compile_at(cond, val, compiler));
+                        } else {
+                            cases.push((
+                                Expression::compile(cond, compiler.budget).at(cond),
+                                Template::compile_at(cond, val, compiler),
+                            ));
+                        }
+                    }
+                    Operator::Switch { cases, default }
+                } else {
+                    return Err(type_error!(
+                        operator,
+                        "object",
+                        [value],
+                        "$switch can evaluate objects only"
+                    ));
+                }
+            }
+            "$merge" => {
+                check_operator_properties(operator, object, |_| false)?;
+                Operator::Merge(Template::compile(value, compiler))
+            }
+            "$mergeDeep" => {
+                check_operator_properties(operator, object, |_| false)?;
+                Operator::MergeDeep(Template::compile(value, compiler))
+            }
+            "$reverse" => {
+                check_operator_properties(operator, object, |_| false)?;
+                Operator::Reverse(Template::compile(value, compiler))
+            }
+            "$sort" => {
+                check_operator_properties(operator, object, |p| parse_by(p).is_some())?;
+                let by_props: Vec<_> = object.keys().filter(|k| k != &"$sort").collect();
+                // problems with by(..) are only reported when sorting a non-empty array
+                let by = match by_props[..] {
+                    [] => None,
+                    // Unwrap here is safe because the `by(..)` syntax is checked above.
+                    [by_prop] => Some(Ok((
+                        parse_by(by_prop).unwrap().to_owned(),
+                        Expression::compile_value(
+                            object.get(by_prop).unwrap(),
+                            type_error!(
+                                operator,
+                                "string",
+                                [object.get(by_prop).unwrap()],
+                                "invalid expression in $sorted by"
+                            ),
+                            compiler.budget,
+                        )
+                        .at(by_prop),
+                    ))),
+                    _ => Some(Err(operator_error!(operator, "only one by(..) is allowed"))),
+                };
+                Operator::Sort {
+                    value: Template::compile(value, compiler),
+                    by,
+                }
+            }
+
+            // if the operator isn't recognized, then it should be escaped
+            _ => {
+                return Err(operator_error!(
+                    operator,
+                    "$<identifier> is reserved; use $$<identifier> ({})",
+                    operator
+                ))
+            }
+        })
+    }
+}
+
+/// Check for undefined properties for an operator, returning an appropriate error message if
+/// found; the check function is called for each value other than the operator.
+pub(crate) fn check_operator_properties<F>(operator: &str, object: &Object, check: F) -> Result<()>
+where
+    F: Fn(&str) -> bool,
+{
+    // if the object only has one key, we already have it (the operator)
+    if object.len() == 1 {
+        return Ok(());
+    }
+
+    // TODO: avoid this allocation unless necessary
+    let mut unknown = Vec::new();
+
+    for (k, _) in object.iter() {
+        if k == operator {
+            continue;
+        }
+        if !check(k) {
+            unknown.push(k.as_ref());
+        }
+    }
+
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(operator_error!(
+            operator,
+            "{} has undefined properties: {}",
+            operator,
+            unknown.join(" ")
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errors::ErrorKind;
+    use crate::limits::Limits;
+    use serde_json::json;
+
+    fn budget() -> Budget {
+        Budget::new(Limits::default())
+    }
+
+    fn compile(template: serde_json::Value) -> Template {
+        let compiler = Compiler {
+            budget: &budget(),
+            operators: &Arc::default(),
+        };
+        Template::compile(&(&template).into(), &compiler)
+    }
+
+    #[test]
+    fn static_templates_are_literal() {
+        let template = json!({"a": [1, "two", {"three": null}], "$$b": true});
+        match compile(template) {
+            Template::Literal(v) => assert_eq!(
+                v,
+                (&json!({"a": [1, "two", {"three": null}], "$b": true})).into()
+            ),
+            t => panic!("expected a literal, got {:?}", t),
+        }
+    }
+
+    #[test]
+    fn interpolated_strings_are_compiled() {
+        let interpolation = Interpolation::compile("a${b}c$${d}", &budget());
+        match interpolation.fragments() {
+            [Fragment::Literal(a), Fragment::Expression(b, _, 3), Fragment::Literal(c)] => {
+                assert_eq!(a, "a");
+                let b = match b.node() {
+                    Node::Ident(b) => b,
+                    n => panic!("expected an identifier, got {:?}", n),
+                };
+                assert_eq!(b, "b");
+                assert_eq!(c, "c${d}");
+            }
+            f => panic!("unexpected fragments {:?}", f),
+        }
+    }
+
+    #[test]
+    fn interpolation_syntax_error_is_deferred() {
+        let interpolation = Interpolation::compile("a${b", &budget());
+        assert!(matches!(
+            interpolation.fragments(),
+            [Fragment::Literal(_), Fragment::Error(e)] if e.kind() == &ErrorKind::Syntax
+        ));
+    }
+
+    #[test]
+    fn operator_errors_are_deferred() {
+        let template = compile(json!({"$if": "true", "then": {"$nosuch": 1}}));
+        match template {
+            Template::Operator {
+                operator: Ok(op), ..
+            } => match *op {
+                Operator::If {
+                    then: Some(Template::At(ref prop, ref then)),
+                    ..
+                } if prop == "then" => assert!(matches!(
+                    **then,
+                    Template::Operator {
+                        operator: Err(ref e),
+                        ..
+                    } if matches!(e.kind(), ErrorKind::Template { .. })
+                )),
+                op => panic!("unexpected operator {:?}", op),
+            },
+            t => panic!("expected an operator, got {:?}", t),
+        }
+    }
+
+    #[test]
+    fn dynamic_keys_precede_operator() {
+        let template = compile(json!({" ${x}": 1, "$eval": "1"}));
+        match template {
+            Template::Operator { dynamic_keys, .. } => assert_eq!(dynamic_keys.len(), 1),
+            t => panic!("expected an operator, got {:?}", t),
+        }
+    }
+
+    mod check_operator_properties {
+        use super::super::{check_operator_properties, Object};
+        use crate::value::Value;
+
+        fn map(mut keys: Vec<&str>) -> Object {
+            let mut map = Object::new();
+            for key in keys.drain(..) {
+                map.insert(key.into(), Value::Null);
+            }
+            map
+        }
+
+        #[test]
+        fn single_property_is_ok() -> crate::errors::Result<()> {
+            check_operator_properties("$foo", &map(vec!["$foo"]), |_| false)
+        }
+
+        #[test]
+        fn allowed_properties_are_ok() -> crate::errors::Result<()> {
+            check_operator_properties("$foo", &map(vec!["$foo", "a", "b"]), |k| {
+                k == "a" || k == "b"
+            })
+        }
+
+        #[test]
+        fn missing_allowed_properties_are_ok() -> crate::errors::Result<()> {
+            check_operator_properties("$foo", &map(vec!["$foo", "b"]), |k| k == "a" || k == "b")
+        }
+
+        #[test]
+        fn disalloewd_properties_not_ok() {
+            assert_template_error!(
+                check_operator_properties("$foo", &map(vec!["$foo", "nosuch"]), |k| k == "a"),
+                "$foo has undefined properties: nosuch",
+            );
+        }
+
+        #[test]
+        fn disalloewd_properties_sorted() {
+            assert_template_error!(
+                check_operator_properties("$foo", &map(vec!["$foo", "a", "b", "c", "d"]), |k| k
+                    == "a"),
+                "$foo has undefined properties: b c d",
+            );
+        }
+    }
+}
@@ -1,20 +1,32 @@
 #![allow(dead_code)]
-use anyhow::{Error, Result};
-use serde_json::{Map, Number, Value as SerdeValue};
+use crate::errors::{Error, Result};
+use crate::number::Number;
+use serde_json::{Map, Number as SerdeNumber, Value as SerdeValue};
+#[cfg(not(feature = "preserve_order"))]
 use std::collections::BTreeMap;
+use std::borrow::Cow;
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
+use std::sync::Arc;
 
 use crate::interpreter::Context;
 
-/// shorthand for object values
+/// shorthand for object values, with keys in sorted order
+#[cfg(not(feature = "preserve_order"))]
 pub(crate) type Object = BTreeMap<String, Value>;
 
+/// shorthand for object values, with keys in the order they were inserted
+#[cfg(feature = "preserve_order")]
+pub(crate) type Object = indexmap::IndexMap<String, Value>;
+
+/// The signature of the Rust function implementing a JSON-e function
+pub(crate) type FunctionImpl = dyn Fn(&Context, &[Value]) -> Result<Value> + Send + Sync;
+
 /// A custom function (built-in or user-provided)
 #[derive(Clone)]
 pub(crate) struct Function {
-    name: &'static str,
-    f: fn(&Context, &[Value]) -> Result<Value>,
+    name: Cow<'static, str>,
+    f: Arc<FunctionImpl>,
 }
 
 impl fmt::Debug for Function {
@@ -25,13 +37,24 @@ impl fmt::Debug for Function {
 
 impl PartialEq for Function {
     fn eq(&self, other: &Self) -> bool {
-        self.f as *const () == other.f as *const () && self.name == other.name
+        Arc::ptr_eq(&self.f, &other.f) && self.name == other.name
     }
 }
 
 impl Function {
-    pub(crate) fn new(name: &'static str, f: fn(&Context, &[Value]) -> Result<Value>) -> Function {
-        Function { name, f }
+    pub(crate) fn new<N, F>(name: N, f: F) -> Function
+    where
+        N: Into<Cow<'static, str>>,
+        F: Fn(&Context, &[Value]) -> Result<Value> + Send + Sync + 'static,
+    {
+        Function {
+            name: name.into(),
+            f: Arc::new(f),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        self.name.as_ref()
     }
 
     pub(crate) fn call(&self, context: &Context, args: &[Value]) -> Result<Value> {
@@ -42,16 +65,18 @@ impl Function {
 /// Internal representation of a JSON value.  This has a few advantages:
 ///  - can contain functions as first-class objects
 ///  - can represent a deletion marker
-///  - a Number variant suitable for arithmetic
+///  - a Number variant suitable for arithmetic, which keeps 64-bit integers exact
+///  - strings, arrays, and objects are reference-counted, so that cloning a value, as when looking
+///    it up in the context, takes constant time regardless of its size
 #[derive(Debug, PartialEq, Clone)]
 pub(crate) enum Value {
     // Normal JSON types
     Null,
-    String(String),
-    Number(f64),
+    String(Arc<str>),
+    Number(Number),
     Bool(bool),
-    Object(Object),
-    Array(Vec<Value>),
+    Object(Arc<Object>),
+    Array(Arc<[Value]>),
 
     // lack of a value (for an $if without then, for example); this is
     // converted to `null` in JSON.
@@ -62,10 +87,12 @@ pub(crate) enum Value {
 }
 
 impl Value {
-    /// Serialize this value to a JSON string.
+    /// Serialize this value to a JSON string, with object keys in sorted order.
     pub(crate) fn to_json(&self) -> Result<String> {
         let v: SerdeValue = self.try_into()?;
-        Ok(serde_json::to_string(&v)?)
+        #[cfg(feature = "preserve_order")]
+        let v = sort_keys(v);
+        serde_json::to_string(&v).map_err(|e| interpreter_error!(e))
     }
 
     pub(crate) fn is_null(&self) -> bool {
@@ -96,6 +123,20 @@ impl Value {
         matches!(self, Value::DeletionMarker)
     }
 
+    /// The name of this value's type, as returned by `typeof`
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Number(_) => "number",
+            Value::Bool(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+            Value::Null => "null",
+            Value::Function(_) => "function",
+            Value::DeletionMarker => "deletion marker",
+        }
+    }
+
     /// A reference to the string, if this is a String variant
     pub(crate) fn as_str(&self) -> Option<&str> {
         match self {
@@ -105,10 +146,9 @@ impl Value {
     }
 
     /// The numeric value, if this is a Number variant
-    // TODO: &f64???
-    pub(crate) fn as_f64(&self) -> Option<&f64> {
+    pub(crate) fn as_number(&self) -> Option<Number> {
         match self {
-            Value::Number(n) => Some(n),
+            Value::Number(n) => Some(*n),
             _ => None,
         }
     }
@@ -117,20 +157,30 @@ impl Value {
     pub(crate) fn stringify(&self) -> Result<String> {
         Ok(match self {
             Value::Null => "null".to_owned(),
-            Value::String(s) => s.clone(),
+            Value::String(s) => s.to_string(),
             Value::Number(n) => format!("{}", n),
             Value::Bool(b) if *b => "true".to_owned(),
             Value::Bool(b) if !*b => "false".to_owned(),
             // typically callers will ensure this does not occur
-            _ => Err(template_error!("cannot stringify value"))?,
+            _ => return Err(interpreter_error!("cannot stringify value")),
         })
     }
 }
 
-/// Utility function to turn an f64 into a serde Number, using the simplest form.
-/// This conservatively assumes values outside (-u32::MAX..u32::MAX) are best
-/// represented as floats
-fn f64_to_serde_number(value: f64) -> Result<Number> {
+/// Utility function to turn a Number into a serde Number, using the simplest form.  Integers are
+/// kept exactly, while floats conservatively assume that integer values outside
+/// (-u32::MAX..u32::MAX) are best represented as floats
+pub(crate) fn number_to_serde_number(value: Number) -> Result<SerdeNumber> {
+    let value = match value {
+        Number::Integer(i) => {
+            return Ok(match i64::try_from(i) {
+                Ok(i) => i.into(),
+                // integers are always in the range of i64 or u64
+                Err(_) => (i as u64).into(),
+            })
+        }
+        Number::Float(f) => f,
+    };
     if value.fract() == 0.0 {
         if value < 0.0 && value > -(u32::MAX as f64) {
             return Ok((value as i64).into());
@@ -138,14 +188,28 @@ fn f64_to_serde_number(value: f64) -> Result<Number> {
             return Ok((value as u64).into());
         }
     }
-    // the failure conditions here are NaN and Infinity, which we do not see
-    Number::from_f64(value).ok_or_else(|| template_error!("{} cannot be represented in JSON", value))
+    // the failure conditions here are NaN and Infinity
+    SerdeNumber::from_f64(value).ok_or_else(|| interpreter_error!("{} cannot be represented in JSON", value))
+}
+
+/// Sort the keys of all objects in the given value, which otherwise keep their insertion order
+#[cfg(feature = "preserve_order")]
+fn sort_keys(value: SerdeValue) -> SerdeValue {
+    match value {
+        SerdeValue::Object(o) => {
+            let mut entries: Vec<_> = o.into_iter().map(|(k, v)| (k, sort_keys(v))).collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            SerdeValue::Object(entries.into_iter().collect())
+        }
+        SerdeValue::Array(a) => SerdeValue::Array(a.into_iter().map(sort_keys).collect()),
+        v => v,
+    }
 }
 
 impl From<&Value> for bool {
     fn from(value: &Value) -> bool {
         match value {
-            Value::Number(n) => *n != 0.0,
+            Value::Number(n) => !n.is_zero(),
             Value::Bool(b) => *b,
             Value::Null => false,
             Value::String(s) => !s.is_empty(),
@@ -167,13 +231,19 @@ impl From<&SerdeValue> for Value {
     fn from(value: &SerdeValue) -> Value {
         match value {
             SerdeValue::Null => Value::Null,
-            SerdeValue::String(s) => Value::String(s.into()),
-            // the failure conditions here are parse errors on an arbitrary-precision
-            // value; ignorable since we dont' currently support arbitrary precision
-            SerdeValue::Number(n) => Value::Number(n.as_f64().unwrap()),
+            SerdeValue::String(s) => Value::String(s.as_str().into()),
+            SerdeValue::Number(n) => Value::Number(if let Some(i) = n.as_i64() {
+                i.into()
+            } else if let Some(u) = n.as_u64() {
+                u.into()
+            } else {
+                // the failure conditions here are parse errors on an arbitrary-precision
+                // value; ignorable since we dont' currently support arbitrary precision
+                n.as_f64().unwrap().into()
+            }),
             SerdeValue::Bool(b) => Value::Bool(*b),
             SerdeValue::Object(o) => {
-                Value::Object(o.iter().map(|(k, v)| (k.into(), v.into())).collect())
+                Value::Object(Arc::new(o.iter().map(|(k, v)| (k.into(), v.into())).collect()))
             }
             SerdeValue::Array(a) => Value::Array(a.iter().map(|v| v.into()).collect()),
         }
@@ -192,8 +262,8 @@ impl TryFrom<&Value> for SerdeValue {
     fn try_from(value: &Value) -> Result<SerdeValue> {
         Ok(match value {
             Value::Null => SerdeValue::Null,
-            Value::String(s) => SerdeValue::String(s.into()),
-            Value::Number(n) => SerdeValue::Number(f64_to_serde_number(*n)?),
+            Value::String(s) => SerdeValue::String(s.to_string()),
+            Value::Number(n) => SerdeValue::Number(number_to_serde_number(*n)?),
             Value::Bool(b) => SerdeValue::Bool(*b),
             Value::Object(o) => SerdeValue::Object(
                 o.iter()
@@ -207,7 +277,7 @@ impl TryFrom<&Value> for SerdeValue {
             ),
             Value::DeletionMarker => SerdeValue::Null,
             Value::Function(_) => {
-                Err(template_error!("cannot represent JSON-e functions as JSON"))?
+                return Err(interpreter_error!("cannot represent JSON-e functions as JSON"))
             }
         })
     }
@@ -247,25 +317,27 @@ mod test {
 
         let jsone_value = Object(
             vec![
-                ("the".to_string(), String("quick".to_string())),
+                ("the".to_string(), String("quick".into())),
                 (
                     "brown".to_string(),
                     Array(vec![
-                        String("fox".to_string()),
-                        Number(2.0),
-                        Number(3.5),
-                        Number(-5.0),
-                        Number(small_float),
-                        Number(big_float),
-                    ]),
+                        String("fox".into()),
+                        Number(2.0.into()),
+                        Number(3.5.into()),
+                        Number((-5.0).into()),
+                        Number(small_float.into()),
+                        Number(big_float.into()),
+                    ]
+                    .into()),
                 ),
                 ("over".to_string(), Bool(true)),
                 ("the".to_string(), Bool(false)),
-                ("lazy".to_string(), Number(0.0)),
+                ("lazy".to_string(), Number(0.0.into())),
                 ("dog".to_string(), Null),
             ]
             .drain(..)
-            .collect(),
+            .collect::<super::Object>()
+            .into(),
         );
 
         let converted: Value = (&serde_value).into();
@@ -275,6 +347,17 @@ mod test {
         assert_eq!(converted, serde_value);
     }
 
+    #[test]
+    fn integers_are_exact() {
+        let serde_value = json!([u64::MAX, i64::MIN, 9007199254740993u64, -9007199254740993i64]);
+        let converted: Value = (&serde_value).into();
+        let converted: SerdeValue = converted.try_into().unwrap();
+        assert_eq!(converted, serde_value);
+
+        let converted: SerdeValue = Number(3.0.into()).try_into().unwrap();
+        assert_eq!(converted, json!(3));
+    }
+
     #[test]
     fn convert_ref() {
         let serde_value = json!(true);
@@ -291,20 +374,20 @@ mod test {
 
     #[test]
     fn test_is_truthy() {
-        let obj = vec![("x".to_string(), Null)].drain(..).collect();
+        let obj = vec![("x".to_string(), Null)].drain(..).collect::<super::Object>();
         let long = "very very very very very very very very very very long string".to_string();
         let tests = vec![
             (Null, false),
-            (Array(vec![]), false),
-            (Array(vec![Number(1.0)]), true),
-            (Object(super::Object::new()), false),
-            (Object(obj), true),
-            (String("".to_string()), false),
-            (String("short string".to_string()), true),
-            (String(long), true),
-            (Number(0.0), false),
-            (Number(1.0), true),
-            (Number(-1.0), true),
+            (Array(vec![].into()), false),
+            (Array(vec![Number(1.0.into())].into()), true),
+            (Object(super::Object::new().into()), false),
+            (Object(obj.into()), true),
+            (String("".into()), false),
+            (String("short string".into()), true),
+            (String(long.into()), true),
+            (Number(0.0.into()), false),
+            (Number(1.0.into()), true),
+            (Number((-1.0).into()), true),
             (Bool(false), false),
             (Bool(true), true),
             (DeletionMarker, false),
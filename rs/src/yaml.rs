@@ -0,0 +1,304 @@
+//! YAML templates, contexts, and output, with the `yaml` feature.
+//!
+//! YAML documents are converted to JSON before rendering, and the result is converted back.  YAML
+//! can express values that JSON cannot, and rather than changing them silently, these are
+//! [`ErrorKind::Yaml`] errors giving their location:
+//!
+//!  * mapping keys that are not strings, such as `1: one` or `true: yes`;
+//!  * the special floats `.nan`, `.inf`, and `-.inf`;
+//!  * values with tags, such as `!secret value`.
+//!
+//! Merge keys (`<<: *defaults`) are applied before conversion.
+//!
+//! ```
+//! # use json_e::render_yaml;
+//! let template = "
+//! name: ${name}
+//! replicas: {$eval: 'replicas * 2'}
+//! ";
+//! let context = "{name: web, replicas: 3}";
+//! assert_eq!(render_yaml(template, context).unwrap(), "name: web\nreplicas: 6\n");
+//! ```
+
+use crate::errors::{json_pointer, Error, ErrorKind, Result};
+use crate::render::Renderer;
+use serde_json::{Map, Number, Value as SerdeValue};
+use serde_yaml::Value as YamlValue;
+use std::path::Path;
+
+/// Parse a YAML document, such as a template or a context, into the equivalent JSON value.
+pub fn from_yaml_str(yaml: &str) -> Result<SerdeValue, Error> {
+    let mut value: YamlValue = serde_yaml::from_str(yaml).map_err(|e| {
+        error_of_kind!(
+            ErrorKind::Yaml {
+                path: String::new()
+            },
+            "invalid YAML: {}",
+            e
+        )
+    })?;
+    value.apply_merge().map_err(|e| {
+        error_of_kind!(
+            ErrorKind::Yaml {
+                path: String::new()
+            },
+            "invalid YAML merge: {}",
+            e
+        )
+    })?;
+    to_json(value, &mut vec![])
+}
+
+/// Format a JSON value, such as a rendered template, as a YAML document.
+pub fn to_yaml_string(value: &SerdeValue) -> Result<String, Error> {
+    serde_yaml::to_string(value).map_err(|e| {
+        error_of_kind!(
+            ErrorKind::Yaml {
+                path: String::new()
+            },
+            "could not format YAML: {}",
+            e
+        )
+    })
+}
+
+/// Render the given YAML template with the given YAML context, which must be a mapping, giving
+/// the result as YAML.
+pub fn render_yaml(template: &str, context: &str) -> Result<String, Error> {
+    Renderer::new().render_yaml(template, context)
+}
+
+impl Renderer {
+    /// Render the given YAML template with the given YAML context, which must be a mapping, using
+    /// this renderer's functions, operators, loader, limits, and clock.  See
+    /// [`render_yaml`](crate::render_yaml).
+    pub fn render_yaml(&self, template: &str, context: &str) -> Result<String, Error> {
+        let template = from_yaml_str(template)?;
+        let context = from_yaml_str(context)?;
+        to_yaml_string(&self.render(&template, &context)?)
+    }
+}
+
+/// Convert a YAML value to JSON, where `path` is the location of the value in the document.
+fn to_json(value: YamlValue, path: &mut Vec<String>) -> Result<SerdeValue> {
+    Ok(match value {
+        YamlValue::Null => SerdeValue::Null,
+        YamlValue::Bool(b) => SerdeValue::Bool(b),
+        YamlValue::Number(n) => {
+            let number = if let Some(i) = n.as_i64() {
+                Some(Number::from(i))
+            } else if let Some(u) = n.as_u64() {
+                Some(Number::from(u))
+            } else {
+                n.as_f64().and_then(Number::from_f64)
+            };
+            match number {
+                Some(number) => SerdeValue::Number(number),
+                None => {
+                    return Err(yaml_error(
+                        path,
+                        format!("YAML number {} has no JSON equivalent", n),
+                    ))
+                }
+            }
+        }
+        YamlValue::String(s) => SerdeValue::String(s),
+        YamlValue::Sequence(items) => SerdeValue::Array(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(i, item)| {
+                    path.push(i.to_string());
+                    let item = to_json(item, path);
+                    path.pop();
+                    item
+                })
+                .collect::<Result<_>>()?,
+        ),
+        YamlValue::Mapping(mapping) => {
+            let mut object = Map::new();
+            for (key, value) in mapping {
+                let key = match key {
+                    YamlValue::String(key) => key,
+                    key => {
+                        return Err(yaml_error(
+                            path,
+                            format!(
+                                "YAML mapping key {} is not a string; quote it to use it as a key",
+                                describe(&key)
+                            ),
+                        ))
+                    }
+                };
+                path.push(key);
+                let value = to_json(value, path)?;
+                object.insert(path.pop().unwrap(), value);
+            }
+            SerdeValue::Object(object)
+        }
+        YamlValue::Tagged(tagged) => {
+            return Err(yaml_error(
+                path,
+                format!("YAML tag {} is not supported", tagged.tag),
+            ))
+        }
+    })
+}
+
+/// True if the file at the given path should be read as YAML, judging by its extension
+pub(crate) fn is_yaml_path(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("yaml") | Some("yml")
+    )
+}
+
+/// A short description of a YAML value, for error messages
+fn describe(value: &YamlValue) -> String {
+    match value {
+        YamlValue::Null => "null".to_owned(),
+        YamlValue::Bool(b) => b.to_string(),
+        YamlValue::Number(n) => n.to_string(),
+        YamlValue::String(s) => format!("{:?}", s),
+        YamlValue::Sequence(_) => "[...]".to_owned(),
+        YamlValue::Mapping(_) => "{...}".to_owned(),
+        YamlValue::Tagged(tagged) => format!("{} ...", tagged.tag),
+    }
+}
+
+fn yaml_error(path: &[String], message: String) -> Error {
+    let pointer = json_pointer(path);
+    let message = if pointer.is_empty() {
+        message
+    } else {
+        format!("{} at {}", message, pointer)
+    };
+    Error::new(ErrorKind::Yaml { path: pointer }, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses() {
+        let value = from_yaml_str(
+            "
+defaults: &defaults
+  image: web
+  replicas: 2
+service:
+  <<: *defaults
+  replicas: 3
+  ports: [80, 443]
+  weight: 0.5
+  big: 18446744073709551615
+  enabled: true
+  note: ~
+",
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "defaults": {"image": "web", "replicas": 2},
+                "service": {
+                    "image": "web",
+                    "replicas": 3,
+                    "ports": [80, 443],
+                    "weight": 0.5,
+                    "big": 18446744073709551615u64,
+                    "enabled": true,
+                    "note": null,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn unrepresentable_values() {
+        let check = |yaml: &str, path: &str, message: &str| {
+            let err = from_yaml_str(yaml).unwrap_err();
+            assert_eq!(
+                *err.kind(),
+                ErrorKind::Yaml {
+                    path: path.to_owned()
+                },
+                "{}",
+                yaml
+            );
+            assert_eq!(err.to_string(), message, "{}", yaml);
+        };
+        check(
+            "a: {1: one}",
+            "/a",
+            "YAML mapping key 1 is not a string; quote it to use it as a key at /a",
+        );
+        check(
+            "[{true: x}]",
+            "/0",
+            "YAML mapping key true is not a string; quote it to use it as a key at /0",
+        );
+        check(
+            "{~: x}",
+            "",
+            "YAML mapping key null is not a string; quote it to use it as a key",
+        );
+        check(
+            "a: [1, .nan]",
+            "/a/1",
+            "YAML number .nan has no JSON equivalent at /a/1",
+        );
+        check(
+            "a/b: -.inf",
+            "/a~1b",
+            "YAML number -.inf has no JSON equivalent at /a~1b",
+        );
+        check(
+            "a: !secret x",
+            "/a",
+            "YAML tag !secret is not supported at /a",
+        );
+    }
+
+    #[test]
+    fn invalid_yaml() {
+        let err = from_yaml_str("a: [").unwrap_err();
+        assert_eq!(
+            *err.kind(),
+            ErrorKind::Yaml {
+                path: String::new()
+            }
+        );
+        assert!(err.message().starts_with("invalid YAML: "));
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(
+            to_yaml_string(&json!({"a": [1, "true", null], "b": "1.5"})).unwrap(),
+            "a:\n- 1\n- 'true'\n- null\nb: '1.5'\n"
+        );
+    }
+
+    #[test]
+    fn renders() {
+        assert_eq!(
+            render_yaml("{$map: {$eval: items}, each(x): '${x}'}", "items: [1, 2]").unwrap(),
+            "- '1'\n- '2'\n"
+        );
+        let renderer = Renderer::new().function("double", |args| {
+            Ok(json!(args[0].as_f64().unwrap() * 2.0))
+        });
+        assert_eq!(
+            renderer.render_yaml("{$eval: 'double(x)'}", "x: 2").unwrap(),
+            "4\n"
+        );
+        assert_eq!(
+            *render_yaml("{$eval: x}", "[]").unwrap_err().kind(),
+            ErrorKind::InvalidContext
+        );
+        assert!(render_yaml("{$eval: x}", "{1: 2}").is_err());
+    }
+}
@@ -0,0 +1,144 @@
+//! Tests of the `json-e` command.
+
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output};
+
+/// A temporary directory, which is removed when dropped
+struct Directory(PathBuf);
+
+impl Deref for Directory {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Directory {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A fresh directory of input files for a test
+fn directory(test: &str) -> Directory {
+    let dir = Directory(env::temp_dir().join(format!("json-e-cli-{}-{}", test, process::id())));
+    let _ = fs::remove_dir_all(&*dir);
+    fs::create_dir_all(&*dir).unwrap();
+    fs::write(dir.join("template.json"), r#"{"a": "${x}", "b": {"$eval": "y"}}"#).unwrap();
+    fs::write(dir.join("context.json"), r#"{"x": 1, "y": [1]}"#).unwrap();
+    dir
+}
+
+fn json_e(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_json-e"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> Value {
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn renders() {
+    let dir = directory("renders");
+    let output = json_e(&dir, &["template.json", "context.json", "--set", "y=true"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), json!({"a": "1", "b": true}));
+    assert!(String::from_utf8(output.stdout).unwrap().contains("\n  \"a\""));
+
+    let output = json_e(&dir, &["--compact", "template.json", "context.json"]);
+    assert_eq!(output.stdout, b"{\"a\":\"1\",\"b\":[1]}\n");
+}
+
+#[test]
+fn exit_status() {
+    let dir = directory("exit-status");
+    fs::write(dir.join("invalid.json"), "{").unwrap();
+    fs::write(dir.join("array.json"), "[]").unwrap();
+
+    // the template refers to y, which is not in the context
+    assert_eq!(json_e(&dir, &["template.json", "--set", "x=1"]).status.code(), Some(1));
+    assert_eq!(json_e(&dir, &["--bogus"]).status.code(), Some(2));
+    assert_eq!(json_e(&dir, &["missing.json"]).status.code(), Some(3));
+    assert_eq!(json_e(&dir, &["invalid.json"]).status.code(), Some(3));
+    assert_eq!(json_e(&dir, &["template.json", "array.json"]).status.code(), Some(3));
+
+    // context keys are checked as the library checks them
+    fs::write(dir.join("keys.json"), r#"{"a-b": 1}"#).unwrap();
+    for args in [
+        &["template.json", "--set", "a-b=1"][..],
+        &["template.json", "keys.json"],
+    ] {
+        let output = json_e(&dir, args);
+        assert_eq!(output.status.code(), Some(3));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.starts_with("json-e: invalid context: top level keys of context must follow"),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn output_failure() {
+    let dir = directory("output-failure");
+    let output = Command::new(env!("CARGO_BIN_EXE_json-e"))
+        .current_dir(&*dir)
+        .args(["template.json", "context.json"])
+        .stdout(fs::File::create("/dev/full").unwrap())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("could not write output"), "{}", stderr);
+}
+
+#[test]
+fn validate() {
+    let dir = directory("validate");
+    fs::write(
+        dir.join("invalid.json"),
+        r#"{"$if": "x", "then": {"$bogus": 1}, "else": "${x +}"}"#,
+    )
+    .unwrap();
+
+    let output = json_e(&dir, &["validate", "template.json"]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = json_e(&dir, &["validate", "invalid.json"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("at /$if/then"), "{}", stderr);
+    assert!(stderr.contains("at /$if/else"), "{}", stderr);
+    assert!(stderr.contains("2 errors found"), "{}", stderr);
+}
+
+#[cfg(feature = "yaml")]
+#[test]
+fn yaml() {
+    let dir = directory("yaml");
+    fs::write(dir.join("template.yaml"), "a: ${x}\nb: {$eval: y}\n").unwrap();
+    fs::write(dir.join("context.yml"), "x: 1\ny: [1]\n").unwrap();
+    fs::write(dir.join("invalid.yaml"), "{1: x}").unwrap();
+
+    let output = json_e(&dir, &["--compact", "template.yaml", "context.yml"]);
+    assert_eq!(output.stdout, b"{\"a\":\"1\",\"b\":[1]}\n");
+
+    let output = json_e(&dir, &["--yaml", "template.json", "context.json"]);
+    assert_eq!(output.stdout, b"a: '1'\nb:\n- 1\n");
+
+    let output = json_e(&dir, &["template.yaml", "invalid.yaml"]);
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("is not a string"), "{}", stderr);
+}
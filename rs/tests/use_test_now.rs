@@ -0,0 +1,30 @@
+//! Tests of the deprecated `use_test_now`, which are in their own process as it sets the time for
+//! every renderer.
+
+#![allow(deprecated)]
+
+use json_e::{render, use_test_now, Renderer};
+use serde_json::json;
+use std::time::UNIX_EPOCH;
+
+#[test]
+fn use_test_now_sets_the_default_clock() {
+    let template = json!({"$eval": "now"});
+    let renderer = Renderer::new();
+    use_test_now();
+    assert_eq!(
+        render(&template, &json!({})).unwrap(),
+        json!("2017-01-19T16:27:20.974Z")
+    );
+    assert_eq!(
+        renderer.render(&template, &json!({})).unwrap(),
+        json!("2017-01-19T16:27:20.974Z")
+    );
+
+    // a renderer given a time keeps it
+    let renderer = Renderer::new().now(UNIX_EPOCH);
+    assert_eq!(
+        renderer.render(&template, &json!({})).unwrap(),
+        json!("1970-01-01T00:00:00.000Z")
+    );
+}